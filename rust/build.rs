@@ -0,0 +1,9 @@
+use std::time::SystemTime;
+
+fn main() {
+    // Exposed by /healthz so monitors can tell which build is deployed
+    println!(
+        "cargo:rustc-env=BUILD_TIME={}",
+        httpdate::fmt_http_date(SystemTime::now())
+    );
+}
@@ -0,0 +1,94 @@
+//! Structured access logging.
+//!
+//! When the `access_log_endpoint` entry of `dynserv-config` names a real-time
+//! log endpoint, one JSON line is written to it for every request once the
+//! response has been sent. Credentials are never logged: the key ID is a
+//! truncated SHA-256 of whatever credential the client presented. Events
+//! raised while a request is handled go to the same endpoint via [`event`].
+
+use crate::routes::CONFIG_STORE;
+use crate::session::Session;
+use crate::signed_url;
+use crate::stats::{Outcome, Sample};
+use fastly::config_store::ConfigStore;
+use fastly::log::Endpoint;
+use fastly::Request;
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Hex characters of the credential hash kept in the key ID.
+const KEY_ID_LEN: usize = 16;
+
+/// A stable, non-reversible identifier for the client's credential.
+pub fn key_id(req: &Request) -> Option<String> {
+    let credential = req
+        .get_url()
+        .query_pairs()
+        .find(|(k, _)| k == "key" || k == "token")
+        .map(|(_, v)| v.into_owned())
+        .or_else(|| req.get_header_str("Authorization").map(str::to_string))
+        .or_else(|| req.get_header_str("X-Proxy-Key-Id").map(str::to_string))?;
+    let digest = hex::encode(Sha256::digest(credential.as_bytes()));
+    Some(digest[..KEY_ID_LEN].to_string())
+}
+
+/// The key ID of the credential a request presented, or of the one its
+/// session or signed URL was issued to.
+pub fn credential_key_id(req: &Request, session: Option<&Session>) -> Option<String> {
+    key_id(req)
+        .or_else(|| session.and_then(|session| session.key_id(req)))
+        .or_else(|| signed_url::key_id(req))
+}
+
+fn endpoint() -> Option<String> {
+    ConfigStore::try_open(CONFIG_STORE)
+        .ok()
+        .and_then(|store| store.get("access_log_endpoint"))
+}
+
+/// Write an event line for something that happened while handling a request.
+pub fn event(value: &serde_json::Value) {
+    let Some(endpoint) = endpoint() else {
+        return;
+    };
+    if let Ok(mut endpoint) = Endpoint::try_from_name(&endpoint) {
+        let _ = writeln!(endpoint, "{}", value);
+    }
+}
+
+/// Write the request's log line, if an endpoint is configured.
+pub fn emit(request_id: &str, key_id: Option<&str>, sample: &Sample, outcome: &Outcome) {
+    let Some(endpoint) = endpoint() else {
+        return;
+    };
+    if log_fastly::Logger::builder()
+        .max_level(log::LevelFilter::Info)
+        .default_endpoint(endpoint.as_str())
+        .try_init()
+        .is_err()
+    {
+        return;
+    }
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let line = serde_json::json!({
+        "timestamp_ms": timestamp,
+        "request_id": request_id,
+        "key_id": key_id,
+        "tenant": sample.tenant,
+        "target_host": sample.origin,
+        "status": outcome.status.as_u16(),
+        "latency_ms": outcome.latency.as_millis() as u64,
+        "origin_latency_ms": sample.origin_latency.map(|latency| latency.as_millis() as u64),
+        "bytes_in": sample.bytes_in,
+        "bytes_out": outcome.bytes_out,
+        "rejection_reason": sample.error,
+        "tls_ja3": sample.fingerprint.ja3,
+        "tls_ja4": sample.fingerprint.ja4,
+    });
+    log::info!(target: endpoint.as_str(), "{}", line);
+}
@@ -0,0 +1,247 @@
+//! Operational endpoints under `/admin`.
+//!
+//! The admin API is authorized by its own key, the `admin_key` entry of the
+//! `dynserv-key` Config Store, sent in the [`KEY_HEADER`] header. Tenant
+//! credentials are never accepted, and without the entry the API is off.
+//!
+//! - `GET /admin/config`: the effective deployment settings.
+//! - `DELETE /admin/circuits/<host>`: close the origin's circuit breaker.
+//! - `POST /admin/purge?host=<host>`: purge everything cached from the origin.
+//! - `POST /admin/purge?url=<url>`: purge the one target URL.
+//! - `GET /admin/errors?limit=<n>`: the most recent failed or refused requests.
+//! - `GET /admin/audit?limit=<n>`: the most recent proxied destinations, for
+//!   a `key_id` or `tenant` if given.
+//! - `GET` or `PUT /admin/maintenance`: read or switch maintenance mode, with
+//!   a `{"enabled": true}` body to switch it on. Switching it off doesn't
+//!   lift maintenance mode set in the deployment settings.
+//!
+//! Every change is recorded in the audit trail.
+
+use crate::errors::{Code, Problem};
+use crate::{audit, auth, cache, circuit, config, destinations, maintenance, state, stats};
+use fastly::config_store::ConfigStore;
+use fastly::http::{Method, StatusCode};
+use fastly::{Request, Response};
+use serde::Deserialize;
+use url::Url;
+
+/// Header carrying the admin key.
+pub const KEY_HEADER: &str = "X-Admin-Key";
+
+/// Entries listed when the request doesn't ask for a number.
+const DEFAULT_LIMIT: u32 = 50;
+const MAX_LIMIT: u32 = 500;
+
+/// Whether a request is for the admin API.
+pub fn is_admin(req: &Request) -> bool {
+    let path = req.get_path();
+    path == "/admin" || path.starts_with("/admin/")
+}
+
+fn json(body: serde_json::Value) -> Response {
+    Response::from_status(StatusCode::OK)
+        .with_header("Content-Type", "application/json")
+        .with_header("Cache-Control", "no-store")
+        .with_body(serde_json::to_string_pretty(&body).unwrap_or_default())
+}
+
+fn query_param(req: &Request, name: &str) -> Option<String> {
+    req.get_url()
+        .query_pairs()
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v.into_owned())
+}
+
+/// The refusal for a request without the admin key, if it doesn't have it.
+fn refusal(req: &Request) -> Option<Response> {
+    let admin_key = ConfigStore::try_open("dynserv-key")
+        .ok()
+        .and_then(|store| store.get("admin_key"))
+        .filter(|key| !key.is_empty());
+    let Some(admin_key) = admin_key else {
+        return Some(
+            Problem::new(Code::AdminDisabled, "No admin key is configured").into_response(),
+        );
+    };
+    match req.get_header_str(KEY_HEADER) {
+        Some(key) if auth::constant_time_eq(key.as_bytes(), admin_key.as_bytes()) => None,
+        _ => Some(
+            Problem::new(Code::InvalidCredentials, "Invalid or missing admin key").into_response(),
+        ),
+    }
+}
+
+fn no_state_store() -> Response {
+    Problem::new(
+        Code::StateUnavailable,
+        "KV store 'dynserv-state' is not linked",
+    )
+    .into_response()
+}
+
+/// Answer a request for the admin API.
+pub fn respond(req: &mut Request, request_id: &str) -> Response {
+    if let Some(refusal) = refusal(req) {
+        audit::record(request_id, "admin_auth_failed", "admin", req.get_path());
+        return refusal;
+    }
+    let path = req.get_path().trim_end_matches('/').to_string();
+    let method = req.get_method().clone();
+    match (method, path.as_str()) {
+        (Method::GET, "/admin/config") => {
+            let config = config::current();
+            json(serde_json::json!({
+                "proxy": config,
+                "maintenance": config.maintenance.is_on(),
+            }))
+        }
+        (Method::DELETE, path) if path.starts_with("/admin/circuits/") => {
+            flush_circuit(&path["/admin/circuits/".len()..], request_id)
+        }
+        (Method::POST, "/admin/purge") => purge(req, request_id),
+        (Method::GET, "/admin/errors") => recent_errors(req),
+        (Method::GET, "/admin/audit") => recent_destinations(req),
+        (Method::GET, "/admin/maintenance") => maintenance_status(),
+        (Method::PUT, "/admin/maintenance") => set_maintenance(req, request_id),
+        _ => Problem::new(
+            Code::NotFound,
+            "No such admin endpoint, or it doesn't take this method",
+        )
+        .into_response(),
+    }
+}
+
+fn flush_circuit(host: &str, request_id: &str) -> Response {
+    if host.is_empty() {
+        return Problem::new(Code::InvalidParameter, "No host given").into_response();
+    }
+    let Some(store) = state::open() else {
+        return no_state_store();
+    };
+    let flushed = circuit::reset(&store, host);
+    audit::record(request_id, "admin_circuit_flushed", "admin", host);
+    json(serde_json::json!({"host": host, "flushed": flushed}))
+}
+
+fn purge(req: &Request, request_id: &str) -> Response {
+    if let Some(url) = query_param(req, "url") {
+        return purge_url(&url, request_id);
+    }
+    let Some(host) = query_param(req, "host").filter(|host| !host.is_empty()) else {
+        return Problem::new(
+            Code::InvalidParameter,
+            "Name the origin to purge with the 'host' parameter, or the object with 'url'",
+        )
+        .into_response();
+    };
+    if let Err(e) = cache::purge_host(&host) {
+        return Problem::new(Code::PurgeFailed, e).into_response();
+    }
+    audit::record(request_id, "admin_cache_purged", "admin", &host);
+    json(serde_json::json!({"host": host, "purged": true}))
+}
+
+fn purge_url(url: &str, request_id: &str) -> Response {
+    let target = match Url::parse(url) {
+        Ok(target) => target,
+        Err(e) => {
+            return Problem::new(Code::InvalidUrl, e.to_string())
+                .with("parameter", "url")
+                .into_response();
+        }
+    };
+    let keys = match cache::purge_url(&target) {
+        Ok(keys) => keys,
+        Err(e) => return Problem::new(Code::PurgeFailed, e).into_response(),
+    };
+    audit::record(request_id, "admin_url_purged", "admin", target.as_str());
+    json(serde_json::json!({"url": target.as_str(), "purged": true, "cache_keys": keys}))
+}
+
+/// The number of entries to list, or `None` if the request asks for too many or too few.
+fn limit(req: &Request) -> Option<u32> {
+    match query_param(req, "limit").map(|limit| limit.parse::<u32>()) {
+        None => Some(DEFAULT_LIMIT),
+        Some(Ok(limit)) if (1..=MAX_LIMIT).contains(&limit) => Some(limit),
+        Some(_) => None,
+    }
+}
+
+fn invalid_limit() -> Response {
+    Problem::new(
+        Code::InvalidParameter,
+        format!("'limit' must be between 1 and {}", MAX_LIMIT),
+    )
+    .into_response()
+}
+
+fn recent_errors(req: &Request) -> Response {
+    let Some(limit) = limit(req) else {
+        return invalid_limit();
+    };
+    let Some(store) = state::open() else {
+        return no_state_store();
+    };
+    json(serde_json::json!({"errors": stats::recent_errors(&store, limit)}))
+}
+
+fn recent_destinations(req: &Request) -> Response {
+    let Some(limit) = limit(req) else {
+        return invalid_limit();
+    };
+    let Some(store) = state::open() else {
+        return no_state_store();
+    };
+    let filter = destinations::Filter {
+        key_id: query_param(req, "key_id"),
+        tenant: query_param(req, "tenant"),
+    };
+    let records = destinations::recent(&store, &filter, limit as usize);
+    json(serde_json::json!({"destinations": records}))
+}
+
+#[derive(Deserialize)]
+struct MaintenanceChange {
+    enabled: bool,
+}
+
+fn set_maintenance(req: &mut Request, request_id: &str) -> Response {
+    let change: MaintenanceChange = match serde_json::from_slice(&req.take_body_bytes()) {
+        Ok(change) => change,
+        Err(e) => {
+            return Problem::new(
+                Code::InvalidParameter,
+                format!("Expected {{\"enabled\": true|false}}: {}", e),
+            )
+            .into_response();
+        }
+    };
+    let Some(store) = state::open() else {
+        return no_state_store();
+    };
+    if !maintenance::switch(&store, change.enabled) {
+        return Problem::new(
+            Code::StateWriteFailed,
+            "The state store didn't accept the change",
+        )
+        .into_response();
+    }
+    let event = if change.enabled {
+        "admin_maintenance_on"
+    } else {
+        "admin_maintenance_off"
+    };
+    audit::record(request_id, event, "admin", req.get_path());
+    maintenance_status()
+}
+
+/// Whether maintenance mode is on, and which of the switches turned it on.
+fn maintenance_status() -> Response {
+    let configured = config::current().maintenance.enabled;
+    let switched_on = maintenance::is_switched_on();
+    json(serde_json::json!({
+        "enabled": configured || switched_on,
+        "configured": configured,
+        "switched_on": switched_on,
+    }))
+}
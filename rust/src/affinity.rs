@@ -0,0 +1,160 @@
+//! Sticky split and canary assignments for browsers.
+//!
+//! With a tenant's `affinity` set, the origin a client is given by an A/B
+//! [`split`](crate::split) or a [`canary`](crate::canary) rollout is
+//! remembered in a signed cookie, and later requests carrying it get the
+//! same one, so a session on a stateful origin isn't broken by landing on
+//! the other. A split's own bucketing, or a canary's sampling, only decides
+//! for clients without the cookie. A canary that's tripped or stopped still
+//! gets no traffic. The cookie is signed with HMAC-SHA256 using a secret from
+//! `dynserv-secrets`, names the tenant in its signature, and is never
+//! forwarded to origins.
+
+use crate::secrets;
+use crate::split::Variant;
+use fastly::Request;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Affinity {
+    /// Name of the secret the cookie is signed with.
+    pub secret: String,
+    #[serde(default = "default_ttl_secs")]
+    pub ttl_secs: u64,
+    #[serde(default = "default_cookie_name")]
+    pub cookie_name: String,
+}
+
+fn default_ttl_secs() -> u64 {
+    86400
+}
+
+fn default_cookie_name() -> String {
+    "dynserv_affinity".to_string()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Which origins a client was given.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Assignment {
+    pub split: Option<Variant>,
+    /// Whether the client gets hosts' canaries rather than their primaries.
+    pub canary: Option<bool>,
+}
+
+impl Assignment {
+    /// `<split>.<canary>`, each `-` when unassigned.
+    fn encode(self) -> String {
+        let split = self.split.map_or("-", Variant::as_str);
+        let canary = match self.canary {
+            Some(true) => "1",
+            Some(false) => "0",
+            None => "-",
+        };
+        format!("{}.{}", split, canary)
+    }
+
+    fn decode(split: &str, canary: &str) -> Option<Self> {
+        let split = match split {
+            "-" => None,
+            split => Some(Variant::parse(split)?),
+        };
+        let canary = match canary {
+            "1" => Some(true),
+            "0" => Some(false),
+            "-" => None,
+            _ => return None,
+        };
+        Some(Self { split, canary })
+    }
+
+    /// This assignment, keeping what `earlier` assigned for anything it doesn't.
+    pub fn or(self, earlier: Assignment) -> Self {
+        Self {
+            split: self.split.or(earlier.split),
+            canary: self.canary.or(earlier.canary),
+        }
+    }
+}
+
+impl Affinity {
+    fn mac(&self, tenant: &str, payload: &str) -> Option<Hmac<Sha256>> {
+        let key = secrets::read(&self.secret).ok()?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(&key).ok()?;
+        mac.update(tenant.as_bytes());
+        mac.update(b"|");
+        mac.update(payload.as_bytes());
+        Some(mac)
+    }
+
+    fn cookie<'a>(&self, req: &'a Request) -> Option<&'a str> {
+        req.get_header_str("Cookie")?
+            .split(';')
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(name, _)| *name == self.cookie_name)
+            .map(|(_, value)| value)
+    }
+
+    /// The assignment the request's cookie carries, if it's genuine, unexpired
+    /// and the tenant's. Cookies are `<split>.<canary>.<expiry>.<signature>`.
+    pub fn read(&self, req: &Request, tenant: &str) -> Assignment {
+        self.verified(req, tenant).unwrap_or_default()
+    }
+
+    fn verified(&self, req: &Request, tenant: &str) -> Option<Assignment> {
+        let (payload, signature) = self.cookie(req)?.rsplit_once('.')?;
+        let signature = hex::decode(signature).ok()?;
+        let mut fields = payload.split('.');
+        let (Some(split), Some(canary), Some(expires), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return None;
+        };
+        if now() >= expires.parse::<u64>().ok()? {
+            return None;
+        }
+        self.mac(tenant, payload)?.verify_slice(&signature).ok()?;
+        Assignment::decode(split, canary)
+    }
+
+    /// A `Set-Cookie` value for the client's assignment, if it isn't the one
+    /// the request carried.
+    pub fn issue(&self, tenant: &str, assigned: Assignment, carried: Assignment) -> Option<String> {
+        if assigned == carried || assigned == Assignment::default() {
+            return None;
+        }
+        let payload = format!("{}.{}", assigned.encode(), now() + self.ttl_secs);
+        let signature = hex::encode(self.mac(tenant, &payload)?.finalize().into_bytes());
+        Some(format!(
+            "{}={}.{}; Max-Age={}; Path=/; Secure; HttpOnly; SameSite=Lax",
+            self.cookie_name, payload, signature, self.ttl_secs
+        ))
+    }
+
+    /// Remove the affinity cookie so it doesn't reach the origin.
+    pub fn strip(&self, req: &mut Request) {
+        let Some(cookies) = req.get_header_str("Cookie") else {
+            return;
+        };
+        let kept: Vec<&str> = cookies
+            .split(';')
+            .map(str::trim)
+            .filter(|pair| pair.split_once('=').map(|(name, _)| name) != Some(&self.cookie_name))
+            .collect();
+        if kept.is_empty() {
+            req.remove_header("Cookie");
+        } else {
+            let kept = kept.join("; ");
+            req.set_header("Cookie", kept);
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! Audit trail of security-relevant events, exported to object storage.
+//!
+//! Events are written to the state store as individual entries. After a
+//! response has been sent, [`export_if_due`] periodically gathers a batch of
+//! them into an NDJSON object, PUTs it to the configured object-storage URL
+//! (signed with a signing profile) and removes the exported entries, so the
+//! history outlives Fastly's own log retention.
+
+use crate::{backend, limits, routes, signing, ssrf, state};
+use fastly::config_store::ConfigStore;
+use fastly::Request;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::Url;
+
+const EVENT_PREFIX: &str = "audit.event.";
+const LAST_EXPORT_KEY: &str = "audit.last_export";
+
+/// Unexported events are kept this long in case the export target is down.
+const EVENT_TTL: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// Maximum number of events written to a single exported object.
+const EXPORT_BATCH: u32 = 100;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub timestamp: u64,
+    pub request_id: String,
+    pub event: String,
+    pub tenant: String,
+    pub detail: String,
+}
+
+/// Where and how often events are exported, from the `audit_export` entry.
+#[derive(Debug, Clone, Deserialize)]
+struct ExportConfig {
+    /// Object-storage URL prefix; each batch is written beneath it.
+    url: String,
+    #[serde(default = "default_interval_secs")]
+    interval_secs: u64,
+    /// Signing profile applied to the upload.
+    signing_profile: Option<String>,
+}
+
+fn default_interval_secs() -> u64 {
+    300
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Record an event. Without the state store events are dropped.
+pub fn record(request_id: &str, event: &str, tenant: &str, detail: &str) {
+    let Some(store) = state::open() else {
+        return;
+    };
+    let event = AuditEvent {
+        timestamp: now(),
+        request_id: request_id.to_string(),
+        event: event.to_string(),
+        tenant: tenant.to_string(),
+        detail: detail.to_string(),
+    };
+    let key = format!("{}{}.{}", EVENT_PREFIX, event.timestamp, event.request_id);
+    state::put(&store, &key, &event, Some(EVENT_TTL));
+}
+
+/// Export a batch of events if the export interval has elapsed.
+pub fn export_if_due(request_id: &str) {
+    let Some(config) = ConfigStore::try_open(routes::CONFIG_STORE)
+        .ok()
+        .and_then(|store| store.get("audit_export"))
+        .and_then(|json| serde_json::from_str::<ExportConfig>(&json).ok())
+    else {
+        return;
+    };
+    let Some(store) = state::open() else {
+        return;
+    };
+    let now = now();
+    let last_export = state::get::<u64>(&store, LAST_EXPORT_KEY).unwrap_or(0);
+    if now < last_export + config.interval_secs {
+        return;
+    }
+    // Claim this export window first so concurrent requests don't duplicate it
+    state::put(&store, LAST_EXPORT_KEY, &now, None);
+
+    let Ok(page) = store
+        .build_list()
+        .prefix(EVENT_PREFIX)
+        .limit(EXPORT_BATCH)
+        .execute()
+    else {
+        return;
+    };
+    let keys = page.into_keys();
+    let mut batch = String::new();
+    for key in &keys {
+        if let Some(event) = state::get::<AuditEvent>(&store, key) {
+            if let Ok(line) = serde_json::to_string(&event) {
+                batch.push_str(&line);
+                batch.push('\n');
+            }
+        }
+    }
+    if batch.is_empty() {
+        return;
+    }
+
+    if upload(&config, &format!("{}-{}.ndjson", now, request_id), batch) {
+        for key in &keys {
+            let _ = store.delete(key);
+        }
+    }
+}
+
+fn upload(config: &ExportConfig, object_name: &str, body: String) -> bool {
+    let base = if config.url.ends_with('/') {
+        config.url.clone()
+    } else {
+        format!("{}/", config.url)
+    };
+    let Some(target) = Url::parse(&base)
+        .and_then(|base| base.join(object_name))
+        .ok()
+        .and_then(|url| ssrf::validate(url).ok())
+    else {
+        return false;
+    };
+    if limits::reserve_request().is_err() {
+        return false;
+    }
+    let Ok(backend) = backend::create(&target.hostname, target.port) else {
+        return false;
+    };
+
+    let mut req = Request::put(target.url.clone())
+        .with_header("Host", &target.hostname)
+        .with_header("Content-Type", "application/x-ndjson")
+        .with_pass(true);
+    if let Some(name) = &config.signing_profile {
+        let signed = signing::load(name)
+            .and_then(|profile| signing::sign(&mut req, body.as_bytes(), &profile));
+        if signed.is_err() {
+            return false;
+        }
+    }
+    req.set_body(body);
+    req.send(backend.name())
+        .is_ok_and(|resp| resp.get_status().is_success())
+}
@@ -0,0 +1,376 @@
+//! Client authentication.
+//!
+//! Each scheme implements [`AuthProvider`]. The providers enabled for a
+//! deployment are listed, in order, in the `auth` entry of `dynserv-config`;
+//! without it only the static key in `dynserv-key` is accepted. The first
+//! provider to accept the request's credentials decides its tenant.
+
+use crate::errors::{Code, Problem};
+use crate::routes::CONFIG_STORE;
+use crate::session::Session;
+use crate::signed_url::SignedUrls;
+use crate::{errors, policy, secrets, tenant};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use fastly::config_store::ConfigStore;
+use fastly::{Request, Response};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use url::form_urlencoded;
+
+/// Who a request was authenticated as.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub tenant: String,
+    /// Name of the provider that accepted the credentials.
+    pub provider: &'static str,
+}
+
+#[derive(Debug, Clone)]
+pub enum AuthError {
+    /// The provider's credentials aren't present; try the next provider.
+    NoCredentials,
+    /// Credentials were present but not accepted.
+    Invalid,
+    /// The provider is misconfigured.
+    Config(String),
+}
+
+impl AuthError {
+    pub fn into_response(self) -> Response {
+        match self {
+            AuthError::NoCredentials | AuthError::Invalid => {
+                Problem::new(Code::InvalidCredentials, "Invalid or missing API key").into_response()
+            }
+            AuthError::Config(message) => errors::config(&message),
+        }
+    }
+}
+
+pub trait AuthProvider {
+    fn name(&self) -> &'static str;
+    fn authenticate(&self, req: &Request) -> Result<String, AuthError>;
+}
+
+/// Provider settings as stored in the `auth` entry.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "provider", rename_all = "snake_case")]
+pub enum ProviderConfig {
+    /// The single key in the `dynserv-key` Config Store, for the default tenant.
+    Static,
+    /// Per-tenant keys held in the Secret Store, as tenant ID → secret name.
+    SecretStore { tenants: HashMap<String, String> },
+    /// HMAC-SHA256 request signatures, with tenant ID → secret name.
+    Hmac {
+        tenants: HashMap<String, String>,
+        #[serde(default = "default_max_skew_secs")]
+        max_skew_secs: u64,
+    },
+    /// HS256 bearer tokens.
+    Jwt {
+        /// Name of the secret holding the signing key.
+        secret: String,
+        /// Claim holding the tenant ID.
+        #[serde(default = "default_tenant_claim")]
+        tenant_claim: String,
+        issuer: Option<String>,
+        audience: Option<String>,
+    },
+    /// Signed cookies issued to browsers after another provider succeeds.
+    Session(Session),
+    /// Short-lived links minted with `/sign`.
+    SignedUrl(SignedUrls),
+}
+
+fn default_max_skew_secs() -> u64 {
+    300
+}
+
+fn default_tenant_claim() -> String {
+    "sub".to_string()
+}
+
+impl ProviderConfig {
+    fn into_provider(self) -> Box<dyn AuthProvider> {
+        match self {
+            ProviderConfig::Static => Box::new(StaticKey),
+            ProviderConfig::SecretStore { tenants } => Box::new(SecretStoreKeys { tenants }),
+            ProviderConfig::Hmac {
+                tenants,
+                max_skew_secs,
+            } => Box::new(HmacSignature {
+                tenants,
+                max_skew_secs,
+            }),
+            ProviderConfig::Jwt {
+                secret,
+                tenant_claim,
+                issuer,
+                audience,
+            } => Box::new(Jwt {
+                secret,
+                tenant_claim,
+                issuer,
+                audience,
+            }),
+            ProviderConfig::Session(session) => Box::new(session),
+            ProviderConfig::SignedUrl(signed_urls) => Box::new(signed_urls),
+        }
+    }
+}
+
+fn configs() -> Result<Vec<ProviderConfig>, AuthError> {
+    let entry = ConfigStore::try_open(CONFIG_STORE)
+        .ok()
+        .and_then(|store| store.get("auth"));
+    match entry {
+        Some(json) => serde_json::from_str::<Vec<ProviderConfig>>(&json)
+            .map_err(|e| AuthError::Config(format!("Invalid 'auth' entry: {}", e))),
+        None => Ok(vec![ProviderConfig::Static]),
+    }
+}
+
+/// The providers enabled for this deployment.
+pub fn providers() -> Result<Vec<Box<dyn AuthProvider>>, AuthError> {
+    Ok(configs()?
+        .into_iter()
+        .map(ProviderConfig::into_provider)
+        .collect())
+}
+
+/// Session cookie settings, if the `session` provider is enabled.
+pub fn session() -> Option<Session> {
+    configs().ok()?.into_iter().find_map(|config| match config {
+        ProviderConfig::Session(session) => Some(session),
+        _ => None,
+    })
+}
+
+/// URL signing settings, if the `signed_url` provider is enabled.
+pub fn signed_urls() -> Option<SignedUrls> {
+    configs().ok()?.into_iter().find_map(|config| match config {
+        ProviderConfig::SignedUrl(signed_urls) => Some(signed_urls),
+        _ => None,
+    })
+}
+
+/// Authenticate a request against the deployment's providers.
+pub fn authenticate(req: &Request) -> Result<Identity, AuthError> {
+    let mut outcome = AuthError::NoCredentials;
+    for provider in providers()? {
+        match provider.authenticate(req) {
+            Ok(tenant) => {
+                return Ok(Identity {
+                    tenant,
+                    provider: provider.name(),
+                })
+            }
+            // Several providers may read the same credential, so keep looking
+            Err(AuthError::NoCredentials) => {}
+            Err(AuthError::Invalid) => outcome = AuthError::Invalid,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(outcome)
+}
+
+fn query_param(req: &Request, name: &str) -> Option<String> {
+    req.get_url()
+        .query_pairs()
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v.into_owned())
+}
+
+/// Compare secrets without leaking how much of them matched.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+struct StaticKey;
+
+impl AuthProvider for StaticKey {
+    fn name(&self) -> &'static str {
+        "static"
+    }
+
+    fn authenticate(&self, req: &Request) -> Result<String, AuthError> {
+        // Validate API key from config store (required)
+        let valid_key = match ConfigStore::try_open("dynserv-key") {
+            Ok(store) => store.get("key").ok_or_else(|| {
+                AuthError::Config("API key not found in config store".to_string())
+            })?,
+            Err(_) => return Err(AuthError::Config(
+                "Config store 'dynserv-key' not available. Ensure it is linked to this service."
+                    .to_string(),
+            )),
+        };
+        let key = query_param(req, "key").ok_or(AuthError::NoCredentials)?;
+        if constant_time_eq(key.as_bytes(), valid_key.as_bytes()) {
+            Ok(tenant::DEFAULT.to_string())
+        } else {
+            Err(AuthError::Invalid)
+        }
+    }
+}
+
+struct SecretStoreKeys {
+    tenants: HashMap<String, String>,
+}
+
+impl AuthProvider for SecretStoreKeys {
+    fn name(&self) -> &'static str {
+        "secret_store"
+    }
+
+    /// Keys are sent as `key=<tenant>.<key>` so only one secret is read.
+    fn authenticate(&self, req: &Request) -> Result<String, AuthError> {
+        let param = query_param(req, "key").ok_or(AuthError::NoCredentials)?;
+        let (tenant, key) = param.split_once('.').ok_or(AuthError::NoCredentials)?;
+        let secret = self.tenants.get(tenant).ok_or(AuthError::NoCredentials)?;
+        let expected = secrets::read(secret).map_err(AuthError::Config)?;
+        if constant_time_eq(key.as_bytes(), &expected) {
+            Ok(tenant.to_string())
+        } else {
+            Err(AuthError::Invalid)
+        }
+    }
+}
+
+struct HmacSignature {
+    tenants: HashMap<String, String>,
+    max_skew_secs: u64,
+}
+
+/// What an HMAC signature covers: the timestamp, the method, and the
+/// request's canonical path with all of its query parameters, sorted.
+fn hmac_input(timestamp: &str, req: &Request) -> String {
+    let url = req.get_url();
+    let mut pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+    pairs.sort();
+    let query = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(pairs)
+        .finish();
+    let path = policy::canonical_path(url);
+    format!("{}\n{}\n{}?{}", timestamp, req.get_method_str(), path, query)
+}
+
+impl AuthProvider for HmacSignature {
+    fn name(&self) -> &'static str {
+        "hmac"
+    }
+
+    /// Clients send `X-Proxy-Key-Id`, `X-Proxy-Timestamp` and `X-Proxy-Signature`,
+    /// the hex HMAC-SHA256 of [`hmac_input`], so no parameter can be changed.
+    fn authenticate(&self, req: &Request) -> Result<String, AuthError> {
+        let (Some(tenant), Some(timestamp), Some(signature)) = (
+            req.get_header_str("X-Proxy-Key-Id"),
+            req.get_header_str("X-Proxy-Timestamp"),
+            req.get_header_str("X-Proxy-Signature"),
+        ) else {
+            return Err(AuthError::NoCredentials);
+        };
+        let secret = self.tenants.get(tenant).ok_or(AuthError::Invalid)?;
+        let signed_at: u64 = timestamp.parse().map_err(|_| AuthError::Invalid)?;
+        if now().abs_diff(signed_at) > self.max_skew_secs {
+            return Err(AuthError::Invalid);
+        }
+        let signature = hex::decode(signature).map_err(|_| AuthError::Invalid)?;
+
+        let key = secrets::read(secret).map_err(AuthError::Config)?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(&key)
+            .map_err(|e| AuthError::Config(format!("Invalid HMAC key: {}", e)))?;
+        mac.update(hmac_input(timestamp, req).as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| AuthError::Invalid)?;
+        Ok(tenant.to_string())
+    }
+}
+
+struct Jwt {
+    secret: String,
+    tenant_claim: String,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+}
+
+impl AuthProvider for Jwt {
+    fn name(&self) -> &'static str {
+        "jwt"
+    }
+
+    /// Tokens are read from `Authorization: Bearer` or the `token` parameter.
+    fn authenticate(&self, req: &Request) -> Result<String, AuthError> {
+        let token = req
+            .get_header_str("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::to_string)
+            .or_else(|| query_param(req, "token"))
+            .ok_or(AuthError::NoCredentials)?;
+        let mut parts = token.split('.');
+        let (Some(header), Some(payload), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(AuthError::Invalid);
+        };
+
+        let decode = |part: &str| URL_SAFE_NO_PAD.decode(part).map_err(|_| AuthError::Invalid);
+        let header: JwtHeader =
+            serde_json::from_slice(&decode(header)?).map_err(|_| AuthError::Invalid)?;
+        if header.alg != "HS256" {
+            return Err(AuthError::Invalid);
+        }
+        let key = secrets::read(&self.secret).map_err(AuthError::Config)?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(&key)
+            .map_err(|e| AuthError::Config(format!("Invalid JWT key: {}", e)))?;
+        let (signing_input, _) = token.rsplit_once('.').ok_or(AuthError::Invalid)?;
+        mac.update(signing_input.as_bytes());
+        mac.verify_slice(&decode(signature)?)
+            .map_err(|_| AuthError::Invalid)?;
+
+        let claims: serde_json::Value =
+            serde_json::from_slice(&decode(payload)?).map_err(|_| AuthError::Invalid)?;
+        let now = now();
+        if claims["exp"].as_u64().is_some_and(|exp| now >= exp)
+            || claims["nbf"].as_u64().is_some_and(|nbf| now < nbf)
+        {
+            return Err(AuthError::Invalid);
+        }
+        if let Some(issuer) = &self.issuer {
+            if claims["iss"].as_str() != Some(issuer.as_str()) {
+                return Err(AuthError::Invalid);
+            }
+        }
+        if let Some(audience) = &self.audience {
+            let matches = match &claims["aud"] {
+                serde_json::Value::String(aud) => aud == audience,
+                serde_json::Value::Array(auds) => auds.iter().any(|aud| aud == audience.as_str()),
+                _ => false,
+            };
+            if !matches {
+                return Err(AuthError::Invalid);
+            }
+        }
+        claims[self.tenant_claim.as_str()]
+            .as_str()
+            .map(str::to_string)
+            .ok_or(AuthError::Invalid)
+    }
+}
@@ -0,0 +1,175 @@
+//! Dynamic backend construction.
+
+use crate::timeouts::Timeouts;
+use crate::{deadline, pooling, tls};
+use fastly::backend::{Backend, BackendBuilder, BackendCreationError};
+use fastly::experimental::GrpcBackend;
+use fastly_shared::FastlyStatus;
+use std::time::Duration;
+
+/// Create a unique backend name based on host and port.
+///
+/// Backend names must be alphanumeric with underscores/hyphens.
+pub fn name_for(hostname: &str, port: u16) -> String {
+    format!("dyn_{}_{}", sanitize(hostname), port)
+}
+
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// Where a backend connects, the names its TLS handshake uses and how long
+/// its fetches may take.
+#[derive(Debug, Clone, Copy)]
+pub struct Endpoint<'a> {
+    pub hostname: &'a str,
+    pub port: u16,
+    /// Sent as the SNI hostname.
+    pub sni: &'a str,
+    /// The hostname the origin's certificate must be valid for.
+    pub verify_host: &'a str,
+    /// The `Host` requests through the backend are sent with.
+    pub host_header: &'a str,
+    /// The host whose `origin_tls` settings the backend gets.
+    pub tls_host: &'a str,
+    pub timeouts: Timeouts,
+    /// Whether the backend speaks HTTP/2, as gRPC backends do.
+    pub http2: bool,
+}
+
+impl<'a> Endpoint<'a> {
+    /// An endpoint whose TLS names are its own hostname.
+    pub fn new(hostname: &'a str, port: u16) -> Self {
+        Self {
+            hostname,
+            port,
+            sni: hostname,
+            verify_host: hostname,
+            host_header: hostname,
+            tls_host: hostname,
+            timeouts: Timeouts::default(),
+            http2: false,
+        }
+    }
+
+    /// Whether either TLS name differs from the hostname.
+    pub fn is_fronted(&self) -> bool {
+        self.sni != self.hostname || self.verify_host != self.hostname
+    }
+
+    /// The backend's name, which includes any TLS names, `Host`, timeouts or
+    /// protocol that differ from the defaults.
+    pub fn name(&self) -> String {
+        let mut name = name_for(self.hostname, self.port);
+        if self.is_fronted() {
+            name.push_str(&format!(
+                "_sni_{}_{}",
+                sanitize(self.sni),
+                sanitize(self.verify_host)
+            ));
+        }
+        if self.host_header != self.hostname {
+            name.push_str(&format!("_host_{}", sanitize(self.host_header)));
+        }
+        if self.timeouts != Timeouts::default() {
+            name.push_str(&format!(
+                "_t{}_{}_{}",
+                self.timeouts.connect.as_millis(),
+                self.timeouts.first_byte.as_millis(),
+                self.timeouts.between_bytes.as_millis()
+            ));
+        }
+        if self.http2 {
+            name.push_str("_h2");
+        }
+        name
+    }
+}
+
+/// Create (or reuse, if this instance already registered it) a TLS backend for the host.
+pub fn create(hostname: &str, port: u16) -> Result<Backend, BackendCreationError> {
+    create_endpoint(&Endpoint::new(hostname, port))
+}
+
+/// Create a TLS backend for an endpoint, which may use other TLS names or timeouts.
+///
+/// The timeouts are cut to what's left of the request's deadline.
+pub fn create_endpoint(endpoint: &Endpoint) -> Result<Backend, BackendCreationError> {
+    let endpoint = &Endpoint {
+        timeouts: deadline::bound_timeouts(endpoint.timeouts),
+        ..*endpoint
+    };
+    let name = with_settings(endpoint.name(), endpoint.tls_host);
+    let builder = BackendBuilder::new(&name, format!("{}:{}", endpoint.hostname, endpoint.port))
+        .connect_timeout(endpoint.timeouts.connect)
+        .first_byte_timeout(endpoint.timeouts.first_byte)
+        .between_bytes_timeout(endpoint.timeouts.between_bytes)
+        .for_grpc(endpoint.http2);
+    finish(builder, &name, endpoint)
+}
+
+/// Create a TLS backend for an endpoint whose fetches give up after `timeout`.
+pub fn create_bounded(
+    endpoint: &Endpoint,
+    timeout: Duration,
+) -> Result<Backend, BackendCreationError> {
+    let timeout = deadline::bound(timeout);
+    let name = with_settings(format!("{}_bounded", endpoint.name()), endpoint.tls_host);
+    let builder = BackendBuilder::new(&name, format!("{}:{}", endpoint.hostname, endpoint.port))
+        .connect_timeout(Timeouts::default().connect.min(timeout))
+        .first_byte_timeout(timeout)
+        .between_bytes_timeout(timeout);
+    finish(builder, &name, endpoint)
+}
+
+/// Create a short-timeout TLS backend for diagnostic probes of the host.
+pub fn create_probe(
+    hostname: &str,
+    port: u16,
+    timeout: Duration,
+) -> Result<Backend, BackendCreationError> {
+    let name = with_settings(format!("{}_probe", name_for(hostname, port)), hostname);
+    let builder = BackendBuilder::new(&name, format!("{}:{}", hostname, port))
+        .connect_timeout(timeout)
+        .first_byte_timeout(timeout)
+        .between_bytes_timeout(timeout);
+    finish(builder, &name, &Endpoint::new(hostname, port))
+}
+
+/// Backends with the tenant's TLS settings are named apart, so one built
+/// before the tenant was known is never reused in their place.
+fn with_settings(name: String, hostname: &str) -> String {
+    match tls::settings_for(hostname) {
+        Some(_) => format!("{}_tls", name),
+        None => name,
+    }
+}
+
+/// Finish a TLS backend, applying the tenant's connection settings and its
+/// TLS settings for the endpoint's TLS host.
+fn finish(
+    builder: BackendBuilder,
+    name: &str,
+    endpoint: &Endpoint,
+) -> Result<Backend, BackendCreationError> {
+    let mut builder = builder
+        .override_host(endpoint.host_header)
+        .enable_ssl()
+        .sni_hostname(endpoint.sni)
+        .check_certificate(endpoint.verify_host);
+    builder = pooling::apply(builder);
+    if let Some(settings) = tls::settings_for(endpoint.tls_host) {
+        builder = settings.apply(builder).map_err(|message| {
+            tls::note_failure(endpoint.tls_host, &message);
+            BackendCreationError::HostError(FastlyStatus::INVAL)
+        })?;
+    }
+    match builder.finish() {
+        Err(BackendCreationError::NameInUse) => {
+            Backend::from_name(name).map_err(|_| BackendCreationError::NameInUse)
+        }
+        other => other,
+    }
+}
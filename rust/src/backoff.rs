@@ -0,0 +1,79 @@
+//! Local fast-fail while an origin is throttling.
+//!
+//! When a tenant or route enables `shield_retry_after` and the origin
+//! answers 429 or 503 with `Retry-After`, the backoff window is stored per
+//! destination host in the state store. Until it passes, shielded requests
+//! for that host are answered at the edge with our own 429 and the remaining
+//! `Retry-After`, so the proxy doesn't add to the pressure on a rate-limited
+//! origin or risk its standing with it.
+
+use crate::errors::{Code, Problem};
+use crate::state;
+use fastly::http::StatusCode;
+use fastly::kv_store::KVStore;
+use fastly::Response;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Longest backoff honoured, whatever the origin asks for.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Backoff {
+    until: u64,
+    status: u16,
+}
+
+fn key_for(host: &str) -> String {
+    format!("backoff.{}", host)
+}
+
+/// Seconds to wait from a `Retry-After` value: delta-seconds or an HTTP date.
+pub fn retry_after_secs(value: &str, now: u64) -> Option<u64> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(secs);
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    let at = date.duration_since(UNIX_EPOCH).ok()?.as_secs();
+    Some(at.saturating_sub(now))
+}
+
+/// A locally generated response if `host` is still in a backoff window.
+pub fn check(store: &KVStore, host: &str, now: u64, target: &str) -> Option<Response> {
+    let backoff = state::get::<Backoff>(store, &key_for(host))?;
+    if now >= backoff.until {
+        return None;
+    }
+    let detail = "The origin asked clients to back off; retry after the Retry-After delay";
+    Some(
+        Problem::new(Code::OriginBackoff, detail)
+            .with("target", target)
+            .with("origin_status", backoff.status)
+            .into_response()
+            .with_header("Retry-After", (backoff.until - now).to_string()),
+    )
+}
+
+/// Start a backoff window if the origin response asks for one.
+pub fn record(store: &KVStore, host: &str, resp: &Response, now: u64) {
+    let status = resp.get_status();
+    if status != StatusCode::TOO_MANY_REQUESTS && status != StatusCode::SERVICE_UNAVAILABLE {
+        return;
+    }
+    let Some(secs) = resp
+        .get_header_str("Retry-After")
+        .and_then(|value| retry_after_secs(value, now))
+        .map(|secs| secs.min(MAX_BACKOFF.as_secs()))
+        .filter(|secs| *secs > 0)
+    else {
+        return;
+    };
+    let backoff = Backoff {
+        until: now + secs,
+        status: status.as_u16(),
+    };
+    // Entries are checked against `until`, so the TTL only needs to outlast it
+    let ttl = Duration::from_secs(secs.max(60));
+    state::put(store, &key_for(host), &backoff, Some(ttl));
+}
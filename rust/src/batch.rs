@@ -0,0 +1,232 @@
+//! Fetching several URLs in one request.
+//!
+//! `POST /batch` takes a JSON array of target URLs. Each is validated and sent
+//! as if it were the client's own target, all of them are fetched at once, and the
+//! results come back together: as a JSON array in the order given, or with
+//! `format=ndjson` as one line per URL in the order they finished.
+
+use crate::destinations::{self, Decision};
+use crate::errors::{Code, Problem};
+use crate::outbound::{self, Checks};
+use crate::output::{self, Format};
+use crate::policy;
+use crate::tenant::Tenant;
+use crate::{backend, config, deadline, errors, headers, limits, ssrf};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use fastly::http::request::{select, PendingRequest};
+use fastly::http::{HeaderName, HeaderValue, Method, StatusCode};
+use fastly::{Request, Response};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// Largest batch request body.
+const MAX_REQUEST_BYTES: usize = 64 * 1024;
+
+/// Bytes of each origin body returned; anything beyond is cut off.
+const MAX_ITEM_BODY_BYTES: usize = 512 * 1024;
+
+/// How long the whole batch may take. Each fetch's backend times out by then.
+pub const DEADLINE: Duration = Duration::from_secs(10);
+
+fn failed(index: usize, url: &str, message: &str) -> Value {
+    serde_json::json!({"index": index, "url": url, "error": message})
+}
+
+/// The entry's message for a refused URL: the refusal's detail, or its title.
+fn refusal_message(refusal: Response) -> String {
+    let problem: Value = serde_json::from_slice(&refusal.into_body_bytes()).unwrap_or_default();
+    let message = problem["detail"].as_str().or(problem["title"].as_str());
+    message.unwrap_or("Destination refused").to_string()
+}
+
+/// Validate one URL and start fetching it.
+fn start(
+    url: &str,
+    checks: &Checks,
+    injected: &[(HeaderName, HeaderValue)],
+) -> Result<PendingRequest, String> {
+    let url = url::Url::parse(url).map_err(|e| format!("Invalid URL: {}", e))?;
+    let target = ssrf::validate(url).map_err(|rejection| match rejection {
+        ssrf::Rejection::NotHttps => "Only https URLs are supported".to_string(),
+        ssrf::Rejection::MissingHost => "Invalid URL: missing hostname".to_string(),
+        ssrf::Rejection::PrivateAddress => {
+            "Target is a local, private or reserved address".to_string()
+        }
+        ssrf::Rejection::NotAllowed => "Target host is not on the proxy's allowlist".to_string(),
+    })?;
+    if let Some(refusal) = checks.refusal("GET", &target) {
+        return Err(refusal_message(refusal));
+    }
+    limits::reserve_request().map_err(|exhausted| exhausted.as_str().to_string())?;
+    let tenant = checks.tenant;
+    let backend = backend::create_bounded(&outbound::endpoint(tenant, &target), DEADLINE)
+        .map_err(|e| format!("Failed to create backend: {:?}", e))?;
+
+    let mut req = Request::get(target.url.clone());
+    req.set_header("Host", &target.hostname);
+    outbound::mark(&mut req);
+    for (name, value) in injected {
+        req.set_header(name, value);
+    }
+    outbound::authorize(&mut req, tenant, &target)?;
+    req.set_pass(true);
+    req.send_async(backend.name())
+        .map_err(|e| format!("Failed to fetch from origin: {}", e))
+}
+
+/// The result entry for a completed fetch.
+fn result(index: usize, url: &str, tenant: &Tenant, mut resp: Response) -> Value {
+    headers::strip_response(&mut resp);
+    tenant.response_headers.apply(&mut resp);
+    let mut response_headers = Map::new();
+    for name in resp.get_header_names() {
+        let values: Vec<&str> = resp.get_header_all_str(name);
+        response_headers.insert(name.to_string(), Value::String(values.join(", ")));
+    }
+    let status = resp.get_status().as_u16();
+    let prefix = resp.get_body_prefix_mut(MAX_ITEM_BODY_BYTES + 1);
+    let truncated = prefix.len() > MAX_ITEM_BODY_BYTES;
+    let body = &prefix[..prefix.len().min(MAX_ITEM_BODY_BYTES)];
+    serde_json::json!({
+        "index": index,
+        "url": url,
+        "status": status,
+        "headers": response_headers,
+        "body_b64": STANDARD.encode(body),
+        "truncated": truncated,
+    })
+}
+
+/// Answer a `/batch` request for the tenant.
+pub fn respond(req: &mut Request, tenant_id: &str, tenant: &Tenant) -> Response {
+    if req.get_method() != Method::POST {
+        return Problem::new(Code::MethodNotAllowed, "Batches are sent with POST")
+            .into_response()
+            .with_header("Allow", "POST");
+    }
+    let mut body = req.take_body();
+    let prefix = body.get_prefix_mut(MAX_REQUEST_BYTES + 1);
+    if prefix.len() > MAX_REQUEST_BYTES {
+        return Problem::new(Code::BatchTooLarge, "The request body may be at most 64 KiB")
+            .into_response();
+    }
+    let urls: Vec<String> = match serde_json::from_slice(&prefix) {
+        Ok(urls) => urls,
+        Err(e) => {
+            return Problem::new(
+                Code::InvalidBatch,
+                format!("Expected a JSON array of URLs: {}", e),
+            )
+            .into_response();
+        }
+    };
+    let max_batch_urls = config::current().limits.max_batch_urls;
+    if urls.is_empty() || urls.len() > max_batch_urls {
+        return Problem::new(
+            Code::InvalidBatch,
+            format!("A batch holds between 1 and {} URLs", max_batch_urls),
+        )
+        .into_response();
+    }
+    let policy = match policy::load() {
+        Ok(policy) => policy,
+        Err(e) => return errors::config(&e),
+    };
+    let injected = match headers::injected(req.get_url(), &tenant.origin_headers) {
+        Ok(injected) => injected,
+        Err(e) => return Problem::new(Code::InvalidParameter, e).into_response(),
+    };
+
+    let checks = Checks {
+        tenant_id,
+        tenant,
+        policy: &policy,
+        confirmed: None,
+        proxy_host: req.get_url().host_str(),
+    };
+
+    // A URL listed more than once is fetched once, and finished fetches are
+    // matched to their entries by the URL they were sent to
+    let mut results: Vec<Value> = Vec::with_capacity(urls.len());
+    let mut waiting: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    let mut pending: Vec<PendingRequest> = Vec::new();
+    for (index, url) in urls.iter().enumerate() {
+        let sent_url = url::Url::parse(url).map(String::from).unwrap_or_default();
+        if let Some(indices) = waiting.get_mut(&sent_url) {
+            indices.push(index);
+            continue;
+        }
+        match start(url, &checks, &injected) {
+            Ok(request) => {
+                waiting.insert(request.sent_req().get_url_str().to_string(), vec![index]);
+                pending.push(request);
+            }
+            Err(message) => {
+                destinations::batch_item(url, Decision::Refused, None, Some(&message));
+                results.push(failed(index, url, &message));
+            }
+        }
+    }
+
+    // Each backend gives up by the deadline, so waiting for them is bounded
+    let started = Instant::now();
+    let wait = deadline::bound(DEADLINE);
+    while !pending.is_empty() && started.elapsed() < wait {
+        let (done, remaining) = select(pending);
+        pending = remaining;
+        let (sent_url, outcome) = match done {
+            Ok(resp) => (
+                resp.get_backend_request()
+                    .map(|sent| sent.get_url_str().to_string())
+                    .unwrap_or_default(),
+                Ok(resp),
+            ),
+            Err(e) => {
+                let message = e.to_string();
+                (e.into_sent_req().get_url_str().to_string(), Err(message))
+            }
+        };
+        let Some(indices) = waiting.remove(&sent_url) else {
+            continue;
+        };
+        match outcome {
+            Ok(resp) => {
+                let first = result(indices[0], &urls[indices[0]], tenant, resp);
+                let status = first["status"].as_u64().map(|status| status as u16);
+                for index in indices {
+                    destinations::batch_item(&urls[index], Decision::Allowed, status, None);
+                    let mut entry = first.clone();
+                    entry["index"] = index.into();
+                    entry["url"] = urls[index].clone().into();
+                    results.push(entry);
+                }
+            }
+            Err(message) => {
+                for index in indices {
+                    destinations::batch_item(&urls[index], Decision::Allowed, None, Some(&message));
+                    results.push(failed(index, &urls[index], &message));
+                }
+            }
+        }
+    }
+
+    // Whatever is still outstanding is dropped, which cancels it
+    for (_, indices) in waiting {
+        for index in indices {
+            let reason = Some("Deadline exceeded");
+            destinations::batch_item(&urls[index], Decision::Allowed, None, reason);
+            results.push(failed(index, &urls[index], "Deadline exceeded"));
+        }
+    }
+
+    if Format::of(req) == Format::Ndjson {
+        return output::ndjson_response(results);
+    }
+    results.sort_by_key(|result| result["index"].as_u64());
+    Response::from_status(StatusCode::OK)
+        .with_header("Content-Type", "application/json")
+        .with_header("Cache-Control", "no-store")
+        .with_body(Value::Array(results).to_string())
+}
@@ -0,0 +1,64 @@
+//! Tenant rules over client user agents.
+//!
+//! A tenant's `bot_rules` match regular expressions against the client's
+//! `User-Agent`, which is taken as empty when it's missing, so `^$` catches
+//! clients that don't send one. The first rule to match decides: `block`
+//! refuses the request straight away, and `tarpit` holds it for `delay_ms`
+//! before refusing it, slowing down scrapers that retry as fast as they're
+//! answered. Either way it happens before any backend is created.
+
+use crate::deadline;
+use crate::errors::{Code, Problem};
+use crate::url_rules;
+use fastly::{Request, Response};
+use regex::Regex;
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Longest a tarpitted request is held.
+const MAX_TARPIT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    #[default]
+    Block,
+    Tarpit,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BotRule {
+    #[serde(deserialize_with = "url_rules::compile")]
+    pub pattern: Regex,
+    #[serde(default)]
+    pub action: Action,
+    /// How long a `tarpit` rule holds the request before refusing it.
+    #[serde(default = "default_delay_ms")]
+    pub delay_ms: u64,
+}
+
+fn default_delay_ms() -> u64 {
+    5000
+}
+
+/// Refuse a client whose user agent one of the rules matches, after holding
+/// it if the rule is a tarpit.
+pub fn check(rules: &[BotRule], req: &Request) -> Option<Response> {
+    let user_agent = req.get_header_str("User-Agent").unwrap_or_default();
+    let index = rules
+        .iter()
+        .position(|rule| rule.pattern.is_match(user_agent))?;
+    let rule = &rules[index];
+    if rule.action == Action::Tarpit {
+        let delay = Duration::from_millis(rule.delay_ms).min(MAX_TARPIT);
+        std::thread::sleep(deadline::bound(delay));
+    }
+    Some(
+        Problem::new(
+            Code::BotBlocked,
+            "Requests from this user agent are refused",
+        )
+        .with("rule", index)
+        .into_response(),
+    )
+}
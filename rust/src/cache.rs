@@ -0,0 +1,322 @@
+//! Edge caching of origin responses.
+//!
+//! Cacheable GET responses are stored with the Core Cache API. The status and
+//! headers travel in the entry's user metadata so a hit can be rebuilt without
+//! contacting the origin, and `Age` is recomputed from the entry on every hit.
+//!
+//! Clients send many different `Accept-Encoding` strings, so the origin only
+//! ever sees `br`, `gzip` or `identity` and entries are keyed by which one.
+//!
+//! The origin's `Vary` is cut down to `Accept-Encoding` and the names in the
+//! route's `vary`, each once, so entries are only split by headers the route
+//! expects; `Vary: *` and anything else are dropped rather than leaving the
+//! response uncacheable. The Core Cache keeps a variant per combination of
+//! the request's values for the names that remain.
+//!
+//! Entries stay fresh for the route's `ttl_secs`, unless the response's
+//! `Surrogate-Control` gives a `max-age` or says `no-store`; the header is
+//! meant for the edge alone, and clients never see it.
+//!
+//! Entries are kept per tenant, so one tenant's responses are never served to
+//! another. Responses to requests that carried credentials, the tenant's
+//! origin credentials or an `Authorization`, aren't cached unless the route's
+//! policy says they may be.
+//!
+//! Entries carry a surrogate key for their origin host, so everything cached
+//! from one origin can be purged at once with [`purge_host`], and one derived
+//! from their URL and coding, shared by every tenant's entry, so a single URL
+//! can be purged with [`purge_url`].
+
+use crate::compression::{self, Coding};
+use crate::sse;
+use bytes::Bytes;
+use fastly::cache::core::{self, CacheKey};
+use fastly::http::{HeaderName, HeaderValue, Method, StatusCode};
+use fastly::{Request, Response};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::time::{Duration, SystemTime};
+use url::Url;
+
+/// Responses larger than this are returned to the client without being cached.
+const MAX_CACHED_BODY_BYTES: usize = 10 * 1024 * 1024;
+
+/// Every `Accept-Encoding` an entry can be keyed by, once normalized.
+const KEYED_ENCODINGS: [&str; 3] = ["br", "gzip", "identity"];
+
+/// Freshness headers aimed at the edge that must not be passed on to clients.
+const EDGE_ONLY_HEADERS: [&str; 2] = ["Surrogate-Control", "Surrogate-Key"];
+
+/// Per-route cache settings.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CachePolicy {
+    /// How long an entry stays fresh, in seconds.
+    pub ttl_secs: u64,
+    /// Request headers the origin's `Vary` may key entries by, besides
+    /// `Accept-Encoding`. The origin's other `Vary` names are dropped.
+    #[serde(default)]
+    pub vary: Vec<String>,
+    /// Also cache responses to credentialed requests, for origins whose
+    /// responses don't depend on who asked.
+    #[serde(default)]
+    pub credentialed: bool,
+}
+
+impl CachePolicy {
+    fn honours(&self, name: &str) -> bool {
+        self.vary
+            .iter()
+            .any(|honoured| honoured.eq_ignore_ascii_case(name))
+    }
+}
+
+/// The request's values of the headers its route's entries may vary by.
+#[derive(Debug, Clone, Default)]
+pub struct Variant {
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl Variant {
+    pub fn of(req: &Request, policy: &CachePolicy) -> Self {
+        let headers = policy
+            .vary
+            .iter()
+            .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+            .filter_map(|name| {
+                let value = req.get_header(&name)?.clone();
+                Some((name, value))
+            })
+            .collect();
+        Self { headers }
+    }
+}
+
+/// Status and headers stored alongside a cached body.
+#[derive(Serialize, Deserialize)]
+struct Metadata {
+    status: u16,
+    headers: Vec<(String, String)>,
+}
+
+/// Where a tenant's response for a target URL is cached.
+#[derive(Debug, Clone)]
+pub struct Key {
+    cache_key: CacheKey,
+    /// The URL and coding, which every tenant's entry shares.
+    entry: String,
+}
+
+/// The entry for a target URL and normalized `Accept-Encoding`, whoever's it is.
+fn entry_for(url: &Url, accept_encoding: &str) -> String {
+    format!("GET {} {}", accept_encoding, url)
+}
+
+/// The cache key for a tenant's target URL and normalized `Accept-Encoding`.
+/// HEAD requests share the GET entry.
+pub fn key_for(tenant: &str, url: &Url, accept_encoding: &str) -> Key {
+    let entry = entry_for(url, accept_encoding);
+    Key {
+        cache_key: CacheKey::from(format!("{} {}", entry, tenant)),
+        entry,
+    }
+}
+
+/// Replace the request's `Accept-Encoding` with the one coding it should get.
+pub fn normalize_accept_encoding(req: &mut Request) -> &'static str {
+    let normalized = req
+        .get_header_str("Accept-Encoding")
+        .and_then(|accept_encoding| compression::preferred(accept_encoding, true))
+        .map_or("identity", Coding::as_str);
+    req.set_header("Accept-Encoding", normalized);
+    normalized
+}
+
+/// Rebuild a cached response for the request's variant, or `None` on a miss.
+pub fn lookup(key: &Key, method: &Method, variant: &Variant) -> Option<Response> {
+    let lookup = variant.headers.iter().fold(
+        core::lookup(key.cache_key.clone()),
+        |lookup, (name, value)| lookup.header(name, value),
+    );
+    let found = lookup.execute().ok()??;
+    if !found.is_usable() {
+        return None;
+    }
+    let metadata: Metadata = serde_json::from_slice(&found.user_metadata()).ok()?;
+
+    let mut resp = Response::from_status(metadata.status);
+    for (name, value) in metadata.headers {
+        resp.append_header(name, value);
+    }
+    // The entry's age already includes the origin's Age at insertion time
+    resp.set_header("Age", found.age().as_secs().to_string());
+    if method != Method::HEAD {
+        resp.set_body(found.to_stream().ok()?);
+    }
+    Some(resp)
+}
+
+/// The surrogate key of every entry cached from the host.
+fn host_surrogate_key(host: &str) -> String {
+    format!("origin.{}", host.to_ascii_lowercase())
+}
+
+/// The surrogate key of every tenant's entry for a URL and coding.
+fn entry_surrogate_key(entry: &str) -> String {
+    format!("entry.{}", hex::encode(Sha256::digest(entry)))
+}
+
+/// Purge everything cached from the host.
+pub fn purge_host(host: &str) -> Result<(), String> {
+    fastly::http::purge::purge_surrogate_key(&host_surrogate_key(host)).map_err(|e| e.to_string())
+}
+
+/// Purge every tenant's entries cached for a target URL, one per coding,
+/// returning the URL and coding of each.
+pub fn purge_url(url: &Url) -> Result<Vec<String>, String> {
+    KEYED_ENCODINGS
+        .iter()
+        .map(|accept_encoding| {
+            let entry = entry_for(url, accept_encoding);
+            fastly::http::purge::purge_surrogate_key(&entry_surrogate_key(&entry))
+                .map_err(|e| e.to_string())?;
+            Ok(entry)
+        })
+        .collect()
+}
+
+/// Store a cacheable response from the host and return it for delivery to the client.
+pub fn store(
+    key: Key,
+    mut resp: Response,
+    policy: &CachePolicy,
+    host: &str,
+    variant: &Variant,
+) -> Response {
+    let vary_by = normalize_vary(&mut resp, policy);
+    let surrogate_control = resp
+        .get_header_str("Surrogate-Control")
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    strip_edge_headers(&mut resp);
+    if !is_cacheable(&resp) || surrogate_control.contains("no-store") {
+        return resp;
+    }
+    // Directives aimed at the edge say how long it may keep the response
+    let ttl = surrogate_control
+        .split(',')
+        .find_map(|directive| directive.trim().strip_prefix("max-age="))
+        .and_then(|secs| secs.trim().parse::<u64>().ok())
+        .unwrap_or(policy.ttl_secs);
+
+    let prefix = resp.get_body_prefix_mut(MAX_CACHED_BODY_BYTES + 1);
+    if prefix.len() > MAX_CACHED_BODY_BYTES {
+        drop(prefix);
+        return resp;
+    }
+    let body = prefix.take();
+
+    // Origins should send Date; if one didn't, stamp the time we received it
+    if !resp.contains_header("Date") {
+        resp.set_header("Date", httpdate::fmt_http_date(SystemTime::now()));
+    }
+    // Entries are per coding, so shared caches downstream need to know too
+    let varies_by_encoding = resp
+        .get_header_all_str("Vary")
+        .iter()
+        .flat_map(|vary| vary.split(','))
+        .any(|name| name.trim().eq_ignore_ascii_case("accept-encoding"));
+    if resp.contains_header("Content-Encoding") && !varies_by_encoding {
+        resp.append_header("Vary", "Accept-Encoding");
+    }
+    let origin_age = resp
+        .get_header_str("Age")
+        .and_then(|age| age.trim().parse::<u64>().ok())
+        .unwrap_or(0);
+
+    let metadata = Metadata {
+        status: resp.get_status().as_u16(),
+        headers: resp
+            .get_headers()
+            .filter(|(name, _)| *name != "age")
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect(),
+    };
+    let surrogate_keys = [host_surrogate_key(host), entry_surrogate_key(&key.entry)];
+    let inserted = serde_json::to_vec(&metadata).ok().and_then(|metadata| {
+        let insert = variant.headers.iter().fold(
+            core::insert(key.cache_key, Duration::from_secs(ttl)),
+            |insert, (name, value)| insert.header(name, value),
+        );
+        insert
+            .vary_by(&vary_by)
+            .surrogate_keys(surrogate_keys.iter().map(String::as_str))
+            .initial_age(Duration::from_secs(origin_age))
+            .known_length(body.len() as u64)
+            .user_metadata(Bytes::from(metadata))
+            .execute()
+            .ok()
+    });
+    if let Some(mut stream) = inserted {
+        if stream.write_all(&body).is_ok() {
+            let _ = stream.finish();
+        }
+    }
+
+    resp.set_body(body);
+    resp
+}
+
+/// Keep only the honoured names in the response's `Vary`, once each, and
+/// return those entries are keyed by beyond the coding.
+fn normalize_vary(resp: &mut Response, policy: &CachePolicy) -> Vec<HeaderName> {
+    let mut names: Vec<String> = Vec::new();
+    for vary in resp.get_header_all_str("Vary") {
+        for name in vary.split(',') {
+            let name = name.trim().to_ascii_lowercase();
+            let honoured = name == "accept-encoding" || policy.honours(&name);
+            if honoured && !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    resp.remove_header("Vary");
+    if !names.is_empty() {
+        resp.set_header("Vary", names.join(", "));
+    }
+    names
+        .iter()
+        .filter(|name| *name != "accept-encoding")
+        .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+        .collect()
+}
+
+/// Remove headers that only concern the edge before a response reaches the client.
+pub fn strip_edge_headers(resp: &mut Response) {
+    for name in EDGE_ONLY_HEADERS {
+        resp.remove_header(name);
+    }
+}
+
+fn is_cacheable(resp: &Response) -> bool {
+    let status_cacheable = matches!(
+        resp.get_status(),
+        StatusCode::OK
+            | StatusCode::NON_AUTHORITATIVE_INFORMATION
+            | StatusCode::NO_CONTENT
+            | StatusCode::MOVED_PERMANENTLY
+            | StatusCode::NOT_FOUND
+            | StatusCode::GONE
+    );
+    let cache_control = resp
+        .get_header_str("Cache-Control")
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let forbidden = ["no-store", "private", "no-cache"]
+        .iter()
+        .any(|directive| cache_control.contains(directive));
+    status_cacheable
+        && !forbidden
+        && !sse::is_event_stream(resp)
+        && !resp.contains_header("Set-Cookie")
+}
@@ -0,0 +1,60 @@
+//! Overriding the freshness directives origins send.
+//!
+//! A tenant's `cache_control` replaces the `Cache-Control` clients get with
+//! its `client` directives, and hands its `surrogate` directives to the
+//! route's edge cache as if the origin had sent them in `Surrogate-Control`.
+//! Without a `client` override, a request can set one for itself with
+//! `?cc=`, such as `?cc=public,max-age=31536000,immutable` for assets that
+//! never change. The tenant's setting always wins, so one that forces
+//! `no-store` can't be loosened.
+
+use fastly::Response;
+use serde::Deserialize;
+use url::Url;
+
+/// Longest `?cc=` value accepted.
+const MAX_DIRECTIVES_LEN: usize = 256;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct CacheControl {
+    /// `Cache-Control` sent to clients, whatever the origin said.
+    pub client: Option<String>,
+    /// `Surrogate-Control` the edge cache follows instead of the origin's.
+    pub surrogate: Option<String>,
+}
+
+/// The `Cache-Control` a request asks for with `?cc=`, if any.
+pub fn requested(client_url: &Url) -> Result<Option<String>, String> {
+    let Some((_, value)) = client_url.query_pairs().find(|(k, _)| k == "cc") else {
+        return Ok(None);
+    };
+    let value = value.trim();
+    if value.is_empty() || value.len() > MAX_DIRECTIVES_LEN {
+        return Err(format!(
+            "'cc' must be 1 to {} characters of Cache-Control directives",
+            MAX_DIRECTIVES_LEN
+        ));
+    }
+    if !value.chars().all(|c| c.is_ascii_graphic() || c == ' ') {
+        return Err("'cc' may only contain printable ASCII".to_string());
+    }
+    Ok(Some(value.to_string()))
+}
+
+impl CacheControl {
+    /// Give the edge cache the tenant's directives instead of the origin's.
+    pub fn to_edge(&self, resp: &mut Response) {
+        if let Some(surrogate) = &self.surrogate {
+            resp.set_header("Surrogate-Control", surrogate);
+        }
+    }
+
+    /// Replace the `Cache-Control` the client gets, with the tenant's
+    /// directives or else those the request asked for.
+    pub fn to_client(&self, resp: &mut Response, requested: Option<&str>) {
+        if let Some(client) = self.client.as_deref().or(requested) {
+            resp.set_header("Cache-Control", client);
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Canary rollouts to an alternate origin.
+//!
+//! A `canary.<host>` entry in the `dynserv-config` Config Store sends
+//! `percent` of the requests for targets on `host` to an alternate `host`
+//! and `port`, keeping the scheme, path and query. The entry is read on every
+//! request, so a rollout is widened or stopped by editing it, without a
+//! deploy. The canary's outcomes are counted in the state store; once at
+//! least `min_requests` within `window_secs` have failed at `max_error_rate`
+//! or worse, the canary is tripped and everything goes to the primary for
+//! `cooldown_secs`. Without the state store canaries are never tripped. A
+//! tenant's [`affinity`](crate::affinity) cookie keeps clients on whichever
+//! of the two they were given.
+
+use crate::routes::CONFIG_STORE;
+use crate::state;
+use fastly::config_store::ConfigStore;
+use fastly::kv_store::KVStore;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use url::Url;
+
+/// Header marking responses that came from a canary.
+pub const CANARY_HEADER: &str = "X-Proxy-Canary";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Canary {
+    /// The alternate origin's host.
+    pub host: String,
+    /// The alternate origin's port, if it isn't the target's.
+    pub port: Option<u16>,
+    /// Share of requests sent to the canary, from 0 to 100.
+    pub percent: f64,
+    /// Share of failed canary requests, from 0 to 1, that trips the canary.
+    pub max_error_rate: f64,
+    /// Canary requests needed in a window before its error rate counts.
+    pub min_requests: u32,
+    pub window_secs: u64,
+    /// How long a tripped canary gets no traffic.
+    pub cooldown_secs: u64,
+}
+
+impl Default for Canary {
+    fn default() -> Self {
+        Self {
+            host: String::new(),
+            port: None,
+            percent: 0.0,
+            max_error_rate: 0.1,
+            min_requests: 20,
+            window_secs: 60,
+            cooldown_secs: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Health {
+    window_start: u64,
+    requests: u32,
+    failures: u32,
+    tripped_until: Option<u64>,
+}
+
+fn key_for(host: &str) -> String {
+    format!("canary.{}", host)
+}
+
+/// A request for a host with a canary rolling out.
+pub struct Routed {
+    pub primary: String,
+    pub canary: Canary,
+    /// Whether the request was sent to the canary.
+    pub chosen: bool,
+}
+
+/// Point the target at its host's canary if this request is in its share,
+/// or if the client was given the canary before and `sticky` says so.
+pub fn route(target: &mut Url, now: u64, sticky: Option<bool>) -> Result<Option<Routed>, String> {
+    let Some(primary) = target.host_str().map(str::to_string) else {
+        return Ok(None);
+    };
+    let Some(canary) = load(&primary)? else {
+        return Ok(None);
+    };
+    let chosen = canary.chosen(state::open().as_ref(), &primary, now, sticky);
+    if chosen {
+        canary.apply(target)?;
+    }
+    Ok(Some(Routed {
+        primary,
+        canary,
+        chosen,
+    }))
+}
+
+/// The canary rolling out for targets on `host`, if there is one.
+fn load(host: &str) -> Result<Option<Canary>, String> {
+    let Ok(store) = ConfigStore::try_open(CONFIG_STORE) else {
+        return Ok(None);
+    };
+    let key = key_for(host);
+    match store.get(&key) {
+        Some(json) => serde_json::from_str(&json)
+            .map(Some)
+            .map_err(|e| format!("Invalid '{}' entry: {}", key, e)),
+        None => Ok(None),
+    }
+}
+
+impl Canary {
+    /// Whether this request is in the canary's share.
+    fn sampled(&self) -> bool {
+        let mut buf = [0u8; 4];
+        if getrandom::getrandom(&mut buf).is_err() {
+            return false;
+        }
+        let roll = u32::from_le_bytes(buf) as f64 / u32::MAX as f64;
+        roll * 100.0 < self.percent
+    }
+
+    /// Whether the canary for `primary` is tripped and getting no traffic.
+    fn tripped(&self, store: Option<&KVStore>, primary: &str, now: u64) -> bool {
+        store
+            .and_then(|store| state::get::<Health>(store, &key_for(primary)))
+            .and_then(|health| health.tripped_until)
+            .is_some_and(|until| now < until)
+    }
+
+    /// Whether a request for a target on `primary` goes to the canary. A
+    /// stopped or tripped canary gets nothing, even from sticky clients.
+    fn chosen(
+        &self,
+        store: Option<&KVStore>,
+        primary: &str,
+        now: u64,
+        sticky: Option<bool>,
+    ) -> bool {
+        !self.host.is_empty()
+            && self.percent > 0.0
+            && sticky.unwrap_or_else(|| self.sampled())
+            && !self.tripped(store, primary, now)
+    }
+
+    /// Point the target at the canary.
+    fn apply(&self, target: &mut Url) -> Result<(), String> {
+        target
+            .set_host(Some(&self.host))
+            .map_err(|e| format!("Invalid canary host '{}': {}", self.host, e))?;
+        if let Some(port) = self.port {
+            let _ = target.set_port(Some(port));
+        }
+        Ok(())
+    }
+
+    /// Count the outcome of a request sent to the canary for `primary`,
+    /// tripping it if too many have failed.
+    pub fn record(&self, store: &KVStore, primary: &str, success: bool, now: u64) {
+        let key = key_for(primary);
+        let mut health = state::get::<Health>(store, &key).unwrap_or_default();
+        if now >= health.window_start + self.window_secs {
+            health = Health {
+                window_start: now,
+                ..Health::default()
+            };
+        }
+        health.requests += 1;
+        if !success {
+            health.failures += 1;
+        }
+        let error_rate = health.failures as f64 / health.requests as f64;
+        if health.requests >= self.min_requests && error_rate >= self.max_error_rate {
+            health = Health {
+                window_start: now,
+                tripped_until: Some(now + self.cooldown_secs),
+                ..Health::default()
+            };
+        }
+        let ttl = Duration::from_secs(self.window_secs.max(self.cooldown_secs) * 2);
+        state::put(store, &key, &health, Some(ttl));
+    }
+}
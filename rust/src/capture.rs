@@ -0,0 +1,363 @@
+//! Capturing requests to replay them later.
+//!
+//! Tenants with `capture` set keep sanitized copies of some requests in the
+//! state store under `capture.<tenant>.<id>`, with the tenant escaped by
+//! [`state::segment`], for `ttl_secs`: those that
+//! ask with `capture=1`, a sampled `percent` of the rest and, unless
+//! `errors` is turned off, those whose origin failed or answered with a
+//! 5xx. A copy holds the client's method, query and headers, the origin's
+//! status and headers, and up to `max_body_bytes` of each body. The `key`
+//! and `token` parameters, a signed URL's `sig` and `expires` and the `h_`
+//! parameters, which often carry origin credentials, are left out, and
+//! credential headers redacted. Announced uploads are streamed rather than
+//! read early (see [`crate::expect`]), so their bodies aren't kept.
+//! Responses to captured requests carry the capture's ID in
+//! `X-Proxy-Capture`.
+//!
+//! `/debug/replay/<id>` sends a capture's request through the proxy again,
+//! authenticated by the replaying request's own key, token or
+//! `Authorization`, so it gets every check the original got. Captures whose
+//! request body was cut short can't be replayed.
+
+use crate::errors::{Code, Problem};
+use crate::headers::INJECT_PARAM_PREFIX;
+use crate::{sse, state, stats};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use fastly::http::header::{HeaderName, HeaderValue};
+use fastly::http::request::SendError;
+use fastly::http::Method;
+use fastly::{Body, Request, Response};
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::Url;
+
+/// Response header naming the capture a request was stored under.
+pub const CAPTURE_HEADER: &str = "X-Proxy-Capture";
+
+/// Query parameters that authenticate the client, never captured.
+const CREDENTIAL_PARAMS: [&str; 2] = ["key", "token"];
+
+/// A signed URL's signature and expiry, never captured so the link can't be
+/// rebuilt from a capture.
+const SIGNED_URL_PARAMS: [&str; 2] = ["sig", "expires"];
+
+/// Headers whose values are never captured.
+const REDACTED_HEADERS: [&str; 7] = [
+    "authorization",
+    "cookie",
+    "proxy-authorization",
+    "set-cookie",
+    "x-proxy-key-id",
+    "x-proxy-signature",
+    "x-proxy-timestamp",
+];
+
+const REDACTED: &str = "[redacted]";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Capture {
+    /// Share of requests captured, from 0 to 100.
+    pub percent: f64,
+    /// Capture requests whose origin failed or answered with a 5xx.
+    pub errors: bool,
+    /// Most of each body kept.
+    pub max_body_bytes: usize,
+    /// How long captures are kept.
+    pub ttl_secs: u64,
+}
+
+impl Default for Capture {
+    fn default() -> Self {
+        Self {
+            percent: 0.0,
+            errors: true,
+            max_body_bytes: 8192,
+            ttl_secs: 86400,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct CapturedBody {
+    base64: String,
+    truncated: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CapturedRequest {
+    method: String,
+    query: Vec<(String, String)>,
+    headers: Vec<(String, String)>,
+    body: CapturedBody,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CapturedResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: CapturedBody,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Record {
+    id: String,
+    tenant: String,
+    captured_at: u64,
+    request: CapturedRequest,
+    response: Option<CapturedResponse>,
+    error: Option<String>,
+}
+
+/// The ID of the capture the current request was stored under.
+static CAPTURED: Mutex<Option<String>> = Mutex::new(None);
+
+/// Set while the current request is a replay, which is never captured again.
+static REPLAYING: Mutex<bool> = Mutex::new(false);
+
+/// Forget the previous execution's capture.
+pub fn reset() {
+    if let Ok(mut captured) = CAPTURED.lock() {
+        *captured = None;
+    }
+    if let Ok(mut replaying) = REPLAYING.lock() {
+        *replaying = false;
+    }
+}
+
+/// Say which capture the request was stored under, whichever path answered it.
+pub fn annotate(resp: &mut Response) {
+    if let Some(id) = CAPTURED
+        .lock()
+        .ok()
+        .and_then(|mut captured| captured.take())
+    {
+        resp.set_header(CAPTURE_HEADER, id);
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn key(tenant: &str, id: &str) -> String {
+    format!("capture.{}.{}", state::segment(tenant), id)
+}
+
+fn sanitized<'a>(
+    headers: impl Iterator<Item = (&'a HeaderName, &'a HeaderValue)>,
+) -> Vec<(String, String)> {
+    headers
+        .map(|(name, value)| {
+            let value = if REDACTED_HEADERS.contains(&name.as_str()) {
+                REDACTED
+            } else {
+                value.to_str().unwrap_or("[binary]")
+            };
+            (name.as_str().to_string(), value.to_string())
+        })
+        .collect()
+}
+
+/// Keep up to `max` bytes of a body, putting it back whole.
+fn sample(body: &mut Body, max: usize) -> CapturedBody {
+    let mut rest = std::mem::replace(body, Body::new());
+    let mut kept = Vec::new();
+    let _ = (&mut rest).take(max as u64 + 1).read_to_end(&mut kept);
+    *body = Body::from(kept.clone());
+    body.append(rest);
+    let truncated = kept.len() > max;
+    kept.truncate(max);
+    CapturedBody {
+        base64: STANDARD.encode(&kept),
+        truncated,
+    }
+}
+
+fn sampled(percent: f64) -> bool {
+    let mut buf = [0u8; 4];
+    if getrandom::getrandom(&mut buf).is_err() {
+        return false;
+    }
+    let roll = u32::from_le_bytes(buf) as f64 / u32::MAX as f64;
+    roll * 100.0 < percent
+}
+
+/// A request being captured, until its origin has answered.
+pub struct Pending {
+    settings: Capture,
+    chosen: bool,
+    record: Record,
+}
+
+impl Capture {
+    /// Start capturing the client's request, if it may need to be kept.
+    ///
+    /// `client_url` is the URL the client requested from the proxy. The body
+    /// is only sampled if `read_body`; otherwise it's recorded as cut short.
+    pub fn start(
+        &self,
+        req: &mut Request,
+        client_url: &Url,
+        tenant: &str,
+        request_id: &str,
+        read_body: bool,
+    ) -> Option<Pending> {
+        if REPLAYING.lock().is_ok_and(|replaying| *replaying) {
+            return None;
+        }
+        let asked = client_url
+            .query_pairs()
+            .any(|(k, v)| k == "capture" && (v == "1" || v == "true"));
+        let chosen = asked || sampled(self.percent);
+        if !chosen && !self.errors {
+            return None;
+        }
+        let query = client_url
+            .query_pairs()
+            .filter(|(k, _)| {
+                !CREDENTIAL_PARAMS.contains(&k.as_ref())
+                    && !SIGNED_URL_PARAMS.contains(&k.as_ref())
+                    && !k.starts_with(INJECT_PARAM_PREFIX)
+                    && k != "capture"
+            })
+            .map(|(k, v)| (k.into_owned(), v.into_owned()))
+            .collect();
+        let body = match read_body {
+            true => {
+                let mut body = req.take_body();
+                let sampled = sample(&mut body, self.max_body_bytes);
+                req.set_body(body);
+                sampled
+            }
+            false => CapturedBody {
+                base64: String::new(),
+                truncated: true,
+            },
+        };
+        let request = CapturedRequest {
+            method: req.get_method_str().to_string(),
+            query,
+            headers: sanitized(req.get_headers()),
+            body,
+        };
+        Some(Pending {
+            settings: self.clone(),
+            chosen,
+            record: Record {
+                id: request_id.to_string(),
+                tenant: tenant.to_string(),
+                captured_at: now(),
+                request,
+                response: None,
+                error: None,
+            },
+        })
+    }
+}
+
+impl Pending {
+    /// Store the capture if the request was chosen or its origin failed.
+    pub fn finish(mut self, result: &mut Result<Response, SendError>) {
+        let failed = match result {
+            Ok(response) => response.get_status().is_server_error(),
+            Err(_) => true,
+        };
+        let kept = self.chosen || (self.settings.errors && failed);
+        if !kept {
+            return;
+        }
+        match result {
+            Ok(response) => {
+                // Event streams may never end, so their bodies aren't waited for
+                let body = if sse::is_event_stream(response) {
+                    CapturedBody {
+                        truncated: true,
+                        ..CapturedBody::default()
+                    }
+                } else {
+                    let mut body = response.take_body();
+                    let captured = sample(&mut body, self.settings.max_body_bytes);
+                    response.set_body(body);
+                    captured
+                };
+                self.record.response = Some(CapturedResponse {
+                    status: response.get_status().as_u16(),
+                    headers: sanitized(response.get_headers()),
+                    body,
+                });
+            }
+            Err(e) => self.record.error = Some(stats::error_kind(e)),
+        }
+        let Some(store) = state::open() else {
+            return;
+        };
+        let ttl = Duration::from_secs(self.settings.ttl_secs);
+        let key = key(&self.record.tenant, &self.record.id);
+        if state::put(&store, &key, &self.record, Some(ttl)) {
+            if let Ok(mut captured) = CAPTURED.lock() {
+                *captured = Some(self.record.id);
+            }
+        }
+    }
+}
+
+/// The capture a `/debug/replay/<id>` path asks for, if it's one.
+pub fn replay_id(path: &str) -> Option<&str> {
+    path.strip_prefix("/debug/replay/")
+        .filter(|id| !id.is_empty())
+}
+
+/// The tenant's captured request, to send through the proxy again with the
+/// credentials of the request asking for it.
+pub fn replay(req: &Request, tenant: &str, id: &str) -> Result<Request, Problem> {
+    let store = state::open()
+        .ok_or_else(|| Problem::new(Code::StateUnavailable, "Captures need the state store"))?;
+    let record: Record = state::get(&store, &key(tenant, id)).ok_or_else(|| {
+        Problem::new(Code::NotFound, "No capture has this ID").with("capture", id)
+    })?;
+    let captured = record.request;
+    if captured.body.truncated {
+        return Err(Problem::new(
+            Code::CaptureIncomplete,
+            "The capture's request body was cut short, so it can't be sent again",
+        )
+        .with("capture", id));
+    }
+    let method = Method::from_bytes(captured.method.as_bytes())
+        .map_err(|_| Problem::new(Code::CaptureIncomplete, "The capture's method is invalid"))?;
+    let credentials: Vec<(String, String)> = req
+        .get_url()
+        .query_pairs()
+        .filter(|(k, _)| CREDENTIAL_PARAMS.contains(&k.as_ref()))
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+    let mut url = req.get_url().clone();
+    url.set_path("/");
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(captured.query)
+        .extend_pairs(credentials);
+    let mut replay = Request::new(method, url);
+    for (name, value) in captured
+        .headers
+        .iter()
+        .filter(|(_, value)| value != REDACTED)
+    {
+        replay.append_header(name.as_str(), value.as_str());
+    }
+    if let Some(authorization) = req.get_header("Authorization") {
+        replay.set_header("Authorization", authorization.clone());
+    }
+    replay.set_body(STANDARD.decode(&captured.body.base64).unwrap_or_default());
+    if let Ok(mut replaying) = REPLAYING.lock() {
+        *replaying = true;
+    }
+    Ok(replay)
+}
@@ -0,0 +1,111 @@
+//! Fault injection, for testing clients against a flaky origin.
+//!
+//! A tenant's `chaos` disturbs `percent` of its requests, and every request
+//! that asks with `X-Proxy-Chaos: 1`: each is held for `latency_ms` first,
+//! then answered with one of `statuses` instead of being sent to the origin,
+//! or else sent and its body cut to `truncate_bytes`. Faulted responses list
+//! what was done to them in `X-Proxy-Chaos`. Tenants without `chaos` never
+//! get faults, and the header never reaches origins.
+
+use crate::deadline;
+use fastly::http::StatusCode;
+use fastly::{Request, Response};
+use serde::Deserialize;
+use std::io::Read;
+use std::time::Duration;
+
+/// Header asking for faults, and listing those a response got.
+pub const CHAOS_HEADER: &str = "X-Proxy-Chaos";
+
+/// Longest a faulted request is held.
+const MAX_LATENCY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Chaos {
+    /// Share of requests faulted, from 0 to 100.
+    pub percent: f64,
+    /// How long a faulted request is held before anything else happens.
+    pub latency_ms: u64,
+    /// Statuses answered instead of the origin's response, one picked at random.
+    pub statuses: Vec<u16>,
+    /// Most of the origin's body a faulted response keeps.
+    pub truncate_bytes: Option<usize>,
+}
+
+/// What's done to one faulted request.
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    latency: Duration,
+    status: Option<StatusCode>,
+    truncate_bytes: Option<usize>,
+}
+
+/// A random number in `[0, 1]`, if one can be had.
+fn roll() -> Option<f64> {
+    let mut buf = [0u8; 4];
+    getrandom::getrandom(&mut buf).ok()?;
+    Some(u32::from_le_bytes(buf) as f64 / u32::MAX as f64)
+}
+
+/// Whether the request asks for faults.
+pub fn requested(req: &Request) -> bool {
+    req.get_header_str(CHAOS_HEADER)
+        .is_some_and(|value| value.trim() == "1")
+}
+
+impl Chaos {
+    /// The faults for this request, if it's to get any.
+    pub fn faults(&self, requested: bool) -> Option<Faults> {
+        if !requested && roll().is_none_or(|roll| roll * 100.0 >= self.percent) {
+            return None;
+        }
+        let status = match self.statuses.len() {
+            0 => None,
+            count => {
+                let index = roll().map_or(0, |roll| (roll * count as f64) as usize);
+                StatusCode::from_u16(self.statuses[index.min(count - 1)]).ok()
+            }
+        };
+        Some(Faults {
+            latency: Duration::from_millis(self.latency_ms).min(MAX_LATENCY),
+            status,
+            truncate_bytes: self.truncate_bytes,
+        })
+    }
+}
+
+impl Faults {
+    fn applied(&self, last: Option<&str>) -> String {
+        let latency = (!self.latency.is_zero()).then_some("latency");
+        latency
+            .into_iter()
+            .chain(last)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Hold the request, and answer it with a substituted status if it gets one.
+    pub fn before_fetch(&self) -> Option<Response> {
+        if !self.latency.is_zero() {
+            std::thread::sleep(deadline::bound(self.latency));
+        }
+        let status = self.status?;
+        Some(Response::from_status(status).with_header(CHAOS_HEADER, self.applied(Some("status"))))
+    }
+
+    /// Cut the origin's body short, and say what the response got.
+    pub fn after_fetch(&self, resp: &mut Response) {
+        let truncated = self.truncate_bytes.map(|max| {
+            let mut kept = Vec::new();
+            let _ = resp.take_body().take(max as u64).read_to_end(&mut kept);
+            resp.remove_header("Content-Length");
+            resp.set_body(kept);
+            "truncated"
+        });
+        let applied = self.applied(truncated);
+        if !applied.is_empty() {
+            resp.set_header(CHAOS_HEADER, applied);
+        }
+    }
+}
@@ -0,0 +1,63 @@
+//! Character set handling for bodies that are rewritten as text.
+//!
+//! Transforms work on UTF-8. A body is decoded using its byte order mark or
+//! the `charset` parameter of its `Content-Type` (UTF-8 if neither is given)
+//! and is left untouched if it isn't valid in that encoding, rather than
+//! being rewritten with replacement characters. Rewritten bodies are always
+//! UTF-8 and are re-declared as such.
+
+use encoding_rs::{Encoding, UTF_8};
+use std::borrow::Cow;
+
+/// The `charset` parameter of a media type, if present.
+fn charset_param(content_type: &str) -> Option<&str> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("charset")
+            .then(|| value.trim().trim_matches('"'))
+    })
+}
+
+/// Decode a body to UTF-8 text, or `None` if it can't be decoded exactly.
+pub fn decode<'a>(body: &'a [u8], content_type: &str) -> Option<Cow<'a, str>> {
+    let (encoding, body) = match Encoding::for_bom(body) {
+        Some((encoding, bom_len)) => (encoding, &body[bom_len..]),
+        None => {
+            let encoding = match charset_param(content_type) {
+                Some(label) => Encoding::for_label(label.as_bytes())?,
+                None => UTF_8,
+            };
+            (encoding, body)
+        }
+    };
+    encoding.decode_without_bom_handling_and_without_replacement(body)
+}
+
+/// The media type re-declared as UTF-8, keeping its other parameters.
+pub fn as_utf8(content_type: &str) -> String {
+    let mut parts = content_type.split(';');
+    let essence = parts.next().unwrap_or_default().trim();
+    let params: Vec<&str> = parts
+        .map(str::trim)
+        .filter(|param| {
+            !param
+                .split_once('=')
+                .is_some_and(|(name, _)| name.trim().eq_ignore_ascii_case("charset"))
+        })
+        .collect();
+    let mut declared = essence.to_string();
+    for param in params {
+        declared.push_str("; ");
+        declared.push_str(param);
+    }
+    declared.push_str("; charset=utf-8");
+    declared
+}
+
+/// Whether the media type declares a charset other than UTF-8.
+pub fn declares_non_utf8(content_type: &str) -> bool {
+    charset_param(content_type)
+        .and_then(|label| Encoding::for_label(label.as_bytes()))
+        .is_some_and(|encoding| encoding != UTF_8)
+}
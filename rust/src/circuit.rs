@@ -0,0 +1,136 @@
+//! Per-origin circuit breaker.
+//!
+//! Failures (fetch errors and 5xx responses) are counted per origin host in
+//! the state store. Once an origin reaches [`FAILURE_THRESHOLD`] failures
+//! within [`WINDOW`], the circuit opens and requests fail fast for
+//! [`COOLDOWN`]. After that a single probe request is let through: success
+//! closes the circuit, failure re-opens it.
+//!
+//! Batch traffic is shed earlier: once an origin has [`BATCH_SHED_THRESHOLD`]
+//! failures in the window it gets no more batch requests, and probes are
+//! reserved for interactive traffic.
+//!
+//! Only failures and state transitions are written, keeping KV writes off the
+//! happy path. Without the state store the breaker is disabled.
+
+use crate::state;
+use crate::tenant::Priority;
+use fastly::kv_store::KVStore;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+const FAILURE_THRESHOLD: u32 = 5;
+const WINDOW: Duration = Duration::from_secs(60);
+const COOLDOWN: Duration = Duration::from_secs(30);
+
+/// Failures within [`WINDOW`] after which batch traffic is shed.
+const BATCH_SHED_THRESHOLD: u32 = 3;
+
+/// How long breaker records are kept once they stop changing.
+const RECORD_TTL: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Breaker {
+    window_start: u64,
+    failures: u32,
+    opened_at: Option<u64>,
+    probing_since: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// Send the request normally.
+    Closed,
+    /// Send the request as the half-open probe.
+    Probe,
+    /// Fail fast; the origin may be retried after this many seconds.
+    Open { retry_after: u64 },
+    /// The origin is degraded and batch traffic is held back for this many seconds.
+    Shed { retry_after: u64 },
+}
+
+fn key_for(host: &str) -> String {
+    format!("circuit.{}", host)
+}
+
+/// Forget the origin's failures, closing its circuit. Returns whether it had any.
+pub fn reset(store: &KVStore, host: &str) -> bool {
+    store.delete(&key_for(host)).is_ok()
+}
+
+/// Decide whether a request to `host` may be sent.
+pub fn check(store: &KVStore, host: &str, now: u64, priority: Priority) -> Decision {
+    let key = key_for(host);
+    let Some(mut breaker) = state::get::<Breaker>(store, &key) else {
+        return Decision::Closed;
+    };
+    let Some(opened_at) = breaker.opened_at else {
+        let window_end = breaker.window_start + WINDOW.as_secs();
+        if priority == Priority::Batch
+            && breaker.failures >= BATCH_SHED_THRESHOLD
+            && now < window_end
+        {
+            return Decision::Shed {
+                retry_after: window_end - now,
+            };
+        }
+        return Decision::Closed;
+    };
+
+    let reopen_at = opened_at + COOLDOWN.as_secs();
+    if now < reopen_at {
+        return Decision::Open {
+            retry_after: reopen_at - now,
+        };
+    }
+    if priority == Priority::Batch {
+        return Decision::Shed {
+            retry_after: COOLDOWN.as_secs(),
+        };
+    }
+    // Only one probe at a time; a probe that never reported back expires after a cooldown
+    if let Some(probing_since) = breaker.probing_since {
+        if now < probing_since + COOLDOWN.as_secs() {
+            return Decision::Open {
+                retry_after: probing_since + COOLDOWN.as_secs() - now,
+            };
+        }
+    }
+    breaker.probing_since = Some(now);
+    state::put(store, &key, &breaker, Some(RECORD_TTL));
+    Decision::Probe
+}
+
+/// Record the outcome of a request sent after `check`.
+pub fn record(store: &KVStore, host: &str, decision: Decision, success: bool, now: u64) {
+    let key = key_for(host);
+    match (decision, success) {
+        (Decision::Probe, true) => {
+            let _ = store.delete(&key);
+        }
+        (Decision::Probe, false) => {
+            let breaker = Breaker {
+                window_start: now,
+                failures: FAILURE_THRESHOLD,
+                opened_at: Some(now),
+                probing_since: None,
+            };
+            state::put(store, &key, &breaker, Some(RECORD_TTL));
+        }
+        (Decision::Closed, false) => {
+            let mut breaker = state::get::<Breaker>(store, &key).unwrap_or_default();
+            if now >= breaker.window_start + WINDOW.as_secs() {
+                breaker = Breaker {
+                    window_start: now,
+                    ..Breaker::default()
+                };
+            }
+            breaker.failures += 1;
+            if breaker.failures >= FAILURE_THRESHOLD {
+                breaker.opened_at = Some(now);
+            }
+            state::put(store, &key, &breaker, Some(RECORD_TTL));
+        }
+        _ => {}
+    }
+}
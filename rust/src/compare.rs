@@ -0,0 +1,203 @@
+//! Diffing a primary origin's response against its shadow's.
+//!
+//! Statuses and the selected headers are compared as they are. Bodies are
+//! compared decoded: JSON bodies structurally, reporting the paths that
+//! differ, and anything else by SHA-256.
+
+use crate::compression;
+use fastly::Response;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+/// Bodies larger than this aren't compared.
+pub const MAX_COMPARED_BYTES: usize = 1024 * 1024;
+
+/// Differences reported per JSON body; the rest are only counted.
+const MAX_JSON_DIFFERENCES: usize = 20;
+
+/// Headers compared when the tenant doesn't choose any.
+pub fn default_headers() -> Vec<String> {
+    vec![
+        "content-type".to_string(),
+        "cache-control".to_string(),
+        "location".to_string(),
+    ]
+}
+
+/// A response's status, headers and body, read for comparison.
+pub struct Snapshot {
+    status: u16,
+    headers: Vec<(String, String)>,
+    content_type: String,
+    body: Option<Vec<u8>>,
+}
+
+impl Snapshot {
+    /// Read a response for comparison, leaving its body in place.
+    pub fn of(resp: &mut Response) -> Self {
+        let mut copy = resp.clone_without_body();
+        let prefix = resp.get_body_prefix_mut(MAX_COMPARED_BYTES + 1);
+        let bytes = prefix.to_vec();
+        drop(prefix);
+        copy.set_body(bytes);
+        Self::take(copy)
+    }
+
+    /// Read a response that isn't needed afterwards.
+    pub fn take(mut resp: Response) -> Self {
+        compression::decode(&mut resp);
+        let prefix = resp.get_body_prefix_mut(MAX_COMPARED_BYTES + 1);
+        let body = (prefix.len() <= MAX_COMPARED_BYTES).then(|| prefix.to_vec());
+        drop(prefix);
+        Self {
+            status: resp.get_status().as_u16(),
+            headers: resp
+                .get_headers()
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect(),
+            content_type: resp
+                .get_header_str("Content-Type")
+                .unwrap_or_default()
+                .to_ascii_lowercase(),
+            body,
+        }
+    }
+
+    fn header(&self, name: &str) -> Option<String> {
+        let values: Vec<&str> = self
+            .headers
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+            .collect();
+        (!values.is_empty()).then(|| values.join(", "))
+    }
+
+    fn json(&self) -> Option<Value> {
+        let essence = self
+            .content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim();
+        if essence != "application/json" && !essence.ends_with("+json") {
+            return None;
+        }
+        serde_json::from_slice(self.body.as_ref()?).ok()
+    }
+}
+
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Record one difference, keeping at most [`MAX_JSON_DIFFERENCES`] of them.
+fn note(
+    path: &str,
+    primary: Option<Value>,
+    shadow: Option<Value>,
+    out: &mut Vec<Value>,
+    count: &mut usize,
+) {
+    *count += 1;
+    if out.len() < MAX_JSON_DIFFERENCES {
+        out.push(serde_json::json!({"path": path, "primary": primary, "shadow": shadow}));
+    }
+}
+
+/// Collect the paths at which two JSON values differ.
+fn json_diff(path: &str, primary: &Value, shadow: &Value, out: &mut Vec<Value>, count: &mut usize) {
+    match (primary, shadow) {
+        (Value::Object(a), Value::Object(b)) => {
+            for (key, value) in a {
+                let child = format!("{}.{}", path, key);
+                match b.get(key) {
+                    Some(other) => json_diff(&child, value, other, out, count),
+                    None => note(&child, Some(value.clone()), None, out, count),
+                }
+            }
+            for (key, value) in b.iter().filter(|(key, _)| !a.contains_key(*key)) {
+                let child = format!("{}.{}", path, key);
+                note(&child, None, Some(value.clone()), out, count);
+            }
+        }
+        (Value::Array(a), Value::Array(b)) => {
+            for (i, (x, y)) in a.iter().zip(b.iter()).enumerate() {
+                json_diff(&format!("{}[{}]", path, i), x, y, out, count);
+            }
+            if a.len() != b.len() {
+                let length = |n: usize| Some(serde_json::json!({"length": n}));
+                note(path, length(a.len()), length(b.len()), out, count);
+            }
+        }
+        _ if primary == shadow => {}
+        _ if kind(primary) != kind(shadow) => {
+            let kind_of = |value: &Value| Some(serde_json::json!({"type": kind(value)}));
+            note(path, kind_of(primary), kind_of(shadow), out, count);
+        }
+        _ => note(
+            path,
+            Some(primary.clone()),
+            Some(shadow.clone()),
+            out,
+            count,
+        ),
+    }
+}
+
+fn sha256(body: &[u8]) -> String {
+    hex::encode(Sha256::digest(body))
+}
+
+/// Describe how the shadow's response differs from the primary's.
+///
+/// Returns the differences and whether there were none.
+pub fn diff(primary: &Snapshot, shadow: &Snapshot, headers: &[String]) -> (Value, bool) {
+    let mut matched = primary.status == shadow.status;
+    let mut header_diffs = Map::new();
+    for name in headers {
+        let (a, b) = (primary.header(name), shadow.header(name));
+        if a != b {
+            matched = false;
+            header_diffs.insert(
+                name.to_ascii_lowercase(),
+                serde_json::json!({"primary": a, "shadow": b}),
+            );
+        }
+    }
+
+    let body = match (primary.json(), shadow.json()) {
+        (Some(a), Some(b)) => {
+            let mut differences = Vec::new();
+            let mut count = 0;
+            json_diff("$", &a, &b, &mut differences, &mut count);
+            matched &= count == 0;
+            serde_json::json!({"compared": "json", "differences": differences, "count": count})
+        }
+        _ => match (&primary.body, &shadow.body) {
+            (Some(a), Some(b)) => {
+                let (a, b) = (sha256(a), sha256(b));
+                matched &= a == b;
+                serde_json::json!({"compared": "sha256", "primary": a, "shadow": b})
+            }
+            // Too large to buffer
+            _ => serde_json::json!({"compared": "skipped"}),
+        },
+    };
+
+    let diff = serde_json::json!({
+        "status": {"primary": primary.status, "shadow": shadow.status},
+        "headers": header_diffs,
+        "body": body,
+    });
+    (diff, matched)
+}
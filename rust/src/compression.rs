@@ -0,0 +1,255 @@
+//! Decoding, re-encoding and edge compression of response bodies.
+//!
+//! Body transforms only work on plain bodies, so a gzip or brotli response
+//! that one would change is decoded first and encoded again with the same
+//! coding afterwards. Only text-like content types are decoded, and a body
+//! that would decode to more than [`MAX_DECODED_BYTES`] is left compressed.
+//!
+//! Routes with [`EdgeCompression`] also have uncompressed responses
+//! compressed for clients that accept it, for origins that don't compress.
+
+use fastly::http::{Method, StatusCode};
+use fastly::{Body, Response};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::Deserialize;
+use std::io::{Read, Write};
+
+/// Compressed bodies larger than this aren't decoded.
+const MAX_ENCODED_BYTES: usize = 2 * 1024 * 1024;
+
+/// Decoded bodies larger than this are left compressed.
+const MAX_DECODED_BYTES: usize = 8 * 1024 * 1024;
+
+/// Compression levels, balancing size against the time budget at the edge.
+const GZIP_LEVEL: u32 = 6;
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Coding {
+    Gzip,
+    Brotli,
+}
+
+impl Coding {
+    /// The coding named by a `Content-Encoding` value, if it's one we handle.
+    pub fn of(content_encoding: &str) -> Option<Self> {
+        match content_encoding.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Coding::Gzip),
+            "br" => Some(Coding::Brotli),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Coding::Gzip => "gzip",
+            Coding::Brotli => "br",
+        }
+    }
+
+    fn decode(self, encoded: &[u8]) -> Option<Vec<u8>> {
+        let mut decoded = Vec::new();
+        let limit = MAX_DECODED_BYTES as u64 + 1;
+        let read = match self {
+            Coding::Gzip => GzDecoder::new(encoded)
+                .take(limit)
+                .read_to_end(&mut decoded),
+            Coding::Brotli => brotli::Decompressor::new(encoded, 4096)
+                .take(limit)
+                .read_to_end(&mut decoded),
+        };
+        (read.is_ok() && decoded.len() <= MAX_DECODED_BYTES).then_some(decoded)
+    }
+
+    pub fn encode(self, decoded: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Coding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::new(GZIP_LEVEL));
+                encoder.write_all(decoded).ok()?;
+                encoder.finish().ok()
+            }
+            Coding::Brotli => {
+                let mut encoded = Vec::new();
+                {
+                    let mut encoder = brotli::CompressorWriter::new(
+                        &mut encoded,
+                        4096,
+                        BROTLI_QUALITY,
+                        BROTLI_WINDOW,
+                    );
+                    encoder.write_all(decoded).ok()?;
+                }
+                Some(encoded)
+            }
+        }
+    }
+}
+
+/// Text-like content that compresses well and that transforms may rewrite.
+pub fn is_compressible(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || matches!(
+            essence.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/x-www-form-urlencoded"
+                | "application/vnd.apple.mpegurl"
+                | "application/x-mpegurl"
+                | "audio/mpegurl"
+                | "image/svg+xml"
+        )
+}
+
+/// The coding to use for a client's `Accept-Encoding`, preferring brotli.
+pub fn preferred(accept_encoding: &str, brotli: bool) -> Option<Coding> {
+    let mut gzip = false;
+    let mut br = false;
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';').map(str::trim);
+        let coding = params.next().unwrap_or_default().to_ascii_lowercase();
+        let refused = params.any(|param| {
+            param
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q <= 0.0)
+        });
+        match coding.as_str() {
+            "gzip" | "x-gzip" | "*" if !refused => gzip = true,
+            "br" if !refused => br = true,
+            _ => {}
+        }
+    }
+    if br && brotli {
+        Some(Coding::Brotli)
+    } else if gzip {
+        Some(Coding::Gzip)
+    } else {
+        None
+    }
+}
+
+/// Compressing uncompressed responses at the edge.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct EdgeCompression {
+    /// Bodies smaller than this aren't worth compressing.
+    pub min_bytes: usize,
+    /// Media types to compress, such as `text/html` or `text/*`; empty means
+    /// the built-in list of text-like types.
+    pub content_types: Vec<String>,
+    /// Use brotli for clients that accept it, rather than always gzip.
+    pub brotli: bool,
+}
+
+impl Default for EdgeCompression {
+    fn default() -> Self {
+        Self {
+            min_bytes: 1024,
+            content_types: Vec::new(),
+            brotli: true,
+        }
+    }
+}
+
+impl EdgeCompression {
+    fn compresses(&self, content_type: &str) -> bool {
+        if self.content_types.is_empty() {
+            return is_compressible(content_type);
+        }
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+        self.content_types
+            .iter()
+            .any(|allowed| match allowed.strip_suffix("/*") {
+                Some(kind) => essence
+                    .split_once('/')
+                    .is_some_and(|(k, _)| k.eq_ignore_ascii_case(kind)),
+                None => allowed.eq_ignore_ascii_case(essence),
+            })
+    }
+
+    /// Compress an uncompressed response for a client sending `accept_encoding`.
+    pub fn apply(&self, resp: &mut Response, method: &Method, accept_encoding: Option<&str>) {
+        let status = resp.get_status();
+        if *method == Method::HEAD
+            || matches!(status, StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED)
+            || status == StatusCode::PARTIAL_CONTENT
+            || resp.contains_header("Content-Encoding")
+            || resp
+                .get_header_str("Cache-Control")
+                .is_some_and(|cc| cc.to_ascii_lowercase().contains("no-transform"))
+            || !self.compresses(resp.get_header_str("Content-Type").unwrap_or_default())
+        {
+            return;
+        }
+        // The representation depends on Accept-Encoding whether or not this client gets it
+        resp.append_header("Vary", "Accept-Encoding");
+        let Some(coding) = accept_encoding.and_then(|ae| preferred(ae, self.brotli)) else {
+            return;
+        };
+        let body = resp.get_body_mut();
+        let prefix = body.get_prefix_mut(MAX_DECODED_BYTES + 1);
+        if prefix.len() < self.min_bytes || prefix.len() > MAX_DECODED_BYTES {
+            return;
+        }
+        let decoded = prefix.take();
+        match coding.encode(&decoded) {
+            Some(encoded) if encoded.len() < decoded.len() => {
+                *body = Body::from(encoded);
+                resp.set_header("Content-Encoding", coding.as_str());
+                resp.remove_header("Content-Length");
+                // The compressed bytes differ from those the origin's tag names
+                if let Some(etag) = resp.get_header_str("ETag").filter(|e| !e.starts_with("W/")) {
+                    let weak = format!("W/{}", etag);
+                    resp.set_header("ETag", weak);
+                }
+            }
+            _ => *body = Body::from(decoded),
+        }
+    }
+}
+
+/// Decode a compressed response's body in place, returning the coding it had.
+pub fn decode(resp: &mut Response) -> Option<Coding> {
+    let coding = Coding::of(resp.get_header_str("Content-Encoding")?)?;
+    if !is_compressible(resp.get_header_str("Content-Type").unwrap_or_default()) {
+        return None;
+    }
+    let body = resp.get_body_mut();
+    let prefix = body.get_prefix_mut(MAX_ENCODED_BYTES + 1);
+    if prefix.len() > MAX_ENCODED_BYTES {
+        return None;
+    }
+    let encoded = prefix.take();
+    let Some(decoded) = coding.decode(&encoded) else {
+        *body = Body::from(encoded);
+        return None;
+    };
+    *body = Body::from(decoded);
+    resp.remove_header("Content-Encoding");
+    resp.remove_header("Content-Length");
+    Some(coding)
+}
+
+/// Compress a plain response's body with `coding`.
+pub fn encode(resp: &mut Response, coding: Coding) {
+    let decoded = resp.take_body_bytes();
+    match coding.encode(&decoded) {
+        Some(encoded) => {
+            resp.set_body(encoded);
+            resp.set_header("Content-Encoding", coding.as_str());
+        }
+        None => resp.set_body(decoded),
+    }
+    resp.remove_header("Content-Length");
+}
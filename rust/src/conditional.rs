@@ -0,0 +1,101 @@
+//! Conditional requests answered at the edge.
+//!
+//! On cached routes the origin is always asked for the full response, so the
+//! cache can be filled, and the client's `If-None-Match` or
+//! `If-Modified-Since` is checked against the response it would get, after
+//! any rewriting. Rewritten bodies get an `ETag` of their own, since the
+//! origin's no longer describes them.
+
+use crate::compression;
+use fastly::http::{Method, StatusCode};
+use fastly::{Body, Request, Response};
+use sha2::{Digest, Sha256};
+use std::time::SystemTime;
+
+/// Rewritten bodies larger than this lose the origin's `ETag` without getting a new one.
+const MAX_TAGGED_BODY_BYTES: usize = 8 * 1024 * 1024;
+
+/// Headers describing the body, which a `304` has none of.
+const BODY_HEADERS: [&str; 3] = ["Content-Length", "Content-Range", "Content-Type"];
+
+/// The client's preconditions, held back from the origin.
+#[derive(Debug, Default)]
+pub struct Conditions {
+    if_none_match: Option<String>,
+    if_modified_since: Option<SystemTime>,
+}
+
+impl Conditions {
+    /// Remove the client's preconditions from a GET or HEAD request.
+    pub fn take(req: &mut Request) -> Self {
+        if !matches!(*req.get_method(), Method::GET | Method::HEAD) {
+            return Self::default();
+        }
+        let if_none_match = req.get_header_str("If-None-Match").map(str::to_string);
+        let if_modified_since = req
+            .get_header_str("If-Modified-Since")
+            .and_then(|date| httpdate::parse_http_date(date).ok());
+        req.remove_header("If-None-Match");
+        req.remove_header("If-Modified-Since");
+        Self {
+            if_none_match,
+            if_modified_since,
+        }
+    }
+
+    /// Whether the client already has the response's current representation.
+    fn not_modified(&self, resp: &Response) -> bool {
+        if resp.get_status() != StatusCode::OK {
+            return false;
+        }
+        // If-None-Match takes precedence, and uses the weak comparison
+        if let Some(if_none_match) = &self.if_none_match {
+            let Some(etag) = resp.get_header_str("ETag") else {
+                return false;
+            };
+            let etag = etag.trim().trim_start_matches("W/");
+            return if_none_match
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+        }
+        let last_modified = resp
+            .get_header_str("Last-Modified")
+            .and_then(|date| httpdate::parse_http_date(date).ok());
+        matches!(
+            (self.if_modified_since, last_modified),
+            (Some(since), Some(modified)) if modified <= since
+        )
+    }
+
+    /// Turn the response into a `304` if the client's copy is current.
+    pub fn apply(&self, resp: &mut Response) {
+        if !self.not_modified(resp) {
+            return;
+        }
+        resp.set_status(StatusCode::NOT_MODIFIED);
+        resp.set_body(Body::new());
+        for name in BODY_HEADERS {
+            resp.remove_header(name);
+        }
+    }
+}
+
+/// Give a rewritten response a strong `ETag` computed from its body.
+///
+/// Binary types, which no rewriting touches, keep the origin's.
+pub fn tag(resp: &mut Response) {
+    if !compression::is_compressible(resp.get_header_str("Content-Type").unwrap_or_default()) {
+        return;
+    }
+    resp.remove_header("ETag");
+    let body = resp.get_body_mut();
+    let prefix = body.get_prefix_mut(MAX_TAGGED_BODY_BYTES + 1);
+    if prefix.len() > MAX_TAGGED_BODY_BYTES {
+        return;
+    }
+    let bytes = prefix.take();
+    let digest = Sha256::digest(&bytes);
+    *body = Body::from(bytes);
+    resp.set_header("ETag", format!("\"{}\"", hex::encode(&digest[..16])));
+}
@@ -0,0 +1,205 @@
+//! Deployment-wide proxy settings.
+//!
+//! The `proxy` entry in `dynserv-config` holds a [`ProxyConfig`]: the default
+//! origin timeouts and request deadline, resource limits, which of the
+//! proxy's own endpoints are enabled, the hosts targets may be on, the TLS
+//! fingerprints clients may not have, the shield requests go through,
+//! maintenance mode, where the destination audit log, usage records and
+//! latency histograms go, how proxy loops are recognised and how the proxy
+//! names itself in `Via`. Anything left out keeps its compiled-in default.
+//!
+//! An entry for the environment the service runs in is layered on top:
+//! `proxy.local` under Viceroy, for local development, and `proxy.staging`
+//! on a staging deployment. Its settings replace the `proxy` entry's, object
+//! by object, so an override only needs the values it changes.
+
+use crate::routes::{self, CONFIG_STORE};
+use crate::timeouts::Timeouts;
+use crate::{
+    deadline, destinations, fingerprint, latency, loops, maintenance, metering, shielding, via,
+};
+use fastly::config_store::ConfigStore;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProxyConfig {
+    pub timeouts: DefaultTimeouts,
+    pub deadline: deadline::Settings,
+    pub limits: Limits,
+    pub features: Features,
+    /// Hosts targets may be on, as exact names or `*.` patterns. Empty
+    /// allows any host that passes the other checks.
+    pub allowed_hosts: Vec<String>,
+    pub tls_fingerprints: fingerprint::Settings,
+    pub shielding: shielding::Settings,
+    pub maintenance: maintenance::Settings,
+    pub destination_log: destinations::Settings,
+    pub metering: metering::Settings,
+    pub latency: latency::Settings,
+    pub loops: loops::Settings,
+    pub via: via::Settings,
+}
+
+/// Origin timeouts for requests that don't set their own.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DefaultTimeouts {
+    pub connect_secs: f64,
+    pub first_byte_secs: f64,
+    pub between_bytes_secs: f64,
+}
+
+impl Default for DefaultTimeouts {
+    fn default() -> Self {
+        Self {
+            connect_secs: 10.0,
+            first_byte_secs: 30.0,
+            between_bytes_secs: 30.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    /// Backend requests a single execution may start. Compute's own limit is 32.
+    pub max_backend_requests: u32,
+    /// URLs accepted in one `/batch` request.
+    pub max_batch_urls: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_backend_requests: 28,
+            max_batch_urls: 20,
+        }
+    }
+}
+
+/// The proxy's own endpoints, each of which can be turned off.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Features {
+    /// `POST /batch`.
+    pub batch: bool,
+    /// `/debug/echo`, `/debug/plan`, `/debug/replay` and `dry_run=1`.
+    pub debug: bool,
+    /// `/stats` and `/metrics`.
+    pub stats: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self {
+            batch: true,
+            debug: true,
+            stats: true,
+        }
+    }
+}
+
+impl ProxyConfig {
+    /// The default origin timeouts.
+    pub fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            connect: Duration::from_secs_f64(self.timeouts.connect_secs),
+            first_byte: Duration::from_secs_f64(self.timeouts.first_byte_secs),
+            between_bytes: Duration::from_secs_f64(self.timeouts.between_bytes_secs),
+        }
+    }
+
+    /// Whether targets may be on the host.
+    pub fn allows_host(&self, host: &str) -> bool {
+        self.allowed_hosts.is_empty()
+            || self
+                .allowed_hosts
+                .iter()
+                .any(|pattern| routes::host_matches(pattern, host))
+    }
+}
+
+/// The settings for this request, once they're loaded.
+static CURRENT: Mutex<Option<ProxyConfig>> = Mutex::new(None);
+
+/// The entry overriding `proxy` in the environment the service runs in.
+fn environment_entry() -> Option<&'static str> {
+    if std::env::var("FASTLY_HOSTNAME").is_ok_and(|host| host == "localhost") {
+        Some("proxy.local")
+    } else if std::env::var("FASTLY_IS_STAGING").is_ok_and(|staging| staging == "1") {
+        Some("proxy.staging")
+    } else {
+        None
+    }
+}
+
+/// Replace `base`'s values with `overrides`', merging objects key by key.
+fn merge(base: &mut Value, overrides: Value) {
+    match (base, overrides) {
+        (Value::Object(base), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                merge(base.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+fn check(config: &ProxyConfig) -> Result<(), String> {
+    let timeouts = &config.timeouts;
+    for (name, secs) in [
+        ("connect_secs", timeouts.connect_secs),
+        ("first_byte_secs", timeouts.first_byte_secs),
+        ("between_bytes_secs", timeouts.between_bytes_secs),
+    ] {
+        if !secs.is_finite() || secs <= 0.0 {
+            return Err(format!(
+                "timeouts.{} must be a positive number of seconds",
+                name
+            ));
+        }
+    }
+    let total_secs = config.deadline.total_secs;
+    if !total_secs.is_finite() || total_secs <= 0.0 {
+        return Err("deadline.total_secs must be a positive number of seconds".to_string());
+    }
+    if !(0.0..=1.0).contains(&config.metering.sample_rate) {
+        return Err("metering.sample_rate must be between 0 and 1".to_string());
+    }
+    Ok(())
+}
+
+/// Load the settings for this request from `dynserv-config`.
+pub fn load() -> Result<ProxyConfig, String> {
+    let mut layered = Value::Object(Default::default());
+    if let Ok(store) = ConfigStore::try_open(CONFIG_STORE) {
+        for entry in std::iter::once("proxy").chain(environment_entry()) {
+            let Some(json) = store.get(entry) else {
+                continue;
+            };
+            let layer = serde_json::from_str(&json)
+                .map_err(|e| format!("Invalid '{}' entry: {}", entry, e))?;
+            merge(&mut layered, layer);
+        }
+    }
+    let config: ProxyConfig =
+        serde_json::from_value(layered).map_err(|e| format!("Invalid 'proxy' entry: {}", e))?;
+    check(&config).map_err(|e| format!("Invalid 'proxy' entry: {}", e))?;
+    if let Ok(mut current) = CURRENT.lock() {
+        *current = Some(config.clone());
+    }
+    Ok(config)
+}
+
+/// The settings loaded for this request, or the defaults before they are.
+pub fn current() -> ProxyConfig {
+    CURRENT
+        .lock()
+        .ok()
+        .and_then(|current| current.clone())
+        .unwrap_or_default()
+}
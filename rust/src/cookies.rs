@@ -0,0 +1,47 @@
+//! Rescoping origin cookies to the proxy.
+//!
+//! Clients reach every origin through the proxy's own host and path, so an
+//! origin's `Domain` and `Path` attributes would stop its cookies from ever
+//! being sent back. Rewritten cookies are host-only cookies for the proxy,
+//! valid for all of its paths, and `SameSite=None` becomes `SameSite=Lax`.
+
+use fastly::Response;
+
+/// Rewrite one `Set-Cookie` value to scope it to the proxy.
+fn rescope(set_cookie: &str) -> String {
+    let mut parts = set_cookie.split(';').map(str::trim);
+    let mut rewritten = vec![parts.next().unwrap_or_default().to_string()];
+    for attribute in parts {
+        let name = attribute
+            .split_once('=')
+            .map_or(attribute, |(name, _)| name)
+            .trim()
+            .to_ascii_lowercase();
+        match name.as_str() {
+            "domain" | "path" => {}
+            // Cross-site use only made sense from the origin's own site
+            "samesite" if attribute.to_ascii_lowercase().ends_with("none") => {
+                rewritten.push("SameSite=Lax".to_string())
+            }
+            _ => rewritten.push(attribute.to_string()),
+        }
+    }
+    rewritten.push("Path=/".to_string());
+    rewritten.join("; ")
+}
+
+/// Rescope every cookie the response sets.
+pub fn rescope_all(resp: &mut Response) {
+    let cookies: Vec<String> = resp
+        .get_header_all_str("Set-Cookie")
+        .into_iter()
+        .map(rescope)
+        .collect();
+    if cookies.is_empty() {
+        return;
+    }
+    resp.remove_header("Set-Cookie");
+    for cookie in cookies {
+        resp.append_header("Set-Cookie", cookie);
+    }
+}
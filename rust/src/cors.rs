@@ -0,0 +1,136 @@
+//! Cross-origin resource sharing.
+//!
+//! A tenant with `cors` settings has preflight requests answered at the edge
+//! without contacting the origin, and its other responses to allowed origins
+//! carry the matching `Access-Control-*` headers, error responses included.
+
+use fastly::http::{Method, StatusCode};
+use fastly::{Request, Response};
+use serde::Deserialize;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Cors {
+    /// Origins allowed to call the proxy, such as `https://app.example.com`, or `*`.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    /// Request headers browsers may send; empty allows whatever the preflight asks for.
+    pub allowed_headers: Vec<String>,
+    /// Response headers scripts may read.
+    pub expose_headers: Vec<String>,
+    pub allow_credentials: bool,
+    pub max_age_secs: u64,
+    /// Answer with the request's origin instead of `*`, even when any origin is allowed.
+    pub reflect_origin: bool,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: vec!["GET".to_string(), "HEAD".to_string(), "POST".to_string()],
+            allowed_headers: Vec::new(),
+            expose_headers: Vec::new(),
+            allow_credentials: false,
+            max_age_secs: 600,
+            reflect_origin: false,
+        }
+    }
+}
+
+/// A CORS preflight, rather than a request to proxy.
+pub fn is_preflight(req: &Request) -> bool {
+    req.get_method() == Method::OPTIONS
+        && req.contains_header("Origin")
+        && req.contains_header("Access-Control-Request-Method")
+}
+
+impl Cors {
+    /// The `Access-Control-Allow-Origin` value for a request's origin, if it's allowed.
+    fn allow_origin(&self, origin: &str) -> Option<String> {
+        let any = self.allowed_origins.iter().any(|o| o == "*");
+        if !any
+            && !self
+                .allowed_origins
+                .iter()
+                .any(|o| o.eq_ignore_ascii_case(origin))
+        {
+            return None;
+        }
+        // Credentialed responses may not use the wildcard
+        if any && !self.reflect_origin && !self.allow_credentials {
+            Some("*".to_string())
+        } else {
+            Some(origin.to_string())
+        }
+    }
+
+    fn annotate(&self, resp: &mut Response, origin: &str) {
+        resp.append_header("Vary", "Origin");
+        let Some(allow_origin) = self.allow_origin(origin) else {
+            return;
+        };
+        resp.set_header("Access-Control-Allow-Origin", allow_origin);
+        if self.allow_credentials {
+            resp.set_header("Access-Control-Allow-Credentials", "true");
+        }
+        if !self.expose_headers.is_empty() {
+            resp.set_header(
+                "Access-Control-Expose-Headers",
+                self.expose_headers.join(", "),
+            );
+        }
+    }
+
+    /// Answer a preflight at the edge. The origin headers are added by [`annotate`].
+    pub fn preflight(&self, req: &Request) -> Response {
+        let origin = req.get_header_str("Origin").unwrap_or_default();
+        let method = req
+            .get_header_str("Access-Control-Request-Method")
+            .unwrap_or_default();
+        let method_allowed = self
+            .allowed_methods
+            .iter()
+            .any(|m| m.eq_ignore_ascii_case(method));
+        let mut resp = Response::from_status(StatusCode::NO_CONTENT);
+        if !method_allowed || self.allow_origin(origin).is_none() {
+            return resp;
+        }
+        resp.set_header(
+            "Access-Control-Allow-Methods",
+            self.allowed_methods.join(", "),
+        );
+        let headers = if self.allowed_headers.is_empty() {
+            req.get_header_str("Access-Control-Request-Headers")
+                .map(str::to_string)
+        } else {
+            Some(self.allowed_headers.join(", "))
+        };
+        if let Some(headers) = headers {
+            resp.set_header("Access-Control-Allow-Headers", headers);
+        }
+        resp.set_header("Access-Control-Max-Age", self.max_age_secs.to_string());
+        resp
+    }
+}
+
+/// The current request's settings and `Origin`, once its tenant is known.
+static CURRENT: Mutex<Option<(Cors, String)>> = Mutex::new(None);
+
+/// Use the tenant's settings for the current request's response.
+pub fn configure(cors: &Cors, req: &Request) {
+    let Some(origin) = req.get_header_str("Origin") else {
+        return;
+    };
+    if let Ok(mut current) = CURRENT.lock() {
+        *current = Some((cors.clone(), origin.to_string()));
+    }
+}
+
+/// Add CORS headers to the response, whichever path produced it.
+pub fn annotate(resp: &mut Response) {
+    if let Some((cors, origin)) = CURRENT.lock().ok().and_then(|mut current| current.take()) {
+        cors.annotate(resp, &origin);
+    }
+}
@@ -0,0 +1,86 @@
+//! Origin credentials held by the proxy.
+//!
+//! A tenant can attach credentials to its requests for matching origins:
+//! HTTP Basic, a bearer token, a static header such as an API key, or an
+//! OAuth2 access token the proxy obtains itself (see [`crate::oauth`]). Secrets
+//! are read from the `dynserv-secrets` Secret Store when a request needs
+//! them, so neither clients nor the proxy's config ever hold them.
+
+use crate::oauth::ClientCredentials;
+use crate::{routes, secrets};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use fastly::http::{HeaderName, HeaderValue};
+use fastly::Request;
+use serde::Deserialize;
+
+/// Credentials for requests to origins matching `host`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct OriginCredential {
+    /// Destination host, exact or `*.example.com`.
+    pub host: String,
+    #[serde(flatten)]
+    pub credential: Credential,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Credential {
+    /// `Authorization: Basic`, with the password read from a secret.
+    Basic { username: String, password: String },
+    /// `Authorization: Bearer`, with the token read from a secret.
+    Bearer { token: String },
+    /// Any header, set to a secret's value.
+    Header { name: String, secret: String },
+    /// `Authorization: Bearer`, with a token from the client-credentials grant.
+    Oauth2(ClientCredentials),
+}
+
+fn text(name: &str) -> Result<String, String> {
+    String::from_utf8(secrets::read(name)?).map_err(|_| format!("Secret '{}' isn't text", name))
+}
+
+impl Credential {
+    /// The header carrying these credentials.
+    fn header(&self) -> Result<(HeaderName, HeaderValue), String> {
+        let (name, value) = match self {
+            Credential::Basic { username, password } => {
+                let pair = format!("{}:{}", username, text(password)?);
+                ("authorization", format!("Basic {}", STANDARD.encode(pair)))
+            }
+            Credential::Bearer { token } => ("authorization", format!("Bearer {}", text(token)?)),
+            Credential::Header { name, secret } => (name.as_str(), text(secret)?),
+            Credential::Oauth2(client) => ("authorization", format!("Bearer {}", client.token()?)),
+        };
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("'{}' is not a valid header name", name))?;
+        let value = HeaderValue::from_str(value.trim())
+            .map_err(|_| format!("Invalid value for header '{}'", name))?;
+        Ok((name, value))
+    }
+}
+
+/// The first entry matching `host`, if any.
+pub fn matching<'a>(
+    credentials: &'a [OriginCredential],
+    host: &str,
+) -> Option<&'a OriginCredential> {
+    credentials
+        .iter()
+        .find(|entry| routes::host_matches(&entry.host, host))
+}
+
+/// Attach the first matching entry's credentials to a request for `host`,
+/// replacing any the client sent.
+pub fn attach(
+    req: &mut Request,
+    credentials: &[OriginCredential],
+    host: &str,
+) -> Result<(), String> {
+    let Some(entry) = matching(credentials, host) else {
+        return Ok(());
+    };
+    let (name, value) = entry.credential.header()?;
+    req.set_header(name, value);
+    Ok(())
+}
@@ -0,0 +1,148 @@
+//! The overall deadline for a request.
+//!
+//! Each request gets a budget, counted from when it arrived: the deployment's
+//! `total_secs`, the tenant's own `deadline_secs`, or less if the client sent
+//! a deadline of its own in [`DEADLINE_HEADER`] or `grpc-timeout`. Backends
+//! are built with timeouts no longer than what's left of it, retries,
+//! hedges and redirect hops aren't started once it has run out, and origins
+//! are told it so they can give up in time too.
+
+use crate::timeouts::Timeouts;
+use fastly::Request;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The deadline as Unix time in milliseconds, from clients and to origins.
+pub const DEADLINE_HEADER: &str = "X-Request-Deadline";
+
+/// Less time than this left isn't worth starting another origin request for.
+const MIN_REMAINING: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Budget for a request, unless its tenant sets another.
+    pub total_secs: f64,
+    /// Send the deadline to origins.
+    pub propagate: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            total_secs: 55.0,
+            propagate: true,
+        }
+    }
+}
+
+struct Budget {
+    started: Option<Instant>,
+    deadline: Option<Instant>,
+}
+
+static CURRENT: Mutex<Budget> = Mutex::new(Budget {
+    started: None,
+    deadline: None,
+});
+
+/// Start counting for a request that has just arrived.
+pub fn reset() {
+    if let Ok(mut current) = CURRENT.lock() {
+        current.started = Some(Instant::now());
+        current.deadline = None;
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis()
+}
+
+/// How long the client is prepared to wait, if it said.
+fn requested_by_client(req: &Request) -> Option<Duration> {
+    if let Some(deadline) = req.get_header_str(DEADLINE_HEADER) {
+        let deadline: u128 = deadline.trim().parse().ok()?;
+        let now = unix_millis(SystemTime::now());
+        let left = u64::try_from(deadline.saturating_sub(now)).unwrap_or(u64::MAX);
+        return Some(Duration::from_millis(left));
+    }
+    grpc_timeout(req.get_header_str("grpc-timeout")?)
+}
+
+/// A gRPC timeout: up to eight digits and a unit.
+fn grpc_timeout(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let digits = value.get(..value.len().checked_sub(1)?)?;
+    if digits.is_empty() || digits.len() > 8 {
+        return None;
+    }
+    let amount: u64 = digits.parse().ok()?;
+    Some(match &value[digits.len()..] {
+        "H" => Duration::from_secs(amount * 3600),
+        "M" => Duration::from_secs(amount * 60),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    })
+}
+
+/// What's left of the request's budget, once it has one.
+pub fn remaining() -> Option<Duration> {
+    let current = CURRENT.lock().ok()?;
+    let deadline = current.deadline?;
+    Some(deadline.saturating_duration_since(Instant::now()))
+}
+
+/// Whether too little of the budget is left to start an origin request.
+pub fn expired() -> bool {
+    remaining().is_some_and(|remaining| remaining < MIN_REMAINING)
+}
+
+/// A timeout no longer than what's left of the budget.
+pub fn bound(timeout: Duration) -> Duration {
+    match remaining() {
+        Some(remaining) => timeout.min(remaining.max(MIN_REMAINING)),
+        None => timeout,
+    }
+}
+
+/// Backend timeouts no longer than what's left of the budget.
+pub fn bound_timeouts(timeouts: Timeouts) -> Timeouts {
+    Timeouts {
+        connect: bound(timeouts.connect),
+        first_byte: bound(timeouts.first_byte),
+        between_bytes: bound(timeouts.between_bytes),
+    }
+}
+
+impl Settings {
+    /// Set the request's budget, now its tenant is known.
+    pub fn start(&self, tenant_secs: Option<f64>, req: &Request) {
+        let secs = tenant_secs
+            .filter(|secs| secs.is_finite() && *secs > 0.0)
+            .unwrap_or(self.total_secs);
+        let mut budget = Duration::from_secs_f64(secs);
+        if let Some(requested) = requested_by_client(req) {
+            budget = budget.min(requested);
+        }
+        if let Ok(mut current) = CURRENT.lock() {
+            let started = *current.started.get_or_insert_with(Instant::now);
+            current.deadline = started.checked_add(budget);
+        }
+    }
+
+    /// Tell the origin when the proxy will give up on it.
+    pub fn propagate(&self, req: &mut Request, grpc: bool) {
+        let Some(remaining) = remaining().filter(|_| self.propagate) else {
+            return;
+        };
+        let deadline = unix_millis(SystemTime::now() + remaining);
+        req.set_header(DEADLINE_HEADER, deadline.to_string());
+        if grpc {
+            req.set_header("grpc-timeout", format!("{}m", remaining.as_millis().max(1)));
+        }
+    }
+}
@@ -0,0 +1,266 @@
+//! Audit log of every proxied destination.
+//!
+//! Each target a request asked for, including every URL of a batch, gets a
+//! record of who asked, what was decided and how the origin answered. Once
+//! the response has been sent the records are written to the log endpoint
+//! named by the `destination_log` settings of the deployment's
+//! [`ProxyConfig`](crate::config::ProxyConfig), and the newest `keep_last` of
+//! them are kept in the state store for `GET /admin/audit`.
+
+use crate::stats::{Outcome, Sample};
+use crate::{config, state};
+use fastly::kv_store::KVStore;
+use fastly::log::Endpoint;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Prefix of the kept records, which are keyed newest first.
+const PREFIX: &str = "destinations.";
+
+/// Keys read per list page.
+const LIST_PAGE: u32 = 1000;
+
+/// Where destination records go.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Real-time log endpoint each record is written to.
+    pub endpoint: Option<String>,
+    /// Records kept in the state store; 0 keeps none.
+    pub keep_last: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    /// Sent to the origin, or answered from the edge cache.
+    Allowed,
+    /// Refused, or failed before it could be sent.
+    Refused,
+    /// Described by a dry run without being sent.
+    DryRun,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    pub timestamp: u64,
+    pub request_id: String,
+    pub key_id: Option<String>,
+    pub tenant: Option<String>,
+    pub client_ip: Option<String>,
+    pub target_url: String,
+    pub decision: Decision,
+    /// Why the destination was refused or failed.
+    pub reason: Option<String>,
+    /// The origin's status, or the proxy's for a refusal.
+    pub status: Option<u16>,
+}
+
+/// A destination asked for by the request being handled.
+struct Destination {
+    url: String,
+    decision: Decision,
+    reason: Option<String>,
+    status: Option<u16>,
+    /// The request's own target, whose status is the response's.
+    primary: bool,
+}
+
+static CURRENT: Mutex<Vec<Destination>> = Mutex::new(Vec::new());
+
+fn push(destination: Destination) {
+    if let Ok(mut current) = CURRENT.lock() {
+        current.push(destination);
+    }
+}
+
+/// Note the request's target, refused until [`decide`] says otherwise.
+pub fn requested(url: &str) {
+    push(Destination {
+        url: url.to_string(),
+        decision: Decision::Refused,
+        reason: None,
+        status: None,
+        primary: true,
+    });
+}
+
+/// Settle the decision for the request's target.
+pub fn decide(decision: Decision) {
+    if let Ok(mut current) = CURRENT.lock() {
+        for destination in current.iter_mut().filter(|destination| destination.primary) {
+            destination.decision = decision;
+        }
+    }
+}
+
+/// Note a batch URL and its outcome.
+pub fn batch_item(url: &str, decision: Decision, status: Option<u16>, reason: Option<&str>) {
+    push(Destination {
+        url: url.to_string(),
+        decision,
+        reason: reason.map(str::to_string),
+        status,
+        primary: false,
+    });
+}
+
+/// Forget the previous request's destinations.
+pub fn reset() {
+    if let Ok(mut current) = CURRENT.lock() {
+        current.clear();
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Write the request's destination records once its response has been sent.
+pub fn emit(
+    request_id: &str,
+    key_id: Option<&str>,
+    client_ip: Option<IpAddr>,
+    sample: &Sample,
+    outcome: &Outcome,
+) {
+    let destinations = match CURRENT.lock() {
+        Ok(mut current) => std::mem::take(&mut *current),
+        Err(_) => return,
+    };
+    let settings = config::current().destination_log;
+    if destinations.is_empty() || (settings.endpoint.is_none() && settings.keep_last == 0) {
+        return;
+    }
+    let timestamp = now();
+    let records: Vec<Record> = destinations
+        .into_iter()
+        .map(|destination| {
+            let reason = match destination.decision {
+                Decision::Refused if destination.primary => sample.error.clone(),
+                _ => destination.reason,
+            };
+            let status = if destination.primary {
+                Some(outcome.status.as_u16())
+            } else {
+                destination.status
+            };
+            Record {
+                timestamp,
+                request_id: request_id.to_string(),
+                key_id: key_id.map(str::to_string),
+                tenant: sample.tenant.clone(),
+                client_ip: client_ip.map(|ip| ip.to_string()),
+                target_url: destination.url,
+                decision: destination.decision,
+                reason,
+                status,
+            }
+        })
+        .collect();
+
+    if let Some(mut endpoint) = settings
+        .endpoint
+        .as_deref()
+        .and_then(|name| Endpoint::try_from_name(name).ok())
+    {
+        for record in &records {
+            if let Ok(line) = serde_json::to_string(record) {
+                let _ = writeln!(endpoint, "{}", line);
+            }
+        }
+    }
+    if settings.keep_last > 0 {
+        if let Some(store) = state::open() {
+            keep(&store, &records, settings.keep_last);
+        }
+    }
+}
+
+/// Save the records and drop any beyond the newest `keep_last`.
+fn keep(store: &KVStore, records: &[Record], keep_last: u32) {
+    for (index, record) in records.iter().enumerate() {
+        // Counting down from a far-off time lists the newest records first
+        let key = format!(
+            "{}{:012}.{}.{}",
+            PREFIX,
+            u64::from(u32::MAX).saturating_sub(record.timestamp),
+            record.request_id,
+            index
+        );
+        state::put(store, &key, record, None);
+    }
+    let Ok(kept) = store.build_list().prefix(PREFIX).limit(keep_last).execute() else {
+        return;
+    };
+    let Some(cursor) = kept.next_cursor() else {
+        return;
+    };
+    let older = store
+        .build_list()
+        .prefix(PREFIX)
+        .limit(LIST_PAGE)
+        .cursor(&cursor)
+        .execute();
+    if let Ok(older) = older {
+        for key in older.into_keys() {
+            let _ = store.delete(&key);
+        }
+    }
+}
+
+/// Which kept records to show.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub key_id: Option<String>,
+    pub tenant: Option<String>,
+}
+
+impl Filter {
+    fn matches(&self, record: &Record) -> bool {
+        self.key_id
+            .as_ref()
+            .is_none_or(|key_id| record.key_id.as_ref() == Some(key_id))
+            && self
+                .tenant
+                .as_ref()
+                .is_none_or(|tenant| record.tenant.as_ref() == Some(tenant))
+    }
+}
+
+/// The newest kept records matching the filter, newest first.
+pub fn recent(store: &KVStore, filter: &Filter, limit: usize) -> Vec<Record> {
+    let mut records = Vec::new();
+    let mut cursor: Option<String> = None;
+    loop {
+        let list = store.build_list().prefix(PREFIX).limit(LIST_PAGE);
+        let list = match &cursor {
+            Some(cursor) => list.cursor(cursor),
+            None => list,
+        };
+        let Ok(page) = list.execute() else {
+            break;
+        };
+        cursor = page.next_cursor();
+        for key in page.into_keys() {
+            if let Some(record) = state::get::<Record>(store, &key) {
+                if filter.matches(&record) {
+                    records.push(record);
+                    if records.len() == limit {
+                        return records;
+                    }
+                }
+            }
+        }
+        if cursor.is_none() {
+            break;
+        }
+    }
+    records
+}
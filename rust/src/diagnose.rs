@@ -0,0 +1,85 @@
+//! Diagnostics for failed origin fetches.
+//!
+//! Routes with `diagnose_failures` set get a `hint` in their fetch error
+//! responses. The send error already says whether DNS resolution succeeded;
+//! for connection-level failures a quick `HEAD /` probe checks whether the
+//! origin is reachable at all.
+
+use crate::{backend, errors, limits};
+use fastly::http::request::{SendError, SendErrorCause};
+use fastly::{Request, Response};
+use std::time::Duration;
+
+/// Timeout for each phase of the reachability probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Build the fetch error response, including a diagnostic hint.
+pub fn failure_response(err: &SendError, hostname: &str, port: u16, target: &str) -> Response {
+    let (dns, hint) = match err.root_cause() {
+        SendErrorCause::DnsTimeout => (
+            "timeout",
+            format!("DNS lookup for {} timed out; check the domain's nameservers", hostname),
+        ),
+        SendErrorCause::DnsError { .. } | SendErrorCause::DestinationNotFound => (
+            "failed",
+            format!("{} did not resolve; check the hostname and its DNS records", hostname),
+        ),
+        SendErrorCause::TlsCertificateError => (
+            "resolved",
+            format!(
+                "The TLS certificate for {} was rejected; it must be valid for the hostname and chain to a public CA",
+                hostname
+            ),
+        ),
+        SendErrorCause::TlsProtocolError
+        | SendErrorCause::TlsAlertReceived { .. }
+        | SendErrorCause::TlsConfigurationError => (
+            "resolved",
+            format!("The TLS handshake with {} failed; check the origin's TLS configuration", hostname),
+        ),
+        SendErrorCause::DestinationUnavailable
+        | SendErrorCause::DestinationIpUnroutable
+        | SendErrorCause::ConnectionRefused
+        | SendErrorCause::ConnectionTerminated
+        | SendErrorCause::ConnectionTimeout
+        | SendErrorCause::HttpResponseTimeout => ("resolved", reachability_hint(hostname, port)),
+        _ => (
+            "resolved",
+            format!("{} returned an invalid or incomplete HTTP response", hostname),
+        ),
+    };
+
+    errors::fetch_failed(err, target)
+        .with("dns", dns)
+        .with("hint", hint)
+        .into_response()
+}
+
+fn reachability_hint(hostname: &str, port: u16) -> String {
+    if limits::reserve_request().is_err() {
+        return format!(
+            "Could not connect to {}:{}; no budget left to probe it",
+            hostname, port
+        );
+    }
+    let probe = backend::create_probe(hostname, port, PROBE_TIMEOUT)
+        .ok()
+        .map(|backend| {
+            Request::head(format!("https://{}:{}/", hostname, port))
+                .with_header("Host", hostname)
+                .with_pass(true)
+                .send(backend.name())
+        });
+    match probe {
+        Some(Ok(resp)) => format!(
+            "{}:{} is reachable (HEAD / returned {}); the failure is likely specific to this request or intermittent",
+            hostname,
+            port,
+            resp.get_status().as_u16()
+        ),
+        _ => format!(
+            "{}:{} is not accepting connections from Fastly; check the origin is up and not blocking Fastly's IP ranges",
+            hostname, port
+        ),
+    }
+}
@@ -0,0 +1,68 @@
+//! `/debug/echo`: the client request as the proxy sees it.
+
+use crate::headers::{self, HeaderRules};
+use crate::shielding;
+use fastly::geo::geo_lookup;
+use fastly::http::StatusCode;
+use fastly::{Request, Response};
+
+/// Describe the incoming request after the forwarding rules have been applied.
+pub fn respond(req: &Request, rules: &HeaderRules) -> Response {
+    let mut forwarded = req.clone_without_body();
+    headers::strip(&mut forwarded);
+    rules.apply(&mut forwarded);
+
+    // Don't reflect the API key back
+    let mut url = req.get_url().clone();
+    let pairs: Vec<(String, String)> = req
+        .get_url()
+        .query_pairs()
+        .map(|(k, v)| {
+            let v = if k == "key" || k == "token" {
+                "[redacted]".into()
+            } else {
+                v
+            };
+            (k.into_owned(), v.into_owned())
+        })
+        .collect();
+    if !pairs.is_empty() {
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+
+    let headers: Vec<serde_json::Value> = forwarded
+        .get_headers()
+        .map(|(name, value)| {
+            serde_json::json!([name.as_str(), value.to_str().unwrap_or("[binary]")])
+        })
+        .collect();
+
+    let client_ip = shielding::client_ip(req);
+    let geo = client_ip.and_then(geo_lookup).map(|geo| {
+        serde_json::json!({
+            "as_name": geo.as_name(),
+            "as_number": geo.as_number(),
+            "city": geo.city(),
+            "continent": geo.continent().as_code(),
+            "country_code": geo.country_code(),
+            "region": geo.region(),
+            "latitude": geo.latitude(),
+            "longitude": geo.longitude(),
+        })
+    });
+
+    let body = serde_json::json!({
+        "method": req.get_method_str(),
+        "url": url.as_str(),
+        "http_version": format!("{:?}", req.get_version()),
+        "headers": headers,
+        "client": {
+            "ip": client_ip.map(|ip| ip.to_string()),
+            "geo": geo,
+        },
+    });
+    Response::from_status(StatusCode::OK)
+        .with_header("Content-Type", "application/json")
+        .with_header("Cache-Control", "no-store")
+        .with_body(serde_json::to_string_pretty(&body).unwrap_or_default())
+}
@@ -0,0 +1,155 @@
+//! Tenants' own error pages.
+//!
+//! The proxy's error responses are JSON meant for developers. A tenant
+//! serving consumer-facing apps can replace them with its own JSON or HTML
+//! by storing templates in the state store, under
+//! `error_pages.<tenant>.<status>` for one status or
+//! `error_pages.<tenant>.<class>`, such as `5xx`, for a whole class:
+//!
+//! ```json
+//! {"content_type": "text/html", "body": "<p>Sorry, try again ({{request_id}})</p>"}
+//! ```
+//!
+//! `{{request_id}}`, `{{status}}`, `{{target_host}}` and `{{reason}}` are
+//! replaced, escaped for the template's content type. Only errors from the
+//! proxy itself are replaced; the origin's own responses pass through as they
+//! are.
+
+use crate::state;
+use fastly::Response;
+use serde::Deserialize;
+use std::sync::Mutex;
+
+const PREFIX: &str = "error_pages.";
+
+#[derive(Debug, Clone, Deserialize)]
+struct Template {
+    content_type: String,
+    body: String,
+}
+
+/// What's known about the request so far, for filling in templates.
+struct Current {
+    tenant: Option<String>,
+    target_host: Option<String>,
+    from_origin: bool,
+}
+
+static CURRENT: Mutex<Current> = Mutex::new(Current {
+    tenant: None,
+    target_host: None,
+    from_origin: false,
+});
+
+fn with_current(f: impl FnOnce(&mut Current)) {
+    if let Ok(mut current) = CURRENT.lock() {
+        f(&mut current);
+    }
+}
+
+/// Forget the previous request's details.
+pub fn reset() {
+    with_current(|current| {
+        current.tenant = None;
+        current.target_host = None;
+        current.from_origin = false;
+    });
+}
+
+/// Use the tenant's templates for this request's errors.
+pub fn configure(tenant: &str) {
+    with_current(|current| current.tenant = Some(tenant.to_string()));
+}
+
+pub fn set_target_host(host: &str) {
+    with_current(|current| current.target_host = Some(host.to_string()));
+}
+
+/// Note that the response is the origin's, which is never replaced.
+pub fn from_origin() {
+    with_current(|current| current.from_origin = true);
+}
+
+/// Why the proxy refused or failed the request, from its problem body.
+fn reason(resp: &mut Response) -> String {
+    let body: serde_json::Value =
+        serde_json::from_slice(&resp.take_body_bytes()).unwrap_or_default();
+    ["detail", "title"]
+        .iter()
+        .find_map(|field| body[field].as_str())
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// A value escaped for use inside a JSON string.
+fn escape_json(value: &str) -> String {
+    let quoted = serde_json::Value::from(value).to_string();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+impl Template {
+    fn render(&self, variables: &[(&str, &str)]) -> String {
+        let content_type = self.content_type.to_ascii_lowercase();
+        let escape: fn(&str) -> String = if content_type.contains("json") {
+            escape_json
+        } else if content_type.contains("html") || content_type.contains("xml") {
+            escape_html
+        } else {
+            str::to_string
+        };
+        variables
+            .iter()
+            .fold(self.body.clone(), |body, (name, value)| {
+                body.replace(&format!("{{{{{}}}}}", name), &escape(value))
+            })
+    }
+}
+
+/// Replace the proxy's error response with the tenant's template for its status.
+pub fn apply(mut resp: Response, request_id: &str) -> Response {
+    let status = resp.get_status();
+    if !(status.is_client_error() || status.is_server_error()) {
+        return resp;
+    }
+    let Ok(current) = CURRENT.lock() else {
+        return resp;
+    };
+    let (Some(tenant), false) = (&current.tenant, current.from_origin) else {
+        return resp;
+    };
+    let Some(store) = state::open() else {
+        return resp;
+    };
+    let code = status.as_u16();
+    let class = format!("{}xx", code / 100);
+    let Some(template) = [code.to_string(), class]
+        .iter()
+        .find_map(|key| state::get::<Template>(&store, &format!("{}{}.{}", PREFIX, tenant, key)))
+    else {
+        return resp;
+    };
+
+    let reason = reason(&mut resp);
+    let body = template.render(&[
+        ("request_id", request_id),
+        ("status", &code.to_string()),
+        (
+            "target_host",
+            current.target_host.as_deref().unwrap_or_default(),
+        ),
+        ("reason", &reason),
+    ]);
+    resp.remove_header("Content-Length");
+    resp.set_header("Content-Type", &template.content_type);
+    resp.set_body(body);
+    resp
+}
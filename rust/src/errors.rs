@@ -0,0 +1,311 @@
+//! Error responses.
+//!
+//! Refusals and failures are answered with an RFC 7807
+//! `application/problem+json` body, so clients can tell them apart from
+//! whatever the origin would have sent. Each carries a stable [`Code`] to
+//! branch on; the `title` and `detail` are for people and may change.
+//!
+//! ```json
+//! {"type": "urn:dynserv:problem:ssrf_blocked", "code": "ssrf_blocked", "status": 403,
+//!  "title": "Destination not allowed", "detail": "Target is a local, private or reserved address"}
+//! ```
+
+use fastly::http::request::{SendError, SendErrorCause};
+use fastly::http::StatusCode;
+use fastly::Response;
+use serde_json::{Map, Value};
+
+/// Why the proxy refused or failed a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Code {
+    ConfigurationError,
+    InternalError,
+    InvalidCredentials,
+    KeyRevoked,
+    KeyExpired,
+    ClientIpNotAllowed,
+    FingerprintBlocked,
+    BotBlocked,
+    AdminDisabled,
+    NotFound,
+    EndpointDisabled,
+    MethodNotAllowed,
+    ExpectationFailed,
+    InvalidMethodOverride,
+    InvalidParameter,
+    MissingUrl,
+    InvalidUrl,
+    HttpsRequired,
+    MissingHost,
+    SsrfBlocked,
+    HostNotAllowed,
+    PolicyDenied,
+    UrlDenied,
+    RobotsDisallowed,
+    ConfirmationRequired,
+    CaptureIncomplete,
+    TlsOverrideNotAllowed,
+    DnsOverrideNotAllowed,
+    MocksNotAllowed,
+    Http2NotAllowed,
+    WebsocketsNotAllowed,
+    GeoBlocked,
+    ResidencyViolation,
+    CircuitOpen,
+    BatchShed,
+    OriginBackoff,
+    ResourceExhausted,
+    QuotaExceeded,
+    Maintenance,
+    StateUnavailable,
+    BackendCreateFailed,
+    OriginFetchFailed,
+    OriginTimeout,
+    DeadlineExceeded,
+    RedirectBlocked,
+    TooManyRedirects,
+    EsiIncludeFailed,
+    WebsocketHandoffFailed,
+    InvalidBatch,
+    BatchTooLarge,
+    PurgeFailed,
+    StateWriteFailed,
+    LoopDetected,
+}
+
+impl Code {
+    /// The code as it appears in responses. These never change.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Code::ConfigurationError => "configuration_error",
+            Code::InternalError => "internal_error",
+            Code::InvalidCredentials => "invalid_credentials",
+            Code::KeyRevoked => "key_revoked",
+            Code::KeyExpired => "key_expired",
+            Code::ClientIpNotAllowed => "client_ip_not_allowed",
+            Code::FingerprintBlocked => "fingerprint_blocked",
+            Code::BotBlocked => "bot_blocked",
+            Code::AdminDisabled => "admin_disabled",
+            Code::NotFound => "not_found",
+            Code::EndpointDisabled => "endpoint_disabled",
+            Code::MethodNotAllowed => "method_not_allowed",
+            Code::ExpectationFailed => "expectation_failed",
+            Code::InvalidMethodOverride => "invalid_method_override",
+            Code::InvalidParameter => "invalid_parameter",
+            Code::MissingUrl => "missing_url",
+            Code::InvalidUrl => "invalid_url",
+            Code::HttpsRequired => "https_required",
+            Code::MissingHost => "missing_host",
+            Code::SsrfBlocked => "ssrf_blocked",
+            Code::HostNotAllowed => "host_not_allowed",
+            Code::PolicyDenied => "policy_denied",
+            Code::UrlDenied => "url_denied",
+            Code::RobotsDisallowed => "robots_disallowed",
+            Code::ConfirmationRequired => "confirmation_required",
+            Code::CaptureIncomplete => "capture_incomplete",
+            Code::TlsOverrideNotAllowed => "tls_override_not_allowed",
+            Code::DnsOverrideNotAllowed => "dns_override_not_allowed",
+            Code::MocksNotAllowed => "mocks_not_allowed",
+            Code::Http2NotAllowed => "http2_not_allowed",
+            Code::WebsocketsNotAllowed => "websockets_not_allowed",
+            Code::GeoBlocked => "geo_blocked",
+            Code::ResidencyViolation => "residency_violation",
+            Code::CircuitOpen => "circuit_open",
+            Code::BatchShed => "batch_shed",
+            Code::OriginBackoff => "origin_backoff",
+            Code::ResourceExhausted => "resource_exhausted",
+            Code::QuotaExceeded => "quota_exceeded",
+            Code::Maintenance => "maintenance",
+            Code::StateUnavailable => "state_unavailable",
+            Code::BackendCreateFailed => "backend_create_failed",
+            Code::OriginFetchFailed => "origin_fetch_failed",
+            Code::OriginTimeout => "origin_timeout",
+            Code::DeadlineExceeded => "deadline_exceeded",
+            Code::RedirectBlocked => "redirect_blocked",
+            Code::TooManyRedirects => "too_many_redirects",
+            Code::EsiIncludeFailed => "esi_include_failed",
+            Code::WebsocketHandoffFailed => "websocket_handoff_failed",
+            Code::InvalidBatch => "invalid_batch",
+            Code::BatchTooLarge => "batch_too_large",
+            Code::PurgeFailed => "purge_failed",
+            Code::StateWriteFailed => "state_write_failed",
+            Code::LoopDetected => "loop_detected",
+        }
+    }
+
+    /// The status the error is answered with, unless settings choose another.
+    pub fn status(self) -> StatusCode {
+        match self {
+            Code::ConfigurationError | Code::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+            Code::InvalidMethodOverride
+            | Code::InvalidParameter
+            | Code::MissingUrl
+            | Code::InvalidUrl
+            | Code::HttpsRequired
+            | Code::MissingHost
+            | Code::InvalidBatch => StatusCode::BAD_REQUEST,
+            Code::InvalidCredentials
+            | Code::KeyRevoked
+            | Code::KeyExpired
+            | Code::ClientIpNotAllowed
+            | Code::FingerprintBlocked
+            | Code::BotBlocked
+            | Code::SsrfBlocked
+            | Code::HostNotAllowed
+            | Code::PolicyDenied
+            | Code::UrlDenied
+            | Code::RobotsDisallowed
+            | Code::TlsOverrideNotAllowed
+            | Code::DnsOverrideNotAllowed
+            | Code::MocksNotAllowed
+            | Code::Http2NotAllowed
+            | Code::WebsocketsNotAllowed => StatusCode::FORBIDDEN,
+            Code::AdminDisabled | Code::NotFound | Code::EndpointDisabled => StatusCode::NOT_FOUND,
+            Code::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            Code::ExpectationFailed => StatusCode::EXPECTATION_FAILED,
+            Code::QuotaExceeded | Code::OriginBackoff => StatusCode::TOO_MANY_REQUESTS,
+            Code::BatchTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Code::ConfirmationRequired => StatusCode::PRECONDITION_REQUIRED,
+            Code::CaptureIncomplete => StatusCode::CONFLICT,
+            Code::GeoBlocked | Code::ResidencyViolation => {
+                StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS
+            }
+            Code::BackendCreateFailed
+            | Code::OriginFetchFailed
+            | Code::RedirectBlocked
+            | Code::TooManyRedirects
+            | Code::EsiIncludeFailed
+            | Code::WebsocketHandoffFailed
+            | Code::PurgeFailed
+            | Code::StateWriteFailed => StatusCode::BAD_GATEWAY,
+            Code::CircuitOpen
+            | Code::BatchShed
+            | Code::ResourceExhausted
+            | Code::Maintenance
+            | Code::StateUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            Code::OriginTimeout | Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+            Code::LoopDetected => StatusCode::LOOP_DETECTED,
+        }
+    }
+
+    /// A short summary of the error.
+    pub fn title(self) -> &'static str {
+        match self {
+            Code::ConfigurationError => "Configuration error",
+            Code::InternalError => "Internal error",
+            Code::InvalidCredentials | Code::KeyRevoked | Code::KeyExpired => "Unauthorized",
+            Code::ClientIpNotAllowed | Code::FingerprintBlocked => "Unauthorized",
+            Code::BotBlocked => "Client not allowed",
+            Code::AdminDisabled => "Admin API disabled",
+            Code::NotFound => "Not found",
+            Code::EndpointDisabled => "Endpoint disabled",
+            Code::MethodNotAllowed => "Method not allowed",
+            Code::ExpectationFailed => "Expectation not supported",
+            Code::InvalidMethodOverride => "Invalid method override",
+            Code::InvalidParameter => "Invalid parameter",
+            Code::MissingUrl => "Missing 'url' query parameter",
+            Code::InvalidUrl | Code::MissingHost => "Invalid URL provided",
+            Code::HttpsRequired => "Only https URLs are supported",
+            Code::SsrfBlocked | Code::HostNotAllowed | Code::PolicyDenied | Code::UrlDenied => {
+                "Destination not allowed"
+            }
+            Code::ConfirmationRequired => "Confirmation required",
+            Code::CaptureIncomplete => "Capture can't be replayed",
+            Code::TlsOverrideNotAllowed => "TLS name overrides not allowed",
+            Code::DnsOverrideNotAllowed => "DNS overrides not allowed",
+            Code::MocksNotAllowed => "Mocks not allowed",
+            Code::Http2NotAllowed => "HTTP/2 not allowed",
+            Code::WebsocketsNotAllowed => "WebSockets not allowed",
+            Code::GeoBlocked => "Not available in your location",
+            Code::RobotsDisallowed => "Disallowed by robots.txt",
+            Code::ResidencyViolation => "Data residency violation",
+            Code::CircuitOpen | Code::BatchShed => "Origin unavailable",
+            Code::OriginBackoff => "Origin is throttling",
+            Code::ResourceExhausted => "Resource limit reached",
+            Code::QuotaExceeded => "Daily quota exceeded",
+            Code::Maintenance => "Service unavailable",
+            Code::StateUnavailable => "State unavailable",
+            Code::BackendCreateFailed => "Failed to create backend",
+            Code::OriginFetchFailed => "Failed to fetch from origin",
+            Code::OriginTimeout => "Origin timed out",
+            Code::DeadlineExceeded => "Request deadline exceeded",
+            Code::RedirectBlocked => "Redirect blocked",
+            Code::TooManyRedirects => "Too many redirects",
+            Code::EsiIncludeFailed => "ESI include failed",
+            Code::WebsocketHandoffFailed => "WebSocket handoff failed",
+            Code::InvalidBatch => "Invalid batch",
+            Code::BatchTooLarge => "Batch too large",
+            Code::PurgeFailed => "Purge failed",
+            Code::StateWriteFailed => "State change failed",
+            Code::LoopDetected => "Proxy loop detected",
+        }
+    }
+}
+
+/// An error response, with any fields particular to the error.
+#[derive(Debug, Clone)]
+pub struct Problem {
+    code: Code,
+    status: StatusCode,
+    detail: String,
+    extensions: Map<String, Value>,
+}
+
+impl Problem {
+    pub fn new(code: Code, detail: impl Into<String>) -> Self {
+        Self {
+            code,
+            status: code.status(),
+            detail: detail.into(),
+            extensions: Map::new(),
+        }
+    }
+
+    /// Add a field to the body.
+    pub fn with(mut self, name: &str, value: impl Into<Value>) -> Self {
+        self.extensions.insert(name.to_string(), value.into());
+        self
+    }
+
+    /// Answer with another status than the code's own.
+    pub fn with_status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub fn into_response(self) -> Response {
+        let mut body = Map::new();
+        body.insert(
+            "type".to_string(),
+            format!("urn:dynserv:problem:{}", self.code.as_str()).into(),
+        );
+        body.insert("code".to_string(), self.code.as_str().into());
+        body.insert("status".to_string(), self.status.as_u16().into());
+        body.insert("title".to_string(), self.code.title().into());
+        if !self.detail.is_empty() {
+            body.insert("detail".to_string(), self.detail.into());
+        }
+        for (name, value) in self.extensions {
+            body.entry(name).or_insert(value);
+        }
+        Response::from_status(self.status)
+            .with_header("Content-Type", "application/problem+json")
+            .with_body(Value::Object(body).to_string())
+    }
+}
+
+/// A 500 for settings that couldn't be loaded or don't make sense.
+pub fn config(message: &str) -> Response {
+    Problem::new(Code::ConfigurationError, message).into_response()
+}
+
+/// A failed origin fetch, telling timeouts apart from other failures.
+pub fn fetch_failed(err: &SendError, target: &str) -> Problem {
+    let code = match err.root_cause() {
+        SendErrorCause::DnsTimeout
+        | SendErrorCause::ConnectionTimeout
+        | SendErrorCause::HttpResponseTimeout => Code::OriginTimeout,
+        _ => Code::OriginFetchFailed,
+    };
+    Problem::new(code, err.to_string()).with("target", target)
+}
@@ -0,0 +1,211 @@
+//! Edge Side Includes in proxied HTML.
+//!
+//! `<esi:include src="..."/>` is replaced with the fragment it names, fetched
+//! as a new destination: it's resolved against the page's URL and goes through
+//! the same SSRF checks, destination policy and residency rules as the
+//! client's target. `<esi:remove>` elements are dropped and `<!--esi ... -->`
+//! comments are unwrapped. Fragments are not themselves processed.
+
+use crate::errors::{Code, Problem};
+use crate::policy::{self, Policy};
+use crate::residency::{self, Residency};
+use crate::{backend, charset, compression, html, limits, ssrf};
+use fastly::http::Method;
+use fastly::{Body, Request, Response};
+use lol_html::html_content::ContentType;
+use lol_html::{comments, element, HtmlRewriter, Settings};
+use serde::Deserialize;
+use std::io::Write;
+use url::Url;
+
+/// Bytes of the page read per processing step.
+const CHUNK: usize = 16 * 1024;
+
+/// Fragments larger than this fail to include.
+const MAX_FRAGMENT_BYTES: usize = 1024 * 1024;
+
+/// Per-route ESI settings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct EsiPolicy {
+    /// Includes processed per page; any more fail.
+    pub max_includes: usize,
+}
+
+impl Default for EsiPolicy {
+    fn default() -> Self {
+        Self { max_includes: 16 }
+    }
+}
+
+/// What fragment requests are checked against and built from.
+pub struct Includes<'a> {
+    pub tenant: &'a str,
+    pub policy: &'a Policy,
+    pub confirmed: Option<&'a str>,
+    pub residency: Option<&'a Residency>,
+    /// A bodiless copy of the origin request.
+    pub template: &'a Request,
+}
+
+impl Includes<'_> {
+    /// Fetch one fragment as text.
+    fn fetch(&self, page: &Url, src: &str) -> Result<String, String> {
+        let url = page.join(src.trim()).map_err(|e| e.to_string())?;
+        let target = ssrf::validate(url).map_err(|rejection| match rejection {
+            ssrf::Rejection::NotHttps => "not an https URL".to_string(),
+            ssrf::Rejection::MissingHost => "missing hostname".to_string(),
+            ssrf::Rejection::PrivateAddress => "private or reserved address".to_string(),
+            ssrf::Rejection::NotAllowed => "host not on the allowlist".to_string(),
+        })?;
+        let decision = self.policy.evaluate(&policy::Subject {
+            tenant: self.tenant,
+            method: "GET",
+            target: &target,
+            confirmed: self.confirmed,
+        });
+        if decision.refusal().is_some() {
+            return Err("refused by destination policy".to_string());
+        }
+        if let Some(residency) = self.residency {
+            residency::check_target(residency, &target)
+                .map_err(|_| "outside the allowed regions".to_string())?;
+        }
+        limits::reserve_request().map_err(|exhausted| exhausted.as_str().to_string())?;
+        let backend =
+            backend::create(&target.hostname, target.port).map_err(|e| format!("{:?}", e))?;
+
+        let mut req = self.template.clone_without_body();
+        req.set_method(Method::GET);
+        req.remove_header("Range");
+        // Don't hand credentials meant for the page's origin to another
+        if page.host_str() != Some(target.hostname.as_str()) {
+            req.remove_header("Authorization");
+            req.remove_header("Cookie");
+        }
+        req.set_url(target.url.clone());
+        req.set_header("Host", &target.hostname);
+        let mut resp = req.send(backend.name()).map_err(|e| e.to_string())?;
+        if !resp.get_status().is_success() {
+            return Err(format!("origin returned {}", resp.get_status().as_u16()));
+        }
+        compression::decode(&mut resp);
+        if resp.contains_header("Content-Encoding") {
+            return Err("compressed with an unsupported coding".to_string());
+        }
+        let content_type = resp
+            .get_header_str("Content-Type")
+            .unwrap_or_default()
+            .to_string();
+        let prefix = resp.get_body_prefix_mut(MAX_FRAGMENT_BYTES + 1);
+        if prefix.len() > MAX_FRAGMENT_BYTES {
+            return Err("larger than 1 MiB".to_string());
+        }
+        let bytes = prefix.take();
+        charset::decode(&bytes, &content_type)
+            .map(|text| text.into_owned())
+            .ok_or_else(|| "not text in its declared charset".to_string())
+    }
+}
+
+/// Process the ESI in an HTML response from `page`, returning an error
+/// response if an include failed without `onerror="continue"`.
+pub fn process(
+    resp: &mut Response,
+    page: &Url,
+    esi: &EsiPolicy,
+    includes: &Includes,
+) -> Option<Response> {
+    let content_type = resp
+        .get_header_str("Content-Type")
+        .unwrap_or_default()
+        .to_string();
+    if !html::is_html(&content_type) || resp.contains_header("Content-Encoding") {
+        return None;
+    }
+    let mut body = resp.take_body();
+    let mut processed = Body::new();
+    let mut unparsed: Option<Vec<u8>> = None;
+    let mut included = 0;
+    let mut failure: Option<(String, String)> = None;
+    {
+        let mut rewriter = HtmlRewriter::new(
+            Settings {
+                element_content_handlers: vec![
+                    element!("esi\\:include", |el| {
+                        let continue_on_error = el
+                            .get_attribute("onerror")
+                            .is_some_and(|onerror| onerror == "continue");
+                        let sources = [el.get_attribute("src"), el.get_attribute("alt")];
+                        let mut error = None;
+                        included += 1;
+                        if included > esi.max_includes {
+                            error = Some((
+                                String::new(),
+                                format!("more than {} includes", esi.max_includes),
+                            ));
+                        } else {
+                            for src in sources.into_iter().flatten() {
+                                match includes.fetch(page, &src) {
+                                    Ok(fragment) => {
+                                        el.replace(&fragment, ContentType::Html);
+                                        return Ok(());
+                                    }
+                                    Err(e) => error = Some((src, e)),
+                                }
+                            }
+                        }
+                        el.remove();
+                        if !continue_on_error && failure.is_none() {
+                            failure = error.or_else(|| Some((String::new(), "no src".to_string())));
+                        }
+                        Ok(())
+                    }),
+                    element!("esi\\:remove", |el| {
+                        el.remove();
+                        Ok(())
+                    }),
+                    // Markup only meant for ESI processors, hidden from others
+                    comments!("*", |comment| {
+                        if let Some(markup) = comment.text().strip_prefix("esi") {
+                            let markup = markup.to_string();
+                            comment.replace(&markup, ContentType::Html);
+                        }
+                        Ok(())
+                    }),
+                ],
+                encoding: html::encoding_of(&content_type),
+                ..Settings::new()
+            },
+            |chunk: &[u8]| {
+                let _ = processed.write_all(chunk);
+            },
+        );
+        for chunk in body.read_chunks(CHUNK) {
+            let Ok(chunk) = chunk else {
+                break;
+            };
+            if rewriter.write(&chunk).is_err() {
+                unparsed = Some(chunk);
+                break;
+            }
+        }
+        if unparsed.is_none() {
+            let _ = rewriter.end();
+        }
+    }
+    if let Some((src, message)) = failure {
+        return Some(
+            Problem::new(Code::EsiIncludeFailed, message)
+                .with("src", src)
+                .into_response(),
+        );
+    }
+    if let Some(chunk) = unparsed {
+        let _ = processed.write_all(&chunk);
+        processed.append(body);
+    }
+    resp.remove_header("Content-Length");
+    resp.set_body(processed);
+    None
+}
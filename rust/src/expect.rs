@@ -0,0 +1,74 @@
+//! `Expect: 100-continue` uploads.
+//!
+//! Everything that could refuse an upload (authentication, the tenant's
+//! methods and limits, the destination's checks) is decided before the body
+//! is touched, so a refused upload gets its final status without the client
+//! sending it. An accepted one is relayed with `Expect: 100-continue` and
+//! streamed: the origin gets [`CONTINUE_WAIT`] to answer with a final status
+//! before any of the body is read, as curl waits, and if it does the client
+//! gets that answer and never sends the body. Otherwise the proxy starts
+//! reading the body, which is when Fastly's edge sends the client its
+//! `100 Continue`, and passes it to the origin as it arrives. Streamed
+//! uploads get no fallback retry or mirrored copy, both of which need the
+//! whole body in memory first. Other expectations are refused with `417`.
+
+use crate::errors::{Code, Problem};
+use crate::{deadline, trailers};
+use fastly::http::request::{PollResult, SendError};
+use fastly::{Request, Response};
+use std::time::{Duration, Instant};
+
+/// How long the origin has to refuse an upload before its body is sent.
+const CONTINUE_WAIT: Duration = Duration::from_secs(1);
+
+/// How often the origin is polled for an early answer.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Whether the client announced its upload with `Expect: 100-continue`.
+pub fn continues(req: &Request) -> bool {
+    req.get_header_str("Expect")
+        .is_some_and(|expect| expect.trim().eq_ignore_ascii_case("100-continue"))
+}
+
+/// Refuse an expectation the proxy can't meet.
+pub fn check(req: &Request) -> Option<Response> {
+    let expect = req.get_header_str("Expect")?;
+    if continues(req) {
+        return None;
+    }
+    Some(
+        Problem::new(
+            Code::ExpectationFailed,
+            "Only the 100-continue expectation is supported",
+        )
+        .with("expect", expect)
+        .into_response(),
+    )
+}
+
+/// Send an announced upload, streaming its body once the origin hasn't
+/// refused it within [`CONTINUE_WAIT`].
+pub fn send(mut req: Request, backend: &str) -> Result<Response, SendError> {
+    let mut body = req.take_body();
+    req.set_header("Expect", "100-continue");
+    let (mut upload, mut pending) = req.send_async_streaming(backend)?;
+
+    let wait = deadline::bound(CONTINUE_WAIT);
+    let started = Instant::now();
+    while started.elapsed() < wait {
+        match pending.poll() {
+            PollResult::Done(result) => {
+                // Answered early: the client's body is left unread
+                let _ = upload.finish();
+                return result;
+            }
+            PollResult::Pending(still) => pending = still,
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+
+    let _ = std::io::copy(&mut body, &mut upload);
+    trailers::copy(&mut body, &mut upload);
+    let _ = upload.finish();
+    pending.wait()
+}
@@ -0,0 +1,32 @@
+//! Fallback to a secondary origin when the primary fails.
+
+use crate::tenant::Tenant;
+use crate::{backend, credentials, limits, signing, ssrf};
+use fastly::http::request::SendError;
+use fastly::{Request, Response};
+
+/// Whether the primary outcome should be retried against the fallback.
+pub fn should_fall_back(result: &Result<Response, SendError>, statuses: &[u16]) -> bool {
+    match result {
+        Ok(resp) => statuses.contains(&resp.get_status().as_u16()),
+        Err(_) => true,
+    }
+}
+
+/// Send a copy of the origin request to an already-validated fallback target.
+///
+/// The copy is taken before the primary target's credentials are attached and
+/// it's signed, and gets the tenant's credentials and signature for the
+/// fallback host instead. Returns `None` if the fallback couldn't be reached,
+/// in which case the primary outcome should be returned instead.
+pub fn send(mut req: Request, target: &ssrf::Target, tenant: &Tenant) -> Option<Response> {
+    req.set_url(target.url.clone());
+    req.set_header("Host", &target.hostname);
+    credentials::attach(&mut req, &tenant.origin_credentials, &target.hostname).ok()?;
+    signing::sign_for(&mut req, &tenant.signed_origins, &target.hostname).ok()?;
+    limits::reserve_request().ok()?;
+    let backend = backend::create(&target.hostname, target.port).ok()?;
+    let mut resp = req.send(backend.name()).ok()?;
+    resp.set_header("X-Proxy-Fallback", "1");
+    Some(resp)
+}
@@ -0,0 +1,186 @@
+//! Trimming JSON responses to the fields a client asked for.
+//!
+//! `fields=a.b,c[*].d` keeps only those paths of the origin's JSON, in the
+//! same structure: `.` steps into an object member, `[*]` into every element
+//! of an array (or member of an object) and `[n]` into one element. Responses
+//! that aren't JSON, are compressed or are too large to buffer are passed
+//! through whole.
+
+use crate::charset;
+use fastly::{Body, Response};
+use serde_json::{Map, Value};
+use url::Url;
+
+/// Responses larger than this are passed through unfiltered.
+const MAX_FILTER_BODY_BYTES: usize = 4 * 1024 * 1024;
+
+/// Limits on the `fields` parameter itself.
+const MAX_FIELDS: usize = 64;
+const MAX_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Key(String),
+    Each,
+    Index(usize),
+}
+
+/// The paths requested with `fields`.
+#[derive(Debug, Clone)]
+pub struct Fields {
+    paths: Vec<Vec<Segment>>,
+}
+
+fn parse_path(path: &str) -> Result<Vec<Segment>, String> {
+    let invalid = || format!("Invalid field path '{}'", path);
+    let mut segments = Vec::new();
+    for part in path.split('.') {
+        let (key, mut brackets) = match part.find('[') {
+            Some(start) => part.split_at(start),
+            None => (part, ""),
+        };
+        if !key.is_empty() {
+            segments.push(Segment::Key(key.to_string()));
+        } else if brackets.is_empty() || !segments.is_empty() {
+            // Only a path into a top-level array, like `[*].id`, starts without a name
+            return Err(invalid());
+        }
+        while !brackets.is_empty() {
+            let end = brackets.find(']').ok_or_else(invalid)?;
+            let inner = brackets.strip_prefix('[').ok_or_else(invalid)?;
+            segments.push(match &inner[..end - 1] {
+                "*" => Segment::Each,
+                index => Segment::Index(index.parse().map_err(|_| invalid())?),
+            });
+            brackets = &brackets[end + 1..];
+        }
+    }
+    if segments.len() > MAX_DEPTH {
+        return Err(format!(
+            "Field path '{}' is deeper than {}",
+            path, MAX_DEPTH
+        ));
+    }
+    Ok(segments)
+}
+
+impl Fields {
+    /// The fields requested by the client URL's `fields` parameter, if any.
+    pub fn requested(client_url: &Url) -> Result<Option<Self>, String> {
+        let Some((_, value)) = client_url.query_pairs().find(|(k, _)| k == "fields") else {
+            return Ok(None);
+        };
+        let paths: Vec<&str> = value
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .collect();
+        if paths.is_empty() {
+            return Err("No field paths given".to_string());
+        }
+        if paths.len() > MAX_FIELDS {
+            return Err(format!(
+                "At most {} field paths may be requested",
+                MAX_FIELDS
+            ));
+        }
+        let paths = paths
+            .into_iter()
+            .map(parse_path)
+            .collect::<Result<_, _>>()?;
+        Ok(Some(Self { paths }))
+    }
+
+    /// Replace a JSON response's body with just the requested fields.
+    pub fn filter(&self, resp: &mut Response) {
+        let content_type = resp
+            .get_header_str("Content-Type")
+            .unwrap_or_default()
+            .to_string();
+        if !is_json(&content_type) || resp.contains_header("Content-Encoding") {
+            return;
+        }
+        let body = resp.get_body_mut();
+        let prefix = body.get_prefix_mut(MAX_FILTER_BODY_BYTES + 1);
+        if prefix.len() > MAX_FILTER_BODY_BYTES {
+            return;
+        }
+        let original = prefix.take();
+        let paths: Vec<&[Segment]> = self.paths.iter().map(Vec::as_slice).collect();
+        let filtered = charset::decode(&original, &content_type)
+            .and_then(|text| serde_json::from_str::<Value>(&text).ok())
+            .and_then(|value| select(&value, &paths))
+            .and_then(|value| serde_json::to_vec(&value).ok());
+        let Some(filtered) = filtered else {
+            *body = Body::from(original);
+            return;
+        };
+        *body = Body::from(filtered);
+        if charset::declares_non_utf8(&content_type) {
+            resp.set_header("Content-Type", charset::as_utf8(&content_type));
+        }
+        resp.remove_header("Content-Length");
+    }
+}
+
+fn is_json(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence == "application/json" || essence.ends_with("+json")
+}
+
+/// The remainders of the paths whose next segment matches `matches`.
+fn rests<'a>(paths: &[&'a [Segment]], matches: impl Fn(&Segment) -> bool) -> Vec<&'a [Segment]> {
+    paths
+        .iter()
+        .filter(|path| matches(&path[0]))
+        .map(|path| &path[1..])
+        .collect()
+}
+
+/// The parts of `value` selected by `paths`, or `None` if none of them match.
+fn select(value: &Value, paths: &[&[Segment]]) -> Option<Value> {
+    if paths.is_empty() {
+        return None;
+    }
+    // A path that ends here selects the whole value
+    if paths.iter().any(|path| path.is_empty()) {
+        return Some(value.clone());
+    }
+    match value {
+        Value::Object(map) => {
+            let selected: Map<String, Value> = map
+                .iter()
+                .filter_map(|(key, member)| {
+                    let rests = rests(paths, |segment| match segment {
+                        Segment::Key(name) => name == key,
+                        Segment::Each => true,
+                        Segment::Index(_) => false,
+                    });
+                    select(member, &rests).map(|selected| (key.clone(), selected))
+                })
+                .collect();
+            Some(Value::Object(selected))
+        }
+        Value::Array(elements) => {
+            let selected: Vec<Value> = elements
+                .iter()
+                .enumerate()
+                .filter_map(|(i, element)| {
+                    let rests = rests(paths, |segment| match segment {
+                        Segment::Each => true,
+                        Segment::Index(n) => *n == i,
+                        Segment::Key(_) => false,
+                    });
+                    select(element, &rests)
+                })
+                .collect();
+            Some(Value::Array(selected))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,68 @@
+//! TLS fingerprints of clients.
+//!
+//! Fastly computes the JA3 and JA4 fingerprints of the client's TLS
+//! ClientHello, which identify the TLS library that made the connection more
+//! than the client says about itself. Both go in the access log line, and
+//! the deployment's `tls_fingerprints.blocked` list refuses clients whose
+//! JA3 (as hex MD5) or JA4 is on it, such as scraping tools abusing a key.
+//! Plain HTTP requests have no fingerprint and are never refused by one.
+
+use crate::errors::{Code, Problem};
+use fastly::{Request, Response};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    pub ja3: Option<String>,
+    pub ja4: Option<String>,
+}
+
+impl Fingerprint {
+    pub const NONE: Fingerprint = Fingerprint {
+        ja3: None,
+        ja4: None,
+    };
+
+    /// The fingerprints of the client's connection, if it was made over TLS.
+    pub fn of(req: &Request) -> Self {
+        Self {
+            ja3: req.get_tls_ja3_md5().map(hex::encode),
+            ja4: req.get_tls_ja4().map(str::to_string),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// JA3 or JA4 fingerprints whose clients are refused.
+    pub blocked: Vec<String>,
+}
+
+impl Settings {
+    /// The blocked entry the fingerprint matches, if any.
+    fn blocking(&self, fingerprint: &Fingerprint) -> Option<&str> {
+        let ja3 = fingerprint.ja3.as_deref();
+        let ja4 = fingerprint.ja4.as_deref();
+        self.blocked
+            .iter()
+            .map(|blocked| blocked.trim())
+            .find(|blocked| {
+                ja3.is_some_and(|ja3| ja3.eq_ignore_ascii_case(blocked))
+                    || ja4.is_some_and(|ja4| ja4 == *blocked)
+            })
+    }
+
+    /// Refuse a client whose fingerprint is blocked.
+    pub fn check(&self, fingerprint: &Fingerprint) -> Option<Response> {
+        let blocked = self.blocking(fingerprint)?;
+        Some(
+            Problem::new(
+                Code::FingerprintBlocked,
+                "Requests from this client's TLS fingerprint are refused",
+            )
+            .with("fingerprint", blocked)
+            .into_response(),
+        )
+    }
+}
@@ -0,0 +1,932 @@
+//! Forwarding the client's request to its target.
+//!
+//! [`handle`] authenticates the client, answers the proxy's own endpoints,
+//! validates the target against SSRF rules and the destination policy, then
+//! fetches it through a dynamic backend and applies the route's and tenant's
+//! rules to the response.
+
+use crate::errors::{Code, Problem};
+use crate::redirect::RedirectPolicy;
+use crate::webhook::Event;
+use crate::{
+    access_log, admin, affinity, audit, auth, backend, backoff, batch, bots, cache, cache_control,
+    canary, capture, chaos, circuit, compression, conditional, config, cors, credentials, deadline,
+    destinations, diagnose, echo, error_pages, errors, esi, expect, fallback, fields, fingerprint,
+    grpc, headers, health, hedge, html, images, limits, manifest, method, metrics, mirror, mock,
+    outbound, output, plan, policy, pooling, quota, redirect, residency, resolve, routes, session,
+    shielding, signed_url, split, sse, ssrf, state, stats, telemetry, tenant, timeouts, timing,
+    tls, trace, trailers, transform, upstream, watchdog, webhook, websocket,
+};
+use fastly::http::Method;
+use fastly::{Error, Request, Response};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use url::Url;
+
+/// Refusal for one of the proxy's own endpoints that the deployment turned off.
+fn endpoint_disabled() -> Response {
+    Problem::new(Code::EndpointDisabled, "This endpoint is turned off for the deployment")
+        .into_response()
+}
+
+/// Handle the client's request, answering the proxy's own errors with the
+/// tenant's error pages when it has them, adding the proxy to `Via` and
+/// saying how much of the key's quota is left.
+pub fn handle(
+    req: Request,
+    request_id: &str,
+    trace: &trace::TraceContext,
+    session: Option<&session::Session>,
+) -> Result<Response, Error> {
+    capture::reset();
+    deadline::reset();
+    error_pages::reset();
+    destinations::reset();
+    quota::reset();
+    shielding::reset();
+    let resp = proxy(req, request_id, trace, session)?;
+    let mut resp = error_pages::apply(resp, request_id);
+    config::current().via.add_to_response(&mut resp);
+    quota::annotate(&mut resp);
+    capture::annotate(&mut resp);
+    Ok(resp)
+}
+
+fn proxy(
+    mut req: Request,
+    request_id: &str,
+    trace: &trace::TraceContext,
+    session: Option<&session::Session>,
+) -> Result<Response, Error> {
+    let req_url = req.get_url().clone();
+
+    // Health checks are answered before authentication
+    if req_url.path() == "/healthz" {
+        return Ok(health::respond());
+    }
+    let proxy_config = match config::load() {
+        Ok(config) => config,
+        Err(e) => return Ok(errors::config(&e)),
+    };
+    let features = proxy_config.features;
+
+    // The admin API has its own key, and stays up during maintenance
+    if admin::is_admin(&req) {
+        return Ok(admin::respond(&mut req, request_id));
+    }
+    if proxy_config.maintenance.is_on() {
+        return Ok(proxy_config.maintenance.response(&req));
+    }
+    if let Some(refusal) = proxy_config.loops.check_request(&req) {
+        stats::note_error("loop_detected");
+        return Ok(refusal);
+    }
+    let fingerprint = fingerprint::Fingerprint::of(&req);
+    if let Some(refusal) = proxy_config.tls_fingerprints.check(&fingerprint) {
+        audit::record(request_id, "fingerprint_blocked", "unknown", req_url.path());
+        stats::note_error("fingerprint_blocked");
+        return Ok(refusal);
+    }
+
+    // Edge POPs hand requests to the shield, which fetches from origins for all of them
+    if let Some(resp) = proxy_config.shielding.forward(&mut req) {
+        return Ok(resp);
+    }
+    let validate_span = telemetry::Span::start("validate");
+    let validate_started = Instant::now();
+
+    // Authenticate the client and load its tenant's settings
+    let identity = match auth::authenticate(&req) {
+        Ok(identity) => identity,
+        Err(e) => {
+            // Preflights can't carry header credentials, so the default tenant answers them
+            if cors::is_preflight(&req) && matches!(e, auth::AuthError::NoCredentials) {
+                if let Some(cors) = tenant::load(tenant::DEFAULT).ok().and_then(|t| t.cors) {
+                    cors::configure(&cors, &req);
+                    return Ok(cors.preflight(&req));
+                }
+            }
+            if !matches!(e, auth::AuthError::Config(_)) {
+                audit::record(request_id, "auth_failed", "unknown", req_url.path());
+            }
+            return Ok(e.into_response());
+        }
+    };
+
+    let tenant = match tenant::load(&identity.tenant) {
+        Ok(tenant) => tenant,
+        Err(e) => {
+            return Ok(errors::config(&e));
+        }
+    };
+    if !tenant.allows_provider(identity.provider) {
+        audit::record(request_id, "auth_failed", &identity.tenant, req_url.path());
+        return Ok(auth::AuthError::Invalid.into_response());
+    }
+    stats::set_tenant(&identity.tenant);
+    error_pages::configure(&identity.tenant);
+    limits::set_priority(tenant.priority);
+    tls::configure(&tenant.origin_tls, tenant.tls_versions);
+    pooling::configure(tenant.connections);
+    proxy_config.deadline.start(tenant.deadline_secs, &req);
+    if let Some(cors) = &tenant.cors {
+        cors::configure(cors, &req);
+    }
+    let session_cookie = session.and_then(|session| session.issue(&identity, &req));
+    let mut timing = timing::ServerTiming::new(&req, tenant.server_timing);
+
+    // Refuse revoked or expired keys, letting the tenant know via their webhook
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let key_event = if tenant.banned {
+        Some((Event::KeyBanned, Problem::new(Code::KeyRevoked, "API key has been revoked")))
+    } else if tenant.expires_at.is_some_and(|expires_at| now >= expires_at) {
+        Some((Event::KeyExpired, Problem::new(Code::KeyExpired, "API key has expired")))
+    } else {
+        None
+    };
+    if let Some((event, problem)) = key_event {
+        audit::record(request_id, event.as_str(), &identity.tenant, req_url.path());
+        if let Some(webhook_url) = &tenant.webhook_url {
+            webhook::notify(&identity.tenant, webhook_url, event);
+        }
+        return Ok(problem.into_response());
+    }
+
+    // A leaked key is no use outside the networks it's bound to
+    if !tenant.allows_client(shielding::client_ip(&req)) {
+        audit::record(request_id, "client_ip_rejected", &identity.tenant, req_url.path());
+        let detail = "API key may not be used from this address";
+        return Ok(Problem::new(Code::ClientIpNotAllowed, detail).into_response());
+    }
+
+    // Refuse clients connecting from where the tenant's content may not be served
+    if let Some(refusal) = tenant.client_countries.as_ref().and_then(|cc| cc.check(&req)) {
+        stats::note_error("geo_blocked");
+        return Ok(refusal);
+    }
+
+    // Scrapers the tenant recognises by user agent get nothing, tarpitted or not
+    if let Some(refusal) = bots::check(&tenant.bot_rules, &req) {
+        stats::note_error("bot_blocked");
+        return Ok(refusal);
+    }
+
+    if let (Some(cors), true) = (&tenant.cors, cors::is_preflight(&req)) {
+        validate_span.end(true);
+        return Ok(cors.preflight(&req));
+    }
+
+    // Count the request against its key's daily quota, keyed by tenant without a key ID
+    if let Some(quota) = &tenant.quota {
+        let key_id = access_log::credential_key_id(&req, session)
+            .unwrap_or_else(|| identity.tenant.clone());
+        if let Some(refusal) = quota.check(&key_id, &identity.tenant, tenant.webhook_url.as_deref())
+        {
+            stats::note_error("quota_exceeded");
+            return Ok(refusal);
+        }
+    }
+
+    // Send a captured request through the proxy again, as this client
+    if let Some(id) = capture::replay_id(req_url.path()) {
+        if !features.debug {
+            return Ok(endpoint_disabled());
+        }
+        validate_span.end(true);
+        return match capture::replay(&req, &identity.tenant, id) {
+            Ok(replay) => proxy(replay, request_id, trace, session),
+            Err(problem) => Ok(problem.into_response()),
+        };
+    }
+
+    // Endpoints answered by the proxy itself
+    let local = match req_url.path() {
+        "/batch" if features.batch => Some(batch::respond(&mut req, &identity.tenant, &tenant)),
+        "/debug/echo" if features.debug => Some(echo::respond(&req, &tenant.request_headers)),
+        "/stats" if features.stats => {
+            Some(stats::respond(&identity.tenant, output::Format::of(&req)))
+        }
+        "/metrics" if features.stats => Some(metrics::respond(&identity.tenant)),
+        "/sign" => Some(signed_url::respond(&req, &identity)),
+        "/mocks" => Some(mock::respond(&mut req, &identity.tenant, tenant.mocks.as_ref())),
+        "/batch" | "/debug/echo" | "/stats" | "/metrics" => Some(endpoint_disabled()),
+        _ => None,
+    };
+    if let Some(response) = local {
+        validate_span.end(true);
+        return Ok(response);
+    }
+
+    if let Some(refusal) = method::apply(&mut req, &tenant.allowed_methods) {
+        stats::note_error("method_not_allowed");
+        return Ok(refusal);
+    }
+    if let Some(refusal) = expect::check(&req) {
+        stats::note_error("expectation_failed");
+        return Ok(refusal);
+    }
+    // Whether to answer from fixtures is settled before proxy headers are stripped
+    let mock_requested = mock::requested(&req);
+    if let Some(refusal) = mock::check(mock_requested, tenant.mocks.as_ref()) {
+        return Ok(refusal);
+    }
+    let chaos_requested = chaos::requested(&req);
+    // Announced uploads are relayed and streamed, never held for a fallback or mirror
+    let streams_upload = expect::continues(&req);
+    let dry_run = plan::requested(&req);
+    if dry_run && !features.debug {
+        return Ok(endpoint_disabled());
+    }
+
+    let injected_headers = match headers::injected(&req_url, &tenant.origin_headers) {
+        Ok(headers) => headers,
+        Err(message) => {
+            return Ok(Problem::new(Code::InvalidParameter, message).into_response());
+        }
+    };
+
+    let fields = match fields::Fields::requested(&req_url) {
+        Ok(fields) => fields,
+        Err(message) => {
+            return Ok(Problem::new(Code::InvalidParameter, message)
+                .with("parameter", "fields")
+                .into_response());
+        }
+    };
+
+    let requested_cache_control = match cache_control::requested(&req_url) {
+        Ok(requested) => requested,
+        Err(message) => {
+            return Ok(Problem::new(Code::InvalidParameter, message)
+                .with("parameter", "cc")
+                .into_response());
+        }
+    };
+
+    let image_request = match images::ImageRequest::requested(&req_url) {
+        Ok(image_request) => image_request,
+        Err(message) => {
+            return Ok(Problem::new(Code::InvalidParameter, message).into_response());
+        }
+    };
+
+    // Get the target URL from the query parameter
+    let target_url_param = req_url.query_pairs().find(|(k, _)| k == "url").map(|(_, v)| v);
+    let target_url_str = match target_url_param {
+        Some(url) => url.to_string(),
+        None => {
+            return Ok(Problem::new(Code::MissingUrl, "")
+                .with("usage", "Add ?url=https://example.com/path to your request")
+                .into_response());
+        }
+    };
+    // Every target asked for is audited, whatever is decided about it
+    destinations::requested(&target_url_str);
+
+    // Parse the target URL
+    let mut target_url = match Url::parse(&target_url_str) {
+        Ok(url) => url,
+        Err(e) => {
+            return Ok(Problem::new(Code::InvalidUrl, e.to_string()).into_response());
+        }
+    };
+    // Tenants splitting traffic send each client to its own of two origins, sticky if they ask
+    let carried = tenant
+        .affinity
+        .as_ref()
+        .map(|affinity| affinity.read(&req, &identity.tenant))
+        .unwrap_or_default();
+    let split_variant = tenant
+        .split
+        .as_ref()
+        .and_then(|split| split.apply(&req, &mut target_url, carried.split));
+    // A canary rollout sends a share of the host's traffic to its alternate origin
+    let canaried = match canary::route(&mut target_url, now, carried.canary) {
+        Ok(canaried) => canaried,
+        Err(e) => {
+            return Ok(errors::config(&e));
+        }
+    };
+    let assigned = affinity::Assignment {
+        split: split_variant,
+        canary: canaried.as_ref().map(|canaried| canaried.chosen),
+    }
+    .or(carried);
+    let affinity_cookie = tenant
+        .affinity
+        .as_ref()
+        .and_then(|affinity| affinity.issue(&identity.tenant, assigned, carried));
+
+    // Loops are refused before the SSRF checks, which don't allow the proxy's own host
+    if let Some(host) = target_url.host_str() {
+        stats::set_origin(host);
+        error_pages::set_target_host(host);
+        if let Some(refusal) = proxy_config.loops.check_target(req_url.host_str(), host) {
+            stats::note_error("loop_detected");
+            return Ok(refusal);
+        }
+    }
+    let target = match ssrf::validate(target_url) {
+        Ok(target) => target,
+        Err(rejection) => {
+            stats::note_rejection(rejection);
+            return Ok(rejection.into_response());
+        }
+    };
+
+    // Apply the deployment's destination policy to the target and any fallback
+    let policy = match policy::load() {
+        Ok(policy) => policy,
+        Err(e) => {
+            return Ok(errors::config(&e));
+        }
+    };
+    let confirmed = req.get_header_str(policy::CONFIRM_HEADER).map(str::to_string);
+    let checks = outbound::Checks {
+        tenant_id: &identity.tenant,
+        tenant: &tenant,
+        policy: &policy,
+        confirmed: confirmed.as_deref(),
+        proxy_host: req_url.host_str(),
+    };
+    if let Some(refusal) = checks.admit(req.get_method_str(), &target) {
+        return Ok(refusal);
+    }
+    let decision = checks.decision(req.get_method_str(), &target);
+    let ssrf::Target {
+        url: target_url,
+        hostname,
+        port,
+    } = target;
+
+    // Validate the fallback target up front so it gets the same checks as the primary
+    let fallback_url_param = req_url
+        .query_pairs()
+        .find(|(k, _)| k == "fallback_url")
+        .map(|(_, v)| v.into_owned())
+        .or_else(|| tenant.fallback_url.clone());
+    let fallback_target = match fallback_url_param.map(|url| Url::parse(&url)) {
+        Some(Ok(url)) => match ssrf::validate(url) {
+            Ok(target) => Some(target),
+            Err(rejection) => {
+                stats::note_rejection(rejection);
+                return Ok(rejection.into_response());
+            }
+        },
+        Some(Err(e)) => {
+            return Ok(Problem::new(Code::InvalidUrl, e.to_string())
+                .with("parameter", "fallback_url")
+                .into_response());
+        }
+        None => None,
+    };
+    if let Some(refusal) = fallback_target
+        .as_ref()
+        .and_then(|fallback| checks.admit(req.get_method_str(), fallback))
+    {
+        return Ok(refusal);
+    }
+
+    // Connect to the target's address, but handshake with other TLS names if the tenant may
+    let tls_names = match tls::requested_names(&req_url) {
+        Ok(names) => names,
+        Err(e) => {
+            return Ok(Problem::new(Code::InvalidParameter, e).into_response());
+        }
+    };
+    let host_header = match tls::requested_host_header(&req_url) {
+        Ok(host_header) => host_header,
+        Err(e) => {
+            return Ok(Problem::new(Code::InvalidParameter, e).into_response());
+        }
+    };
+    if (tls_names != (None, None) || host_header.is_some()) && !tenant.tls_name_overrides {
+        return Ok(Problem::new(
+            Code::TlsOverrideNotAllowed,
+            "The sni, verify_host and host_header parameters aren't enabled for this tenant",
+        )
+        .into_response());
+    }
+    // A Host of the client's choosing must be one the proxy could connect to itself
+    if let Some(host_header) = &host_header {
+        let as_target = Url::parse(&format!("https://{}/", host_header))
+            .map_err(|_| ssrf::Rejection::MissingHost);
+        if let Err(rejection) = as_target.and_then(ssrf::validate) {
+            stats::note_rejection(rejection);
+            return Ok(rejection.into_response());
+        }
+    }
+    let host_header = host_header.as_deref().unwrap_or(&hostname);
+    // Connect to an address of the client's choosing, once it's passed the target's checks
+    let resolved = match resolve::requested(&req_url, &hostname) {
+        Ok(resolved) => resolved.map(resolve::backend_host),
+        Err(problem) => return Ok(problem.into_response()),
+    };
+    if resolved.is_some() && !tenant.dns_overrides {
+        return Ok(Problem::new(
+            Code::DnsOverrideNotAllowed,
+            "The resolve parameter isn't enabled for this tenant",
+        )
+        .into_response());
+    }
+    let defaults = tenant.event_streams.timeouts_for(&req);
+    let timeouts = match timeouts::requested(&req_url, &tenant.max_timeouts, defaults) {
+        Ok(timeouts) => timeouts,
+        Err(e) => {
+            return Ok(Problem::new(Code::InvalidParameter, e).into_response());
+        }
+    };
+    // Speak HTTP/2 to the origin for gRPC calls, or when asked, if the tenant may
+    if grpc::param_requested(&req_url) && !tenant.http2 {
+        return Ok(Problem::new(
+            Code::Http2NotAllowed,
+            "HTTP/2 to origins isn't enabled for this tenant",
+        )
+        .into_response());
+    }
+    let http2 = tenant.http2 && (grpc::param_requested(&req_url) || grpc::is_grpc(&req));
+
+    // WebSocket upgrades are handed off to the origin once the request is ready
+    let upgrade = websocket::is_upgrade(&req);
+    if upgrade && tenant.websockets.is_none() {
+        return Ok(Problem::new(
+            Code::WebsocketsNotAllowed,
+            "WebSocket upgrades aren't enabled for this tenant",
+        )
+        .into_response());
+    }
+    // Origins behind a partner's egress proxy are reached through it, keeping their Host
+    let upstream = upstream::find(&tenant.upstream_proxies, &hostname);
+    let endpoint = match upstream {
+        Some(upstream) => backend::Endpoint {
+            timeouts,
+            http2,
+            ..upstream.endpoint(host_header)
+        },
+        None => backend::Endpoint {
+            timeouts,
+            http2,
+            sni: tls_names.0.as_deref().unwrap_or(&hostname),
+            verify_host: tls_names.1.as_deref().unwrap_or(&hostname),
+            host_header,
+            tls_host: &hostname,
+            ..backend::Endpoint::new(resolved.as_deref().unwrap_or(&hostname), port)
+        },
+    };
+
+    // Look up per-route settings for this destination
+    let routes = match routes::load() {
+        Ok(routes) => routes,
+        Err(e) => {
+            return Ok(errors::config(&e));
+        }
+    };
+    let route = routes::find(&routes, &hostname, target_url.path());
+
+    // Fail fast while the origin's circuit is open, shedding batch traffic first
+    let state_store = state::open();
+    let circuit = match &state_store {
+        Some(store) if !dry_run => circuit::check(store, &hostname, now, tenant.priority),
+        _ => circuit::Decision::Closed,
+    };
+    let shed = match circuit {
+        circuit::Decision::Open { retry_after } => Some((
+            retry_after,
+            Code::CircuitOpen,
+            "Circuit open after repeated origin failures",
+        )),
+        circuit::Decision::Shed { retry_after } => Some((
+            retry_after,
+            Code::BatchShed,
+            "Batch traffic is held back while the origin is degraded",
+        )),
+        _ => None,
+    };
+    if let Some((retry_after, code, message)) = shed {
+        stats::note_error(code.as_str());
+        return Ok(Problem::new(code, message)
+            .with("target", target_url_str.as_str())
+            .into_response()
+            .with_header("Retry-After", retry_after.to_string()));
+    }
+
+    // Don't add to the load on an origin that asked clients to back off
+    let shield_retry_after = (tenant.shield_retry_after
+        || route.is_some_and(|route| route.shield_retry_after))
+        && !dry_run;
+    if let (Some(store), true) = (&state_store, shield_retry_after) {
+        if let Some(response) = backoff::check(store, &hostname, now, &target_url_str) {
+            stats::note_error("origin_backoff");
+            return Ok(response);
+        }
+    }
+
+    let checked_target = ssrf::Target {
+        url: target_url.clone(),
+        hostname: hostname.clone(),
+        port,
+    };
+    // Only send to origins located where the tenant's data may go, and only
+    // fetch for crawlers what the origin's robots.txt allows them
+    if let Some(refusal) = (!dry_run).then(|| checks.reach(&checked_target)).flatten() {
+        return Ok(refusal);
+    }
+
+    validate_span.end(true);
+    timing.add("validate", validate_started.elapsed());
+
+    // Create the dynamic backend with TLS
+    let mut backend_span = telemetry::Span::start("backend_create");
+    backend_span.attr("server.address", hostname.as_str());
+    backend_span.attr("server.port", port);
+    let backend_started = Instant::now();
+    let backend = match backend::create_endpoint(&endpoint) {
+        Ok(b) => {
+            backend_span.end(true);
+            timing.add("backend_create", backend_started.elapsed());
+            b
+        }
+        Err(e) => {
+            stats::note_error("backend_creation");
+            return Ok(Problem::new(Code::BackendCreateFailed, format!("{:?}", e))
+                .with("target", target_url_str.as_str())
+                .into_response());
+        }
+    };
+
+    // Copy the client's request while it's still the client's, in case it's kept.
+    // An announced upload's body waits for the origin, so it isn't sampled
+    let pending_capture = tenant.capture.as_ref().filter(|_| !dry_run).and_then(|capture| {
+        capture.start(&mut req, &req_url, &identity.tenant, request_id, !streams_upload)
+    });
+
+    // Modify the request URL to the target, which carries its own query string
+    req.set_url(target_url.clone());
+
+    // The client's own preferences, for compressing the response at the edge
+    let client_method = req.get_method().clone();
+    let accept_encoding = req.get_header_str("Accept-Encoding").map(str::to_string);
+
+    // Remove headers that shouldn't be forwarded, then describe the client as the route asks
+    let client_forwarding = headers::ClientForwarding::of(&req);
+    let client_trailers = trailers::ClientTrailers::of(&req);
+    headers::strip(&mut req);
+    client_trailers.forward(&mut req);
+    outbound::mark(&mut req);
+    if let Some(variant) = split_variant {
+        req.set_header(split::VARIANT_HEADER, variant.as_str());
+    }
+    if endpoint.http2 {
+        grpc::prepare(&mut req);
+    }
+    proxy_config.deadline.propagate(&mut req, endpoint.http2);
+    tenant.request_headers.apply(&mut req);
+    let forwarded = route.map(|route| route.forwarded).unwrap_or_default();
+    headers::add_forwarded(&mut req, forwarded, client_forwarding, &req_url);
+    for (name, value) in injected_headers {
+        req.set_header(name, value);
+    }
+    if tenant.forward_client_metadata {
+        headers::add_client_metadata(&mut req);
+    }
+    if let Some(session) = session {
+        session.strip(&mut req);
+    }
+    if let Some(affinity) = &tenant.affinity {
+        affinity.strip(&mut req);
+    }
+
+    // Continue the client's trace with a span for the origin fetch
+    let fetch_span_id = trace::new_span_id();
+    trace.propagate(&mut req, &fetch_span_id);
+
+    // Set the host header to match the target, or the fronted origin's Host
+    req.set_header("Host", host_header);
+
+    // Set pass to bypass cache, unless the Image Optimizer is to fetch the image
+    let images = route.and_then(|route| route.images.as_ref());
+    let image_options = images.and_then(|images| {
+        let accept = req.get_header_str("Accept");
+        images.options(image_request.as_ref(), accept, req.get_method(), &target_url)
+    });
+    let optimize_image = image_options.is_some();
+    let negotiates_image = images.is_some_and(|images| {
+        images.negotiates(image_request.as_ref(), req.get_method(), &target_url)
+    });
+    match image_options {
+        Some(options) => req.set_image_optimizer(options),
+        None => req.set_pass(true),
+    }
+
+    // Keep a bodiless copy of the request if redirects will be followed at the edge
+    let redirect_policy = route.map(|route| route.redirects.clone()).unwrap_or_default();
+    let redirect_template = match redirect_policy {
+        RedirectPolicy::Follow { .. } => Some(req.clone_without_body()),
+        _ => None,
+    };
+    let esi_template = route
+        .and_then(|route| route.esi.as_ref())
+        .map(|_| req.clone_without_body());
+
+    if let Some(route) = route {
+        transform::apply_to_request(&mut req, &route.request_transforms);
+    }
+
+    if dry_run {
+        destinations::decide(destinations::Decision::DryRun);
+        return Ok(plan::describe(
+            &req,
+            &endpoint,
+            route,
+            &redirect_policy,
+            fallback_target.as_ref(),
+            &decision,
+        ));
+    }
+
+    // Test environments get the tenant's fixtures, and never the origin
+    if let Some(mocks) = tenant.mocks.as_ref().filter(|mocks| mocks.applies(mock_requested)) {
+        destinations::decide(destinations::Decision::Allowed);
+        return Ok(mocks.answer(&identity.tenant, &client_method, &target_url));
+    }
+
+    // Resilience tests get the tenant's faults in place of a healthy origin
+    let faults = tenant.chaos.as_ref().and_then(|chaos| chaos.faults(chaos_requested));
+    if let Some(substituted) = faults.as_ref().and_then(chaos::Faults::before_fetch) {
+        destinations::decide(destinations::Decision::Allowed);
+        return Ok(substituted);
+    }
+
+    // Copies for the mirror and fallback are taken before the target's credentials
+    // are attached or it's signed, and each gets those for its own host
+    let copies = !streams_upload && !upgrade;
+    let mirror_req = tenant
+        .mirror
+        .as_ref()
+        .filter(|_| copies)
+        .and_then(|mirror| mirror.copy(&mut req));
+    let fallback_req = fallback_target
+        .as_ref()
+        .filter(|_| copies)
+        .map(|_| req.clone_with_body());
+
+    // Attach the tenant's origin credentials, then sign the request now it's
+    // final. Dry runs stop short of this, so plans never show secrets
+    if let Err(e) = outbound::authorize(&mut req, &tenant, &checked_target) {
+        return Ok(errors::config(&e));
+    }
+    destinations::decide(destinations::Decision::Allowed);
+
+    if let (true, Some(websockets)) = (upgrade, tenant.websockets) {
+        return Ok(websocket::handoff(req, backend.name(), websockets));
+    }
+
+    // Copy a share of the tenant's traffic to its shadow origin
+    if let (Some(mirror), Some(copy)) = (&tenant.mirror, mirror_req) {
+        mirror.send(copy, &target_url, &identity.tenant, &tenant, &policy);
+    }
+
+    // Serve GET/HEAD from the edge cache when the route enables it. Credentialed
+    // requests only share entries if the route says their responses may be shared
+    let credentialed = req.contains_header("Authorization")
+        || req.contains_header("Cookie")
+        || credentials::matching(&tenant.origin_credentials, &hostname).is_some();
+    let cache_policy = route
+        .and_then(|route| route.cache.as_ref())
+        .filter(|_| !optimize_image);
+    let cache_key = cache_policy
+        .filter(|_| matches!(*req.get_method(), Method::GET | Method::HEAD))
+        .filter(|policy| !credentialed || policy.credentialed)
+        .map(|_| {
+            let accept_encoding = cache::normalize_accept_encoding(&mut req);
+            cache::key_for(&identity.tenant, &target_url, accept_encoding)
+        });
+    // The origin is asked for the full response and the client's preconditions checked here
+    let conditions = match cache_key {
+        Some(_) => conditional::Conditions::take(&mut req),
+        None => conditional::Conditions::default(),
+    };
+    let variant = cache_policy
+        .map(|policy| cache::Variant::of(&req, policy))
+        .unwrap_or_default();
+    let cached = cache_key
+        .as_ref()
+        .and_then(|key| cache::lookup(key, req.get_method(), &variant));
+    let from_cache = cached.is_some();
+    if from_cache {
+        stats::note_cache_hit();
+    }
+    let store_on_miss = req.get_method() == Method::GET;
+
+    let origin_started = Instant::now();
+    let mut result = match cached {
+        Some(response) => Ok(response),
+        None => {
+            if let Err(exhausted) = limits::reserve_request() {
+                destinations::decide(destinations::Decision::Refused);
+                return Ok(exhausted.into_response());
+            }
+            // Fetch from the dynamic backend, hedging GETs when the route asks for it
+            let hedge_delay = route
+                .and_then(|route| route.hedge_after_ms)
+                .filter(|_| req.get_method() == Method::GET)
+                .map(Duration::from_millis);
+            let mut fetch_span = telemetry::Span::client("origin_fetch", &fetch_span_id);
+            fetch_span.attr("http.request.method", req.get_method_str());
+            fetch_span.attr("url.full", target_url.as_str());
+            let sent = match hedge_delay {
+                Some(delay) => hedge::send(req, backend.name(), delay),
+                None if streams_upload => expect::send(req, backend.name()),
+                None => req.send(backend.name()),
+            };
+            stats::set_origin_latency(origin_started.elapsed());
+            timing.add("origin_ttfb", origin_started.elapsed());
+            if let Ok(response) = &sent {
+                fetch_span.attr("http.response.status_code", response.get_status().as_u16());
+            }
+            let fetched = matches!(&sent, Ok(response) if !response.get_status().is_server_error());
+            fetch_span.end(fetched);
+            sent.map(|mut response| match (cache_key, cache_policy) {
+                (Some(key), Some(policy)) if store_on_miss => {
+                    tenant.cache_control.to_edge(&mut response);
+                    cache::store(key, response, policy, &hostname, &variant)
+                }
+                _ => response,
+            })
+        }
+    };
+
+    if let (Some(store), false) = (&state_store, from_cache) {
+        let success = matches!(&result, Ok(response) if !response.get_status().is_server_error());
+        circuit::record(store, &hostname, circuit, success, now);
+        if let Some(canaried) = canaried.as_ref().filter(|canaried| canaried.chosen) {
+            canaried.canary.record(store, &canaried.primary, success, now);
+        }
+        if let (true, Ok(response)) = (shield_retry_after, &result) {
+            backoff::record(store, &hostname, response, now);
+        }
+    }
+
+    // The primary fetch is kept, not any fallback that stands in for it
+    if let Some(pending) = pending_capture {
+        pending.finish(&mut result);
+    }
+
+    // Retry against the fallback if the primary failed
+    let mut origin_url = target_url.clone();
+    let result = match (fallback_target, fallback_req) {
+        (Some(fallback), Some(fallback_req))
+            if fallback::should_fall_back(&result, &tenant.fallback_statuses)
+                && tenant.residency.as_ref().is_none_or(|residency| {
+                    residency::check_target(residency, &fallback).is_ok()
+                })
+                && tenant.robots.as_ref().is_none_or(|robots| robots.allows(&fallback)) =>
+        {
+            match fallback::send(fallback_req, &fallback, &tenant) {
+                Some(response) => {
+                    origin_url = fallback.url;
+                    Ok(response)
+                }
+                None => result,
+            }
+        }
+        _ => result,
+    };
+
+    if let Err(e) = &result {
+        stats::note_error(&stats::error_kind(e));
+    }
+    // DNS may have moved since the destination was verified
+    if let (Some(residency), Ok(response)) = (&tenant.residency, &result) {
+        let host = origin_url.host_str().unwrap_or_default();
+        if let Err(violation) = residency::check_response(residency, host, response) {
+            stats::note_error("residency_violation");
+            return Ok(violation.into_response(residency));
+        }
+    }
+    match result {
+        Ok(mut response) => {
+            if let Some(faults) = &faults {
+                faults.after_fetch(&mut response);
+            }
+            // Event streams pass through as they arrive, so nothing may buffer them
+            let streaming = sse::is_event_stream(&response);
+            if !streaming {
+                mirror::observe(&mut response);
+            }
+            match (&redirect_policy, &redirect_template) {
+                (RedirectPolicy::Follow { max_hops }, Some(template)) => {
+                    let hops = redirect::Hops {
+                        checks,
+                        template,
+                        timeouts: endpoint.timeouts,
+                        http2: endpoint.http2,
+                    };
+                    response = redirect::follow(response, &hops, &origin_url, *max_hops);
+                }
+                (RedirectPolicy::RewriteToProxy, _) => {
+                    redirect::rewrite_to_proxy(&mut response, &origin_url, &req_url);
+                }
+                (RedirectPolicy::Block, _) => {
+                    if let Some(blocked) = redirect::blocked(&response, &origin_url) {
+                        return Ok(blocked);
+                    }
+                }
+                _ => {}
+            }
+            let announced = trailers::announced(&response);
+            headers::strip_response(&mut response);
+            tenant.response_headers.apply(&mut response);
+            // Bodies are rewritten uncompressed and compressed again afterwards
+            let transforms_body = !streaming
+                && (fields.is_some() || route.is_some_and(routes::Route::transforms_responses));
+            if !transforms_body {
+                trailers::announce(&mut response, announced.as_deref());
+            }
+            let recompress = if transforms_body {
+                compression::decode(&mut response)
+            } else {
+                None
+            };
+            if let Some(policy) = route.and_then(|route| route.watchdog.as_ref()) {
+                watchdog::arm(policy.clone(), origin_url.host_str().unwrap_or_default());
+            }
+            if let Some(route) = route.filter(|_| !streaming) {
+                transform::apply_to_response(&mut response, &route.response_transforms);
+                if let (Some(esi), Some(template)) = (&route.esi, &esi_template) {
+                    let includes = esi::Includes {
+                        tenant: &identity.tenant,
+                        policy: &policy,
+                        confirmed: confirmed.as_deref(),
+                        residency: tenant.residency.as_ref(),
+                        template,
+                    };
+                    if let Some(failed) = esi::process(&mut response, &origin_url, esi, &includes)
+                    {
+                        stats::note_error("esi_include_failed");
+                        return Ok(failed);
+                    }
+                }
+                if route.rewrite_links {
+                    html::rewrite_links(&mut response, &origin_url, &req_url);
+                }
+                if route.rewrite_manifests {
+                    manifest::rewrite(&mut response, &origin_url, &req_url);
+                }
+            }
+            if let Some(fields) = fields.as_ref().filter(|_| !streaming) {
+                fields.filter(&mut response);
+            }
+            if let Some(coding) = recompress {
+                compression::encode(&mut response, coding);
+            }
+            if transforms_body {
+                conditional::tag(&mut response);
+            }
+            if let Some(policy) = route
+                .and_then(|route| route.compress.as_ref())
+                .filter(|_| !streaming)
+            {
+                policy.apply(&mut response, &client_method, accept_encoding.as_deref());
+            }
+            if negotiates_image {
+                // The format depends on Accept whether or not this client gets a newer one
+                response.append_header("Vary", "Accept");
+            }
+            tenant
+                .cache_control
+                .to_client(&mut response, requested_cache_control.as_deref());
+            conditions.apply(&mut response);
+            limits::annotate(&mut response);
+            if let Some(variant) = split_variant {
+                response.set_header(split::VARIANT_HEADER, variant.as_str());
+            }
+            if canaried.as_ref().is_some_and(|canaried| canaried.chosen) {
+                response.set_header(canary::CANARY_HEADER, "1");
+            }
+            if let Some(cookie) = session_cookie {
+                response.append_header("Set-Cookie", cookie);
+            }
+            if let Some(cookie) = affinity_cookie {
+                response.append_header("Set-Cookie", cookie);
+            }
+            // Includes redirects followed, the fallback and any body transforms
+            timing.add("origin_total", origin_started.elapsed());
+            timing.apply(&mut response);
+            error_pages::from_origin();
+            Ok(response)
+        }
+        Err(e) => {
+            let mut response = if route.is_some_and(|route| route.diagnose_failures) {
+                diagnose::failure_response(&e, &hostname, port, &target_url_str)
+            } else {
+                errors::fetch_failed(&e, &target_url_str).into_response()
+            };
+            timing.add("origin_total", origin_started.elapsed());
+            timing.apply(&mut response);
+            Ok(response)
+        }
+    }
+}
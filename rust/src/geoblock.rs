@@ -0,0 +1,64 @@
+//! Per-tenant restrictions on where clients may connect from.
+
+use crate::errors::{Code, Problem};
+use crate::shielding;
+use fastly::geo::geo_lookup;
+use fastly::http::StatusCode;
+use fastly::{Request, Response};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// Refuse clients in the listed countries.
+    #[default]
+    Block,
+    /// Only accept clients in the listed countries.
+    Allow,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientCountries {
+    #[serde(default)]
+    pub mode: Mode,
+    /// ISO 3166-1 alpha-2 country codes.
+    pub countries: Vec<String>,
+    /// `451` (the default) or `403`.
+    #[serde(default = "default_status")]
+    pub status: u16,
+}
+
+fn default_status() -> u16 {
+    451
+}
+
+impl ClientCountries {
+    /// The refusal for a client outside the permitted countries, if it is.
+    ///
+    /// Clients that can't be located are refused only in `allow` mode.
+    pub fn check(&self, req: &Request) -> Option<Response> {
+        let country = shielding::client_ip(req)
+            .and_then(geo_lookup)
+            .map(|geo| geo.country_code().to_string());
+        let listed = country
+            .as_deref()
+            .is_some_and(|code| self.countries.iter().any(|c| c.eq_ignore_ascii_case(code)));
+        let permitted = match self.mode {
+            Mode::Block => !listed,
+            Mode::Allow => listed,
+        };
+        if permitted {
+            return None;
+        }
+        let status = match self.status {
+            403 => StatusCode::FORBIDDEN,
+            _ => StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS,
+        };
+        Some(
+            Problem::new(Code::GeoBlocked, "")
+                .with_status(status)
+                .with("country", country)
+                .into_response(),
+        )
+    }
+}
@@ -0,0 +1,39 @@
+//! HTTP/2 and gRPC to origins.
+//!
+//! Dynamic backends speak HTTP/1.1 unless they're built for gRPC, which
+//! speaks HTTP/2 to the origin. Tenants with `http2` enabled get such a
+//! backend for gRPC requests (`Content-Type: application/grpc`) and for
+//! requests with `http2=1`, for other h2-only APIs.
+//!
+//! gRPC reports each call's status in response trailers, so gRPC requests
+//! always ask for the origin's [`trailers`](crate::trailers).
+
+use crate::trailers;
+use fastly::Request;
+use url::Url;
+
+/// Whether a request is a gRPC call.
+pub fn is_grpc(req: &Request) -> bool {
+    req.get_header_str("Content-Type")
+        .is_some_and(|content_type| {
+            let essence = content_type.split(';').next().unwrap_or_default().trim();
+            essence.eq_ignore_ascii_case("application/grpc")
+                || essence
+                    .to_ascii_lowercase()
+                    .starts_with("application/grpc+")
+        })
+}
+
+/// Whether the client asked for HTTP/2 to the origin with the `http2` parameter.
+pub fn param_requested(client_url: &Url) -> bool {
+    client_url
+        .query_pairs()
+        .any(|(k, v)| k == "http2" && (v == "1" || v == "true"))
+}
+
+/// Ready a gRPC request for an HTTP/2 origin, after hop-by-hop headers are gone.
+pub fn prepare(req: &mut Request) {
+    if is_grpc(req) {
+        trailers::expect(req);
+    }
+}
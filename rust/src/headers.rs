@@ -0,0 +1,310 @@
+//! Rules for which headers cross the proxy, in either direction.
+
+use crate::{cookies, shielding};
+use fastly::geo::geo_lookup;
+use fastly::http::header::{HeaderName, HeaderValue};
+use fastly::{Request, Response};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use url::Url;
+
+/// Client-supplied forwarding headers that would mislead the origin, and
+/// headers addressed to the proxy itself.
+const STRIPPED_HEADERS: [&str; 13] = [
+    "expect",
+    "forwarded",
+    "x-http-method-override",
+    "x-forwarded-for",
+    "x-forwarded-host",
+    "x-forwarded-proto",
+    "x-proxy-chaos",
+    "x-proxy-confirm",
+    "x-proxy-key-id",
+    "x-proxy-mock",
+    "x-proxy-shield",
+    "x-proxy-signature",
+    "x-proxy-timestamp",
+];
+
+/// Client credentials that only reach origins when a tenant allows them.
+const DEFAULT_DENIED_HEADERS: [&str; 2] = ["authorization", "cookie"];
+
+/// Headers describing the body, kept even by an `only` list.
+const BODY_HEADERS: [&str; 2] = ["content-length", "content-type"];
+
+/// Origin response headers that reveal the origin's internals, removed by default.
+const DEFAULT_DENIED_RESPONSE_HEADERS: [&str; 11] = [
+    "set-cookie",
+    "server",
+    "x-powered-by",
+    "x-aspnet-version",
+    "x-aspnetmvc-version",
+    "x-runtime",
+    "x-amzn-trace-id",
+    "x-b3-traceid",
+    "x-b3-spanid",
+    "x-b3-parentspanid",
+    "uber-trace-id",
+];
+
+/// A tenant's rules for which origin response headers reach the client.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ResponseHeaderRules {
+    /// Default-denied headers to pass through anyway.
+    pub allow: Vec<String>,
+    /// Further headers never to pass through.
+    pub deny: Vec<String>,
+    /// Pass the origin's `Set-Cookie` headers to the client.
+    pub pass_cookies: bool,
+    /// Leave passed cookies' `Domain`, `Path` and `SameSite` as the origin set them.
+    pub keep_cookie_scope: bool,
+}
+
+impl ResponseHeaderRules {
+    fn passes(&self, name: &str) -> bool {
+        let listed = |list: &[String]| list.iter().any(|n| n.eq_ignore_ascii_case(name));
+        if listed(&self.deny) {
+            return false;
+        }
+        (name == "set-cookie" && self.pass_cookies)
+            || !DEFAULT_DENIED_RESPONSE_HEADERS.contains(&name)
+            || listed(&self.allow)
+    }
+
+    /// Remove the origin headers these rules don't pass through.
+    pub fn apply(&self, resp: &mut Response) {
+        let denied: Vec<HeaderName> = resp
+            .get_header_names()
+            .filter(|name| !self.passes(name.as_str()))
+            .cloned()
+            .collect();
+        for name in denied {
+            resp.remove_header(name);
+        }
+        if self.pass_cookies && !self.keep_cookie_scope {
+            cookies::rescope_all(resp);
+        }
+    }
+}
+
+/// Query parameters carrying headers to add to the origin request, e.g. `h_X-Custom=value`.
+pub const INJECT_PARAM_PREFIX: &str = "h_";
+
+/// Headers the proxy sets itself, which injected headers may not override.
+const NOT_INJECTABLE_HEADERS: [&str; 14] = [
+    "host",
+    "content-length",
+    "content-encoding",
+    "traceparent",
+    "tracestate",
+    "x-client-ip",
+    "x-client-geo-country",
+    "x-client-geo-city",
+    "x-client-asn",
+    "x-proxy-signature",
+    "x-proxy-loop",
+    "via",
+    "x-request-deadline",
+    "grpc-timeout",
+];
+
+fn injectable(name: &HeaderName) -> bool {
+    let name = name.as_str();
+    !NOT_INJECTABLE_HEADERS.contains(&name)
+        && !HOP_BY_HOP_HEADERS.contains(&name)
+        && !STRIPPED_HEADERS.contains(&name)
+}
+
+/// Headers to add to the origin request: the tenant's static set, then any
+/// from `h_` query parameters, which take precedence.
+pub fn injected(
+    client_url: &Url,
+    tenant_headers: &BTreeMap<String, String>,
+) -> Result<Vec<(HeaderName, HeaderValue)>, String> {
+    let params = client_url.query_pairs().filter_map(|(k, v)| {
+        k.strip_prefix(INJECT_PARAM_PREFIX)
+            .map(|name| (name.to_string(), v.into_owned()))
+    });
+    let mut headers: Vec<(HeaderName, HeaderValue)> = Vec::new();
+    for (name, value) in tenant_headers.clone().into_iter().chain(params) {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("'{}' is not a valid header name", name))?;
+        if !injectable(&name) {
+            return Err(format!("'{}' can't be set by clients", name));
+        }
+        let value = HeaderValue::from_str(&value)
+            .map_err(|_| format!("Invalid value for header '{}'", name))?;
+        headers.retain(|(existing, _)| *existing != name);
+        headers.push((name, value));
+    }
+    Ok(headers)
+}
+
+/// A tenant's rules for which client headers are forwarded.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct HeaderRules {
+    /// Default-denied headers to forward anyway.
+    pub allow: Vec<String>,
+    /// Further headers never to forward.
+    pub deny: Vec<String>,
+    /// Forward only these headers.
+    pub only: Option<Vec<String>>,
+}
+
+impl HeaderRules {
+    fn forwards(&self, name: &str) -> bool {
+        let listed = |list: &[String]| list.iter().any(|n| n.eq_ignore_ascii_case(name));
+        if let Some(only) = &self.only {
+            return listed(only) || BODY_HEADERS.contains(&name);
+        }
+        if listed(&self.deny) {
+            return false;
+        }
+        !DEFAULT_DENIED_HEADERS.contains(&name) || listed(&self.allow)
+    }
+
+    /// Remove the client headers these rules don't forward.
+    pub fn apply(&self, req: &mut Request) {
+        let denied: Vec<HeaderName> = req
+            .get_header_names()
+            .filter(|name| !self.forwards(name.as_str()))
+            .cloned()
+            .collect();
+        for name in denied {
+            req.remove_header(name);
+        }
+    }
+}
+
+/// Headers that describe a single connection and never cross the proxy (RFC 9110 §7.6.1).
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// The hop-by-hop headers of a message, including any its `Connection` header names.
+fn hop_by_hop(connection: Vec<&str>) -> Vec<HeaderName> {
+    connection
+        .iter()
+        .flat_map(|value| value.split(','))
+        .chain(HOP_BY_HOP_HEADERS)
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect()
+}
+
+/// How the origin is told who the client is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardedMode {
+    /// Send no forwarding headers.
+    #[default]
+    Strip,
+    /// Append the client's address to the `X-Forwarded-For` it sent.
+    Append,
+    /// Send an RFC 7239 `Forwarded` header, after any the client sent.
+    Forwarded,
+}
+
+/// The client's forwarding headers, read before [`strip`] removes them.
+pub struct ClientForwarding {
+    x_forwarded_for: Option<String>,
+    forwarded: Option<String>,
+}
+
+impl ClientForwarding {
+    pub fn of(req: &Request) -> Self {
+        Self {
+            x_forwarded_for: req.get_header_str("X-Forwarded-For").map(str::to_string),
+            forwarded: req.get_header_str("Forwarded").map(str::to_string),
+        }
+    }
+}
+
+fn append(prior: Option<String>, value: String) -> String {
+    match prior {
+        Some(prior) if !prior.trim().is_empty() => format!("{}, {}", prior, value),
+        _ => value,
+    }
+}
+
+/// Add the forwarding headers the route asks for after [`strip`].
+///
+/// `client_url` is the URL the client requested from the proxy.
+pub fn add_forwarded(
+    req: &mut Request,
+    mode: ForwardedMode,
+    client: ClientForwarding,
+    client_url: &Url,
+) {
+    let Some(ip) = shielding::client_ip(req) else {
+        return;
+    };
+    match mode {
+        ForwardedMode::Strip => {}
+        ForwardedMode::Append => {
+            req.set_header("X-Forwarded-For", append(client.x_forwarded_for, ip.to_string()));
+        }
+        ForwardedMode::Forwarded => {
+            let node = match ip {
+                IpAddr::V4(ip) => ip.to_string(),
+                IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+            };
+            let mut element = format!("for={};proto={}", node, client_url.scheme());
+            if let Some(host) = client_url.host_str() {
+                let host = match client_url.port() {
+                    Some(port) => format!("{}:{}", host, port),
+                    None => host.to_string(),
+                };
+                element.push_str(&format!(";host=\"{}\"", host));
+            }
+            req.set_header("Forwarded", append(client.forwarded, element));
+        }
+    }
+}
+
+/// Headers describing the client, only ever set by [`add_client_metadata`].
+const CLIENT_METADATA_HEADERS: [&str; 4] = [
+    "x-client-ip",
+    "x-client-geo-country",
+    "x-client-geo-city",
+    "x-client-asn",
+];
+
+/// Remove headers that shouldn't be forwarded.
+pub fn strip(req: &mut Request) {
+    for name in hop_by_hop(req.get_header_all_str("Connection")) {
+        req.remove_header(name);
+    }
+    for name in STRIPPED_HEADERS.iter().chain(&CLIENT_METADATA_HEADERS) {
+        req.remove_header(*name);
+    }
+}
+
+/// Remove the origin's hop-by-hop headers before its response goes to the client.
+pub fn strip_response(resp: &mut Response) {
+    for name in hop_by_hop(resp.get_header_all_str("Connection")) {
+        resp.remove_header(name);
+    }
+}
+
+/// Tell the origin who the client is, from Fastly's view of the connection.
+pub fn add_client_metadata(req: &mut Request) {
+    let Some(ip) = shielding::client_ip(req) else {
+        return;
+    };
+    req.set_header("X-Client-IP", ip.to_string());
+    if let Some(geo) = geo_lookup(ip) {
+        req.set_header("X-Client-Geo-Country", geo.country_code());
+        req.set_header("X-Client-Geo-City", geo.city());
+        req.set_header("X-Client-ASN", geo.as_number().to_string());
+    }
+}
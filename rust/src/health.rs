@@ -0,0 +1,55 @@
+//! The `/healthz` endpoint.
+//!
+//! Served before authentication so external monitors can tell a broken deploy
+//! (missing or unreadable stores) apart from origin problems.
+
+use crate::{routes, secrets, state};
+use fastly::config_store::ConfigStore;
+use fastly::http::StatusCode;
+use fastly::Response;
+
+pub fn respond() -> Response {
+    // The API key store is required; everything else is optional
+    let key_store = match ConfigStore::try_open("dynserv-key") {
+        Ok(store) if store.contains("key") => "ok",
+        Ok(_) => "missing_key",
+        Err(_) => "unavailable",
+    };
+    let config_store = match ConfigStore::try_open(routes::CONFIG_STORE) {
+        Ok(_) => "ok",
+        Err(_) => "not_linked",
+    };
+    let secret_store = match secrets::open() {
+        Ok(store) if store.contains("healthz").is_ok() => "ok",
+        Ok(_) => "unreadable",
+        Err(_) => "not_linked",
+    };
+    let state_store = match state::open() {
+        Some(_) => "ok",
+        None => "not_linked",
+    };
+
+    let healthy = key_store == "ok" && secret_store != "unreadable";
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Response::from_status(status)
+        .with_header("Content-Type", "application/json")
+        .with_header("Cache-Control", "no-store")
+        .with_body(
+            serde_json::json!({
+                "status": if healthy { "ok" } else { "degraded" },
+                "version": env!("CARGO_PKG_VERSION"),
+                "built_at": env!("BUILD_TIME"),
+                "checks": {
+                    "key_store": key_store,
+                    "config_store": config_store,
+                    "secret_store": secret_store,
+                    "state_store": state_store,
+                },
+            })
+            .to_string(),
+        )
+}
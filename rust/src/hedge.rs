@@ -0,0 +1,43 @@
+//! Hedged origin requests.
+//!
+//! A hedged request is sent once, and if no response has arrived after the
+//! configured delay an identical second request is fired. Whichever completes
+//! first is returned and the other is dropped, which cancels it.
+
+use crate::limits;
+use fastly::http::request::{select, PollResult, SendError};
+use fastly::{Request, Response};
+use std::time::{Duration, Instant};
+
+/// How often the primary request is polled while waiting for the hedge delay.
+const POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+/// Send a bodiless request, hedging with a duplicate after `delay`.
+pub fn send(req: Request, backend: &str, delay: Duration) -> Result<Response, SendError> {
+    let hedge = req.clone_without_body();
+    let started = Instant::now();
+    let mut primary = req.send_async(backend)?;
+
+    while started.elapsed() < delay {
+        match primary.poll() {
+            PollResult::Done(result) => return result,
+            PollResult::Pending(pending) => primary = pending,
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+
+    // Hedging is an optimisation; without budget for a second request just wait
+    if limits::reserve_request().is_err() {
+        return primary.wait();
+    }
+    let secondary = match hedge.send_async(backend) {
+        Ok(pending) => pending,
+        Err(_) => return primary.wait(),
+    };
+
+    // Prefer a successful response: if the first to finish failed, wait for the other.
+    match select(vec![primary, secondary]) {
+        (Err(_), mut remaining) if !remaining.is_empty() => remaining.remove(0).wait(),
+        (result, _) => result,
+    }
+}
@@ -0,0 +1,263 @@
+//! Rewriting links in HTML pages so browsing stays inside the proxy.
+//!
+//! `href`, `src`, `srcset`, `action` and `url()` references in `style`
+//! attributes, and `<meta http-equiv="refresh">` targets, are resolved
+//! against the page's URL and pointed back at the proxy with the destination
+//! in the `url` parameter. `Link` and `Refresh` response headers get the same
+//! treatment, whatever the response's type. Only `https`
+//! destinations are rewritten, since those are the only ones the proxy can
+//! fetch; fragments and `data:`, `javascript:`, `mailto:` and similar links
+//! are left alone. The page is rewritten as it's read, in its own encoding.
+
+use crate::redirect;
+use encoding_rs::Encoding;
+use fastly::{Body, Response};
+use lol_html::html_content::Element;
+use lol_html::{element, AsciiCompatibleEncoding, HtmlRewriter, Settings};
+use std::io::Write;
+use url::Url;
+
+/// Bytes of the origin's page read per rewriting step.
+const CHUNK: usize = 16 * 1024;
+
+pub fn is_html(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    mime.eq_ignore_ascii_case("text/html") || mime.eq_ignore_ascii_case("application/xhtml+xml")
+}
+
+/// The page's encoding, if it declares one lol_html can rewrite in place.
+pub fn encoding_of(content_type: &str) -> AsciiCompatibleEncoding {
+    content_type
+        .split(';')
+        .skip(1)
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("charset"))
+        .and_then(|(_, label)| Encoding::for_label(label.trim().trim_matches('"').as_bytes()))
+        .and_then(AsciiCompatibleEncoding::new)
+        .unwrap_or_else(AsciiCompatibleEncoding::utf_8)
+}
+
+struct Links<'a> {
+    page: &'a Url,
+    proxy: &'a Url,
+}
+
+impl Links<'_> {
+    /// The proxied form of one reference, or `None` to leave it as it is.
+    fn rewrite(&self, reference: &str) -> Option<String> {
+        let reference = reference.trim();
+        if reference.is_empty() || reference.starts_with('#') {
+            return None;
+        }
+        let destination = self.page.join(reference).ok()?;
+        if destination.scheme() != "https" {
+            return None;
+        }
+        Some(redirect::proxy_url_for(self.proxy, &destination).to_string())
+    }
+
+    fn attribute(&self, el: &mut Element, name: &str) {
+        let Some(value) = el.get_attribute(name) else {
+            return;
+        };
+        let rewritten = match name {
+            "srcset" => self.srcset(&value),
+            "style" => self.css(&value),
+            "content" => self.refresh(&value),
+            _ => self.rewrite(&value),
+        };
+        if let Some(rewritten) = rewritten {
+            let _ = el.set_attribute(name, &rewritten);
+        }
+    }
+
+    /// `srcset` is a comma-separated list of URLs, each with an optional descriptor.
+    fn srcset(&self, value: &str) -> Option<String> {
+        let candidates: Vec<String> = value
+            .split(',')
+            .map(|candidate| {
+                let candidate = candidate.trim();
+                let (reference, descriptor) = candidate
+                    .split_once(char::is_whitespace)
+                    .unwrap_or((candidate, ""));
+                match self.rewrite(reference) {
+                    Some(rewritten) if descriptor.is_empty() => rewritten,
+                    Some(rewritten) => format!("{} {}", rewritten, descriptor.trim()),
+                    None => candidate.to_string(),
+                }
+            })
+            .collect();
+        Some(candidates.join(", "))
+    }
+
+    /// A refresh is a delay, optionally followed by `; url=` and a URL.
+    fn refresh(&self, value: &str) -> Option<String> {
+        let (delay, target) = value.split_once([';', ','])?;
+        let target = target.trim_start();
+        let target = match target.get(..3) {
+            Some(prefix) if prefix.eq_ignore_ascii_case("url") => {
+                let after = target[3..].trim_start();
+                after.strip_prefix('=').map_or(target, str::trim_start)
+            }
+            _ => target,
+        };
+        let reference = target.trim_matches(|c| c == '"' || c == '\'');
+        let rewritten = self.rewrite(reference)?;
+        Some(format!("{}; url={}", delay.trim(), rewritten))
+    }
+
+    /// `Link` is a comma-separated list of `<url>` references, each with
+    /// parameters such as `rel=preload`.
+    fn link_header(&self, value: &str) -> Option<String> {
+        let mut out = String::with_capacity(value.len());
+        let mut rest = value;
+        let mut changed = false;
+        while let Some(start) = rest.find('<') {
+            let Some(end) = rest[start..].find('>').map(|end| start + end) else {
+                break;
+            };
+            out.push_str(&rest[..=start]);
+            let reference = &rest[start + 1..end];
+            match self.rewrite(reference) {
+                Some(rewritten) => {
+                    out.push_str(&rewritten);
+                    changed = true;
+                }
+                None => out.push_str(reference),
+            }
+            rest = &rest[end..];
+        }
+        out.push_str(rest);
+        changed.then_some(out)
+    }
+
+    /// Rewrite the references in `Link` and `Refresh` response headers.
+    fn headers(&self, resp: &mut Response) {
+        let links: Vec<String> = resp
+            .get_header_all_str("Link")
+            .into_iter()
+            .map(|value| self.link_header(value).unwrap_or_else(|| value.to_string()))
+            .collect();
+        if !links.is_empty() {
+            resp.remove_header("Link");
+            for link in links {
+                resp.append_header("Link", link);
+            }
+        }
+        let refresh = resp
+            .get_header_str("Refresh")
+            .and_then(|value| self.refresh(value));
+        if let Some(refresh) = refresh {
+            resp.set_header("Refresh", refresh);
+        }
+    }
+
+    /// Rewrite every `url(...)` in a CSS declaration list.
+    fn css(&self, value: &str) -> Option<String> {
+        let mut out = String::with_capacity(value.len());
+        let mut rest = value;
+        while let Some(start) = rest.to_ascii_lowercase().find("url(") {
+            let (before, after) = rest.split_at(start + 4);
+            out.push_str(before);
+            let Some(end) = after.find(')') else {
+                rest = after;
+                break;
+            };
+            let inner = after[..end].trim();
+            let quote = inner
+                .chars()
+                .next()
+                .filter(|c| *c == '"' || *c == '\'')
+                .map(String::from)
+                .unwrap_or_default();
+            let reference = inner.trim_matches(|c| c == '"' || c == '\'');
+            match self.rewrite(reference) {
+                Some(rewritten) => {
+                    out.push_str(&format!("{}{}{}", quote, rewritten, quote));
+                }
+                None => out.push_str(&after[..end]),
+            }
+            rest = &after[end..];
+        }
+        out.push_str(rest);
+        Some(out)
+    }
+}
+
+/// Rewrite the links in a response from `page` to go through `proxy`: its
+/// `Link` and `Refresh` headers, and the page itself if it's HTML.
+pub fn rewrite_links(resp: &mut Response, page: &Url, proxy: &Url) {
+    let links = Links { page, proxy };
+    links.headers(resp);
+    let content_type = resp
+        .get_header_str("Content-Type")
+        .unwrap_or_default()
+        .to_string();
+    if !is_html(&content_type) || resp.contains_header("Content-Encoding") {
+        return;
+    }
+    let mut body = resp.take_body();
+    let mut rewritten = Body::new();
+    let mut unparsed: Option<Vec<u8>> = None;
+    {
+        let mut rewriter = HtmlRewriter::new(
+            Settings {
+                element_content_handlers: vec![
+                    element!("[href]", |el| {
+                        links.attribute(el, "href");
+                        Ok(())
+                    }),
+                    element!("[src]", |el| {
+                        links.attribute(el, "src");
+                        Ok(())
+                    }),
+                    element!("[srcset]", |el| {
+                        links.attribute(el, "srcset");
+                        Ok(())
+                    }),
+                    element!("form[action]", |el| {
+                        links.attribute(el, "action");
+                        Ok(())
+                    }),
+                    element!("[style]", |el| {
+                        links.attribute(el, "style");
+                        Ok(())
+                    }),
+                    element!("meta[http-equiv][content]", |el| {
+                        let refresh = el
+                            .get_attribute("http-equiv")
+                            .is_some_and(|value| value.trim().eq_ignore_ascii_case("refresh"));
+                        if refresh {
+                            links.attribute(el, "content");
+                        }
+                        Ok(())
+                    }),
+                ],
+                encoding: encoding_of(&content_type),
+                ..Settings::new()
+            },
+            |chunk: &[u8]| {
+                let _ = rewritten.write_all(chunk);
+            },
+        );
+        for chunk in body.read_chunks(CHUNK) {
+            let Ok(chunk) = chunk else {
+                break;
+            };
+            if rewriter.write(&chunk).is_err() {
+                unparsed = Some(chunk);
+                break;
+            }
+        }
+        if unparsed.is_none() {
+            let _ = rewriter.end();
+        }
+    }
+    // If the page couldn't be parsed, the rest of it is sent as it came
+    if let Some(chunk) = unparsed {
+        let _ = rewritten.write_all(&chunk);
+        rewritten.append(body);
+    }
+    resp.remove_header("Content-Length");
+    resp.set_body(rewritten);
+}
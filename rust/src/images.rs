@@ -0,0 +1,224 @@
+//! Resizing and converting proxied images with the Fastly Image Optimizer.
+//!
+//! On routes with `images` set, a GET or HEAD for an image (judged by the
+//! target path's extension) that asks for `w`, `h` or `fmt` is sent through
+//! the Image Optimizer in the route's `region` instead of straight to the
+//! origin. The optimizer fetches the original from the origin's backend and
+//! caches what it makes by the origin's own cache headers, so these requests
+//! skip the route's edge cache. Sizes are clamped to `max_dimension`.
+//!
+//! Unless `negotiate_formats` is turned off, JPEG and PNG images are also
+//! converted to AVIF or WebP for clients whose `Accept` takes them, when no
+//! `fmt` is given. Their responses carry `Vary: Accept` either way, since
+//! another client could get another format.
+
+use fastly::http::Method;
+use fastly::image_optimizer::{
+    Format, ImageOptimizerOptions, ImageOptimizerRegion, PixelsOrPercentage,
+};
+use serde::Deserialize;
+use url::Url;
+
+/// Extensions of the images the optimizer is given.
+const IMAGE_EXTENSIONS: [&str; 7] = ["jpg", "jpeg", "png", "gif", "webp", "avif", "heic"];
+
+/// Extensions of the images worth converting for clients that take newer formats.
+const NEGOTIATED_EXTENSIONS: [&str; 3] = ["jpg", "jpeg", "png"];
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Region {
+    UsEast,
+    UsCentral,
+    UsWest,
+    EuCentral,
+    EuWest,
+    Asia,
+    Australia,
+}
+
+impl Region {
+    fn sdk(self) -> ImageOptimizerRegion {
+        match self {
+            Region::UsEast => ImageOptimizerRegion::UsEast,
+            Region::UsCentral => ImageOptimizerRegion::UsCentral,
+            Region::UsWest => ImageOptimizerRegion::UsWest,
+            Region::EuCentral => ImageOptimizerRegion::EuCentral,
+            Region::EuWest => ImageOptimizerRegion::EuWest,
+            Region::Asia => ImageOptimizerRegion::Asia,
+            Region::Australia => ImageOptimizerRegion::Australia,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Images {
+    /// Where the optimizer transforms images; close to the origin is best.
+    pub region: Region,
+    /// Largest width or height clients may ask for, in pixels.
+    #[serde(default = "default_max_dimension")]
+    pub max_dimension: u32,
+    /// Convert JPEG and PNG images to AVIF or WebP for clients that accept them.
+    #[serde(default = "default_negotiate_formats")]
+    pub negotiate_formats: bool,
+}
+
+fn default_max_dimension() -> u32 {
+    4096
+}
+
+fn default_negotiate_formats() -> bool {
+    true
+}
+
+/// The transformation a client asked for with `w`, `h` and `fmt`.
+#[derive(Debug, Clone, Default)]
+pub struct ImageRequest {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub format: Option<Format>,
+}
+
+fn format_named(name: &str) -> Option<Format> {
+    Some(match name.to_ascii_lowercase().as_str() {
+        "auto" => Format::Auto,
+        "avif" => Format::AVIF,
+        "gif" => Format::GIF,
+        "jpeg" | "jpg" => Format::JPEG,
+        "jpegxl" | "jxl" => Format::JPEGXL,
+        "png" => Format::PNG,
+        "webp" => Format::WebP,
+        _ => return None,
+    })
+}
+
+impl ImageRequest {
+    /// The transformation the request's parameters ask for, if any.
+    pub fn requested(url: &Url) -> Result<Option<Self>, String> {
+        let mut request = ImageRequest::default();
+        let mut asked = false;
+        for (name, value) in url.query_pairs() {
+            match name.as_ref() {
+                "w" | "h" => {
+                    let pixels = value
+                        .parse::<u32>()
+                        .ok()
+                        .filter(|pixels| *pixels > 0)
+                        .ok_or_else(|| format!("'{}' must be a positive number of pixels", name))?;
+                    if name == "w" {
+                        request.width = Some(pixels);
+                    } else {
+                        request.height = Some(pixels);
+                    }
+                }
+                "fmt" => {
+                    let format = format_named(&value).ok_or_else(|| {
+                        format!(
+                            "'fmt' must be auto, avif, gif, jpeg, jpegxl, png or webp, not '{}'",
+                            value
+                        )
+                    })?;
+                    request.format = Some(format);
+                }
+                _ => continue,
+            }
+            asked = true;
+        }
+        Ok(asked.then_some(request))
+    }
+}
+
+fn extension(target: &Url) -> Option<&str> {
+    target
+        .path()
+        .rsplit_once('.')
+        .map(|(_, extension)| extension)
+}
+
+fn has_extension(target: &Url, extensions: &[&str]) -> bool {
+    extension(target).is_some_and(|extension| {
+        extensions
+            .iter()
+            .any(|known| known.eq_ignore_ascii_case(extension))
+    })
+}
+
+/// Whether the target looks like an image the optimizer can work on.
+pub fn is_image(target: &Url) -> bool {
+    has_extension(target, &IMAGE_EXTENSIONS)
+}
+
+/// Whether an `Accept` header takes a media type, other than with `q=0`.
+fn accepts(accept: &str, mime: &str) -> bool {
+    accept.split(',').any(|range| {
+        let mut params = range.split(';');
+        let matches = params
+            .next()
+            .is_some_and(|range| range.trim().eq_ignore_ascii_case(mime));
+        let refused = params.any(|param| {
+            param
+                .trim()
+                .strip_prefix("q=")
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .is_some_and(|q| q == 0.0)
+        });
+        matches && !refused
+    })
+}
+
+/// The smallest format the client says it takes, if it's newer than JPEG and PNG.
+fn preferred_format(accept: &str) -> Option<Format> {
+    if accepts(accept, "image/avif") {
+        Some(Format::AVIF)
+    } else if accepts(accept, "image/webp") {
+        Some(Format::WebP)
+    } else {
+        None
+    }
+}
+
+fn optimizable(method: &Method, target: &Url) -> bool {
+    matches!(*method, Method::GET | Method::HEAD) && is_image(target)
+}
+
+impl Images {
+    /// Whether the image's format is chosen by the client's `Accept`.
+    pub fn negotiates(
+        &self,
+        request: Option<&ImageRequest>,
+        method: &Method,
+        target: &Url,
+    ) -> bool {
+        self.negotiate_formats
+            && request.is_none_or(|request| request.format.is_none())
+            && optimizable(method, target)
+            && has_extension(target, &NEGOTIATED_EXTENSIONS)
+    }
+
+    /// Optimizer options for a request to `target`, if it's an image request
+    /// the optimizer should handle.
+    pub fn options(
+        &self,
+        request: Option<&ImageRequest>,
+        accept: Option<&str>,
+        method: &Method,
+        target: &Url,
+    ) -> Option<ImageOptimizerOptions> {
+        if !optimizable(method, target) {
+            return None;
+        }
+        let negotiated = accept
+            .filter(|_| self.negotiates(request, method, target))
+            .and_then(preferred_format);
+        if request.is_none() && negotiated.is_none() {
+            return None;
+        }
+        let request = request.cloned().unwrap_or_default();
+        let pixels = |size: u32| PixelsOrPercentage::Pixels(size.min(self.max_dimension));
+        let mut options = ImageOptimizerOptions::from_region(self.region.sdk());
+        options.width = request.width.map(pixels);
+        options.height = request.height.map(pixels);
+        options.format = request.format.or(negotiated);
+        Some(options)
+    }
+}
@@ -0,0 +1,203 @@
+//! Latency histograms per origin.
+//!
+//! Each instance keeps a time-to-first-byte and a total latency histogram for
+//! every origin it has fetched from, per tenant, and writes them out at most
+//! every `flush_secs` of the `latency` settings in the deployment's
+//! [`ProxyConfig`](crate::config::ProxyConfig): to the state store under
+//! `latency.<tenant>.<origin>.<bucket>`, with the tenant escaped by
+//! [`state::segment`], where `/stats` reads them back, and as NDJSON to the
+//! real-time log `endpoint` if there is one. An instance
+//! flushes after its first request, so nothing is lost when it handles only
+//! one; one that's reused adds up its requests in memory in between. A
+//! flushed bucket is read-modify-write, like the other stats.
+
+use crate::stats::{self, Outcome, Sample, LATENCY_BOUNDS};
+use crate::{config, state};
+use fastly::kv_store::KVStore;
+use fastly::log::Endpoint;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const BUCKET: Duration = Duration::from_secs(900);
+const HOUR: u64 = 3600;
+const DAY: u64 = 24 * 3600;
+
+/// Buckets are kept a little longer than the longest window reported.
+const BUCKET_TTL: Duration = Duration::from_secs(DAY + 3600);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Shortest time between an instance's flushes.
+    pub flush_secs: u64,
+    /// Real-time log endpoint flushed histograms are also written to.
+    pub endpoint: Option<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            flush_secs: 60,
+            endpoint: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Histogram {
+    /// Requests per bucket: one per [`LATENCY_BOUNDS`] entry, then the overflow.
+    pub counts: Vec<u64>,
+    pub ms_total: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        let bucket = LATENCY_BOUNDS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BOUNDS.len());
+        self.counts.resize(LATENCY_BOUNDS.len() + 1, 0);
+        self.counts[bucket] += 1;
+        self.ms_total += latency.as_millis() as u64;
+    }
+
+    fn add(&mut self, other: &Histogram) {
+        if self.counts.len() < other.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        for (total, count) in self.counts.iter_mut().zip(&other.counts) {
+            *total += count;
+        }
+        self.ms_total += other.ms_total;
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OriginLatency {
+    /// Time until the origin's response headers arrived.
+    pub ttfb: Histogram,
+    /// Time until the whole response was ready to send to the client.
+    pub total: Histogram,
+}
+
+impl OriginLatency {
+    fn add(&mut self, other: &OriginLatency) {
+        self.ttfb.add(&other.ttfb);
+        self.total.add(&other.total);
+    }
+}
+
+/// Histograms not yet flushed, by tenant and origin.
+static PENDING: Mutex<BTreeMap<(String, String), OriginLatency>> = Mutex::new(BTreeMap::new());
+
+/// When this instance last flushed, if it has.
+static LAST_FLUSH: Mutex<Option<Instant>> = Mutex::new(None);
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Add the finished request's latencies, if it was fetched from an origin,
+/// and flush if it's time.
+pub fn observe(sample: &Sample, outcome: &Outcome) {
+    let (Some(tenant), Some(origin), Some(ttfb)) =
+        (&sample.tenant, &sample.origin, sample.origin_latency)
+    else {
+        return;
+    };
+    if let Ok(mut pending) = PENDING.lock() {
+        let latency = pending.entry((tenant.clone(), origin.clone())).or_default();
+        latency.ttfb.observe(ttfb);
+        latency.total.observe(outcome.latency);
+    }
+    flush_if_due();
+}
+
+fn flush_if_due() {
+    let settings = config::current().latency;
+    {
+        let Ok(mut last_flush) = LAST_FLUSH.lock() else {
+            return;
+        };
+        let due = last_flush
+            .is_none_or(|flushed| flushed.elapsed() >= Duration::from_secs(settings.flush_secs));
+        if !due {
+            return;
+        }
+        *last_flush = Some(Instant::now());
+    }
+    let Some(pending) = PENDING
+        .lock()
+        .ok()
+        .map(|mut pending| std::mem::take(&mut *pending))
+    else {
+        return;
+    };
+    let store = state::open();
+    let mut endpoint = settings
+        .endpoint
+        .as_deref()
+        .and_then(|name| Endpoint::try_from_name(name).ok());
+    let bucket = now() / BUCKET.as_secs() * BUCKET.as_secs();
+    for ((tenant, origin), latency) in pending {
+        if let Some(endpoint) = endpoint.as_mut() {
+            let line = serde_json::json!({
+                "timestamp": now(),
+                "tenant": tenant,
+                "origin": origin,
+                "bounds_secs": LATENCY_BOUNDS,
+                "latency": latency,
+            });
+            let _ = writeln!(endpoint, "{}", line);
+        }
+        if let Some(store) = &store {
+            let key = format!("latency.{}.{}.{}", state::segment(&tenant), origin, bucket);
+            let mut flushed = state::get::<OriginLatency>(store, &key).unwrap_or_default();
+            flushed.add(&latency);
+            state::put(store, &key, &flushed, Some(BUCKET_TTL));
+        }
+    }
+}
+
+#[derive(Default, Serialize)]
+pub struct Windows {
+    pub hour: OriginLatency,
+    pub day: OriginLatency,
+}
+
+/// Each of a tenant's origins' flushed histograms over the last hour and day.
+pub fn aggregate(store: &KVStore, tenant: &str) -> BTreeMap<String, Windows> {
+    let now = now();
+    let mut origins: BTreeMap<String, Windows> = BTreeMap::new();
+    let prefix = format!("latency.{}.", state::segment(tenant));
+    for key in stats::list_keys(store, &prefix) {
+        let Some((origin, bucket)) = key[prefix.len()..].rsplit_once('.') else {
+            continue;
+        };
+        let Ok(bucket) = bucket.parse::<u64>() else {
+            continue;
+        };
+        let age = now.saturating_sub(bucket);
+        if age >= DAY {
+            continue;
+        }
+        let Some(latency) = state::get::<OriginLatency>(store, &key) else {
+            continue;
+        };
+        let windows = origins.entry(origin.to_string()).or_default();
+        windows.day.add(&latency);
+        if age < HOUR {
+            windows.hour.add(&latency);
+        }
+    }
+    origins
+}
@@ -0,0 +1,139 @@
+//! Dynamic Backends proxy for Fastly Compute.
+//!
+//! [`serve`] answers the client's request with [`forward::handle`], sends the
+//! response and then does the work that shouldn't delay it. The modules are
+//! public so the integration tests can exercise them under Viceroy.
+
+use fastly::{Error, Request};
+
+pub mod access_log;
+pub mod admin;
+pub mod affinity;
+pub mod audit;
+pub mod auth;
+pub mod backend;
+pub mod backoff;
+pub mod batch;
+pub mod bots;
+pub mod cache;
+pub mod cache_control;
+pub mod canary;
+pub mod capture;
+pub mod chaos;
+pub mod charset;
+pub mod cidr;
+pub mod circuit;
+pub mod compare;
+pub mod compression;
+pub mod conditional;
+pub mod config;
+pub mod cookies;
+pub mod cors;
+pub mod credentials;
+pub mod deadline;
+pub mod destinations;
+pub mod diagnose;
+pub mod echo;
+pub mod error_pages;
+pub mod errors;
+pub mod esi;
+pub mod expect;
+pub mod fallback;
+pub mod fields;
+pub mod fingerprint;
+pub mod forward;
+pub mod geoblock;
+pub mod grpc;
+pub mod headers;
+pub mod health;
+pub mod hedge;
+pub mod html;
+pub mod images;
+pub mod latency;
+pub mod limits;
+pub mod loops;
+pub mod maintenance;
+pub mod manifest;
+pub mod metering;
+pub mod method;
+pub mod metrics;
+pub mod mirror;
+pub mod mock;
+pub mod oauth;
+pub mod outbound;
+pub mod output;
+pub mod plan;
+pub mod policy;
+pub mod pooling;
+pub mod quota;
+pub mod redirect;
+pub mod residency;
+pub mod resolve;
+pub mod robots;
+pub mod routes;
+pub mod secrets;
+pub mod session;
+pub mod shielding;
+pub mod signed_url;
+pub mod signing;
+pub mod split;
+pub mod sse;
+pub mod ssrf;
+pub mod state;
+pub mod stats;
+pub mod telemetry;
+pub mod tenant;
+pub mod timeouts;
+pub mod timing;
+pub mod tls;
+pub mod trace;
+pub mod trailers;
+pub mod transform;
+pub mod upstream;
+pub mod url_rules;
+pub mod via;
+pub mod watchdog;
+pub mod webhook;
+pub mod websocket;
+
+/// Handle the client's request for this execution of the service.
+pub fn serve() -> Result<(), Error> {
+    fastly::init();
+    let req = Request::from_client();
+    let request_id = req
+        .get_client_request_id()
+        .unwrap_or_else(|| fastly::compute_runtime::sandbox_id())
+        .to_string();
+    stats::begin(&req);
+    let session = auth::session();
+    let key_id = access_log::credential_key_id(&req, session.as_ref());
+    let client_ip = req.get_client_ip_addr();
+    let trace = trace::TraceContext::from_request(&req);
+    telemetry::begin(&trace);
+    let mut resp = match forward::handle(req, &request_id, &trace, session.as_ref()) {
+        Ok(resp) => resp,
+        Err(e) => errors::Problem::new(errors::Code::InternalError, e.to_string()).into_response(),
+    };
+    let client_ip = shielding::forwarded_client().or(client_ip);
+    cors::annotate(&mut resp);
+    let outcome = stats::Outcome::of(&resp);
+    let send_span = telemetry::Span::start("send_response");
+    if !websocket::handed_off() {
+        watchdog::send(resp, &request_id);
+    }
+    send_span.end(true);
+
+    // Work that shouldn't delay the client runs once the response has been sent
+    telemetry::finish(outcome.status.as_u16());
+    let sample = stats::take();
+    access_log::emit(&request_id, key_id.as_deref(), &sample, &outcome);
+    destinations::emit(&request_id, key_id.as_deref(), client_ip, &sample, &outcome);
+    metering::emit(key_id.as_deref(), &sample, &outcome);
+    quota::record(outcome.bytes_out);
+    stats::record(&sample, &outcome);
+    latency::observe(&sample, &outcome);
+    stats::record_error(&request_id, &sample, &outcome);
+    audit::export_if_due(&request_id);
+    mirror::finish(&request_id, sample.tenant.as_deref());
+    Ok(())
+}
@@ -0,0 +1,117 @@
+//! Instance resource budgets for fan-out work.
+//!
+//! Compute traps an instance that goes over its memory or backend request
+//! limits, and the client sees an opaque platform error. Anything that issues
+//! extra origin requests (hedges, fallbacks, redirect hops, notifications)
+//! reserves from this budget first, so work is shed before a hard limit is
+//! hit. Batch traffic gets half the budget, so its extras go first. No more
+//! requests are started once the request's [deadline](crate::deadline) has
+//! passed. Shed work is surfaced on the client response via
+//! [`EXHAUSTED_HEADER`].
+
+use crate::{config, deadline};
+use crate::errors::{Code, Problem};
+use crate::tenant::Priority;
+use fastly::Response;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+
+/// Linear memory above which new fan-out work is refused. Instances get 128 MiB.
+const MEMORY_SOFT_LIMIT_BYTES: usize = 96 * 1024 * 1024;
+
+/// Response header naming the resource that caused work to be shed.
+pub const EXHAUSTED_HEADER: &str = "X-Proxy-Resource-Exhausted";
+
+static BACKEND_REQUESTS: AtomicU32 = AtomicU32::new(0);
+static SHED: AtomicU8 = AtomicU8::new(0);
+static BATCH: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exhausted {
+    BackendRequests = 1,
+    Memory = 2,
+    Deadline = 3,
+}
+
+impl Exhausted {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Exhausted::BackendRequests => "backend_requests",
+            Exhausted::Memory => "memory",
+            Exhausted::Deadline => "deadline",
+        }
+    }
+
+    /// An error for work that couldn't be started at all.
+    pub fn into_response(self) -> Response {
+        let problem = match self {
+            Exhausted::Deadline => Problem::new(
+                Code::DeadlineExceeded,
+                "The request's deadline passed before the origin could be tried",
+            ),
+            _ => Problem::new(Code::ResourceExhausted, ""),
+        };
+        problem
+            .with("resource_exhausted", self.as_str())
+            .into_response()
+            .with_header(EXHAUSTED_HEADER, self.as_str())
+    }
+}
+
+fn memory_bytes() -> usize {
+    #[cfg(target_arch = "wasm32")]
+    {
+        core::arch::wasm32::memory_size::<0>() * 65536
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+/// Apply the budget for the request's priority class.
+pub fn set_priority(priority: Priority) {
+    BATCH.store(priority == Priority::Batch, Ordering::Relaxed);
+}
+
+/// Reserve one backend request, or record why it has to be shed.
+pub fn reserve_request() -> Result<(), Exhausted> {
+    let max_backend_requests = config::current().limits.max_backend_requests;
+    let (max_requests, memory_limit) = if BATCH.load(Ordering::Relaxed) {
+        (max_backend_requests / 2, MEMORY_SOFT_LIMIT_BYTES / 2)
+    } else {
+        (max_backend_requests, MEMORY_SOFT_LIMIT_BYTES)
+    };
+    let exhausted = if deadline::expired() {
+        Some(Exhausted::Deadline)
+    } else if memory_bytes() >= memory_limit {
+        Some(Exhausted::Memory)
+    } else if BACKEND_REQUESTS.fetch_add(1, Ordering::Relaxed) >= max_requests {
+        Some(Exhausted::BackendRequests)
+    } else {
+        None
+    };
+    match exhausted {
+        Some(exhausted) => {
+            SHED.store(exhausted as u8, Ordering::Relaxed);
+            Err(exhausted)
+        }
+        None => Ok(()),
+    }
+}
+
+/// The resource that caused work to be shed during this execution, if any.
+pub fn shed() -> Option<Exhausted> {
+    match SHED.load(Ordering::Relaxed) {
+        1 => Some(Exhausted::BackendRequests),
+        2 => Some(Exhausted::Memory),
+        3 => Some(Exhausted::Deadline),
+        _ => None,
+    }
+}
+
+/// Mark a response that was produced with some work shed.
+pub fn annotate(resp: &mut Response) {
+    if let Some(exhausted) = shed() {
+        resp.set_header(EXHAUSTED_HEADER, exhausted.as_str());
+    }
+}
@@ -0,0 +1,72 @@
+//! Proxy-loop prevention.
+//!
+//! A target on the proxy's own domain would have the proxy fetch from itself,
+//! over and over, each hop spending a backend request and the tenant's quota.
+//! Such targets are refused, and so is any request already carrying the
+//! proxy's loop token in `X-Proxy-Loop` or `Via`, which every request it
+//! sends to an origin is marked with, in case the origin sends it back.
+
+use crate::errors::{Code, Problem};
+use crate::routes;
+use fastly::{Request, Response};
+use serde::{Deserialize, Serialize};
+
+pub const LOOP_HEADER: &str = "X-Proxy-Loop";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Marks requests that have passed through this deployment.
+    pub token: String,
+    /// The proxy's own domains, as exact names or `*.` patterns, besides the
+    /// one the request was sent to.
+    pub own_hosts: Vec<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            token: "dynserv".to_string(),
+            own_hosts: Vec::new(),
+        }
+    }
+}
+
+fn detected(detail: &str) -> Response {
+    Problem::new(Code::LoopDetected, detail).into_response()
+}
+
+impl Settings {
+    /// Whether a header value lists the token, as a word of its own.
+    fn lists_token(&self, value: &str) -> bool {
+        value
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .any(|word| word.eq_ignore_ascii_case(&self.token))
+    }
+
+    /// The refusal for a request that has already passed through the proxy.
+    pub fn check_request(&self, req: &Request) -> Option<Response> {
+        let looped = [LOOP_HEADER, "Via"].iter().any(|name| {
+            req.get_header_all_str(*name)
+                .iter()
+                .any(|value| self.lists_token(value))
+        });
+        looped.then(|| detected("The request has already passed through this proxy"))
+    }
+
+    /// The refusal for a target on the proxy's own domain, which the client
+    /// reached it on as `proxy_host`.
+    pub fn check_target(&self, proxy_host: Option<&str>, target_host: &str) -> Option<Response> {
+        let own = proxy_host.is_some_and(|host| host.eq_ignore_ascii_case(target_host))
+            || self
+                .own_hosts
+                .iter()
+                .any(|pattern| routes::host_matches(pattern, target_host));
+        own.then(|| detected("The target is the proxy itself"))
+    }
+
+    /// Mark a request to an origin as having passed through the proxy.
+    pub fn mark(&self, req: &mut Request) {
+        req.append_header(LOOP_HEADER, &self.token);
+    }
+}
@@ -0,0 +1,91 @@
+//! Maintenance mode.
+//!
+//! While it's on, every request other than health checks and the admin API
+//! is answered with a `503` and a `Retry-After`, so origins can be taken
+//! offline without a deploy. It's on when the `maintenance` settings of the
+//! deployment's [`ProxyConfig`](crate::config::ProxyConfig) say so, or when
+//! it's been switched on through the admin API, which keeps its switch in
+//! the state store.
+
+use crate::errors::{Code, Problem};
+use crate::state;
+use fastly::http::StatusCode;
+use fastly::kv_store::KVStore;
+use fastly::{Request, Response};
+use serde::{Deserialize, Serialize};
+
+const KEY: &str = "maintenance";
+
+/// How maintenance mode is set and what clients are told during it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub enabled: bool,
+    /// Seconds clients are asked to wait before trying again.
+    pub retry_after_secs: u64,
+    /// The `message` of the JSON response.
+    pub message: String,
+    /// A page for clients that accept HTML, instead of the JSON response.
+    pub html: Option<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            retry_after_secs: 300,
+            message: "The proxy is down for maintenance".to_string(),
+            html: None,
+        }
+    }
+}
+
+/// The admin API's switch, kept in the state store.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct Switch {
+    enabled: bool,
+}
+
+/// Whether maintenance mode was switched on through the admin API.
+pub fn is_switched_on() -> bool {
+    state::open()
+        .and_then(|store| state::get::<Switch>(&store, KEY))
+        .is_some_and(|switch| switch.enabled)
+}
+
+/// Switch maintenance mode on or off. Returns whether the change was saved.
+pub fn switch(store: &KVStore, enabled: bool) -> bool {
+    state::put(store, KEY, &Switch { enabled }, None)
+}
+
+impl Settings {
+    /// Whether the proxy is in maintenance mode.
+    pub fn is_on(&self) -> bool {
+        self.enabled || is_switched_on()
+    }
+
+    /// The response to a request made during maintenance.
+    pub fn response(&self, req: &Request) -> Response {
+        let resp = match &self.html {
+            Some(html) if accepts_html(req) => {
+                Response::from_status(StatusCode::SERVICE_UNAVAILABLE)
+                    .with_header("Content-Type", "text/html; charset=utf-8")
+                    .with_body(html.as_str())
+            }
+            _ => Problem::new(Code::Maintenance, self.message.as_str()).into_response(),
+        };
+        resp.with_header("Retry-After", self.retry_after_secs.to_string())
+            .with_header("Cache-Control", "no-store")
+    }
+}
+
+/// Whether the client, likely a browser, would rather have a page than JSON.
+fn accepts_html(req: &Request) -> bool {
+    req.get_header_all_str("Accept")
+        .iter()
+        .flat_map(|accept| accept.split(','))
+        .any(|range| {
+            let essence = range.split(';').next().unwrap_or_default().trim();
+            essence.eq_ignore_ascii_case("text/html")
+        })
+}
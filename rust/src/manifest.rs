@@ -0,0 +1,173 @@
+//! Rewriting HLS and DASH manifests so players fetch everything through the proxy.
+//!
+//! Every variant playlist, segment, key and init URI is resolved against the
+//! manifest's URL and replaced with a proxy URL, so live streams keep working
+//! past the top-level playlist. DASH `$Number$`-style template identifiers are
+//! kept literal so players can still expand them.
+
+use crate::redirect;
+use fastly::{Body, Response};
+use url::Url;
+
+/// Manifests larger than this are passed through untouched.
+const MAX_MANIFEST_BYTES: usize = 2 * 1024 * 1024;
+
+/// DASH attributes holding segment URLs.
+const DASH_URL_ATTRIBUTES: [&str; 4] = ["media", "initialization", "sourceURL", "xlink:href"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Hls,
+    Dash,
+}
+
+fn kind_of(content_type: &str) -> Option<Kind> {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    match mime.as_str() {
+        "application/vnd.apple.mpegurl" | "application/x-mpegurl" | "audio/mpegurl" => {
+            Some(Kind::Hls)
+        }
+        "application/dash+xml" => Some(Kind::Dash),
+        _ => None,
+    }
+}
+
+struct Rewriter<'a> {
+    base: Url,
+    proxy: &'a Url,
+    kind: Kind,
+}
+
+impl Rewriter<'_> {
+    fn proxied(&self, reference: &str) -> Option<String> {
+        let destination = self.base.join(reference.trim()).ok()?;
+        if destination.scheme() != "https" {
+            return None;
+        }
+        let proxied = redirect::proxy_url_for(self.proxy, &destination).to_string();
+        Some(match self.kind {
+            // `$` is valid in a query string, and players must see the template as written
+            Kind::Dash => proxied.replace("%24", "$"),
+            Kind::Hls => proxied,
+        })
+    }
+
+    /// Rewrite the values of `name="..."` attributes in `text`.
+    fn attributes(&self, text: &str, name: &str) -> String {
+        let needle = format!("{}=\"", name);
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find(&needle) {
+            // Don't match the end of a longer attribute name
+            let preceded_by_name = rest[..start]
+                .chars()
+                .last()
+                .is_some_and(|c| c.is_alphanumeric() || c == '-');
+            let value_start = start + needle.len();
+            out.push_str(&rest[..value_start]);
+            rest = &rest[value_start..];
+            let Some(end) = rest.find('"') else {
+                break;
+            };
+            let value = &rest[..end];
+            let proxied = match self.kind {
+                // XML attribute values are entity-encoded
+                Kind::Dash => self
+                    .proxied(&value.replace("&amp;", "&"))
+                    .map(|proxied| proxied.replace('&', "&amp;")),
+                Kind::Hls => self.proxied(value),
+            };
+            match proxied.filter(|_| !preceded_by_name) {
+                Some(proxied) => out.push_str(&proxied),
+                None => out.push_str(value),
+            }
+            rest = &rest[end..];
+        }
+        out.push_str(rest);
+        out
+    }
+
+    fn hls(&self, text: &str) -> String {
+        let lines: Vec<String> = text
+            .lines()
+            .map(|line| {
+                let trimmed = line.trim();
+                if trimmed.is_empty() {
+                    line.to_string()
+                } else if trimmed.starts_with('#') {
+                    self.attributes(line, "URI")
+                } else {
+                    self.proxied(trimmed).unwrap_or_else(|| line.to_string())
+                }
+            })
+            .collect();
+        let mut out = lines.join("\n");
+        if text.ends_with('\n') {
+            out.push('\n');
+        }
+        out
+    }
+
+    fn dash(&mut self, text: &str) -> String {
+        // Segment URLs are relative to the first BaseURL, which is then dropped
+        let mut text = text.to_string();
+        if let Some((start, end, value)) = base_url(&text) {
+            if let Ok(base) = self.base.join(value.trim()) {
+                self.base = base;
+            }
+            text.replace_range(start..end, "");
+            while let Some((start, end, _)) = base_url(&text) {
+                text.replace_range(start..end, "");
+            }
+        }
+        DASH_URL_ATTRIBUTES
+            .iter()
+            .fold(text, |text, name| self.attributes(&text, name))
+    }
+}
+
+/// The span and value of the first `<BaseURL>` element.
+fn base_url(text: &str) -> Option<(usize, usize, String)> {
+    let start = text.find("<BaseURL")?;
+    let open_end = start + text[start..].find('>')? + 1;
+    let close = open_end + text[open_end..].find("</BaseURL>")?;
+    let value = text[open_end..close].replace("&amp;", "&");
+    Some((start, close + "</BaseURL>".len(), value))
+}
+
+/// Rewrite a manifest response fetched from `manifest_url` to go through `proxy`.
+pub fn rewrite(resp: &mut Response, manifest_url: &Url, proxy: &Url) {
+    let content_type = resp.get_header_str("Content-Type").unwrap_or_default();
+    let Some(kind) = kind_of(content_type) else {
+        return;
+    };
+    if resp.contains_header("Content-Encoding") {
+        return;
+    }
+    let body = resp.get_body_mut();
+    let prefix = body.get_prefix_mut(MAX_MANIFEST_BYTES + 1);
+    if prefix.len() > MAX_MANIFEST_BYTES {
+        return;
+    }
+    let original = prefix.take();
+    let Ok(text) = String::from_utf8(original.clone()) else {
+        *body = Body::from(original);
+        return;
+    };
+    let mut rewriter = Rewriter {
+        base: manifest_url.clone(),
+        proxy,
+        kind,
+    };
+    let rewritten = match kind {
+        Kind::Hls => rewriter.hls(&text),
+        Kind::Dash => rewriter.dash(&text),
+    };
+    *body = Body::from(rewritten);
+    resp.remove_header("Content-Length");
+}
@@ -0,0 +1,193 @@
+//! Usage records for billing.
+//!
+//! With an `endpoint` in the `metering` settings of the deployment's
+//! [`ProxyConfig`](crate::config::ProxyConfig), usage by each key is written
+//! there as NDJSON [`UsageRecord`]s, once the response has been sent.
+//! Without `batch_secs` each request gets a record of its own, for a
+//! `sample_rate` share of requests; the rate is in the record so totals can
+//! be scaled back up. With it, usage is added up per key in the state store
+//! over windows of `batch_secs`, and the first request after a window ends
+//! writes its records and clears it.
+
+use crate::stats::{Outcome, Sample};
+use crate::{config, state};
+use fastly::kv_store::KVStore;
+use fastly::log::Endpoint;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The schema every record names, which changes only if its fields do.
+pub const SCHEMA: &str = "dynserv.usage.v1";
+
+/// Prefix of the usage being added up, keyed by window then key.
+const PREFIX: &str = "metering.";
+
+/// The end of the last window whose records were written.
+const LAST_FLUSH_KEY: &str = "metering_flushed";
+
+/// Keys read per list page.
+const LIST_PAGE: u32 = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Real-time log endpoint records are written to.
+    pub endpoint: Option<String>,
+    /// Share of requests recorded when they aren't batched, from 0 to 1.
+    pub sample_rate: f64,
+    /// Length of the windows usage is added up over; 0 records each request.
+    pub batch_secs: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            sample_rate: 1.0,
+            batch_secs: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageRecord {
+    pub schema: String,
+    /// Unix seconds the usage was counted from and until.
+    pub period_start: u64,
+    pub period_end: u64,
+    /// The key ID, or the tenant for requests without one.
+    pub key_id: String,
+    pub tenant: String,
+    pub requests: u64,
+    /// Bytes of responses fetched from origins, as delivered to the client.
+    pub origin_bytes: u64,
+    /// Requests answered from the edge cache.
+    pub cache_hits: u64,
+    /// Share of requests counted; divide by it to estimate the totals.
+    pub sample_rate: f64,
+}
+
+/// Usage added up for a key during a window.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Tally {
+    tenant: String,
+    requests: u64,
+    origin_bytes: u64,
+    cache_hits: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn sampled(rate: f64) -> bool {
+    if rate >= 1.0 {
+        return true;
+    }
+    let mut buf = [0u8; 4];
+    if getrandom::getrandom(&mut buf).is_err() {
+        return false;
+    }
+    (u32::from_le_bytes(buf) as f64 / u32::MAX as f64) < rate
+}
+
+fn write(endpoint: &mut Endpoint, record: &UsageRecord) {
+    if let Ok(line) = serde_json::to_string(record) {
+        let _ = writeln!(endpoint, "{}", line);
+    }
+}
+
+/// Meter the request once its response has been sent.
+pub fn emit(key_id: Option<&str>, sample: &Sample, outcome: &Outcome) {
+    let settings = config::current().metering;
+    let Some(mut endpoint) = settings
+        .endpoint
+        .as_deref()
+        .and_then(|name| Endpoint::try_from_name(name).ok())
+    else {
+        return;
+    };
+    let Some(tenant) = &sample.tenant else {
+        return;
+    };
+    let key_id = key_id.unwrap_or(tenant);
+    let origin_bytes = if sample.cache_hit {
+        0
+    } else {
+        outcome.bytes_out
+    };
+    let now = now();
+
+    if settings.batch_secs == 0 {
+        if sampled(settings.sample_rate) {
+            let record = UsageRecord {
+                schema: SCHEMA.to_string(),
+                period_start: now,
+                period_end: now,
+                key_id: key_id.to_string(),
+                tenant: tenant.clone(),
+                requests: 1,
+                origin_bytes,
+                cache_hits: u64::from(sample.cache_hit),
+                sample_rate: settings.sample_rate,
+            };
+            write(&mut endpoint, &record);
+        }
+        return;
+    }
+    let Some(store) = state::open() else {
+        return;
+    };
+    let window = now / settings.batch_secs * settings.batch_secs;
+    let key = format!("{}{}.{}", PREFIX, window, key_id);
+    let mut tally = state::get::<Tally>(&store, &key).unwrap_or_default();
+    tally.tenant = tenant.clone();
+    tally.requests += 1;
+    tally.origin_bytes += origin_bytes;
+    tally.cache_hits += u64::from(sample.cache_hit);
+    // Kept until well after the window, in case nothing flushes it sooner
+    let ttl = Duration::from_secs(settings.batch_secs.saturating_mul(4).max(3600));
+    state::put(&store, &key, &tally, Some(ttl));
+
+    let flushed = state::get::<u64>(&store, LAST_FLUSH_KEY).unwrap_or(0);
+    if flushed < window {
+        // Claim the flush first so concurrent requests don't duplicate it
+        state::put(&store, LAST_FLUSH_KEY, &window, None);
+        flush(&store, &mut endpoint, window, settings.batch_secs);
+    }
+}
+
+/// Write and clear the usage of every window that ended by `before`.
+fn flush(store: &KVStore, endpoint: &mut Endpoint, before: u64, batch_secs: u64) {
+    let Ok(page) = store.build_list().prefix(PREFIX).limit(LIST_PAGE).execute() else {
+        return;
+    };
+    for key in page.into_keys() {
+        let Some((window, key_id)) = key[PREFIX.len()..].split_once('.') else {
+            continue;
+        };
+        let Some(window) = window.parse::<u64>().ok().filter(|window| *window < before) else {
+            continue;
+        };
+        if let Some(tally) = state::get::<Tally>(store, &key) {
+            let record = UsageRecord {
+                schema: SCHEMA.to_string(),
+                period_start: window,
+                period_end: window + batch_secs,
+                key_id: key_id.to_string(),
+                tenant: tally.tenant,
+                requests: tally.requests,
+                origin_bytes: tally.origin_bytes,
+                cache_hits: tally.cache_hits,
+                sample_rate: 1.0,
+            };
+            write(endpoint, &record);
+        }
+        let _ = store.delete(&key);
+    }
+}
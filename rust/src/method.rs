@@ -0,0 +1,59 @@
+//! Which methods may be proxied, and overriding the client's method.
+//!
+//! Clients that can only send GET or POST can ask for another method with
+//! `X-HTTP-Method-Override` or `?method=`. The effective method, overridden
+//! or not, must be in the tenant's allowlist.
+
+use crate::errors::{Code, Problem};
+use fastly::http::Method;
+use fastly::{Request, Response};
+
+pub const OVERRIDE_HEADER: &str = "X-HTTP-Method-Override";
+
+pub fn default_allowed() -> Vec<String> {
+    vec!["GET".to_string(), "HEAD".to_string(), "POST".to_string()]
+}
+
+fn not_allowed(method: &str, allowed: &[String]) -> Response {
+    Problem::new(
+        Code::MethodNotAllowed,
+        format!("{} isn't allowed for this tenant", method),
+    )
+    .with("method", method)
+    .with("allowed_methods", allowed)
+    .into_response()
+    .with_header("Allow", allowed.join(", "))
+}
+
+/// Apply any method override to the request and check the result against
+/// `allowed`, returning the refusal if it isn't.
+pub fn apply(req: &mut Request, allowed: &[String]) -> Option<Response> {
+    let overridden = req
+        .get_header_str(OVERRIDE_HEADER)
+        .map(str::to_string)
+        .or_else(|| {
+            req.get_url()
+                .query_pairs()
+                .find(|(k, _)| k == "method")
+                .map(|(_, v)| v.into_owned())
+        });
+    if let Some(method) = overridden {
+        if !matches!(*req.get_method(), Method::GET | Method::POST) {
+            let detail = "Only GET and POST requests can override their method";
+            return Some(Problem::new(Code::InvalidMethodOverride, detail).into_response());
+        }
+        let method = method.to_ascii_uppercase();
+        match Method::from_bytes(method.as_bytes()) {
+            Ok(parsed) if allowed.iter().any(|m| m.eq_ignore_ascii_case(&method)) => {
+                req.set_method(parsed)
+            }
+            _ => return Some(not_allowed(&method, allowed)),
+        }
+    }
+    let method = req.get_method_str();
+    if allowed.iter().any(|m| m.eq_ignore_ascii_case(method)) {
+        None
+    } else {
+        Some(not_allowed(method, allowed))
+    }
+}
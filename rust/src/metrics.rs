@@ -0,0 +1,144 @@
+//! `/metrics`: the tenant's usage stats in Prometheus text format.
+//!
+//! Values cover the last hour of [`stats`] buckets rather than counting from
+//! process start, since Compute instances don't live long enough to hold
+//! counters. They're exposed as gauges so a scrape that sees a value fall
+//! doesn't treat it as a counter reset.
+
+use crate::state;
+use crate::stats::{self, Counters, LATENCY_BOUNDS};
+use fastly::http::StatusCode;
+use fastly::Response;
+use std::fmt::Write;
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+struct Exposition {
+    out: String,
+}
+
+impl Exposition {
+    fn family(&mut self, name: &str, help: &str) {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} gauge", name);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+            .collect();
+        let _ = writeln!(self.out, "{}{{{}}} {}", name, labels.join(","), value);
+    }
+}
+
+/// Render the tenant's last-hour counters per origin.
+pub fn respond(tenant: &str) -> Response {
+    let Some(store) = state::open() else {
+        return stats::unavailable();
+    };
+    let (_, origins) = stats::aggregate(&store, tenant);
+    let origins: Vec<(&String, &Counters)> = origins
+        .iter()
+        .map(|(origin, windows)| (origin, &windows.hour))
+        .collect();
+
+    let mut exp = Exposition { out: String::new() };
+
+    exp.family(
+        "dynserv_requests",
+        "Requests in the last hour by origin and response status class.",
+    );
+    for (origin, counters) in &origins {
+        for (class, count) in &counters.status {
+            exp.sample(
+                "dynserv_requests",
+                &[("tenant", tenant), ("origin", origin), ("status", class)],
+                count,
+            );
+        }
+    }
+
+    exp.family(
+        "dynserv_errors",
+        "Failed requests in the last hour by origin and error type.",
+    );
+    for (origin, counters) in &origins {
+        for (kind, count) in &counters.errors {
+            exp.sample(
+                "dynserv_errors",
+                &[("tenant", tenant), ("origin", origin), ("type", kind)],
+                count,
+            );
+        }
+    }
+
+    exp.family(
+        "dynserv_backend_creation_failures",
+        "Dynamic backends that couldn't be created in the last hour.",
+    );
+    exp.family(
+        "dynserv_ssrf_rejections",
+        "Destinations refused by SSRF checks in the last hour.",
+    );
+    for (origin, counters) in &origins {
+        let labels = [("tenant", tenant), ("origin", origin.as_str())];
+        let errors = |kind: &str| counters.errors.get(kind).copied().unwrap_or(0);
+        exp.sample(
+            "dynserv_backend_creation_failures",
+            &labels,
+            errors("backend_creation"),
+        );
+        exp.sample(
+            "dynserv_ssrf_rejections",
+            &labels,
+            errors("destination_rejected"),
+        );
+    }
+
+    exp.family(
+        "dynserv_request_duration_seconds",
+        "Time to response headers in the last hour, as a cumulative histogram.",
+    );
+    for (origin, counters) in &origins {
+        let mut cumulative = 0;
+        for (i, bound) in LATENCY_BOUNDS.iter().enumerate() {
+            cumulative += counters.latency.get(i).copied().unwrap_or(0);
+            exp.sample(
+                "dynserv_request_duration_seconds_bucket",
+                &[
+                    ("tenant", tenant),
+                    ("origin", origin),
+                    ("le", &bound.to_string()),
+                ],
+                cumulative,
+            );
+        }
+        let labels = [("tenant", tenant), ("origin", origin.as_str())];
+        exp.sample(
+            "dynserv_request_duration_seconds_bucket",
+            &[labels[0], labels[1], ("le", "+Inf")],
+            counters.requests,
+        );
+        exp.sample(
+            "dynserv_request_duration_seconds_sum",
+            &labels,
+            counters.latency_ms_total as f64 / 1000.0,
+        );
+        exp.sample(
+            "dynserv_request_duration_seconds_count",
+            &labels,
+            counters.requests,
+        );
+    }
+
+    Response::from_status(StatusCode::OK)
+        .with_header("Content-Type", "text/plain; version=0.0.4")
+        .with_header("Cache-Control", "no-store")
+        .with_body(exp.out)
+}
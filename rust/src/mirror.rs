@@ -0,0 +1,183 @@
+//! Mirroring traffic to a shadow origin.
+//!
+//! A sampled share of a tenant's origin requests is copied to a second
+//! origin, keeping the method, path, query, headers and body. The copy is
+//! taken before the target's credentials are attached and it's signed, and
+//! gets only the tenant's credentials and signature for the shadow host.
+//! Mirrored requests are sent alongside the real one and waited for only
+//! after the client has its response; their responses are discarded.
+//!
+//! In compare mode the shadow's response is instead diffed against the
+//! primary's (see [`compare`]) and the result is written to the access log
+//! endpoint as a `shadow_compare` event, for validating a migration.
+
+use crate::backend::Endpoint;
+use crate::compare::{self, Snapshot};
+use crate::policy::{self, Policy};
+use crate::tenant::Tenant;
+use crate::{access_log, backend, credentials, limits, residency, signing, ssrf, url_rules};
+use fastly::http::request::PendingRequest;
+use fastly::{Request, Response};
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::time::Duration;
+use url::Url;
+
+/// Header marking a request as a mirrored copy.
+pub const MIRROR_HEADER: &str = "X-Proxy-Mirror";
+
+/// How long a mirrored fetch may take before it's abandoned.
+const MIRROR_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Mirror {
+    /// The shadow origin, such as `https://shadow.example.com`; the target's
+    /// path and query are kept.
+    pub origin: String,
+    /// Share of requests mirrored, from 0 to 100.
+    pub percent: f64,
+    /// Diff the shadow's responses against the primary's instead of discarding them.
+    pub compare: bool,
+    /// Headers included in the diff.
+    pub compare_headers: Vec<String>,
+}
+
+impl Default for Mirror {
+    fn default() -> Self {
+        Self {
+            origin: String::new(),
+            percent: 100.0,
+            compare: false,
+            compare_headers: compare::default_headers(),
+        }
+    }
+}
+
+/// A mirrored request to compare once it finishes.
+struct Comparison {
+    shadow: String,
+    headers: Vec<String>,
+}
+
+thread_local! {
+    /// Mirrored requests in flight for this execution. Pending requests
+    /// aren't `Send`, so unlike other per-execution state this is thread-local.
+    static PENDING: RefCell<Vec<(PendingRequest, Option<Comparison>)>> =
+        const { RefCell::new(Vec::new()) };
+    /// The primary response, kept while a comparison is pending.
+    static PRIMARY: RefCell<Option<Snapshot>> = const { RefCell::new(None) };
+}
+
+impl Mirror {
+    /// Whether this request is in the mirrored share.
+    fn sampled(&self) -> bool {
+        let mut buf = [0u8; 4];
+        if getrandom::getrandom(&mut buf).is_err() {
+            return false;
+        }
+        let roll = u32::from_le_bytes(buf) as f64 / u32::MAX as f64;
+        roll * 100.0 < self.percent
+    }
+
+    /// The mirror destination for a target URL.
+    fn target_for(&self, target: &Url) -> Option<ssrf::Target> {
+        let mut url = Url::parse(&self.origin).ok()?;
+        url.set_path(target.path());
+        url.set_query(target.query());
+        ssrf::validate(url).ok()
+    }
+
+    /// A copy of the origin request to mirror, if it's in the sampled share.
+    pub fn copy(&self, req: &mut Request) -> Option<Request> {
+        self.sampled().then(|| req.clone_with_body())
+    }
+
+    /// Start mirroring a copy of the origin request, if the shadow origin is allowed.
+    pub fn send(
+        &self,
+        mut copy: Request,
+        target: &Url,
+        tenant_id: &str,
+        tenant: &Tenant,
+        policy: &Policy,
+    ) {
+        let Some(mirror) = self.target_for(target) else {
+            return;
+        };
+        let decision = policy.evaluate(&policy::Subject {
+            tenant: tenant_id,
+            method: copy.get_method_str(),
+            target: &mirror,
+            confirmed: None,
+        });
+        if decision.refusal().is_some()
+            || url_rules::denying_rule(&tenant.url_rules, &mirror.url).is_some()
+            || tenant
+                .residency
+                .as_ref()
+                .is_some_and(|residency| residency::check_target(residency, &mirror).is_err())
+        {
+            return;
+        }
+        copy.set_url(mirror.url.clone());
+        copy.set_header("Host", &mirror.hostname);
+        copy.set_header(MIRROR_HEADER, "1");
+        let host = &mirror.hostname;
+        let authorized = credentials::attach(&mut copy, &tenant.origin_credentials, host)
+            .and_then(|()| signing::sign_for(&mut copy, &tenant.signed_origins, host));
+        if authorized.is_err() || limits::reserve_request().is_err() {
+            return;
+        }
+        let endpoint = Endpoint::new(&mirror.hostname, mirror.port);
+        let Ok(backend) = backend::create_bounded(&endpoint, MIRROR_TIMEOUT) else {
+            return;
+        };
+        let comparison = self.compare.then(|| Comparison {
+            shadow: mirror.url.to_string(),
+            headers: self.compare_headers.clone(),
+        });
+        if let Ok(pending) = copy.send_async(backend.name()) {
+            PENDING.with_borrow_mut(|in_flight| in_flight.push((pending, comparison)));
+        }
+    }
+}
+
+/// Keep the primary origin's response if a comparison will need it.
+pub fn observe(resp: &mut Response) {
+    let comparing = PENDING.with_borrow(|in_flight| in_flight.iter().any(|(_, c)| c.is_some()));
+    if comparing && PRIMARY.with_borrow(Option::is_none) {
+        let snapshot = Snapshot::of(resp);
+        PRIMARY.set(Some(snapshot));
+    }
+}
+
+/// Wait for mirrored requests to finish, logging any comparisons.
+pub fn finish(request_id: &str, tenant: Option<&str>) {
+    let pending = PENDING.with_borrow_mut(std::mem::take);
+    let primary = PRIMARY.take();
+    for (request, comparison) in pending {
+        let result = request.wait();
+        let Some(comparison) = comparison else {
+            continue;
+        };
+        let mut event = serde_json::json!({
+            "event": "shadow_compare",
+            "request_id": request_id,
+            "tenant": tenant,
+            "shadow_url": comparison.shadow,
+        });
+        match (&primary, result) {
+            (Some(primary), Ok(shadow)) => {
+                let shadow = Snapshot::take(shadow);
+                let (diff, matched) = compare::diff(primary, &shadow, &comparison.headers);
+                event["matched"] = matched.into();
+                event["diff"] = diff;
+            }
+            // The primary fetch failed or was never made
+            (None, _) => event["error"] = "no primary response".into(),
+            (_, Err(e)) => event["error"] = format!("shadow fetch failed: {}", e).into(),
+        }
+        access_log::event(&event);
+    }
+}
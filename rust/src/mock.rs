@@ -0,0 +1,219 @@
+//! Canned origin responses for test environments.
+//!
+//! Tenants with `mocks` set keep fixtures in the state store under
+//! `mocks.<tenant>`, registered with `PUT /mocks` (a JSON array), listed with
+//! `GET /mocks` and removed with `DELETE /mocks`. A request with
+//! `X-Proxy-Mock: 1`, or every request when the tenant's `always` is set, is
+//! answered by the first fixture whose method and URL pattern match its
+//! target instead of by the origin, once the target has passed its checks.
+//! Mocked requests no fixture matches get `404` rather than reaching the
+//! origin, so a test environment never talks to a real third party by
+//! accident. Mocked responses are sent as registered, with `X-Proxy-Mock: 1`
+//! added.
+
+use crate::errors::{self, Code, Problem};
+use crate::{state, url_rules};
+use fastly::http::{HeaderName, HeaderValue, Method, StatusCode};
+use fastly::{Request, Response};
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use url::Url;
+
+/// Header asking for a fixture instead of the origin, and marking mocked responses.
+pub const MOCK_HEADER: &str = "X-Proxy-Mock";
+
+/// Most fixtures a tenant may register.
+const MAX_FIXTURES: usize = 100;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct Mocks {
+    /// Answer every request from the fixtures, with or without the header.
+    pub always: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Fixture {
+    /// The method answered, or every method when unset.
+    #[serde(default)]
+    pub method: Option<String>,
+    /// Regex over the full target URL.
+    #[serde(deserialize_with = "url_rules::compile")]
+    pub url: Regex,
+    #[serde(default = "default_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub body: String,
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+impl Fixture {
+    fn matches(&self, method: &Method, target: &Url) -> bool {
+        self.method
+            .as_deref()
+            .is_none_or(|wanted| wanted.eq_ignore_ascii_case(method.as_str()))
+            && self.url.is_match(target.as_str())
+    }
+
+    /// The fixture's status and headers, or why they can't be sent.
+    fn parts(&self) -> Result<(StatusCode, Vec<(HeaderName, HeaderValue)>), String> {
+        let status = StatusCode::from_u16(self.status)
+            .ok()
+            .filter(|_| (100..600).contains(&self.status))
+            .ok_or_else(|| format!("Status {} isn't between 100 and 599", self.status))?;
+        let headers = self
+            .headers
+            .iter()
+            .map(|(name, value)| {
+                let name = HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| format!("'{}' is not a valid header name", name))?;
+                let value = HeaderValue::from_str(value)
+                    .map_err(|_| format!("Invalid value for header '{}'", name))?;
+                Ok((name, value))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok((status, headers))
+    }
+
+    fn response(&self) -> Response {
+        // Fixtures are checked when registered, so only an entry written
+        // another way could fail here
+        let (status, headers) = match self.parts() {
+            Ok(parts) => parts,
+            Err(e) => return errors::config(&e).with_header(MOCK_HEADER, "1"),
+        };
+        let mut resp = Response::from_status(status).with_body(self.body.as_str());
+        for (name, value) in headers {
+            resp.set_header(name, value);
+        }
+        resp.with_header(MOCK_HEADER, "1")
+    }
+}
+
+fn key(tenant: &str) -> String {
+    format!("mocks.{}", tenant)
+}
+
+/// Whether the request asks for a fixture.
+pub fn requested(req: &Request) -> bool {
+    req.get_header_str(MOCK_HEADER)
+        .is_some_and(|value| value.trim() == "1")
+}
+
+fn not_allowed() -> Response {
+    Problem::new(
+        Code::MocksNotAllowed,
+        "Mock responses aren't enabled for this tenant",
+    )
+    .into_response()
+}
+
+/// Refuse a request for a fixture from a tenant without mocks.
+pub fn check(requested: bool, mocks: Option<&Mocks>) -> Option<Response> {
+    (requested && mocks.is_none()).then(not_allowed)
+}
+
+impl Mocks {
+    /// Whether a request is to be answered from the fixtures, given whether
+    /// it asked to be.
+    pub fn applies(&self, requested: bool) -> bool {
+        self.always || requested
+    }
+
+    /// The response of the first fixture for the request, or its refusal if
+    /// none matches.
+    pub fn answer(&self, tenant: &str, method: &Method, target: &Url) -> Response {
+        let fixtures: Vec<Fixture> = state::open()
+            .and_then(|store| state::get(&store, &key(tenant)))
+            .unwrap_or_default();
+        match fixtures
+            .iter()
+            .find(|fixture| fixture.matches(method, target))
+        {
+            Some(fixture) => fixture.response(),
+            None => Problem::new(Code::NotFound, "No mock matches the request")
+                .with("target", target.as_str())
+                .into_response()
+                .with_header(MOCK_HEADER, "1"),
+        }
+    }
+}
+
+fn json(body: &serde_json::Value) -> Response {
+    Response::from_status(StatusCode::OK)
+        .with_header("Content-Type", "application/json")
+        .with_header("Cache-Control", "no-store")
+        .with_body(serde_json::to_string_pretty(body).unwrap_or_default())
+}
+
+/// Answer `/mocks`, where tenants with mocks manage their fixtures.
+pub fn respond(req: &mut Request, tenant: &str, mocks: Option<&Mocks>) -> Response {
+    if mocks.is_none() {
+        return not_allowed();
+    }
+    let Some(store) = state::open() else {
+        return Problem::new(Code::StateUnavailable, "Mocks need the state store").into_response();
+    };
+    let key = key(tenant);
+    match *req.get_method() {
+        Method::GET => {
+            let fixtures: serde_json::Value = state::get(&store, &key).unwrap_or_default();
+            json(&serde_json::json!({"mocks": fixtures.as_array().cloned().unwrap_or_default()}))
+        }
+        Method::PUT => {
+            let body = req.take_body_bytes();
+            let value: serde_json::Value = match serde_json::from_slice(&body) {
+                Ok(value) => value,
+                Err(e) => {
+                    return Problem::new(Code::InvalidParameter, format!("Invalid JSON: {}", e))
+                        .into_response();
+                }
+            };
+            let fixtures = match Vec::<Fixture>::deserialize(&value) {
+                Ok(fixtures) => fixtures,
+                Err(e) => {
+                    return Problem::new(
+                        Code::InvalidParameter,
+                        format!("Expected an array of mocks: {}", e),
+                    )
+                    .into_response();
+                }
+            };
+            if fixtures.len() > MAX_FIXTURES {
+                return Problem::new(
+                    Code::InvalidParameter,
+                    format!("At most {} mocks may be registered", MAX_FIXTURES),
+                )
+                .into_response();
+            }
+            for (index, fixture) in fixtures.iter().enumerate() {
+                if let Err(e) = fixture.parts() {
+                    return Problem::new(Code::InvalidParameter, e)
+                        .with("mock", index)
+                        .into_response();
+                }
+            }
+            if !state::put(&store, &key, &value, None) {
+                return Problem::new(
+                    Code::StateWriteFailed,
+                    "The state store didn't accept the mocks",
+                )
+                .into_response();
+            }
+            json(&serde_json::json!({"mocks": value}))
+        }
+        Method::DELETE => {
+            let _ = store.delete(&key);
+            json(&serde_json::json!({"mocks": []}))
+        }
+        _ => Problem::new(Code::MethodNotAllowed, "Use GET, PUT or DELETE")
+            .into_response()
+            .with_header("Allow", "GET, PUT, DELETE"),
+    }
+}
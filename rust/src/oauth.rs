@@ -0,0 +1,158 @@
+//! OAuth2 access tokens for origins, from the client-credentials grant.
+//!
+//! The proxy asks the configured token endpoint for a token on the tenant's
+//! behalf and keeps it in the `dynserv-state` KV Store until shortly before
+//! it expires, so clients never manage origin tokens themselves. Without the
+//! store a token is requested for every request.
+
+use crate::backend::{self, Endpoint};
+use crate::{limits, secrets, ssrf, state};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use fastly::http::StatusCode;
+use fastly::Request;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long a token request may take.
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Tokens are replaced this long before they expire.
+const EXPIRY_MARGIN_SECS: u64 = 30;
+
+/// Lifetime assumed for tokens issued without `expires_in`.
+const DEFAULT_EXPIRES_IN_SECS: u64 = 300;
+
+/// A client registered with an authorization server.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientCredentials {
+    /// The authorization server's token endpoint.
+    pub token_url: String,
+    pub client_id: String,
+    /// Name of the secret holding the client secret.
+    pub client_secret: String,
+    #[serde(default)]
+    pub scope: Option<String>,
+    #[serde(default)]
+    pub audience: Option<String>,
+}
+
+/// A token as kept in the KV Store.
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedToken {
+    access_token: String,
+    expires_at: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    #[serde(default)]
+    token_type: Option<String>,
+    #[serde(default)]
+    expires_in: Option<u64>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl ClientCredentials {
+    /// The KV key for this client's tokens; a different scope or audience gets its own.
+    fn key(&self) -> String {
+        let mut hasher = Sha256::new();
+        for part in [
+            Some(self.token_url.as_str()),
+            Some(self.client_id.as_str()),
+            self.scope.as_deref(),
+            self.audience.as_deref(),
+        ] {
+            hasher.update(part.unwrap_or_default());
+            hasher.update([0]);
+        }
+        format!("oauth.{}", hex::encode(&hasher.finalize()[..16]))
+    }
+
+    /// A current access token, from the store or else newly requested.
+    pub fn token(&self) -> Result<String, String> {
+        let store = state::open();
+        let key = self.key();
+        let now = now();
+        if let Some(cached) = store
+            .as_ref()
+            .and_then(|store| state::get::<CachedToken>(store, &key))
+        {
+            if cached.expires_at > now + EXPIRY_MARGIN_SECS {
+                return Ok(cached.access_token);
+            }
+        }
+
+        let issued = self.request()?;
+        let expires_in = issued.expires_in.unwrap_or(DEFAULT_EXPIRES_IN_SECS);
+        if let (Some(store), Some(ttl)) = (&store, expires_in.checked_sub(EXPIRY_MARGIN_SECS)) {
+            let cached = CachedToken {
+                access_token: issued.access_token.clone(),
+                expires_at: now + expires_in,
+            };
+            state::put(store, &key, &cached, Some(Duration::from_secs(ttl.max(1))));
+        }
+        Ok(issued.access_token)
+    }
+
+    /// Ask the token endpoint for a new token.
+    fn request(&self) -> Result<TokenResponse, String> {
+        let url = url::Url::parse(&self.token_url)
+            .map_err(|e| format!("Invalid token_url '{}': {}", self.token_url, e))?;
+        let target = ssrf::validate(url)
+            .map_err(|_| format!("token_url '{}' isn't a public https URL", self.token_url))?;
+        let secret = String::from_utf8(secrets::read(&self.client_secret)?)
+            .map_err(|_| format!("Secret '{}' isn't text", self.client_secret))?;
+        limits::reserve_request().map_err(|exhausted| exhausted.as_str().to_string())?;
+        let endpoint = Endpoint::new(&target.hostname, target.port);
+        let backend = backend::create_bounded(&endpoint, TOKEN_TIMEOUT)
+            .map_err(|e| format!("Failed to create backend: {:?}", e))?;
+
+        let mut form = url::form_urlencoded::Serializer::new(String::new());
+        form.append_pair("grant_type", "client_credentials");
+        if let Some(scope) = &self.scope {
+            form.append_pair("scope", scope);
+        }
+        if let Some(audience) = &self.audience {
+            form.append_pair("audience", audience);
+        }
+        // Client credentials go in HTTP Basic, each form-encoded first (RFC 6749 §2.3.1)
+        let encode = |value: &str| url::form_urlencoded::byte_serialize(value.as_bytes()).collect();
+        let client: (String, String) = (encode(&self.client_id), encode(secret.trim()));
+        let basic = STANDARD.encode(format!("{}:{}", client.0, client.1));
+
+        let mut resp = Request::post(target.url.clone())
+            .with_header("Host", &target.hostname)
+            .with_header("Authorization", format!("Basic {}", basic))
+            .with_header("Content-Type", "application/x-www-form-urlencoded")
+            .with_header("Accept", "application/json")
+            .with_body(form.finish())
+            .with_pass(true)
+            .send(backend.name())
+            .map_err(|e| format!("Token request failed: {}", e))?;
+        if resp.get_status() != StatusCode::OK {
+            return Err(format!(
+                "Token endpoint returned {}",
+                resp.get_status().as_u16()
+            ));
+        }
+        let issued: TokenResponse = serde_json::from_slice(&resp.take_body_bytes())
+            .map_err(|e| format!("Invalid token response: {}", e))?;
+        if issued
+            .token_type
+            .as_deref()
+            .is_some_and(|kind| !kind.eq_ignore_ascii_case("bearer"))
+        {
+            return Err("Token endpoint issued a non-bearer token".to_string());
+        }
+        Ok(issued)
+    }
+}
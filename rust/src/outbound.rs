@@ -0,0 +1,107 @@
+//! The checks and preparation every request sent on a client's behalf gets.
+//!
+//! The client's target, each redirect hop followed at the edge and each URL
+//! of a batch are destinations of their own, and go through the same steps.
+//! A destination is refused if it's the proxy itself, or if the destination
+//! policy, the tenant's URL rules, residency or robots rules don't allow it.
+//! Its request is marked with the proxy's loop token and `Via`, goes through
+//! the tenant's upstream proxy for its host if it has one, and gets the
+//! tenant's credentials and signature for its host last.
+
+use crate::backend::Endpoint;
+use crate::policy::{self, Policy};
+use crate::tenant::Tenant;
+use crate::{config, credentials, residency, signing, ssrf, stats, upstream, url_rules};
+use fastly::{Request, Response};
+
+/// What a destination is checked against.
+#[derive(Clone, Copy)]
+pub struct Checks<'a> {
+    pub tenant_id: &'a str,
+    pub tenant: &'a Tenant,
+    pub policy: &'a Policy,
+    pub confirmed: Option<&'a str>,
+    /// The host the client reached the proxy on.
+    pub proxy_host: Option<&'a str>,
+}
+
+impl Checks<'_> {
+    /// The destination policy's decision to send `method` to the target.
+    pub fn decision(&self, method: &str, target: &ssrf::Target) -> policy::Decision {
+        self.policy.evaluate(&policy::Subject {
+            tenant: self.tenant_id,
+            method,
+            target,
+            confirmed: self.confirmed,
+        })
+    }
+
+    /// The refusal for a target that's the proxy itself, or that the policy
+    /// or URL rules don't allow `method` to be sent to.
+    pub fn admit(&self, method: &str, target: &ssrf::Target) -> Option<Response> {
+        let loops = config::current().loops;
+        if let Some(refusal) = loops.check_target(self.proxy_host, &target.hostname) {
+            stats::note_error("loop_detected");
+            return Some(refusal);
+        }
+        let decision = self.decision(method, target);
+        if let Some(refusal) = decision.refusal() {
+            stats::note_error(decision.error_kind());
+            return Some(refusal);
+        }
+        if let Some(rule) = url_rules::denying_rule(&self.tenant.url_rules, &target.url) {
+            stats::note_error("url_denied");
+            return Some(url_rules::refusal(rule));
+        }
+        None
+    }
+
+    /// The refusal for a target outside the tenant's regions, or one its
+    /// robots.txt disallows. These look the target up, so dry runs skip them.
+    pub fn reach(&self, target: &ssrf::Target) -> Option<Response> {
+        if let Some(residency) = &self.tenant.residency {
+            if let Err(violation) = residency::check_target(residency, target) {
+                stats::note_error("residency_violation");
+                return Some(violation.into_response(residency));
+            }
+        }
+        if let Some(refusal) = self.tenant.robots.as_ref().and_then(|r| r.check(target)) {
+            stats::note_error("robots_disallowed");
+            return Some(refusal);
+        }
+        None
+    }
+
+    /// The refusal for a target the client couldn't send `method` to.
+    pub fn refusal(&self, method: &str, target: &ssrf::Target) -> Option<Response> {
+        self.admit(method, target).or_else(|| self.reach(target))
+    }
+}
+
+/// Mark a request as sent on by the proxy, with its loop token and `Via`.
+pub fn mark(req: &mut Request) {
+    let config = config::current();
+    config.loops.mark(req);
+    config.via.add_to_request(req);
+}
+
+/// Where requests to the target connect: the tenant's upstream proxy for its
+/// host, if it has one.
+pub fn endpoint<'a>(tenant: &'a Tenant, target: &'a ssrf::Target) -> Endpoint<'a> {
+    match upstream::find(&tenant.upstream_proxies, &target.hostname) {
+        Some(upstream) => upstream.endpoint(&target.hostname),
+        None => Endpoint::new(&target.hostname, target.port),
+    }
+}
+
+/// Attach the tenant's credentials for the target's host, tell its upstream
+/// proxy where the request is going, then sign it. Call once the request is
+/// otherwise final.
+pub fn authorize(req: &mut Request, tenant: &Tenant, target: &ssrf::Target) -> Result<(), String> {
+    let host = &target.hostname;
+    credentials::attach(req, &tenant.origin_credentials, host)?;
+    if let Some(upstream) = upstream::find(&tenant.upstream_proxies, host) {
+        upstream.prepare(req, &target.url)?;
+    }
+    signing::sign_for(req, &tenant.signed_origins, host)
+}
@@ -0,0 +1,50 @@
+//! Output formats for endpoints that return collections of results.
+//!
+//! `format=ndjson` writes one JSON object per line instead of a single
+//! document, so command-line consumers can process each result with
+//! line-oriented tools as soon as it arrives.
+
+use fastly::http::StatusCode;
+use fastly::{Body, Request, Response};
+use std::io::Write;
+
+pub const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Ndjson,
+}
+
+impl Format {
+    /// The format requested by the `format` query parameter. Defaults to JSON.
+    pub fn of(req: &Request) -> Self {
+        match req
+            .get_url()
+            .query_pairs()
+            .find(|(k, _)| k == "format")
+            .map(|(_, v)| v)
+        {
+            Some(v) if v == "ndjson" => Format::Ndjson,
+            _ => Format::Json,
+        }
+    }
+}
+
+/// Write one NDJSON line.
+pub fn write_line(out: &mut impl Write, value: &serde_json::Value) -> std::io::Result<()> {
+    serde_json::to_writer(&mut *out, value)?;
+    out.write_all(b"\n")
+}
+
+/// A complete NDJSON response built from `lines`.
+pub fn ndjson_response(lines: impl IntoIterator<Item = serde_json::Value>) -> Response {
+    let mut body = Body::new();
+    for line in lines {
+        let _ = write_line(&mut body, &line);
+    }
+    Response::from_status(StatusCode::OK)
+        .with_header("Content-Type", NDJSON_CONTENT_TYPE)
+        .with_header("Cache-Control", "no-store")
+        .with_body(body)
+}
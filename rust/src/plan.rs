@@ -0,0 +1,80 @@
+//! Dry-run descriptions of what the proxy would send.
+
+use crate::policy::Decision;
+use crate::redirect::RedirectPolicy;
+use crate::routes::Route;
+use crate::{backend, ssrf};
+use fastly::http::StatusCode;
+use fastly::{Request, Response};
+
+/// Header values that are reported by name only.
+const REDACTED_HEADERS: [&str; 3] = ["authorization", "cookie", "proxy-authorization"];
+
+/// Whether the client asked for a plan instead of a proxied response.
+pub fn requested(req: &Request) -> bool {
+    req.get_path() == "/debug/plan"
+        || req
+            .get_url()
+            .query_pairs()
+            .any(|(k, v)| k == "dry_run" && (v == "1" || v == "true"))
+}
+
+/// Describe the prepared origin request without sending it.
+pub fn describe(
+    req: &Request,
+    endpoint: &backend::Endpoint,
+    route: Option<&Route>,
+    redirects: &RedirectPolicy,
+    fallback: Option<&ssrf::Target>,
+    policy: &Decision,
+) -> Response {
+    let headers: Vec<serde_json::Value> = req
+        .get_headers()
+        .map(|(name, value)| {
+            let value = if REDACTED_HEADERS.contains(&name.as_str()) {
+                "[redacted]"
+            } else {
+                value.to_str().unwrap_or("[binary]")
+            };
+            serde_json::json!([name.as_str(), value])
+        })
+        .collect();
+
+    let plan = serde_json::json!({
+        "dry_run": true,
+        "method": req.get_method_str(),
+        "url": req.get_url_str(),
+        "backend": {
+            "name": endpoint.name(),
+            "host": endpoint.hostname,
+            "port": endpoint.port,
+            "host_header": endpoint.host_header,
+            "tls": {
+                "enabled": true,
+                "sni_hostname": endpoint.sni,
+                "check_certificate": endpoint.verify_host,
+            },
+            "http2": endpoint.http2,
+            "timeouts_ms": {
+                "connect": endpoint.timeouts.connect.as_millis() as u64,
+                "first_byte": endpoint.timeouts.first_byte.as_millis() as u64,
+                "between_bytes": endpoint.timeouts.between_bytes.as_millis() as u64,
+            },
+        },
+        "headers": headers,
+        "route": route.map(|route| serde_json::json!({
+            "host": route.host,
+            "path_prefix": route.path_prefix,
+            "hedge_after_ms": route.hedge_after_ms,
+            "cache_ttl_secs": route.cache.as_ref().map(|cache| cache.ttl_secs),
+        })),
+        "redirects": redirects,
+        "fallback_url": fallback.map(|fallback| fallback.url.as_str()),
+        "policy": policy,
+    });
+
+    Response::from_status(StatusCode::OK)
+        .with_header("Content-Type", "application/json")
+        .with_header("Cache-Control", "no-store")
+        .with_body(serde_json::to_string_pretty(&plan).unwrap_or_default())
+}
@@ -0,0 +1,232 @@
+//! Destination policy.
+//!
+//! The optional `policy` entry of `dynserv-config` holds an ordered list of
+//! rules matching on destination host, port and path, request method and
+//! tenant. The first matching rule decides the request: `allow` lets it
+//! through, `deny` refuses it and `challenge` lets it through only when the
+//! client confirms with `X-Proxy-Confirm: <rule id>`. Requests no rule
+//! matches get the policy's default action.
+//!
+//! Rules refine the built-in checks in [`crate::ssrf`], which always apply
+//! first; an `allow` rule can't open up a private address.
+//!
+//! Path prefixes match the target's [`canonical_path`], so spelling a path
+//! differently, such as `/%61dmin` for `/admin`, can't slip past a rule.
+
+use crate::errors::{Code, Problem};
+use crate::routes::{self, CONFIG_STORE};
+use crate::ssrf;
+use fastly::config_store::ConfigStore;
+use fastly::Response;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+/// Header clients use to confirm a challenged request.
+pub const CONFIRM_HEADER: &str = "X-Proxy-Confirm";
+
+/// What confirms a challenge from the default action.
+const DEFAULT_RULE: &str = "default";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    #[default]
+    Allow,
+    Deny,
+    Challenge,
+}
+
+/// A rule matches when every condition it sets matches; empty lists match anything.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Rule {
+    pub id: String,
+    pub action: Action,
+    /// Destination hosts, exact or `*.example.com`.
+    pub hosts: Vec<String>,
+    pub ports: Vec<u16>,
+    pub path_prefixes: Vec<String>,
+    pub methods: Vec<String>,
+    pub tenants: Vec<String>,
+    /// Shown to the client when the rule refuses or challenges a request.
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Policy {
+    pub default: Action,
+    pub rules: Vec<Rule>,
+}
+
+/// The request being checked.
+pub struct Subject<'a> {
+    pub tenant: &'a str,
+    pub method: &'a str,
+    pub target: &'a ssrf::Target,
+    /// The rule ID in the client's [`CONFIRM_HEADER`], if any.
+    pub confirmed: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Step {
+    pub rule: String,
+    pub matched: bool,
+}
+
+/// The outcome of evaluating the policy, with the rules considered on the way.
+#[derive(Debug, Clone, Serialize)]
+pub struct Decision {
+    pub action: Action,
+    /// The rule that decided, or `None` for the default action.
+    pub rule: Option<String>,
+    #[serde(skip)]
+    reason: Option<String>,
+    /// Whether a challenge was confirmed by the client.
+    pub confirmed: bool,
+    pub trace: Vec<Step>,
+}
+
+/// Decode a percent-encoded unreserved character (RFC 3986), which means the
+/// same either way, and upper-case the hex of any other escape.
+fn decode_unreserved(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = String::with_capacity(segment.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| segment.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) => {
+                decoded.push(byte as char);
+            }
+            Some(byte) => decoded.push_str(&format!("%{:02X}", byte)),
+            None => {
+                decoded.push(bytes[i] as char);
+                i += 1;
+                continue;
+            }
+        }
+        i += 3;
+    }
+    decoded
+}
+
+/// The URL's path with unreserved characters decoded and dot segments
+/// removed, which is what rules on paths match against.
+pub fn canonical_path(url: &Url) -> String {
+    let mut segments: Vec<String> = Vec::new();
+    let mut ends_in_dot = false;
+    for segment in url.path().split('/').skip(1) {
+        let segment = decode_unreserved(segment);
+        ends_in_dot = segment == "." || segment == "..";
+        match segment.as_str() {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+    if ends_in_dot {
+        segments.push(String::new());
+    }
+    format!("/{}", segments.join("/"))
+}
+
+impl Rule {
+    fn matches(&self, subject: &Subject) -> bool {
+        let target = subject.target;
+        let path = canonical_path(&target.url);
+        (self.hosts.is_empty()
+            || self
+                .hosts
+                .iter()
+                .any(|host| routes::host_matches(host, &target.hostname)))
+            && (self.ports.is_empty() || self.ports.contains(&target.port))
+            && (self.path_prefixes.is_empty()
+                || self
+                    .path_prefixes
+                    .iter()
+                    .any(|prefix| path.starts_with(prefix.as_str())))
+            && (self.methods.is_empty()
+                || self
+                    .methods
+                    .iter()
+                    .any(|method| method.eq_ignore_ascii_case(subject.method)))
+            && (self.tenants.is_empty() || self.tenants.iter().any(|t| t == subject.tenant))
+    }
+}
+
+impl Policy {
+    pub fn evaluate(&self, subject: &Subject) -> Decision {
+        let mut trace = Vec::new();
+        let mut decided = None;
+        for rule in &self.rules {
+            let matched = rule.matches(subject);
+            trace.push(Step {
+                rule: rule.id.clone(),
+                matched,
+            });
+            if matched {
+                decided = Some(rule);
+                break;
+            }
+        }
+        let action = decided.map_or(self.default, |rule| rule.action);
+        let rule = decided.map(|rule| rule.id.clone());
+        let confirmed = action == Action::Challenge
+            && subject.confirmed == Some(rule.as_deref().unwrap_or(DEFAULT_RULE));
+        Decision {
+            action,
+            rule,
+            reason: decided.and_then(|rule| rule.reason.clone()),
+            confirmed,
+            trace,
+        }
+    }
+}
+
+impl Decision {
+    /// The response refusing the request, or `None` if it may go ahead.
+    pub fn refusal(&self) -> Option<Response> {
+        let code = match self.action {
+            Action::Allow => return None,
+            Action::Challenge if self.confirmed => return None,
+            Action::Deny => Code::PolicyDenied,
+            Action::Challenge => Code::ConfirmationRequired,
+        };
+        let mut problem = Problem::new(code, self.reason.clone().unwrap_or_default())
+            .with("rule", self.rule.clone())
+            .with("trace", serde_json::json!(self.trace));
+        if self.action == Action::Challenge {
+            problem = problem
+                .with("confirm_header", CONFIRM_HEADER)
+                .with("confirm_value", self.rule.as_deref().unwrap_or(DEFAULT_RULE));
+        }
+        Some(problem.into_response())
+    }
+
+    /// A short name for the refusal, for stats and logs.
+    pub fn error_kind(&self) -> &'static str {
+        match self.action {
+            Action::Challenge => "policy_challenge",
+            _ => "policy_denied",
+        }
+    }
+}
+
+/// Load the destination policy. A missing store or entry allows everything.
+pub fn load() -> Result<Policy, String> {
+    let Ok(store) = ConfigStore::try_open(CONFIG_STORE) else {
+        return Ok(Policy::default());
+    };
+    match store.get("policy") {
+        Some(json) => {
+            serde_json::from_str(&json).map_err(|e| format!("Invalid 'policy' entry: {}", e))
+        }
+        None => Ok(Policy::default()),
+    }
+}
@@ -0,0 +1,90 @@
+//! Connection reuse for dynamic backends.
+//!
+//! Dynamic backends pool their connections by default, so a later request to
+//! the same origin with the same backend settings can skip the TCP and TLS
+//! handshakes. A tenant's `connections` settings tune that pooling and the
+//! keepalives that keep idle connections open, for every backend built for it.
+
+use fastly::backend::BackendBuilder;
+use serde::Deserialize;
+use std::num::NonZeroU32;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// TCP keepalive probes on idle pooled connections.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct TcpKeepalive {
+    /// Idle time before the first probe.
+    pub time_secs: NonZeroU32,
+    /// Time between unanswered probes.
+    pub interval_secs: NonZeroU32,
+    /// Unanswered probes before the connection is dropped.
+    pub probes: NonZeroU32,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct Connections {
+    /// Whether connections are kept for reuse by later requests.
+    pub pooling: bool,
+    /// How long an idle HTTP connection is kept open.
+    pub http_keepalive_secs: Option<u64>,
+    pub tcp_keepalive: Option<TcpKeepalive>,
+    /// Connections open to one backend at once; 0 means no limit.
+    pub max_connections: Option<u32>,
+    /// Requests sent over one connection before it's closed; 0 means no limit.
+    pub max_use: Option<u32>,
+    /// How long a connection is used before it's closed.
+    pub max_lifetime_secs: Option<u64>,
+}
+
+impl Default for Connections {
+    fn default() -> Self {
+        Self {
+            pooling: true,
+            http_keepalive_secs: None,
+            tcp_keepalive: None,
+            max_connections: None,
+            max_use: None,
+            max_lifetime_secs: None,
+        }
+    }
+}
+
+/// The current tenant's settings, once it's known.
+static CURRENT: Mutex<Option<Connections>> = Mutex::new(None);
+
+/// Use the tenant's settings for the backends built from now on.
+pub fn configure(connections: Connections) {
+    if let Ok(mut current) = CURRENT.lock() {
+        *current = Some(connections);
+    }
+}
+
+/// Apply the current tenant's settings to a backend being built.
+pub fn apply(mut builder: BackendBuilder) -> BackendBuilder {
+    let Some(connections) = CURRENT.lock().ok().and_then(|current| *current) else {
+        return builder;
+    };
+    builder = builder.enable_pooling(connections.pooling);
+    if let Some(secs) = connections.http_keepalive_secs {
+        builder = builder.http_keepalive_time(Duration::from_secs(secs));
+    }
+    if let Some(keepalive) = connections.tcp_keepalive {
+        builder = builder
+            .tcp_keepalive_enable(true)
+            .tcp_keepalive_time_secs(keepalive.time_secs)
+            .tcp_keepalive_interval_secs(keepalive.interval_secs)
+            .tcp_keepalive_probes(keepalive.probes);
+    }
+    if let Some(max) = connections.max_connections {
+        builder = builder.max_connections(max);
+    }
+    if let Some(max) = connections.max_use {
+        builder = builder.max_use(max);
+    }
+    if let Some(secs) = connections.max_lifetime_secs {
+        builder = builder.max_lifetime(Duration::from_secs(secs));
+    }
+    builder
+}
@@ -0,0 +1,185 @@
+//! Daily quotas per API key.
+//!
+//! A tenant's `quota` caps the requests each of its keys may make, and the
+//! response bytes they may receive, per UTC day. Usage is kept in the state
+//! store under `quota.<key id>.<day>`: the request is counted when it's let
+//! through, and its response bytes once the response has been sent, so the
+//! byte quota can be overshot by the response that crosses it. Updates are
+//! read-modify-write, and concurrent requests can lose increments.
+//!
+//! Every response to a key with a quota says where it stands, in
+//! `X-RateLimit-Limit`, `X-RateLimit-Remaining` and `X-RateLimit-Reset`
+//! (seconds until the day ends). The request quota is described if there is
+//! one, the byte quota otherwise.
+
+use crate::errors::{Code, Problem};
+use crate::state;
+use crate::webhook::{self, Event};
+use fastly::Response;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAY: u64 = 24 * 3600;
+
+/// Usage is kept a little longer than the day it counts.
+const USAGE_TTL: Duration = Duration::from_secs(DAY + 3600);
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct Quota {
+    pub daily_requests: Option<u64>,
+    pub daily_bytes: Option<u64>,
+    /// Share of either quota, in percent, at which the tenant's webhook hears
+    /// that it's nearly used up.
+    pub warn_at_percent: u8,
+}
+
+impl Default for Quota {
+    fn default() -> Self {
+        Self {
+            daily_requests: None,
+            daily_bytes: None,
+            warn_at_percent: 80,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Usage {
+    requests: u64,
+    bytes: u64,
+}
+
+/// The quota of the request being handled, and its key's usage so far.
+struct Current {
+    quota: Quota,
+    key: String,
+    usage: Usage,
+    tenant: String,
+    webhook_url: Option<String>,
+}
+
+static CURRENT: Mutex<Option<Current>> = Mutex::new(None);
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn secs_until_reset() -> u64 {
+    DAY - now() % DAY
+}
+
+impl Quota {
+    /// The quota used up by `usage`, if either is.
+    fn exceeded(&self, usage: &Usage) -> Option<&'static str> {
+        if self
+            .daily_requests
+            .is_some_and(|limit| usage.requests >= limit)
+        {
+            Some("requests")
+        } else if self.daily_bytes.is_some_and(|limit| usage.bytes >= limit) {
+            Some("bytes")
+        } else {
+            None
+        }
+    }
+
+    /// Whether `usage` is past the warning share of either quota.
+    fn nearly_used(&self, usage: &Usage) -> bool {
+        let past = |limit: u64, used: u64| {
+            used.saturating_mul(100) >= limit.saturating_mul(u64::from(self.warn_at_percent))
+        };
+        self.daily_requests
+            .is_some_and(|limit| past(limit, usage.requests))
+            || self
+                .daily_bytes
+                .is_some_and(|limit| past(limit, usage.bytes))
+    }
+
+    /// Count a request against its key's quota, or refuse it with a 429 if
+    /// the key has used it up.
+    ///
+    /// Without the state store quotas can't be kept, and requests are let through.
+    pub fn check(&self, key_id: &str, tenant: &str, webhook_url: Option<&str>) -> Option<Response> {
+        let store = state::open()?;
+        let key = format!("quota.{}.{}", key_id, now() / DAY);
+        let mut usage = state::get::<Usage>(&store, &key).unwrap_or_default();
+        let exceeded = self.exceeded(&usage);
+        if exceeded.is_none() {
+            usage.requests += 1;
+            state::put(&store, &key, &usage, Some(USAGE_TTL));
+        }
+        if let Ok(mut current) = CURRENT.lock() {
+            *current = Some(Current {
+                quota: *self,
+                key,
+                usage,
+                tenant: tenant.to_string(),
+                webhook_url: webhook_url.map(str::to_string),
+            });
+        }
+        let quota = exceeded?;
+        let reset = secs_until_reset();
+        Some(
+            Problem::new(
+                Code::QuotaExceeded,
+                format!("The key's daily {} quota is used up", quota),
+            )
+            .with("quota", quota)
+            .into_response()
+            .with_header("Retry-After", reset.to_string()),
+        )
+    }
+}
+
+/// Forget the previous request's quota.
+pub fn reset() {
+    if let Ok(mut current) = CURRENT.lock() {
+        *current = None;
+    }
+}
+
+/// Tell the client how much of its quota is left.
+pub fn annotate(resp: &mut Response) {
+    let Ok(current) = CURRENT.lock() else {
+        return;
+    };
+    let Some(current) = current.as_ref() else {
+        return;
+    };
+    let (limit, used) = match (current.quota.daily_requests, current.quota.daily_bytes) {
+        (Some(limit), _) => (limit, current.usage.requests),
+        (None, Some(limit)) => (limit, current.usage.bytes),
+        (None, None) => return,
+    };
+    resp.set_header("X-RateLimit-Limit", limit.to_string());
+    resp.set_header(
+        "X-RateLimit-Remaining",
+        limit.saturating_sub(used).to_string(),
+    );
+    resp.set_header("X-RateLimit-Reset", secs_until_reset().to_string());
+}
+
+/// Add the response's bytes to its key's usage once it has been sent, and
+/// warn the tenant if the key has nearly used up its quota.
+pub fn record(bytes_out: u64) {
+    let Some(current) = CURRENT.lock().ok().and_then(|mut current| current.take()) else {
+        return;
+    };
+    let mut usage = current.usage;
+    if current.quota.daily_bytes.is_some() && bytes_out > 0 {
+        if let Some(store) = state::open() {
+            usage = state::get::<Usage>(&store, &current.key).unwrap_or(usage);
+            usage.bytes += bytes_out;
+            state::put(&store, &current.key, &usage, Some(USAGE_TTL));
+        }
+    }
+    if let (true, Some(webhook_url)) = (current.quota.nearly_used(&usage), &current.webhook_url) {
+        webhook::notify(&current.tenant, webhook_url, Event::QuotaNearlyUsed);
+    }
+}
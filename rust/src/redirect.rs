@@ -0,0 +1,179 @@
+//! Per-route handling of origin redirects.
+
+use crate::backend::{self, Endpoint};
+use crate::errors::{self, Code, Problem};
+use crate::outbound::{self, Checks};
+use crate::timeouts::Timeouts;
+use crate::{limits, residency, ssrf, stats};
+use fastly::http::{Method, StatusCode};
+use fastly::{Request, Response};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum RedirectPolicy {
+    /// Return the origin's redirect to the client unchanged.
+    #[default]
+    PassThrough,
+    /// Rewrite `Location` so the client follows the redirect back through the proxy.
+    RewriteToProxy,
+    /// Follow redirects at the edge, up to `max_hops` times.
+    Follow {
+        #[serde(default = "default_max_hops")]
+        max_hops: u8,
+    },
+    /// Refuse to return redirects to the client.
+    Block,
+}
+
+fn default_max_hops() -> u8 {
+    5
+}
+
+/// The resolved redirect destination, if the response is a redirect.
+pub fn location(resp: &Response, base: &Url) -> Option<Url> {
+    match resp.get_status() {
+        StatusCode::MOVED_PERMANENTLY
+        | StatusCode::FOUND
+        | StatusCode::SEE_OTHER
+        | StatusCode::TEMPORARY_REDIRECT
+        | StatusCode::PERMANENT_REDIRECT => {}
+        _ => return None,
+    }
+    let location = resp.get_header_str("Location")?;
+    base.join(location).ok()
+}
+
+/// Point `Location` back at the proxy, carrying the redirect target in `url`.
+pub fn rewrite_to_proxy(resp: &mut Response, base: &Url, proxy_url: &Url) {
+    if let Some(destination) = location(resp, base) {
+        resp.set_header("Location", proxy_url_for(proxy_url, &destination).as_str());
+    }
+}
+
+/// Build a proxy URL that fetches `destination`, keeping the other proxy query parameters.
+pub fn proxy_url_for(proxy_url: &Url, destination: &Url) -> Url {
+    let mut rewritten = proxy_url.clone();
+    let pairs: Vec<(String, String)> = proxy_url
+        .query_pairs()
+        .filter(|(k, _)| k != "url")
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+    rewritten
+        .query_pairs_mut()
+        .clear()
+        .extend_pairs(pairs)
+        .append_pair("url", destination.as_str());
+    rewritten
+}
+
+/// Refuse a redirect, returning an error the client can see instead of the `Location`.
+pub fn blocked(resp: &Response, base: &Url) -> Option<Response> {
+    let destination = location(resp, base)?;
+    Some(
+        Problem::new(Code::RedirectBlocked, "Redirects aren't followed for this route")
+            .with("origin_status", resp.get_status().as_u16())
+            .with("location", destination.as_str())
+            .into_response(),
+    )
+}
+
+/// What followed hops are checked against and built from.
+pub struct Hops<'a> {
+    pub checks: Checks<'a>,
+    /// A bodiless copy of the origin request, taken before the target's
+    /// credentials were attached and it was signed.
+    pub template: &'a Request,
+    /// The origin request's backend settings, which each hop's backend gets.
+    pub timeouts: Timeouts,
+    pub http2: bool,
+}
+
+/// Follow redirects at the edge, checking each hop as a new destination: it
+/// goes through the same SSRF and [`outbound`] checks as the client's target,
+/// goes through the tenant's upstream proxy for its host, and gets the
+/// tenant's credentials and signature for its own host. Requests with a body
+/// are retried as GET, except for 307/308 which are passed through.
+pub fn follow(mut resp: Response, hops: &Hops, base: &Url, max_hops: u8) -> Response {
+    let template = hops.template;
+    let bodiless = matches!(*template.get_method(), Method::GET | Method::HEAD);
+    let mut current = base.clone();
+    for _ in 0..max_hops {
+        let Some(next) = location(&resp, &current) else {
+            return resp;
+        };
+        // 307/308 require replaying the body, which has already been sent
+        if !bodiless
+            && matches!(
+                resp.get_status(),
+                StatusCode::TEMPORARY_REDIRECT | StatusCode::PERMANENT_REDIRECT
+            )
+        {
+            return resp;
+        }
+        // Out of budget: hand the client the redirect rather than failing outright
+        if limits::reserve_request().is_err() {
+            return resp;
+        }
+        let target = match ssrf::validate(next) {
+            Ok(target) => target,
+            Err(rejection) => return rejection.into_response(),
+        };
+        let method = if bodiless { template.get_method_str() } else { "GET" };
+        if let Some(refusal) = hops.checks.refusal(method, &target) {
+            return refusal;
+        }
+        let tenant = hops.checks.tenant;
+        let endpoint = Endpoint {
+            timeouts: hops.timeouts,
+            http2: hops.http2,
+            ..outbound::endpoint(tenant, &target)
+        };
+        let backend = match backend::create_endpoint(&endpoint) {
+            Ok(b) => b,
+            Err(e) => {
+                return Problem::new(Code::BackendCreateFailed, format!("{:?}", e))
+                    .with("target", target.url.as_str())
+                    .into_response()
+            }
+        };
+
+        let mut req = template.clone_without_body();
+        if !bodiless {
+            req.set_method(Method::GET);
+        }
+        // Don't hand the client's credentials for one origin to another
+        if current.host_str() != Some(target.hostname.as_str()) {
+            req.remove_header("Authorization");
+            req.remove_header("Cookie");
+        }
+        req.set_url(target.url.clone());
+        req.set_header("Host", &target.hostname);
+        if let Err(e) = outbound::authorize(&mut req, tenant, &target) {
+            return errors::config(&e);
+        }
+
+        resp = match req.send(backend.name()) {
+            Ok(r) => r,
+            Err(e) => return errors::fetch_failed(&e, target.url.as_str()).into_response(),
+        };
+        // DNS may have moved since the hop was checked
+        if let Some(residency) = &tenant.residency {
+            if let Err(violation) = residency::check_response(residency, &target.hostname, &resp) {
+                stats::note_error("residency_violation");
+                return violation.into_response(residency);
+            }
+        }
+        current = target.url;
+    }
+
+    match location(&resp, &current) {
+        Some(destination) => {
+            Problem::new(Code::TooManyRedirects, format!("Stopped after {} hops", max_hops))
+                .with("target", destination.as_str())
+                .into_response()
+        }
+        None => resp,
+    }
+}
@@ -0,0 +1,121 @@
+//! Per-tenant data residency constraints.
+//!
+//! A tenant with a residency constraint may only be proxied to origins whose
+//! addresses geolocate to one of its allowed countries. Compute can't resolve
+//! names itself, so a hostname is verified by sending a bodiless `HEAD /`
+//! probe and geolocating the address the platform connected to. The result is
+//! cached in the state store for [`VERIFIED_TTL`]. Responses are checked again
+//! against the address they actually came from, in case DNS has moved.
+
+use crate::errors::{Code, Problem};
+use crate::{backend, limits, ssrf, state};
+use fastly::geo::geo_lookup;
+use fastly::{Request, Response};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::time::Duration;
+use url::Host;
+
+const VERIFIED_TTL: Duration = Duration::from_secs(3600);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Residency {
+    /// ISO 3166-1 alpha-2 country codes origins may be located in.
+    pub countries: Vec<String>,
+}
+
+/// Where a destination was last seen to be.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Location {
+    ip: IpAddr,
+    country: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Violation {
+    host: String,
+    /// Country the origin was located in, if it could be determined.
+    country: Option<String>,
+}
+
+impl Violation {
+    pub fn into_response(self, residency: &Residency) -> Response {
+        let message = match &self.country {
+            Some(country) => format!("{} is located in {}", self.host, country),
+            None => format!("The location of {} could not be verified", self.host),
+        };
+        Problem::new(Code::ResidencyViolation, message)
+            .with("country", self.country)
+            .with("allowed_countries", residency.countries.clone())
+            .into_response()
+    }
+}
+
+fn country_of(ip: IpAddr) -> Option<String> {
+    geo_lookup(ip).map(|geo| geo.country_code().to_string())
+}
+
+fn allowed(residency: &Residency, host: &str, country: Option<String>) -> Result<(), Violation> {
+    match &country {
+        Some(code) if residency.countries.iter().any(|c| c.eq_ignore_ascii_case(code)) => Ok(()),
+        _ => Err(Violation {
+            host: host.to_string(),
+            country,
+        }),
+    }
+}
+
+/// Check a destination before anything is sent to it.
+pub fn check_target(residency: &Residency, target: &ssrf::Target) -> Result<(), Violation> {
+    let host = target.hostname.as_str();
+    let ip = match Host::parse(host) {
+        Ok(Host::Ipv4(ip)) => Some(IpAddr::V4(ip)),
+        Ok(Host::Ipv6(ip)) => Some(IpAddr::V6(ip)),
+        _ => None,
+    };
+    if let Some(ip) = ip {
+        return allowed(residency, host, country_of(ip));
+    }
+
+    let store = state::open();
+    let key = format!("residency.{}", host);
+    let cached = store
+        .as_ref()
+        .and_then(|store| state::get::<Location>(store, &key));
+    let location = match cached {
+        Some(location) => Some(location),
+        None => {
+            let location = probe(target);
+            if let (Some(store), Some(location)) = (&store, &location) {
+                state::put(store, &key, location, Some(VERIFIED_TTL));
+            }
+            location
+        }
+    };
+    allowed(residency, host, location.map(|location| location.country))
+}
+
+/// Check the address a response was actually received from.
+pub fn check_response(residency: &Residency, host: &str, resp: &Response) -> Result<(), Violation> {
+    match resp.get_backend_addr() {
+        Some(addr) => allowed(residency, host, country_of(addr.ip())),
+        None => Ok(()),
+    }
+}
+
+/// Connect to the destination without sending any tenant data.
+fn probe(target: &ssrf::Target) -> Option<Location> {
+    limits::reserve_request().ok()?;
+    let backend = backend::create_probe(&target.hostname, target.port, PROBE_TIMEOUT).ok()?;
+    let resp = Request::head(format!("https://{}:{}/", target.hostname, target.port))
+        .with_header("Host", &target.hostname)
+        .with_pass(true)
+        .send(backend.name())
+        .ok()?;
+    let ip = resp.get_backend_addr()?.ip();
+    Some(Location {
+        ip,
+        country: country_of(ip)?,
+    })
+}
@@ -0,0 +1,54 @@
+//! Pinning a target's host to an address, like curl's `--resolve`.
+//!
+//! `?resolve=host:ip` connects to `ip` for a target on `host`, while the TLS
+//! handshake and the `Host` header keep using `host`: for trying an origin's
+//! new address before DNS moves to it, or reaching one server behind a load
+//! balancer. Tenants need `dns_overrides` to use it, and the address must
+//! pass the same private-range checks as a target's.
+
+use crate::errors::{Code, Problem};
+use crate::ssrf::{self, Rejection};
+use crate::stats;
+use std::net::IpAddr;
+use url::Url;
+
+fn invalid(message: &str) -> Problem {
+    Problem::new(Code::InvalidParameter, message).with("parameter", "resolve")
+}
+
+/// The address the request pins the target's host to, if it asks for one.
+pub fn requested(client_url: &Url, target_host: &str) -> Result<Option<IpAddr>, Problem> {
+    let Some((_, value)) = client_url.query_pairs().find(|(k, _)| k == "resolve") else {
+        return Ok(None);
+    };
+    let Some((host, address)) = value.split_once(':') else {
+        return Err(invalid("'resolve' must be host:ip"));
+    };
+    if !host.eq_ignore_ascii_case(target_host.trim_end_matches('.')) {
+        return Err(invalid("'resolve' must name the target's host"));
+    }
+    let address = address.trim_start_matches('[').trim_end_matches(']');
+    let Ok(ip) = address.parse::<IpAddr>() else {
+        return Err(invalid("'resolve' must end in an IPv4 or IPv6 address"));
+    };
+    let private = match ip {
+        IpAddr::V4(ip) => ssrf::is_private_v4(ip),
+        IpAddr::V6(ip) => ssrf::is_private_v6(ip),
+    };
+    if private {
+        stats::note_rejection(Rejection::PrivateAddress);
+        return Err(Problem::new(
+            Code::SsrfBlocked,
+            "The resolve address is a local, private or reserved address",
+        ));
+    }
+    Ok(Some(ip))
+}
+
+/// How the address is written as a backend's target host.
+pub fn backend_host(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{}]", ip),
+    }
+}
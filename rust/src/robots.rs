@@ -0,0 +1,238 @@
+//! robots.txt compliance for crawling tenants.
+//!
+//! A tenant with `robots` set only fetches what each origin's `/robots.txt`
+//! allows its `user_agent`, following RFC 9309: the groups naming the agent's
+//! product token apply, or the `*` groups if none do, and the longest
+//! matching `Allow` or `Disallow` rule decides, `Allow` winning ties. A file
+//! that's missing or refused (4xx) allows everything; one that can't be
+//! fetched (5xx or no response) disallows everything until it can. Files are
+//! kept in the state store for `cache_secs`, and failures for a minute.
+
+use crate::errors::{Code, Problem};
+use crate::{backend, limits, ssrf, state, url_rules};
+use fastly::{Request, Response};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Longest file read; rules past it are ignored, as RFC 9309 allows.
+const MAX_FILE_BYTES: usize = 500 * 1024;
+
+/// How long a file that couldn't be fetched is kept before it's tried again.
+const UNREACHABLE_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Robots {
+    /// The agent whose rules apply, such as `ExampleBot/2.1`.
+    pub user_agent: String,
+    /// How long a fetched robots.txt is kept.
+    pub cache_secs: u64,
+}
+
+impl Default for Robots {
+    fn default() -> Self {
+        Self {
+            user_agent: "dynserv".to_string(),
+            cache_secs: 86400,
+        }
+    }
+}
+
+/// What was fetched from an origin's `/robots.txt`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum File {
+    Rules(String),
+    AllowAll,
+    DisallowAll,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    allow: bool,
+    pattern: String,
+}
+
+/// The product token of a user agent, lowercased: `examplebot` for
+/// `ExampleBot/2.1 (+https://example.com/bot)`.
+fn product_token(user_agent: &str) -> String {
+    user_agent
+        .split(|c: char| c == '/' || c.is_whitespace())
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+/// The rules of the groups that apply to `agent`.
+fn rules_for(file: &str, agent: &str) -> Vec<Rule> {
+    let agent = product_token(agent);
+    let mut named = Vec::new();
+    let mut wildcard = Vec::new();
+    let mut agents: Vec<String> = Vec::new();
+    let mut in_rules = false;
+    let mut agent_named = false;
+    for line in file.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        let allow = match key.trim().to_ascii_lowercase().as_str() {
+            "user-agent" => {
+                // A user-agent line after rules starts the next group
+                if in_rules {
+                    agents.clear();
+                    in_rules = false;
+                }
+                let value = value.to_ascii_lowercase();
+                agent_named |= value == agent;
+                agents.push(value);
+                continue;
+            }
+            "allow" => true,
+            "disallow" => false,
+            _ => continue,
+        };
+        in_rules = true;
+        if value.is_empty() {
+            continue;
+        }
+        let rule = Rule {
+            allow,
+            pattern: value.to_string(),
+        };
+        if agents.contains(&agent) {
+            named.push(rule.clone());
+        }
+        if agents.iter().any(|a| a == "*") {
+            wildcard.push(rule);
+        }
+    }
+    if agent_named {
+        named
+    } else {
+        wildcard
+    }
+}
+
+/// Whether a rule's pattern, with `*` wildcards and an optional `$` anchor,
+/// matches the start of `path`.
+fn matches(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    let mut parts = pattern.split('*');
+    let Some(mut rest) = path.strip_prefix(parts.next().unwrap_or_default()) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return !anchored || rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    if anchored {
+        rest.ends_with(last)
+    } else {
+        rest.contains(last)
+    }
+}
+
+/// Whether the rules allow `path`, the path and query of a URL.
+fn allowed(rules: &[Rule], path: &str) -> bool {
+    if path == "/robots.txt" {
+        return true;
+    }
+    rules
+        .iter()
+        .filter(|rule| matches(&rule.pattern, path))
+        .max_by_key(|rule| (rule.pattern.len(), rule.allow))
+        .is_none_or(|rule| rule.allow)
+}
+
+impl Robots {
+    /// Fetch the origin's robots.txt, or `None` if the request couldn't be
+    /// made at all.
+    fn fetch(&self, target: &ssrf::Target) -> Option<File> {
+        limits::reserve_request().ok()?;
+        let Ok(backend) = backend::create(&target.hostname, target.port) else {
+            return Some(File::DisallowAll);
+        };
+        let sent = Request::get(format!(
+            "https://{}:{}/robots.txt",
+            target.hostname, target.port
+        ))
+        .with_header("Host", &target.hostname)
+        .with_header("User-Agent", &self.user_agent)
+        .with_pass(true)
+        .send(backend.name());
+        let Ok(mut resp) = sent else {
+            return Some(File::DisallowAll);
+        };
+        let status = resp.get_status();
+        Some(if status.is_success() {
+            let mut body = resp.take_body_bytes();
+            body.truncate(MAX_FILE_BYTES);
+            File::Rules(String::from_utf8_lossy(&body).into_owned())
+        } else if status.is_client_error() || status.is_redirection() {
+            File::AllowAll
+        } else {
+            File::DisallowAll
+        })
+    }
+
+    /// The origin's robots.txt, from the state store if it was fetched lately.
+    fn file(&self, target: &ssrf::Target) -> File {
+        let store = state::open();
+        let key = format!("robots.{}:{}", target.hostname, target.port);
+        if let Some(file) = store.as_ref().and_then(|store| state::get(store, &key)) {
+            return file;
+        }
+        let Some(file) = self.fetch(target) else {
+            return File::DisallowAll;
+        };
+        if let Some(store) = &store {
+            let ttl = match file {
+                File::DisallowAll => UNREACHABLE_TTL,
+                _ => Duration::from_secs(self.cache_secs),
+            };
+            state::put(store, &key, &file, Some(ttl));
+        }
+        file
+    }
+
+    /// Whether the origin's robots.txt allows the target to be fetched.
+    pub fn allows(&self, target: &ssrf::Target) -> bool {
+        match self.file(target) {
+            File::Rules(file) => allowed(
+                &rules_for(&file, &self.user_agent),
+                &url_rules::subject(&target.url),
+            ),
+            File::AllowAll => true,
+            File::DisallowAll => false,
+        }
+    }
+
+    /// Refuse the target if the origin's robots.txt disallows it.
+    pub fn check(&self, target: &ssrf::Target) -> Option<Response> {
+        if self.allows(target) {
+            return None;
+        }
+        Some(
+            Problem::new(
+                Code::RobotsDisallowed,
+                format!(
+                    "{}'s robots.txt doesn't allow {} to fetch this URL",
+                    target.hostname, self.user_agent
+                ),
+            )
+            .with("user_agent", self.user_agent.as_str())
+            .into_response(),
+        )
+    }
+}
@@ -0,0 +1,104 @@
+//! Per-route configuration.
+//!
+//! Routes are read from the optional `routes` entry of the `dynserv-config`
+//! Config Store as a JSON array. The first route whose host (and optional path
+//! prefix) matches the target URL applies to the request.
+
+use crate::cache::CachePolicy;
+use crate::compression::EdgeCompression;
+use crate::esi::EsiPolicy;
+use crate::headers::ForwardedMode;
+use crate::images::Images;
+use crate::redirect::RedirectPolicy;
+use crate::transform::Transform;
+use crate::watchdog::WatchdogPolicy;
+use fastly::config_store::ConfigStore;
+use serde::Deserialize;
+
+/// Name of the Config Store holding optional proxy configuration.
+pub const CONFIG_STORE: &str = "dynserv-config";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Route {
+    /// Destination host this route applies to. `*.example.com` matches any subdomain.
+    pub host: String,
+    /// Only apply the route when the target path starts with this prefix.
+    pub path_prefix: Option<String>,
+    /// Transforms applied to the outbound request body, in order.
+    pub request_transforms: Vec<Transform>,
+    /// Transforms applied to the origin response body, in order.
+    pub response_transforms: Vec<Transform>,
+    /// Fire a duplicate GET if the origin hasn't responded after this many milliseconds.
+    pub hedge_after_ms: Option<u64>,
+    /// How redirects from the origin are handled.
+    pub redirects: RedirectPolicy,
+    /// Cache GET responses at the edge instead of always passing to the origin.
+    pub cache: Option<CachePolicy>,
+    /// Add a diagnostic `hint` to fetch error responses.
+    pub diagnose_failures: bool,
+    /// Answer locally while the origin's 429/503 `Retry-After` window lasts.
+    pub shield_retry_after: bool,
+    /// Stream the response body through the edge, logging progress and stalls.
+    pub watchdog: Option<WatchdogPolicy>,
+    /// Which forwarding headers tell the origin about the client.
+    pub forwarded: ForwardedMode,
+    /// Point links in HTML responses back through the proxy.
+    pub rewrite_links: bool,
+    /// Point the URIs in HLS and DASH manifests back through the proxy.
+    pub rewrite_manifests: bool,
+    /// Compress uncompressed responses for clients that accept it.
+    pub compress: Option<EdgeCompression>,
+    /// Process ESI includes in HTML responses.
+    pub esi: Option<EsiPolicy>,
+    /// Resize and convert images through the Fastly Image Optimizer.
+    pub images: Option<Images>,
+}
+
+/// Whether `host` matches a host pattern, exact or `*.example.com`, ignoring case.
+pub fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => host
+            .to_ascii_lowercase()
+            .strip_suffix(&suffix.to_ascii_lowercase())
+            .is_some_and(|rest| rest.ends_with('.')),
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+impl Route {
+    fn matches(&self, host: &str, path: &str) -> bool {
+        let host_matches = host_matches(&self.host, host);
+        let path_matches = self
+            .path_prefix
+            .as_deref()
+            .is_none_or(|prefix| path.starts_with(prefix));
+        host_matches && path_matches
+    }
+
+    /// Whether the route rewrites response bodies.
+    pub fn transforms_responses(&self) -> bool {
+        !self.response_transforms.is_empty()
+            || self.esi.is_some()
+            || self.rewrite_links
+            || self.rewrite_manifests
+    }
+}
+
+/// Load the configured routes. A missing store or entry means no routes.
+pub fn load() -> Result<Vec<Route>, String> {
+    let Ok(store) = ConfigStore::try_open(CONFIG_STORE) else {
+        return Ok(Vec::new());
+    };
+    match store.get("routes") {
+        Some(json) => {
+            serde_json::from_str(&json).map_err(|e| format!("Invalid 'routes' entry: {}", e))
+        }
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Find the first route matching the target host and path.
+pub fn find<'a>(routes: &'a [Route], host: &str, path: &str) -> Option<&'a Route> {
+    routes.iter().find(|route| route.matches(host, path))
+}
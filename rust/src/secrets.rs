@@ -0,0 +1,19 @@
+//! Secrets kept in the `dynserv-secrets` Secret Store.
+
+use fastly::secret_store::{OpenError, SecretStore};
+
+/// Name of the Secret Store holding credentials.
+pub const SECRET_STORE: &str = "dynserv-secrets";
+
+pub fn open() -> Result<SecretStore, OpenError> {
+    SecretStore::open(SECRET_STORE)
+}
+
+/// Read a secret's plaintext by name.
+pub fn read(name: &str) -> Result<Vec<u8>, String> {
+    let store = open().map_err(|e| format!("Secret store unavailable: {}", e))?;
+    let secret = store
+        .get(name)
+        .ok_or_else(|| format!("Secret '{}' not found", name))?;
+    Ok(secret.plaintext().to_vec())
+}
@@ -0,0 +1,131 @@
+//! Signed session cookies for browsers.
+//!
+//! With the `session` provider enabled, a request authenticated by any other
+//! provider gets a short-lived cookie naming its tenant and the key ID of the
+//! credential it presented. Same-site requests that carry the cookie, such as
+//! page assets rewritten through the proxy, are then authorized without the
+//! key in their URL. The cookie is signed with HMAC-SHA256 using a secret
+//! from `dynserv-secrets`, and is never forwarded to origins.
+
+use crate::auth::{AuthError, AuthProvider, Identity};
+use crate::{access_log, secrets};
+use fastly::Request;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name of the provider that accepts session cookies.
+pub const PROVIDER: &str = "session";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Session {
+    /// Name of the secret the cookie is signed with.
+    pub secret: String,
+    #[serde(default = "default_ttl_secs")]
+    pub ttl_secs: u64,
+    #[serde(default = "default_cookie_name")]
+    pub cookie_name: String,
+}
+
+fn default_ttl_secs() -> u64 {
+    900
+}
+
+fn default_cookie_name() -> String {
+    "dynserv_session".to_string()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl Session {
+    fn mac(&self, payload: &str) -> Result<Hmac<Sha256>, AuthError> {
+        let key = secrets::read(&self.secret).map_err(AuthError::Config)?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(&key)
+            .map_err(|e| AuthError::Config(format!("Invalid session key: {}", e)))?;
+        mac.update(payload.as_bytes());
+        Ok(mac)
+    }
+
+    /// The session cookie's value on the request, if it has one.
+    fn cookie<'a>(&self, req: &'a Request) -> Option<&'a str> {
+        req.get_header_str("Cookie")?
+            .split(';')
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(name, _)| *name == self.cookie_name)
+            .map(|(_, value)| value)
+    }
+
+    /// A `Set-Cookie` value for a freshly authenticated request.
+    ///
+    /// Requests already authorized by a session don't get a new one, so a
+    /// session always ends [`Session::ttl_secs`] after its key was last used.
+    pub fn issue(&self, identity: &Identity, req: &Request) -> Option<String> {
+        if identity.provider == PROVIDER {
+            return None;
+        }
+        let key_id = access_log::key_id(req)?;
+        let payload = format!("{}.{}.{}", identity.tenant, key_id, now() + self.ttl_secs);
+        let signature = hex::encode(self.mac(&payload).ok()?.finalize().into_bytes());
+        Some(format!(
+            "{}={}.{}; Max-Age={}; Path=/; Secure; HttpOnly; SameSite=Strict",
+            self.cookie_name, payload, signature, self.ttl_secs
+        ))
+    }
+
+    /// The key ID a request's session was issued to, without verifying it.
+    pub fn key_id(&self, req: &Request) -> Option<String> {
+        let mut fields = self.cookie(req)?.rsplitn(4, '.');
+        fields.nth(2).map(str::to_string)
+    }
+
+    /// Remove the session cookie so it doesn't reach the origin.
+    pub fn strip(&self, req: &mut Request) {
+        let Some(cookies) = req.get_header_str("Cookie") else {
+            return;
+        };
+        let kept: Vec<&str> = cookies
+            .split(';')
+            .map(str::trim)
+            .filter(|pair| pair.split_once('=').map(|(name, _)| name) != Some(&self.cookie_name))
+            .collect();
+        if kept.is_empty() {
+            req.remove_header("Cookie");
+        } else {
+            let kept = kept.join("; ");
+            req.set_header("Cookie", kept);
+        }
+    }
+}
+
+impl AuthProvider for Session {
+    fn name(&self) -> &'static str {
+        PROVIDER
+    }
+
+    /// Cookies are `<tenant>.<key id>.<expiry>.<signature>`.
+    fn authenticate(&self, req: &Request) -> Result<String, AuthError> {
+        let value = self.cookie(req).ok_or(AuthError::NoCredentials)?;
+        let (payload, signature) = value.rsplit_once('.').ok_or(AuthError::Invalid)?;
+        let signature = hex::decode(signature).map_err(|_| AuthError::Invalid)?;
+        let mut fields = payload.rsplitn(3, '.');
+        let (Some(expires), Some(_key_id), Some(tenant)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(AuthError::Invalid);
+        };
+        let expires: u64 = expires.parse().map_err(|_| AuthError::Invalid)?;
+        if now() >= expires {
+            return Err(AuthError::Invalid);
+        }
+        self.mac(payload)?
+            .verify_slice(&signature)
+            .map_err(|_| AuthError::Invalid)?;
+        Ok(tenant.to_string())
+    }
+}
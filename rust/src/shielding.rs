@@ -0,0 +1,155 @@
+//! Two-tier shielding through a designated POP.
+//!
+//! With the `shielding` settings of the deployment's
+//! [`ProxyConfig`](crate::config::ProxyConfig) naming a shield `pop`, or a
+//! static `backend` that reaches the service again, every POP other than the
+//! shield hands its proxy requests to the shield as they arrived, and relays
+//! what it answers. Only the shield fetches from origins, so its edge cache
+//! serves the whole world's traffic and origins see far fewer connections.
+//!
+//! The hop carries the client's address in `X-Proxy-Shield`, signed with
+//! HMAC-SHA256 using a secret from `dynserv-secrets` and valid for a minute,
+//! so the shield's client checks see the real client rather than the POP.
+//! Requests with a valid signature are handled where they arrive. When the
+//! shield can't be reached, requests are handled at the edge instead.
+
+use crate::{limits, secrets};
+use fastly::shielding::Shield;
+use fastly::{Backend, Request, Response};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Header carrying the client's signed address from edge to shield.
+pub const SHIELD_HEADER: &str = "X-Proxy-Shield";
+
+/// How long a signed hop stays valid, in seconds.
+const HOP_TTL_SECS: u64 = 60;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Shield POP code, such as `pdx-or-us`.
+    pub pop: Option<String>,
+    /// Static backend to shield through instead of a POP, such as one pointing
+    /// at the service's own domain.
+    pub backend: Option<String>,
+    /// Name of the secret hops are signed with.
+    pub secret: String,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            pop: None,
+            backend: None,
+            secret: "shielding".to_string(),
+        }
+    }
+}
+
+/// The client address a verified hop carried, for this request.
+static CLIENT_IP: Mutex<Option<IpAddr>> = Mutex::new(None);
+
+/// Forget the previous request's hop.
+pub fn reset() {
+    if let Ok(mut client_ip) = CLIENT_IP.lock() {
+        *client_ip = None;
+    }
+}
+
+/// The client address an edge POP handed over with this request, if it came
+/// through a shield hop.
+pub fn forwarded_client() -> Option<IpAddr> {
+    CLIENT_IP.lock().ok().and_then(|client_ip| *client_ip)
+}
+
+/// The client's address: the one an edge POP handed over, or else the
+/// connection's own.
+pub fn client_ip(req: &Request) -> Option<IpAddr> {
+    forwarded_client().or_else(|| req.get_client_ip_addr())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl Settings {
+    fn mac(&self, payload: &str) -> Option<Hmac<Sha256>> {
+        let key = secrets::read(&self.secret).ok()?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(&key).ok()?;
+        mac.update(payload.as_bytes());
+        Some(mac)
+    }
+
+    /// Whether the request came from an edge POP with a valid hop, remembering
+    /// the client's address if so. Hops are `<client ip>,<expiry>,<signature>`.
+    fn arrived(&self, req: &Request) -> bool {
+        let Some(hop) = req.get_header_str(SHIELD_HEADER) else {
+            return false;
+        };
+        let mut fields = hop.split(',');
+        let (Some(ip), Some(expires), Some(signature), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return false;
+        };
+        let (Ok(ip), Ok(expires), Ok(signature)) = (
+            ip.parse::<IpAddr>(),
+            expires.parse::<u64>(),
+            hex::decode(signature),
+        ) else {
+            return false;
+        };
+        let payload = format!("{},{}", ip, expires);
+        let verified = now() < expires
+            && self
+                .mac(&payload)
+                .is_some_and(|mac| mac.verify_slice(&signature).is_ok());
+        if verified {
+            if let Ok(mut client_ip) = CLIENT_IP.lock() {
+                *client_ip = Some(ip);
+            }
+        }
+        verified
+    }
+
+    /// The backend to hand requests to, or `None` when this POP is the shield
+    /// or there isn't one.
+    fn shield(&self) -> Option<Backend> {
+        if let Some(pop) = &self.pop {
+            let shield = Shield::new(pop).ok()?;
+            if shield.running_on() {
+                return None;
+            }
+            return shield.encrypted_backend().ok();
+        }
+        Backend::from_name(self.backend.as_deref()?).ok()
+    }
+
+    /// Hand the request to the shield and return its response, unless it
+    /// should be handled here.
+    pub fn forward(&self, req: &mut Request) -> Option<Response> {
+        if self.pop.is_none() && self.backend.is_none() {
+            return None;
+        }
+        if self.arrived(req) {
+            return None;
+        }
+        let backend = self.shield()?;
+        let ip = req.get_client_ip_addr()?;
+        let payload = format!("{},{}", ip, now() + HOP_TTL_SECS);
+        let signature = hex::encode(self.mac(&payload)?.finalize().into_bytes());
+        limits::reserve_request().ok()?;
+        let mut hop = req.clone_with_body();
+        hop.set_header(SHIELD_HEADER, format!("{},{}", payload, signature));
+        hop.set_pass(true);
+        hop.send(backend).ok()
+    }
+}
@@ -0,0 +1,207 @@
+//! Signed proxy URLs.
+//!
+//! A backend service holding an API key can mint a short-lived link for its
+//! end users with `GET /sign?url=<target>&ttl=<secs>`, instead of handing
+//! out its key. The link names the tenant, the key ID of the credential that
+//! minted it and when it expires, and is signed with HMAC-SHA256 over its
+//! path and query using a secret from `dynserv-secrets`. With the
+//! `signed_url` provider enabled it's accepted in place of a key until then;
+//! changing or adding any parameter breaks the signature.
+
+use crate::auth::{self, AuthError, AuthProvider, Identity};
+use crate::errors::{Code, Problem};
+use crate::{access_log, secrets, ssrf};
+use fastly::http::StatusCode;
+use fastly::{Request, Response};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+use url::{form_urlencoded, Url};
+
+/// Name of the provider that accepts signed URLs.
+pub const PROVIDER: &str = "signed_url";
+
+/// The parameter carrying the signature, which isn't itself signed.
+const SIGNATURE_PARAM: &str = "sig";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SignedUrls {
+    /// Name of the secret links are signed with.
+    pub secret: String,
+    /// How long a link lasts when `/sign` isn't given a `ttl`.
+    #[serde(default = "default_ttl_secs")]
+    pub default_ttl_secs: u64,
+    #[serde(default = "default_max_ttl_secs")]
+    pub max_ttl_secs: u64,
+}
+
+fn default_ttl_secs() -> u64 {
+    300
+}
+
+fn default_max_ttl_secs() -> u64 {
+    86400
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn query_param(url: &Url, name: &str) -> Option<String> {
+    url.query_pairs()
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v.into_owned())
+}
+
+/// The path and query of a link as they were signed, without the signature.
+fn signed_input(url: &Url) -> String {
+    let query = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(url.query_pairs().filter(|(k, _)| k != SIGNATURE_PARAM))
+        .finish();
+    format!("{}?{}", url.path(), query)
+}
+
+/// The key ID of the credential that minted a request's link, without
+/// verifying it.
+pub fn key_id(req: &Request) -> Option<String> {
+    let url = req.get_url();
+    query_param(url, SIGNATURE_PARAM)?;
+    query_param(url, "key_id")
+}
+
+impl SignedUrls {
+    fn mac(&self, url: &Url) -> Result<Hmac<Sha256>, AuthError> {
+        let key = secrets::read(&self.secret).map_err(AuthError::Config)?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(&key)
+            .map_err(|e| AuthError::Config(format!("Invalid URL signing key: {}", e)))?;
+        mac.update(signed_input(url).as_bytes());
+        Ok(mac)
+    }
+
+    /// A link through the proxy at `base` that fetches `target` for the tenant.
+    fn sign(
+        &self,
+        base: &Url,
+        target: &Url,
+        tenant: &str,
+        key_id: Option<&str>,
+        expires: u64,
+    ) -> Result<Url, AuthError> {
+        let mut link = base.clone();
+        link.set_path("/");
+        link.set_fragment(None);
+        {
+            let mut query = link.query_pairs_mut();
+            query
+                .clear()
+                .append_pair("url", target.as_str())
+                .append_pair("tenant", tenant);
+            if let Some(key_id) = key_id {
+                query.append_pair("key_id", key_id);
+            }
+            query.append_pair("expires", &expires.to_string());
+        }
+        let signature = hex::encode(self.mac(&link)?.finalize().into_bytes());
+        link.query_pairs_mut()
+            .append_pair(SIGNATURE_PARAM, &signature);
+        Ok(link)
+    }
+}
+
+/// Answer `/sign` for an authenticated client.
+pub fn respond(req: &Request, identity: &Identity) -> Response {
+    let Some(signed_urls) = auth::signed_urls() else {
+        return Problem::new(
+            Code::EndpointDisabled,
+            "Signed URLs aren't enabled for this deployment",
+        )
+        .into_response();
+    };
+    // A link may not be used to mint longer-lived ones
+    if identity.provider == PROVIDER {
+        return Problem::new(
+            Code::InvalidCredentials,
+            "Signed URLs can't be used to sign others",
+        )
+        .into_response();
+    }
+    let client_url = req.get_url();
+    let Some(target) = query_param(client_url, "url") else {
+        return Problem::new(Code::MissingUrl, "")
+            .with(
+                "usage",
+                "Add ?url=https://example.com/path&ttl=300 to your request",
+            )
+            .into_response();
+    };
+    let target = match Url::parse(&target) {
+        Ok(target) => target,
+        Err(e) => return Problem::new(Code::InvalidUrl, e.to_string()).into_response(),
+    };
+    // Links are checked in full when they're used; this catches obvious mistakes now
+    let target = match ssrf::validate(target) {
+        Ok(target) => target.url,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let ttl = match query_param(client_url, "ttl").map(|ttl| ttl.parse::<u64>()) {
+        None => signed_urls.default_ttl_secs,
+        Some(Ok(ttl)) if (1..=signed_urls.max_ttl_secs).contains(&ttl) => ttl,
+        Some(_) => {
+            return Problem::new(
+                Code::InvalidParameter,
+                format!(
+                    "'ttl' must be between 1 and {} seconds",
+                    signed_urls.max_ttl_secs
+                ),
+            )
+            .with("parameter", "ttl")
+            .into_response();
+        }
+    };
+    let expires = now() + ttl;
+    let key_id = access_log::key_id(req);
+    match signed_urls.sign(
+        client_url,
+        &target,
+        &identity.tenant,
+        key_id.as_deref(),
+        expires,
+    ) {
+        Ok(link) => Response::from_status(StatusCode::OK)
+            .with_header("Content-Type", "application/json")
+            .with_header("Cache-Control", "no-store")
+            .with_body(
+                serde_json::json!({"url": link.as_str(), "expires_at": expires}).to_string(),
+            ),
+        Err(e) => e.into_response(),
+    }
+}
+
+impl AuthProvider for SignedUrls {
+    fn name(&self) -> &'static str {
+        PROVIDER
+    }
+
+    /// Links carry `tenant`, `expires` and `sig`, the hex HMAC-SHA256 of the
+    /// path and the rest of the query.
+    fn authenticate(&self, req: &Request) -> Result<String, AuthError> {
+        let url = req.get_url();
+        let signature = query_param(url, SIGNATURE_PARAM).ok_or(AuthError::NoCredentials)?;
+        let signature = hex::decode(signature).map_err(|_| AuthError::Invalid)?;
+        let tenant = query_param(url, "tenant").ok_or(AuthError::Invalid)?;
+        let expires: u64 = query_param(url, "expires")
+            .and_then(|expires| expires.parse().ok())
+            .ok_or(AuthError::Invalid)?;
+        if now() >= expires {
+            return Err(AuthError::Invalid);
+        }
+        self.mac(url)?
+            .verify_slice(&signature)
+            .map_err(|_| AuthError::Invalid)?;
+        Ok(tenant)
+    }
+}
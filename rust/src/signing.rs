@@ -0,0 +1,282 @@
+//! Named request signing profiles.
+//!
+//! Profiles live in the `signing_profiles` entry of `dynserv-config` as a JSON
+//! object keyed by profile name. Secrets referenced by a profile are read from
+//! the `dynserv-secrets` Secret Store, so key material never sits in config.
+//!
+//! Besides the proxy's own requests, a tenant's requests to matching origins
+//! can be signed too, such as with AWS Signature Version 4 for private S3
+//! buckets and API Gateway endpoints.
+
+use crate::{routes, secrets, trailers};
+use fastly::config_store::ConfigStore;
+use fastly::Request;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Hash S3 accepts in place of the payload's, so bodies needn't be buffered.
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SigningProfile {
+    /// HMAC-SHA256 over the request body, hex-encoded into `header`.
+    HmacSha256 {
+        /// Name of the secret holding the HMAC key.
+        secret: String,
+        #[serde(default = "default_signature_header")]
+        header: String,
+    },
+    /// AWS Signature Version 4, sent in `Authorization`.
+    AwsSigv4 {
+        /// Name of the secret holding the access key ID.
+        access_key_id: String,
+        /// Name of the secret holding the secret access key.
+        secret_access_key: String,
+        /// Name of the secret holding a session token, for temporary credentials.
+        #[serde(default)]
+        session_token: Option<String>,
+        region: String,
+        /// Service name, such as `s3` or `execute-api`.
+        service: String,
+    },
+}
+
+/// Sign a tenant's requests to matching origins with a profile.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SignedOrigin {
+    /// Destination host, exact or `*.example.com`.
+    pub host: String,
+    pub profile: String,
+}
+
+/// The profile for requests to `host`, if one of the entries matches.
+fn profile_for<'a>(signed_origins: &'a [SignedOrigin], host: &str) -> Option<&'a str> {
+    signed_origins
+        .iter()
+        .find(|origin| routes::host_matches(&origin.host, host))
+        .map(|origin| origin.profile.as_str())
+}
+
+/// Sign a tenant's request to `host` with the first matching entry's profile,
+/// if any. Call once the request is otherwise final.
+pub fn sign_for(
+    req: &mut Request,
+    signed_origins: &[SignedOrigin],
+    host: &str,
+) -> Result<(), String> {
+    let Some(name) = profile_for(signed_origins, host) else {
+        return Ok(());
+    };
+    let profile = load(name)?;
+    if !profile.needs_body() {
+        return sign(req, &[], &profile);
+    }
+    trailers::with_body(req, |req, body| sign(req, body, &profile))
+}
+
+fn default_signature_header() -> String {
+    "X-Signature-SHA256".to_string()
+}
+
+/// Look up a signing profile by name.
+pub fn load(name: &str) -> Result<SigningProfile, String> {
+    let profiles = ConfigStore::try_open(routes::CONFIG_STORE)
+        .ok()
+        .and_then(|store| store.get("signing_profiles"))
+        .ok_or_else(|| "No 'signing_profiles' entry configured".to_string())?;
+    let mut profiles: HashMap<String, SigningProfile> = serde_json::from_str(&profiles)
+        .map_err(|e| format!("Invalid 'signing_profiles' entry: {}", e))?;
+    profiles
+        .remove(name)
+        .ok_or_else(|| format!("Unknown signing profile '{}'", name))
+}
+
+impl SigningProfile {
+    /// Whether [`sign`] needs the request body, which must then be buffered.
+    pub fn needs_body(&self) -> bool {
+        match self {
+            SigningProfile::HmacSha256 { .. } => true,
+            SigningProfile::AwsSigv4 { service, .. } => service != "s3",
+        }
+    }
+}
+
+/// Sign an outbound request whose body is `body`.
+///
+/// For profiles that don't [need the body](SigningProfile::needs_body), `body` is ignored.
+pub fn sign(req: &mut Request, body: &[u8], profile: &SigningProfile) -> Result<(), String> {
+    match profile {
+        SigningProfile::HmacSha256 { secret, header } => {
+            let key = secrets::read(secret)?;
+            let mut mac = Hmac::<Sha256>::new_from_slice(&key)
+                .map_err(|e| format!("Invalid HMAC key: {}", e))?;
+            mac.update(body);
+            req.set_header(header, hex::encode(mac.finalize().into_bytes()));
+            Ok(())
+        }
+        SigningProfile::AwsSigv4 {
+            access_key_id,
+            secret_access_key,
+            session_token,
+            region,
+            service,
+        } => {
+            let text = |name: &str| {
+                secrets::read(name).and_then(|secret| {
+                    String::from_utf8(secret).map_err(|_| format!("Secret '{}' isn't text", name))
+                })
+            };
+            let credentials = Credentials {
+                access_key_id: text(access_key_id)?,
+                secret_access_key: text(secret_access_key)?,
+                session_token: session_token.as_deref().map(text).transpose()?,
+            };
+            let payload_hash = if profile.needs_body() {
+                hex::encode(Sha256::digest(body))
+            } else {
+                UNSIGNED_PAYLOAD.to_string()
+            };
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            sigv4(req, &credentials, region, service, &payload_hash, now);
+            Ok(())
+        }
+    }
+}
+
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encode everything but the characters SigV4 leaves unreserved.
+fn aws_encode(value: &str, keep_slash: bool) -> String {
+    let mut out = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(byte as char)
+            }
+            b'/' if keep_slash => out.push('/'),
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+/// `YYYYMMDD'T'HHMMSS'Z'` for a Unix timestamp.
+fn amz_date(unix_secs: u64) -> String {
+    let days = (unix_secs / 86_400) as i64;
+    let secs = unix_secs % 86_400;
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Add the SigV4 `Authorization` header and the `x-amz-*` headers it covers.
+fn sigv4(
+    req: &mut Request,
+    credentials: &Credentials,
+    region: &str,
+    service: &str,
+    payload_hash: &str,
+    now: u64,
+) {
+    let amz_date = amz_date(now);
+    let date = &amz_date[..8];
+    let url = req.get_url().clone();
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string(),
+    };
+
+    req.set_header("x-amz-date", &amz_date);
+    req.set_header("x-amz-content-sha256", payload_hash);
+    let mut signed: Vec<(String, String)> = vec![
+        ("host".to_string(), host),
+        ("x-amz-content-sha256".to_string(), payload_hash.to_string()),
+        ("x-amz-date".to_string(), amz_date.clone()),
+    ];
+    if let Some(token) = &credentials.session_token {
+        req.set_header("x-amz-security-token", token);
+        signed.push(("x-amz-security-token".to_string(), token.clone()));
+    }
+    let signed_headers: Vec<&str> = signed.iter().map(|(name, _)| name.as_str()).collect();
+    let signed_headers = signed_headers.join(";");
+    let canonical_headers: String = signed
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+
+    // S3 takes the path as sent; other services want each segment encoded again
+    let path = if service == "s3" {
+        url.path().to_string()
+    } else {
+        aws_encode(url.path(), true)
+    };
+    let mut query: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(k, v)| (aws_encode(&k, false), aws_encode(&v, false)))
+        .collect();
+    query.sort();
+    let query: Vec<String> = query.into_iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+
+    let canonical_request = [
+        req.get_method_str(),
+        &path,
+        &query.join("&"),
+        &canonical_headers,
+        &signed_headers,
+        payload_hash,
+    ]
+    .join("\n");
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+    let key = format!("AWS4{}", credentials.secret_access_key);
+    let key = hmac(key.as_bytes(), date);
+    let key = hmac(&key, region);
+    let key = hmac(&key, service);
+    let key = hmac(&key, "aws4_request");
+    let signature = hex::encode(hmac(&key, &string_to_sign));
+    req.set_header(
+        "Authorization",
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            credentials.access_key_id, scope, signed_headers, signature
+        ),
+    );
+}
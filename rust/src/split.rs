@@ -0,0 +1,119 @@
+//! A/B traffic splitting between two origins.
+//!
+//! A tenant's `split` names two origins, `a` and `b`, and the share of
+//! clients sent to `b`. Targets on either origin have their origin replaced
+//! by the client's, keeping the path and query, so the same URL reaches one
+//! origin or the other. Clients are bucketed by hashing the value of the
+//! split's `cookie`, when it's set and the request carries it, or else their
+//! IP address, so each keeps getting the same origin, or by the tenant's
+//! [`affinity`](crate::affinity) cookie if it has one. The origin request and
+//! the response carry the bucket in `X-Proxy-Variant` for analytics to
+//! segment by.
+
+use crate::shielding;
+use fastly::Request;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use url::{Origin, Url};
+
+/// Header naming the client's bucket, `a` or `b`.
+pub const VARIANT_HEADER: &str = "X-Proxy-Variant";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Split {
+    /// The origin clients get unless they're in `b`'s share, such as
+    /// `https://a.example.com`.
+    pub a: String,
+    /// The origin the `b_percent` share of clients gets.
+    pub b: String,
+    /// Share of clients sent to `b`, from 0 to 100.
+    pub b_percent: f64,
+    /// Cookie whose value buckets clients, instead of their address.
+    #[serde(default)]
+    pub cookie: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    A,
+    B,
+}
+
+impl Variant {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Variant::A => "a",
+            Variant::B => "b",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "a" => Some(Variant::A),
+            "b" => Some(Variant::B),
+            _ => None,
+        }
+    }
+}
+
+fn origin_of(url: &str) -> Option<Origin> {
+    Url::parse(url).ok().map(|url| url.origin())
+}
+
+impl Split {
+    /// What the client is bucketed by: the split's cookie, or its address.
+    fn client_key(&self, req: &Request) -> Option<String> {
+        let cookie = self.cookie.as_deref().and_then(|name| {
+            req.get_header_str("Cookie")?
+                .split(';')
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(cookie, _)| *cookie == name)
+                .map(|(_, value)| value.to_string())
+        });
+        cookie.or_else(|| shielding::client_ip(req).map(|ip| ip.to_string()))
+    }
+
+    /// The client's bucket. Clients that can't be told apart get `a`.
+    fn variant(&self, req: &Request) -> Variant {
+        let Some(key) = self.client_key(req) else {
+            return Variant::A;
+        };
+        let digest = Sha256::digest(key.as_bytes());
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&digest[..8]);
+        let roll = (u64::from_be_bytes(bytes) % 10_000) as f64 / 100.0;
+        if roll < self.b_percent {
+            Variant::B
+        } else {
+            Variant::A
+        }
+    }
+
+    /// Point a target on either origin at the client's, returning its bucket,
+    /// or leave the target alone if it's on neither. A `sticky` bucket the
+    /// client was given before is kept.
+    pub fn apply(
+        &self,
+        req: &Request,
+        target: &mut Url,
+        sticky: Option<Variant>,
+    ) -> Option<Variant> {
+        let (Some(a), Some(b)) = (origin_of(&self.a), origin_of(&self.b)) else {
+            return None;
+        };
+        let origin = target.origin();
+        if origin != a && origin != b {
+            return None;
+        }
+        let variant = sticky.unwrap_or_else(|| self.variant(req));
+        let chosen = match variant {
+            Variant::A => &self.a,
+            Variant::B => &self.b,
+        };
+        let mut url = Url::parse(chosen).ok()?;
+        url.set_path(target.path());
+        url.set_query(target.query());
+        *target = url;
+        Some(variant)
+    }
+}
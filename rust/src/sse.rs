@@ -0,0 +1,69 @@
+//! Server-Sent Events.
+//!
+//! Event streams stay open for as long as the origin keeps sending, often
+//! with long quiet spells between events. Requests that accept
+//! `text/event-stream` get a more patient between-bytes timeout, and
+//! responses that are event streams are passed through as they arrive:
+//! never cached, mirrored for comparison, rewritten or compressed, any of
+//! which would buffer the stream.
+
+use crate::timeouts::Timeouts;
+use fastly::{Request, Response};
+use serde::Deserialize;
+use std::time::Duration;
+
+const EVENT_STREAM: &str = "text/event-stream";
+
+/// A tenant's settings for event streams.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct EventStreams {
+    /// Between-bytes timeout for requests accepting event streams, unless
+    /// the request sets `bbto`.
+    pub between_bytes_secs: u64,
+}
+
+impl Default for EventStreams {
+    fn default() -> Self {
+        Self {
+            between_bytes_secs: 300,
+        }
+    }
+}
+
+impl EventStreams {
+    /// The default timeouts for a request, relaxed if it accepts an event stream.
+    pub fn timeouts_for(&self, req: &Request) -> Timeouts {
+        let defaults = Timeouts::default();
+        if !accepts(req) {
+            return defaults;
+        }
+        Timeouts {
+            between_bytes: Duration::from_secs(self.between_bytes_secs),
+            ..defaults
+        }
+    }
+}
+
+fn is_event_stream_type(content_type: &str) -> bool {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .eq_ignore_ascii_case(EVENT_STREAM)
+}
+
+/// Whether the client accepts an event stream.
+pub fn accepts(req: &Request) -> bool {
+    req.get_header_all_str("Accept")
+        .iter()
+        .flat_map(|accept| accept.split(','))
+        .any(is_event_stream_type)
+}
+
+/// Whether a response is an event stream.
+pub fn is_event_stream(resp: &Response) -> bool {
+    resp.get_header_str("Content-Type")
+        .is_some_and(is_event_stream_type)
+}
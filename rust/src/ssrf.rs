@@ -0,0 +1,139 @@
+//! Destination validation.
+//!
+//! Every URL the proxy is about to connect to — the client's target, redirect
+//! hops, fallbacks and any other derived destination — goes through
+//! [`validate`].
+//!
+//! Compute has no DNS lookup API, so only IP literals and well-known local
+//! hostnames can be refused here; names that resolve to private addresses are
+//! not detected. A deployment that sets `allowed_hosts` in its
+//! [`ProxyConfig`](crate::config::ProxyConfig) refuses every other host too.
+
+use crate::config;
+use crate::errors::{Code, Problem};
+use fastly::Response;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use url::{Host, Url};
+
+/// A destination that passed validation.
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub url: Url,
+    pub hostname: String,
+    pub port: u16,
+}
+
+/// Why a destination was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    NotHttps,
+    MissingHost,
+    PrivateAddress,
+    /// Not on the deployment's allowlist.
+    NotAllowed,
+}
+
+impl Rejection {
+    pub fn into_response(self) -> Response {
+        let problem = match self {
+            Rejection::NotHttps => Problem::new(Code::HttpsRequired, "")
+                .with("usage", "Use https:// URLs (e.g., ?url=https://example.com/path)"),
+            Rejection::MissingHost => Problem::new(Code::MissingHost, "The URL has no hostname"),
+            Rejection::PrivateAddress => Problem::new(
+                Code::SsrfBlocked,
+                "Target is a local, private or reserved address",
+            ),
+            Rejection::NotAllowed => Problem::new(
+                Code::HostNotAllowed,
+                "Target host is not on the proxy's allowlist",
+            ),
+        };
+        problem.into_response()
+    }
+}
+
+pub fn validate(url: Url) -> Result<Target, Rejection> {
+    // Only allow https protocol (TLS backends only)
+    if url.scheme() != "https" {
+        return Err(Rejection::NotHttps);
+    }
+
+    let hostname = match url.host_str() {
+        Some(h) => h.to_string(),
+        None => return Err(Rejection::MissingHost),
+    };
+    let private = match url.host() {
+        Some(Host::Ipv4(ip)) => is_private_v4(ip),
+        Some(Host::Ipv6(ip)) => is_private_v6(ip),
+        Some(Host::Domain(domain)) => is_local_name(domain),
+        None => false,
+    };
+    if private {
+        return Err(Rejection::PrivateAddress);
+    }
+    if !config::current().allows_host(&hostname) {
+        return Err(Rejection::NotAllowed);
+    }
+    let port = url.port().unwrap_or(443);
+
+    Ok(Target {
+        url,
+        hostname,
+        port,
+    })
+}
+
+fn is_local_name(domain: &str) -> bool {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    domain == "localhost" || domain.ends_with(".localhost")
+}
+
+pub fn is_private_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // Shared address space (carrier-grade NAT)
+        || (a == 100 && (64..128).contains(&b))
+        // Benchmarking and reserved ranges
+        || (a == 198 && (b == 18 || b == 19))
+        || a >= 240
+}
+
+/// The IPv4 address an IPv6 address reaches, for the ranges that embed one:
+/// IPv4-mapped (`::ffff:0:0/96`), IPv4-compatible (`::/96`), NAT64
+/// (`64:ff9b::/96`) and 6to4 (`2002::/16`).
+fn embedded_v4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let low = Ipv4Addr::from((u32::from(segments[6]) << 16) | u32::from(segments[7]));
+    match segments {
+        [0, 0, 0, 0, 0, 0xffff, _, _] => Some(low),
+        // :: and ::1 are checked as IPv6
+        [0, 0, 0, 0, 0, 0, 0, 0 | 1] => None,
+        [0, 0, 0, 0, 0, 0, _, _] => Some(low),
+        [0x64, 0xff9b, 0, 0, 0, 0, _, _] => Some(low),
+        [0x2002, hi, lo, ..] => Some(Ipv4Addr::from((u32::from(hi) << 16) | u32::from(lo))),
+        _ => None,
+    }
+}
+
+pub fn is_private_v6(ip: Ipv6Addr) -> bool {
+    if let Some(v4) = embedded_v4(ip) {
+        return is_private_v4(v4);
+    }
+    let segments = ip.segments();
+    let first = segments[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // Unique local (fc00::/7) and link-local (fe80::/10)
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80
+        // Documentation (2001:db8::/32)
+        || (first == 0x2001 && segments[1] == 0x0db8)
+}
@@ -0,0 +1,42 @@
+//! Shared state kept in the `dynserv-state` KV Store.
+//!
+//! The store is optional: features that need it fall back to stateless
+//! behaviour when it isn't linked to the service.
+
+use fastly::kv_store::KVStore;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::time::Duration;
+
+/// Name of the KV Store holding cross-request state.
+pub const STATE_STORE: &str = "dynserv-state";
+
+/// Open the state store, if it's linked.
+pub fn open() -> Option<KVStore> {
+    KVStore::open(STATE_STORE).ok().flatten()
+}
+
+/// A name escaped to be one `.`-separated segment of a key, so names that
+/// contain dots can't be mistaken for others followed by more segments.
+pub fn segment(name: &str) -> String {
+    name.replace('%', "%25").replace('.', "%2E")
+}
+
+/// Read a JSON value, treating missing or malformed entries as absent.
+pub fn get<T: DeserializeOwned>(store: &KVStore, key: &str) -> Option<T> {
+    let mut entry = store.lookup(key).ok()?;
+    serde_json::from_slice(&entry.take_body_bytes()).ok()
+}
+
+/// Write a JSON value, optionally expiring it after `ttl`.
+pub fn put<T: Serialize>(store: &KVStore, key: &str, value: &T, ttl: Option<Duration>) -> bool {
+    let Ok(body) = serde_json::to_vec(value) else {
+        return false;
+    };
+    let insert = store.build_insert();
+    let insert = match ttl {
+        Some(ttl) => insert.time_to_live(ttl),
+        None => insert,
+    };
+    insert.execute(key, body).is_ok()
+}
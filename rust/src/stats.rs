@@ -0,0 +1,406 @@
+//! Usage metrics per tenant and origin.
+//!
+//! Counters are kept in the state store in [`BUCKET`]-sized time buckets,
+//! under `stats.<tenant>.tenant.<bucket>` and
+//! `stats.<tenant>.origin.<host>.<bucket>`, with the tenant escaped by
+//! [`state::segment`]. The current request's [`Sample`] is built up as it's
+//! handled and written by [`record`] once the response has been sent, so
+//! metrics never delay the client.
+//!
+//! Updates are read-modify-write and concurrent requests can lose increments;
+//! the numbers are for trends, not billing. `/stats` also reports each
+//! origin's flushed [latency] histograms.
+
+use crate::errors::{Code, Problem};
+use crate::fingerprint::Fingerprint;
+use crate::output::{self, Format};
+use crate::{latency, ssrf, state};
+use fastly::http::request::SendError;
+use fastly::http::StatusCode;
+use fastly::kv_store::KVStore;
+use fastly::{Request, Response};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const BUCKET: Duration = Duration::from_secs(900);
+const HOUR: u64 = 3600;
+const DAY: u64 = 24 * 3600;
+
+/// Buckets are kept a little longer than the longest window reported.
+const BUCKET_TTL: Duration = Duration::from_secs(DAY + 3600);
+
+/// Keys read per list page when aggregating.
+const LIST_PAGE: u32 = 1000;
+
+/// Prefix of the recent error samples, which are keyed newest first.
+const ERROR_SAMPLE_PREFIX: &str = "errors.";
+
+/// Error samples are kept for a day.
+const ERROR_SAMPLE_TTL: Duration = Duration::from_secs(DAY);
+
+/// Upper bounds, in seconds, of the latency histogram buckets.
+pub const LATENCY_BOUNDS: [f64; 8] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Counters {
+    pub requests: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Responses by status class: `2xx`, `4xx`, `5xx`, ...
+    pub status: BTreeMap<String, u64>,
+    /// Failures by type, e.g. `ConnectionTimeout` or `backend_creation`.
+    pub errors: BTreeMap<String, u64>,
+    /// Requests per latency bucket: one per [`LATENCY_BOUNDS`] entry, then the overflow.
+    pub latency: Vec<u64>,
+    pub latency_ms_total: u64,
+}
+
+impl Counters {
+    fn add(&mut self, other: &Counters) {
+        self.requests += other.requests;
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        for (class, count) in &other.status {
+            *self.status.entry(class.clone()).or_default() += count;
+        }
+        for (kind, count) in &other.errors {
+            *self.errors.entry(kind.clone()).or_default() += count;
+        }
+        if self.latency.len() < other.latency.len() {
+            self.latency.resize(other.latency.len(), 0);
+        }
+        for (total, count) in self.latency.iter_mut().zip(&other.latency) {
+            *total += count;
+        }
+        self.latency_ms_total += other.latency_ms_total;
+    }
+}
+
+/// What's known about the request being handled.
+#[derive(Default)]
+pub struct Sample {
+    pub tenant: Option<String>,
+    pub origin: Option<String>,
+    pub started: Option<Instant>,
+    pub bytes_in: u64,
+    /// Time spent waiting for the origin's response headers.
+    pub origin_latency: Option<Duration>,
+    /// Why the request failed or was refused.
+    pub error: Option<String>,
+    /// Whether the response came from the edge cache.
+    pub cache_hit: bool,
+    /// The client connection's TLS fingerprints.
+    pub fingerprint: Fingerprint,
+}
+
+static CURRENT: Mutex<Sample> = Mutex::new(Sample {
+    tenant: None,
+    origin: None,
+    started: None,
+    bytes_in: 0,
+    origin_latency: None,
+    error: None,
+    cache_hit: false,
+    fingerprint: Fingerprint::NONE,
+});
+
+fn with_sample(f: impl FnOnce(&mut Sample)) {
+    if let Ok(mut sample) = CURRENT.lock() {
+        f(&mut sample);
+    }
+}
+
+fn content_length(value: Option<&str>) -> u64 {
+    value.and_then(|v| v.trim().parse().ok()).unwrap_or(0)
+}
+
+/// Start a sample for the client request.
+pub fn begin(req: &Request) {
+    let bytes_in = content_length(req.get_header_str("Content-Length"));
+    let fingerprint = Fingerprint::of(req);
+    with_sample(|sample| {
+        sample.started = Some(Instant::now());
+        sample.bytes_in = bytes_in;
+        sample.fingerprint = fingerprint;
+    });
+}
+
+pub fn set_tenant(tenant: &str) {
+    with_sample(|sample| sample.tenant = Some(tenant.to_string()));
+}
+
+pub fn set_origin(host: &str) {
+    with_sample(|sample| sample.origin = Some(host.to_string()));
+}
+
+pub fn set_origin_latency(latency: Duration) {
+    with_sample(|sample| sample.origin_latency = Some(latency));
+}
+
+pub fn note_cache_hit() {
+    with_sample(|sample| sample.cache_hit = true);
+}
+
+pub fn note_error(kind: &str) {
+    with_sample(|sample| sample.error = Some(kind.to_string()));
+}
+
+/// Record why a destination was refused.
+pub fn note_rejection(rejection: ssrf::Rejection) {
+    note_error(match rejection {
+        ssrf::Rejection::PrivateAddress | ssrf::Rejection::NotAllowed => "destination_rejected",
+        ssrf::Rejection::NotHttps | ssrf::Rejection::MissingHost => "invalid_url",
+    });
+}
+
+/// A short name for a fetch error's root cause, such as `ConnectionTimeout`.
+pub fn error_kind(err: &SendError) -> String {
+    let cause = format!("{:?}", err.root_cause());
+    cause
+        .split(|c: char| !c.is_alphanumeric())
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// The response facts needed by [`record`], taken before it's sent.
+pub struct Outcome {
+    pub status: StatusCode,
+    pub bytes_out: u64,
+    /// Time from receiving the request until the response was ready to send.
+    pub latency: Duration,
+}
+
+impl Outcome {
+    pub fn of(resp: &Response) -> Self {
+        let started = CURRENT.lock().ok().and_then(|sample| sample.started);
+        Self {
+            status: resp.get_status(),
+            bytes_out: content_length(resp.get_header_str("Content-Length")),
+            latency: started.map(|started| started.elapsed()).unwrap_or_default(),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Take the finished request's sample.
+pub fn take() -> Sample {
+    CURRENT
+        .lock()
+        .map(|mut sample| std::mem::take(&mut *sample))
+        .unwrap_or_default()
+}
+
+/// Add the finished request to its tenant's and origin's current buckets.
+pub fn record(sample: &Sample, outcome: &Outcome) {
+    let Some(tenant) = &sample.tenant else {
+        return;
+    };
+    let Some(store) = state::open() else {
+        return;
+    };
+
+    let mut delta = Counters {
+        requests: 1,
+        bytes_in: sample.bytes_in,
+        bytes_out: outcome.bytes_out,
+        ..Counters::default()
+    };
+    delta
+        .status
+        .insert(format!("{}xx", outcome.status.as_u16() / 100), 1);
+    if let Some(kind) = &sample.error {
+        delta.errors.insert(kind.clone(), 1);
+    }
+    let seconds = outcome.latency.as_secs_f64();
+    let bucket = LATENCY_BOUNDS
+        .iter()
+        .position(|bound| seconds <= *bound)
+        .unwrap_or(LATENCY_BOUNDS.len());
+    delta.latency = vec![0; LATENCY_BOUNDS.len() + 1];
+    delta.latency[bucket] = 1;
+    delta.latency_ms_total = outcome.latency.as_millis() as u64;
+
+    let bucket = now() / BUCKET.as_secs() * BUCKET.as_secs();
+    let tenant = state::segment(tenant);
+    let mut keys = vec![format!("stats.{}.tenant.{}", tenant, bucket)];
+    if let Some(origin) = &sample.origin {
+        keys.push(format!("stats.{}.origin.{}.{}", tenant, origin, bucket));
+    }
+    for key in keys {
+        let mut counters = state::get::<Counters>(&store, &key).unwrap_or_default();
+        counters.add(&delta);
+        state::put(&store, &key, &counters, Some(BUCKET_TTL));
+    }
+}
+
+/// A request that failed or was refused, kept for `/admin/errors`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorSample {
+    pub timestamp: u64,
+    pub request_id: String,
+    pub tenant: Option<String>,
+    pub origin: Option<String>,
+    pub error: String,
+    pub status: u16,
+}
+
+/// Keep a sample of the finished request if it failed.
+pub fn record_error(request_id: &str, sample: &Sample, outcome: &Outcome) {
+    let Some(error) = &sample.error else {
+        return;
+    };
+    let Some(store) = state::open() else {
+        return;
+    };
+    let timestamp = now();
+    let error = ErrorSample {
+        timestamp,
+        request_id: request_id.to_string(),
+        tenant: sample.tenant.clone(),
+        origin: sample.origin.clone(),
+        error: error.clone(),
+        status: outcome.status.as_u16(),
+    };
+    // Counting down from a far-off time lists the newest samples first
+    let key = format!(
+        "{}{:012}.{}",
+        ERROR_SAMPLE_PREFIX,
+        u64::from(u32::MAX).saturating_sub(timestamp),
+        request_id
+    );
+    state::put(&store, &key, &error, Some(ERROR_SAMPLE_TTL));
+}
+
+/// The most recent error samples, newest first.
+pub fn recent_errors(store: &KVStore, limit: u32) -> Vec<ErrorSample> {
+    let Ok(page) = store
+        .build_list()
+        .prefix(ERROR_SAMPLE_PREFIX)
+        .limit(limit)
+        .execute()
+    else {
+        return Vec::new();
+    };
+    page.into_keys()
+        .iter()
+        .filter_map(|key| state::get::<ErrorSample>(store, key))
+        .collect()
+}
+
+#[derive(Default, Serialize)]
+pub struct Windows {
+    pub hour: Counters,
+    pub day: Counters,
+}
+
+/// Every key in the store under `prefix`, across list pages.
+pub fn list_keys(store: &KVStore, prefix: &str) -> Vec<String> {
+    let mut keys = Vec::new();
+    let mut cursor: Option<String> = None;
+    loop {
+        let list = store.build_list().prefix(prefix).limit(LIST_PAGE);
+        let list = match &cursor {
+            Some(cursor) => list.cursor(cursor),
+            None => list,
+        };
+        let Ok(page) = list.execute() else {
+            break;
+        };
+        cursor = page.next_cursor();
+        keys.extend(page.into_keys());
+        if cursor.is_none() {
+            break;
+        }
+    }
+    keys
+}
+
+/// Totals for a tenant and each of its origins over the last hour and day.
+pub fn aggregate(store: &KVStore, tenant: &str) -> (Windows, BTreeMap<String, Windows>) {
+    let now = now();
+    let mut totals = Windows::default();
+    let mut origins: BTreeMap<String, Windows> = BTreeMap::new();
+    let prefix = format!("stats.{}.", state::segment(tenant));
+    for key in list_keys(store, &prefix) {
+        let Some((scope, bucket)) = key[prefix.len()..].rsplit_once('.') else {
+            continue;
+        };
+        let Ok(bucket) = bucket.parse::<u64>() else {
+            continue;
+        };
+        let age = now.saturating_sub(bucket);
+        if age >= DAY {
+            continue;
+        }
+        let windows = match scope.strip_prefix("origin.") {
+            Some(origin) => origins.entry(origin.to_string()).or_default(),
+            None if scope == "tenant" => &mut totals,
+            None => continue,
+        };
+        let Some(counters) = state::get::<Counters>(store, &key) else {
+            continue;
+        };
+        windows.day.add(&counters);
+        if age < HOUR {
+            windows.hour.add(&counters);
+        }
+    }
+    (totals, origins)
+}
+
+/// The response when stats are requested without the state store.
+pub fn unavailable() -> Response {
+    Problem::new(Code::StateUnavailable, "KV store 'dynserv-state' is not linked").into_response()
+}
+
+/// `/stats`: the tenant's aggregated counters for the last hour and day.
+///
+/// As NDJSON, each origin is a line of its own, followed by the tenant totals.
+pub fn respond(tenant: &str, format: Format) -> Response {
+    let Some(store) = state::open() else {
+        return unavailable();
+    };
+    let (totals, origins) = aggregate(&store, tenant);
+    let mut latency = latency::aggregate(&store, tenant);
+
+    if format == Format::Ndjson {
+        let lines = origins
+            .into_iter()
+            .map(|(origin, windows)| {
+                let latency = latency.remove(&origin).unwrap_or_default();
+                serde_json::json!({
+                    "tenant": tenant,
+                    "origin": origin,
+                    "stats": windows,
+                    "latency": latency,
+                })
+            })
+            .chain(std::iter::once(
+                serde_json::json!({"tenant": tenant, "origin": null, "stats": totals}),
+            ));
+        return output::ndjson_response(lines);
+    }
+
+    let body = serde_json::json!({
+        "tenant": tenant,
+        "bucket_secs": BUCKET.as_secs(),
+        "totals": totals,
+        "origins": origins,
+        "latency_bounds_secs": LATENCY_BOUNDS,
+        "latency": latency,
+    });
+    Response::from_status(StatusCode::OK)
+        .with_header("Content-Type", "application/json")
+        .with_header("Cache-Control", "no-store")
+        .with_body(serde_json::to_string_pretty(&body).unwrap_or_default())
+}
@@ -0,0 +1,251 @@
+//! OpenTelemetry span export.
+//!
+//! Each sampled request records a server span for the whole request and
+//! child spans for its phases (validation, backend creation, origin fetch and
+//! sending the response). Once the response has been sent they're exported as
+//! one OTLP/JSON `ExportTraceServiceRequest`, to a real-time log endpoint or
+//! POSTed to a collector, as configured by the `otel` entry of
+//! `dynserv-config`. Without that entry nothing is recorded.
+
+use crate::routes::CONFIG_STORE;
+use crate::trace::{self, TraceContext};
+use crate::{backend, limits, ssrf};
+use fastly::config_store::ConfigStore;
+use fastly::log::Endpoint;
+use fastly::Request;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use url::Url;
+
+#[derive(Debug, Clone, Deserialize)]
+struct OtelConfig {
+    /// Real-time log endpoint that receives one OTLP/JSON document per request.
+    log_endpoint: Option<String>,
+    /// OTLP/HTTP traces URL, e.g. `https://collector.example.com/v1/traces`.
+    collector_url: Option<String>,
+    #[serde(default = "default_service_name")]
+    service_name: String,
+}
+
+fn default_service_name() -> String {
+    "dynserv".to_string()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Kind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+struct Recorded {
+    span_id: String,
+    parent_id: Option<String>,
+    name: &'static str,
+    kind: Kind,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+    ok: bool,
+}
+
+struct Trace {
+    context: TraceContext,
+    config: OtelConfig,
+    root_id: String,
+    start: SystemTime,
+    spans: Vec<Recorded>,
+}
+
+static CURRENT: Mutex<Option<Trace>> = Mutex::new(None);
+
+/// Start recording spans for the request, if export is configured and sampled.
+pub fn begin(context: &TraceContext) {
+    if !context.sampled() {
+        return;
+    }
+    let Some(config) = ConfigStore::try_open(CONFIG_STORE)
+        .ok()
+        .and_then(|store| store.get("otel"))
+        .and_then(|json| serde_json::from_str::<OtelConfig>(&json).ok())
+    else {
+        return;
+    };
+    if let Ok(mut current) = CURRENT.lock() {
+        *current = Some(Trace {
+            context: context.clone(),
+            config,
+            root_id: trace::new_span_id(),
+            start: SystemTime::now(),
+            spans: Vec::new(),
+        });
+    }
+}
+
+/// A phase of the request being timed.
+///
+/// The span is recorded when dropped; unless [`Span::end`] marked it as
+/// successful, for example on an early return, it's recorded as an error.
+pub struct Span {
+    name: &'static str,
+    span_id: String,
+    kind: Kind,
+    start: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+    ok: bool,
+}
+
+impl Span {
+    pub fn start(name: &'static str) -> Self {
+        Self {
+            name,
+            span_id: trace::new_span_id(),
+            kind: Kind::Internal,
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+            ok: false,
+        }
+    }
+
+    /// A client span with the ID that was propagated to the origin.
+    pub fn client(name: &'static str, span_id: &str) -> Self {
+        let mut span = Self::start(name);
+        span.span_id = span_id.to_string();
+        span.kind = Kind::Client;
+        span
+    }
+
+    pub fn attr(&mut self, key: &'static str, value: impl Into<Value>) {
+        self.attributes.push((key, value.into()));
+    }
+
+    pub fn end(mut self, ok: bool) {
+        self.ok = ok;
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let Ok(mut current) = CURRENT.lock() else {
+            return;
+        };
+        if let Some(trace) = current.as_mut() {
+            let parent_id = Some(trace.root_id.clone());
+            trace.spans.push(Recorded {
+                span_id: std::mem::take(&mut self.span_id),
+                parent_id,
+                name: self.name,
+                kind: self.kind,
+                start: self.start,
+                end: SystemTime::now(),
+                attributes: std::mem::take(&mut self.attributes),
+                ok: self.ok,
+            });
+        }
+    }
+}
+
+fn nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn any_value(value: &Value) -> Value {
+    match value {
+        Value::Bool(b) => json!({"boolValue": b}),
+        Value::Number(n) if n.is_i64() || n.is_u64() => json!({"intValue": n.to_string()}),
+        Value::Number(n) => json!({"doubleValue": n}),
+        Value::String(s) => json!({"stringValue": s}),
+        other => json!({"stringValue": other.to_string()}),
+    }
+}
+
+fn span_json(trace_id: &str, span: &Recorded) -> Value {
+    let attributes: Vec<Value> = span
+        .attributes
+        .iter()
+        .map(|(key, value)| json!({"key": key, "value": any_value(value)}))
+        .collect();
+    json!({
+        "traceId": trace_id,
+        "spanId": span.span_id,
+        "parentSpanId": span.parent_id.clone().unwrap_or_default(),
+        "name": span.name,
+        "kind": span.kind as u8,
+        "startTimeUnixNano": nanos(span.start),
+        "endTimeUnixNano": nanos(span.end),
+        "attributes": attributes,
+        // STATUS_CODE_OK / STATUS_CODE_ERROR
+        "status": {"code": if span.ok { 1 } else { 2 }},
+    })
+}
+
+/// Close the server span and export everything recorded for the request.
+pub fn finish(status: u16) {
+    let Some(mut trace) = CURRENT.lock().ok().and_then(|mut current| current.take()) else {
+        return;
+    };
+    trace.spans.push(Recorded {
+        span_id: trace.root_id.clone(),
+        parent_id: trace.context.parent_id.clone(),
+        name: "proxy",
+        kind: Kind::Server,
+        start: trace.start,
+        end: SystemTime::now(),
+        attributes: vec![("http.response.status_code", status.into())],
+        ok: status < 500,
+    });
+
+    let spans: Vec<Value> = trace
+        .spans
+        .iter()
+        .map(|span| span_json(&trace.context.trace_id, span))
+        .collect();
+    let document = json!({
+        "resourceSpans": [{
+            "resource": {"attributes": [
+                {"key": "service.name", "value": {"stringValue": trace.config.service_name}},
+            ]},
+            "scopeSpans": [{
+                "scope": {"name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION")},
+                "spans": spans,
+            }],
+        }],
+    })
+    .to_string();
+
+    if let Some(name) = &trace.config.log_endpoint {
+        if let Ok(mut endpoint) = Endpoint::try_from_name(name) {
+            let _ = endpoint.write_all(document.as_bytes());
+        }
+    }
+    if let Some(url) = &trace.config.collector_url {
+        post(url, document);
+    }
+}
+
+fn post(url: &str, document: String) {
+    let Some(target) = Url::parse(url)
+        .ok()
+        .and_then(|url| ssrf::validate(url).ok())
+    else {
+        return;
+    };
+    if limits::reserve_request().is_err() {
+        return;
+    }
+    let Ok(backend) = backend::create(&target.hostname, target.port) else {
+        return;
+    };
+    let _ = Request::post(target.url.clone())
+        .with_header("Host", &target.hostname)
+        .with_header("Content-Type", "application/json")
+        .with_body(document)
+        .with_pass(true)
+        .send(backend.name());
+}
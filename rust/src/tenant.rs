@@ -0,0 +1,216 @@
+//! Per-tenant settings.
+//!
+//! Each tenant's settings are stored as JSON in the `tenant.<id>` entry of the
+//! `dynserv-config` Config Store. Requests authenticated with the static API
+//! key belong to the [`DEFAULT`] tenant; other auth providers name the tenant.
+
+use crate::affinity::Affinity;
+use crate::bots::BotRule;
+use crate::cache_control::CacheControl;
+use crate::capture::Capture;
+use crate::chaos::Chaos;
+use crate::cidr::Cidr;
+use crate::cors::Cors;
+use crate::credentials::OriginCredential;
+use crate::geoblock::ClientCountries;
+use crate::headers::{HeaderRules, ResponseHeaderRules};
+use crate::method;
+use crate::mirror::Mirror;
+use crate::mock::Mocks;
+use crate::pooling::Connections;
+use crate::quota::Quota;
+use crate::residency::Residency;
+use crate::robots::Robots;
+use crate::routes::CONFIG_STORE;
+use crate::signing::SignedOrigin;
+use crate::split::Split;
+use crate::sse::EventStreams;
+use crate::timeouts::MaxTimeouts;
+use crate::tls::{self, OriginTls, TlsVersions};
+use crate::upstream::UpstreamProxy;
+use crate::url_rules::UrlRule;
+use crate::websocket::WebSockets;
+use fastly::config_store::ConfigStore;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+/// Tenant ID for requests authenticated with the static API key.
+pub const DEFAULT: &str = "default";
+
+/// Which traffic is shed first when capacity runs short.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    /// Latency-sensitive traffic, kept for as long as possible.
+    #[default]
+    Interactive,
+    /// Bulk traffic, rejected first under load.
+    Batch,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Tenant {
+    /// Secondary target tried when the primary origin fails.
+    pub fallback_url: Option<String>,
+    /// Origin statuses that trigger the fallback, in addition to fetch errors.
+    pub fallback_statuses: Vec<u16>,
+    /// URL notified about key lifecycle events.
+    pub webhook_url: Option<String>,
+    /// Reject all requests made with this tenant's key.
+    pub banned: bool,
+    /// Unix timestamp (seconds) after which the key is no longer accepted.
+    pub expires_at: Option<u64>,
+    /// Auth providers this tenant may authenticate with; empty allows any.
+    pub auth_providers: Vec<String>,
+    /// Daily request and byte quotas for each of the tenant's keys.
+    pub quota: Option<Quota>,
+    /// Priority class of the tenant's traffic.
+    pub priority: Priority,
+    /// Countries the tenant's origins must be located in.
+    pub residency: Option<Residency>,
+    /// Only fetch what origins' robots.txt files allow this agent.
+    pub robots: Option<Robots>,
+    /// Add `Server-Timing` to every response, not only when `timing=1` is passed.
+    pub server_timing: bool,
+    /// Send the client's IP address, country, city and ASN to origins.
+    pub forward_client_metadata: bool,
+    /// Countries the tenant's clients may or may not connect from.
+    pub client_countries: Option<ClientCountries>,
+    /// Client address ranges the tenant's keys may be used from; empty allows any.
+    pub client_cidrs: Vec<Cidr>,
+    /// Regex rules over client user agents that block or tarpit requests.
+    pub bot_rules: Vec<BotRule>,
+    /// Which client headers are forwarded to origins.
+    pub request_headers: HeaderRules,
+    /// Headers added to every origin request.
+    pub origin_headers: BTreeMap<String, String>,
+    /// Freshness directives for clients and the edge, instead of the origin's.
+    pub cache_control: CacheControl,
+    /// Which origin response headers reach clients.
+    pub response_headers: ResponseHeaderRules,
+    /// Cross-origin access for browser apps.
+    pub cors: Option<Cors>,
+    /// Regex rules over target paths and queries, tried in order.
+    pub url_rules: Vec<UrlRule>,
+    /// Methods the tenant may proxy, after any override.
+    pub allowed_methods: Vec<String>,
+    /// Copy a share of requests to a shadow origin.
+    pub mirror: Option<Mirror>,
+    /// Keep copies of some requests and their responses, to replay them.
+    pub capture: Option<Capture>,
+    /// Answer requests from registered fixtures instead of origins.
+    pub mocks: Option<Mocks>,
+    /// Inject latency, errors and cut-off bodies into a share of requests.
+    pub chaos: Option<Chaos>,
+    /// Answer locally while an origin's 429/503 `Retry-After` window lasts, on every route.
+    pub shield_retry_after: bool,
+    /// Send a share of clients to a second origin, for A/B tests.
+    pub split: Option<Split>,
+    /// Keep each browser on the split variant or canary it was given.
+    pub affinity: Option<Affinity>,
+    /// Origins whose requests are signed, and the signing profile for each.
+    pub signed_origins: Vec<SignedOrigin>,
+    /// Credentials attached to requests for matching origins.
+    pub origin_credentials: Vec<OriginCredential>,
+    /// TLS settings for connections to matching origins.
+    pub origin_tls: Vec<OriginTls>,
+    /// Egress proxies that requests for matching origins are sent through.
+    pub upstream_proxies: Vec<UpstreamProxy>,
+    /// Bounds on the TLS version for all origins.
+    pub tls_versions: TlsVersions,
+    /// Whether requests may set the `sni`, `verify_host` and `host_header` parameters.
+    pub tls_name_overrides: bool,
+    /// Whether requests may pin the target's host to an address with `resolve`.
+    pub dns_overrides: bool,
+    /// The longest origin timeouts clients may ask for.
+    pub max_timeouts: MaxTimeouts,
+    /// The budget for each request, instead of the deployment's.
+    pub deadline_secs: Option<f64>,
+    /// Connection pooling and keepalives for origin backends.
+    pub connections: Connections,
+    /// Whether origins may be reached over HTTP/2, for gRPC and h2-only APIs.
+    pub http2: bool,
+    /// Hand WebSocket upgrades off to origins.
+    pub websockets: Option<WebSockets>,
+    /// How Server-Sent Events streams are proxied.
+    pub event_streams: EventStreams,
+}
+
+impl Default for Tenant {
+    fn default() -> Self {
+        Self {
+            fallback_url: None,
+            fallback_statuses: vec![502, 503, 504],
+            webhook_url: None,
+            banned: false,
+            expires_at: None,
+            auth_providers: Vec::new(),
+            quota: None,
+            priority: Priority::default(),
+            residency: None,
+            robots: None,
+            server_timing: false,
+            forward_client_metadata: false,
+            client_countries: None,
+            client_cidrs: Vec::new(),
+            bot_rules: Vec::new(),
+            request_headers: HeaderRules::default(),
+            origin_headers: BTreeMap::new(),
+            cache_control: CacheControl::default(),
+            response_headers: ResponseHeaderRules::default(),
+            cors: None,
+            url_rules: Vec::new(),
+            allowed_methods: method::default_allowed(),
+            mirror: None,
+            capture: None,
+            mocks: None,
+            chaos: None,
+            shield_retry_after: false,
+            split: None,
+            affinity: None,
+            signed_origins: Vec::new(),
+            origin_credentials: Vec::new(),
+            origin_tls: Vec::new(),
+            upstream_proxies: Vec::new(),
+            tls_versions: TlsVersions::default(),
+            tls_name_overrides: false,
+            dns_overrides: false,
+            max_timeouts: MaxTimeouts::default(),
+            deadline_secs: None,
+            connections: Connections::default(),
+            http2: false,
+            websockets: None,
+            event_streams: EventStreams::default(),
+        }
+    }
+}
+
+impl Tenant {
+    pub fn allows_provider(&self, provider: &str) -> bool {
+        self.auth_providers.is_empty() || self.auth_providers.iter().any(|p| p == provider)
+    }
+
+    pub fn allows_client(&self, ip: Option<IpAddr>) -> bool {
+        self.client_cidrs.is_empty()
+            || ip.is_some_and(|ip| self.client_cidrs.iter().any(|cidr| cidr.contains(ip)))
+    }
+}
+
+/// Load a tenant's settings. A missing store or entry means defaults.
+pub fn load(id: &str) -> Result<Tenant, String> {
+    let Ok(store) = ConfigStore::try_open(CONFIG_STORE) else {
+        return Ok(Tenant::default());
+    };
+    match store.get(&format!("tenant.{}", id)) {
+        Some(json) => {
+            let tenant: Tenant = serde_json::from_str(&json)
+                .map_err(|e| format!("Invalid 'tenant.{}' entry: {}", id, e))?;
+            tls::check(&tenant.origin_tls, tenant.tls_versions)
+                .map_err(|e| format!("Invalid 'tenant.{}' entry: {}", id, e))?;
+            Ok(tenant)
+        }
+        None => Ok(Tenant::default()),
+    }
+}
@@ -0,0 +1,76 @@
+//! Per-request origin timeouts.
+//!
+//! Clients can shorten the origin backend's timeouts to fail fast, or
+//! lengthen them for slow batch work, with the `cto`, `fbto` and `bbto`
+//! parameters (connect, first byte and between bytes, in seconds). Requested
+//! values are clamped to the tenant's maxima.
+
+use crate::config;
+use serde::Deserialize;
+use std::time::Duration;
+use url::Url;
+
+/// Shortest timeout a client can ask for.
+const MIN_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// A backend's timeouts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    pub connect: Duration,
+    pub first_byte: Duration,
+    pub between_bytes: Duration,
+}
+
+/// The deployment's default timeouts, from its [`config::ProxyConfig`].
+impl Default for Timeouts {
+    fn default() -> Self {
+        config::current().timeouts()
+    }
+}
+
+/// The longest timeouts a tenant's clients may ask for.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct MaxTimeouts {
+    pub connect_secs: f64,
+    pub first_byte_secs: f64,
+    pub between_bytes_secs: f64,
+}
+
+impl Default for MaxTimeouts {
+    fn default() -> Self {
+        Self {
+            connect_secs: 30.0,
+            first_byte_secs: 120.0,
+            between_bytes_secs: 120.0,
+        }
+    }
+}
+
+/// A timeout parameter in seconds, clamped to `max_secs`.
+fn parse(client_url: &Url, param: &str, max_secs: f64) -> Result<Option<Duration>, String> {
+    let Some((_, value)) = client_url.query_pairs().find(|(k, _)| k == param) else {
+        return Ok(None);
+    };
+    let secs: f64 = value
+        .parse()
+        .ok()
+        .filter(|secs: &f64| secs.is_finite() && *secs > 0.0)
+        .ok_or_else(|| format!("'{}' must be a positive number of seconds", param))?;
+    let timeout = Duration::from_secs_f64(secs.min(max_secs.max(0.0)));
+    Ok(Some(timeout.max(MIN_TIMEOUT)))
+}
+
+/// The timeouts the client asked for, with `defaults` for any it didn't.
+pub fn requested(
+    client_url: &Url,
+    max: &MaxTimeouts,
+    defaults: Timeouts,
+) -> Result<Timeouts, String> {
+    Ok(Timeouts {
+        connect: parse(client_url, "cto", max.connect_secs)?.unwrap_or(defaults.connect),
+        first_byte: parse(client_url, "fbto", max.first_byte_secs)?.unwrap_or(defaults.first_byte),
+        between_bytes: parse(client_url, "bbto", max.between_bytes_secs)?
+            .unwrap_or(defaults.between_bytes),
+    })
+}
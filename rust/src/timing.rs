@@ -0,0 +1,46 @@
+//! `Server-Timing` response headers.
+//!
+//! Durations of the proxy's own phases are reported when the client asks with
+//! `timing=1` or the tenant enables `server_timing`, so they aren't exposed
+//! by default.
+
+use fastly::{Request, Response};
+use std::time::Duration;
+
+pub struct ServerTiming {
+    enabled: bool,
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl ServerTiming {
+    pub fn new(req: &Request, always: bool) -> Self {
+        let requested = req
+            .get_url()
+            .query_pairs()
+            .any(|(k, v)| k == "timing" && (v == "1" || v == "true"));
+        Self {
+            enabled: always || requested,
+            phases: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, phase: &'static str, duration: Duration) {
+        if self.enabled {
+            self.phases.push((phase, duration));
+        }
+    }
+
+    /// Add the header, with durations in milliseconds.
+    pub fn apply(&self, resp: &mut Response) {
+        if !self.enabled || self.phases.is_empty() {
+            return;
+        }
+        let value = self
+            .phases
+            .iter()
+            .map(|(phase, duration)| format!("{};dur={:.3}", phase, duration.as_secs_f64() * 1e3))
+            .collect::<Vec<_>>()
+            .join(", ");
+        resp.set_header("Server-Timing", value);
+    }
+}
@@ -0,0 +1,259 @@
+//! TLS settings for connections to origins.
+//!
+//! A tenant's `origin_tls` entries apply to every backend built for a
+//! matching host, whichever part of the proxy builds it: the client's
+//! target, fallbacks, redirects, ESI fragments and the rest. Key material is
+//! read from the `dynserv-secrets` Secret Store as each backend is built.
+//!
+//! TLS versions can also be bounded for all of a tenant's origins, with
+//! entries overriding either bound for their hosts.
+//!
+//! Certificate pinning isn't supported: Compute exposes neither certificate
+//! hooks nor the certificate an origin presented, so pins can't be checked.
+//! Entries with `pins` are rejected when the tenant is loaded rather than
+//! connecting unpinned.
+
+use crate::{access_log, routes, secrets};
+use fastly::backend::BackendBuilder;
+use fastly::config_store::ConfigStore;
+use fastly_shared::SslVersion;
+use serde::Deserialize;
+use std::sync::Mutex;
+use url::Url;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.0")]
+    Tls1_0,
+    #[serde(rename = "1.1")]
+    Tls1_1,
+    #[serde(rename = "1.2")]
+    Tls1_2,
+    #[serde(rename = "1.3")]
+    Tls1_3,
+}
+
+impl TlsVersion {
+    fn as_ssl_version(self) -> SslVersion {
+        match self {
+            TlsVersion::Tls1_0 => SslVersion::TLS1,
+            TlsVersion::Tls1_1 => SslVersion::TLS1_1,
+            TlsVersion::Tls1_2 => SslVersion::TLS1_2,
+            TlsVersion::Tls1_3 => SslVersion::TLS1_3,
+        }
+    }
+}
+
+/// The TLS versions a connection may negotiate.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct TlsVersions {
+    pub min_version: Option<TlsVersion>,
+    pub max_version: Option<TlsVersion>,
+}
+
+impl TlsVersions {
+    /// These bounds, with any left unset taken from `defaults`.
+    fn or(self, defaults: TlsVersions) -> TlsVersions {
+        TlsVersions {
+            min_version: self.min_version.or(defaults.min_version),
+            max_version: self.max_version.or(defaults.max_version),
+        }
+    }
+
+    fn is_set(&self) -> bool {
+        self.min_version.is_some() || self.max_version.is_some()
+    }
+
+    fn check(&self, scope: &str) -> Result<(), String> {
+        match (self.min_version, self.max_version) {
+            (Some(min), Some(max)) if min > max => Err(format!(
+                "TLS min_version is above max_version for {}",
+                scope
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Check that every origin's TLS version bounds, once combined, can be met,
+/// and that no entry asks for pinning.
+pub fn check(origin_tls: &[OriginTls], versions: TlsVersions) -> Result<(), String> {
+    versions.check("all origins")?;
+    for entry in origin_tls {
+        entry.versions.or(versions).check(&entry.host)?;
+        if !entry.pins.is_empty() {
+            return Err(format!(
+                "'pins' for {} can't be enforced: certificate pinning isn't supported, \
+                 use a ca_certificate holding only the origin's issuing CA instead",
+                entry.host
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// TLS settings for origins matching `host`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct OriginTls {
+    /// Destination host, exact or `*.example.com`.
+    pub host: String,
+    /// Certificate presented to origins that require mutual TLS.
+    #[serde(default)]
+    pub client_certificate: Option<ClientCertificate>,
+    /// PEM CA certificates the origin's certificate is checked against,
+    /// instead of the public roots.
+    #[serde(default)]
+    pub ca_certificate: Option<Pem>,
+    /// Bounds on the TLS version, overriding the tenant's.
+    #[serde(flatten)]
+    pub versions: TlsVersions,
+    /// Public key pins, which aren't supported: kept so that entries giving
+    /// them are refused instead of silently connecting unpinned.
+    #[serde(default)]
+    pub pins: Vec<String>,
+}
+
+/// Where PEM material that isn't secret is kept.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pem {
+    /// A secret in the `dynserv-secrets` Secret Store.
+    Secret(String),
+    /// An entry in the `dynserv-config` Config Store.
+    Config(String),
+}
+
+impl Pem {
+    fn read(&self) -> Result<String, String> {
+        match self {
+            Pem::Secret(name) => String::from_utf8(secrets::read(name)?)
+                .map_err(|_| format!("Secret '{}' isn't text", name)),
+            Pem::Config(key) => ConfigStore::try_open(routes::CONFIG_STORE)
+                .ok()
+                .and_then(|store| store.get(key))
+                .ok_or_else(|| format!("No '{}' entry configured", key)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientCertificate {
+    /// Name of the secret holding the PEM certificate chain.
+    pub certificate: String,
+    /// Name of the secret holding the PEM private key.
+    pub key: String,
+}
+
+struct Current {
+    origin_tls: Vec<OriginTls>,
+    versions: TlsVersions,
+}
+
+/// The current tenant's settings, once it's known.
+static CURRENT: Mutex<Current> = Mutex::new(Current {
+    origin_tls: Vec::new(),
+    versions: TlsVersions {
+        min_version: None,
+        max_version: None,
+    },
+});
+
+/// Use the tenant's settings for the backends built from now on.
+pub fn configure(origin_tls: &[OriginTls], versions: TlsVersions) {
+    if let Ok(mut current) = CURRENT.lock() {
+        *current = Current {
+            origin_tls: origin_tls.to_vec(),
+            versions,
+        };
+    }
+}
+
+/// The settings for a host, if an entry matches or the tenant bounds TLS versions.
+pub fn settings_for(host: &str) -> Option<OriginTls> {
+    let current = CURRENT.lock().ok()?;
+    let entry = current
+        .origin_tls
+        .iter()
+        .find(|entry| routes::host_matches(&entry.host, host));
+    match entry {
+        Some(entry) => Some(OriginTls {
+            versions: entry.versions.or(current.versions),
+            ..entry.clone()
+        }),
+        None if current.versions.is_set() => Some(OriginTls {
+            host: host.to_string(),
+            client_certificate: None,
+            ca_certificate: None,
+            versions: current.versions,
+            pins: Vec::new(),
+        }),
+        None => None,
+    }
+}
+
+impl OriginTls {
+    /// Apply the settings to a backend being built.
+    pub fn apply(&self, mut builder: BackendBuilder) -> Result<BackendBuilder, String> {
+        if let Some(client) = &self.client_certificate {
+            let certificate = String::from_utf8(secrets::read(&client.certificate)?)
+                .map_err(|_| format!("Secret '{}' isn't text", client.certificate))?;
+            // The key is handed over by reference and never read here
+            let key = secrets::open()
+                .map_err(|e| format!("Secret store unavailable: {}", e))?
+                .get(&client.key)
+                .ok_or_else(|| format!("Secret '{}' not found", client.key))?;
+            builder = builder.provide_client_certificate(certificate, key);
+        }
+        if let Some(ca) = &self.ca_certificate {
+            builder = builder.ca_certificate(ca.read()?);
+        }
+        if let Some(min) = self.versions.min_version {
+            builder = builder.set_min_tls_version(min.as_ssl_version());
+        }
+        if let Some(max) = self.versions.max_version {
+            builder = builder.set_max_tls_version(max.as_ssl_version());
+        }
+        Ok(builder)
+    }
+}
+
+/// A DNS name given as a TLS name parameter.
+fn dns_name(param: &str, value: &str) -> Result<String, String> {
+    match url::Host::parse(value) {
+        Ok(url::Host::Domain(name)) if !name.is_empty() => Ok(name),
+        _ => Err(format!("'{}' must be a DNS hostname", param)),
+    }
+}
+
+/// The `sni` and `verify_host` parameters, which override the names the
+/// target's TLS handshake uses.
+pub fn requested_names(client_url: &Url) -> Result<(Option<String>, Option<String>), String> {
+    let param = |name: &str| {
+        client_url
+            .query_pairs()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| dns_name(name, &v))
+            .transpose()
+    };
+    Ok((param("sni")?, param("verify_host")?))
+}
+
+/// The `host_header` parameter, the `Host` a fronted target is sent with
+/// instead of its own hostname.
+pub fn requested_host_header(client_url: &Url) -> Result<Option<String>, String> {
+    client_url
+        .query_pairs()
+        .find(|(k, _)| k == "host_header")
+        .map(|(_, v)| dns_name("host_header", &v))
+        .transpose()
+}
+
+/// Record why a backend's TLS settings couldn't be applied.
+pub fn note_failure(host: &str, message: &str) {
+    access_log::event(&serde_json::json!({
+        "event": "origin_tls_failed",
+        "target_host": host,
+        "message": message,
+    }));
+}
@@ -0,0 +1,101 @@
+//! W3C Trace Context propagation.
+//!
+//! The incoming `traceparent` is continued with a new span ID for the origin
+//! fetch, or a new sampled trace is started when it's missing or malformed.
+//! `tracestate` is forwarded unchanged alongside a valid parent.
+
+use fastly::Request;
+
+const VERSION: &str = "00";
+const SAMPLED: u8 = 0x01;
+
+#[derive(Debug, Clone)]
+pub struct TraceContext {
+    pub trace_id: String,
+    /// Span ID of the caller, if the trace was continued.
+    pub parent_id: Option<String>,
+    pub flags: u8,
+    pub tracestate: Option<String>,
+}
+
+fn random_hex(bytes: usize) -> String {
+    let mut buf = vec![0u8; bytes];
+    // A zero ID is invalid, so make sure at least one bit is set
+    if getrandom::getrandom(&mut buf).is_err() || buf.iter().all(|b| *b == 0) {
+        buf[bytes - 1] |= 1;
+    }
+    hex::encode(buf)
+}
+
+/// A fresh 8-byte span ID.
+pub fn new_span_id() -> String {
+    random_hex(8)
+}
+
+fn is_id(value: &str, len: usize) -> bool {
+    value.len() == len
+        && value
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        && value.bytes().any(|b| b != b'0')
+}
+
+fn parse(traceparent: &str) -> Option<(String, String, u8)> {
+    let mut parts = traceparent.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent_id = parts.next()?;
+    let flags = parts.next()?;
+    // Later versions may append fields, but version 00 has exactly four
+    if version.len() != 2 || version == "ff" || (version == VERSION && parts.next().is_some()) {
+        return None;
+    }
+    if !is_id(trace_id, 32) || !is_id(parent_id, 16) || flags.len() != 2 {
+        return None;
+    }
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    Some((trace_id.to_string(), parent_id.to_string(), flags))
+}
+
+impl TraceContext {
+    /// Continue the client's trace, or start a new one.
+    pub fn from_request(req: &Request) -> Self {
+        match req.get_header_str("traceparent").and_then(parse) {
+            Some((trace_id, parent_id, flags)) => Self {
+                trace_id,
+                parent_id: Some(parent_id),
+                flags,
+                tracestate: req.get_header_str("tracestate").map(str::to_string),
+            },
+            None => Self {
+                trace_id: random_hex(16),
+                parent_id: None,
+                flags: SAMPLED,
+                tracestate: None,
+            },
+        }
+    }
+
+    /// The `traceparent` value naming `span_id` as the parent.
+    pub fn traceparent(&self, span_id: &str) -> String {
+        format!(
+            "{}-{}-{}-{:02x}",
+            VERSION, self.trace_id, span_id, self.flags
+        )
+    }
+
+    pub fn sampled(&self) -> bool {
+        self.flags & SAMPLED != 0
+    }
+
+    /// Forward the trace to the origin as a child of `span_id`.
+    pub fn propagate(&self, req: &mut Request, span_id: &str) {
+        req.set_header("traceparent", self.traceparent(span_id));
+        match &self.tracestate {
+            Some(tracestate) => req.set_header("tracestate", tracestate),
+            None => {
+                req.remove_header("tracestate");
+            }
+        }
+    }
+}
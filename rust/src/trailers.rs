@@ -0,0 +1,115 @@
+//! HTTP trailers, in both directions.
+//!
+//! Trailers are fields sent after a body, such as gRPC's call status or a
+//! checksum of a streamed upload. `TE` and `Trailer` are hop-by-hop, so the
+//! proxy reads them before they're stripped and speaks for the client: an
+//! origin is told the client takes trailers when it sent `TE: trailers` (or
+//! made a gRPC call), and then the origin's trailers are copied to the
+//! client after the body, under the `Trailer` header that announced them.
+//! A request's own trailers go to the origin with its body, even when the
+//! proxy reads the body to sign it. Bodies the proxy rewrites end up with
+//! no trailers.
+
+use fastly::experimental::{BodyExt, StreamingBodyExt};
+use fastly::http::body::StreamingBody;
+use fastly::{Body, Request, Response};
+use std::io::Read;
+use std::sync::Mutex;
+
+/// Set while the current response's trailers should reach the client.
+static RELAY: Mutex<bool> = Mutex::new(false);
+
+/// What the client said about trailers, read before hop-by-hop headers are stripped.
+pub struct ClientTrailers {
+    takes: bool,
+    sends: Option<String>,
+}
+
+impl ClientTrailers {
+    pub fn of(req: &Request) -> Self {
+        let takes = req
+            .get_header_all_str("TE")
+            .iter()
+            .flat_map(|te| te.split(','))
+            .any(|coding| {
+                let coding = coding.split(';').next().unwrap_or_default();
+                coding.trim().eq_ignore_ascii_case("trailers")
+            });
+        Self {
+            takes,
+            sends: req.get_header_str("Trailer").map(str::to_string),
+        }
+    }
+
+    /// Tell the origin about the client's trailers, once hop-by-hop headers are gone.
+    pub fn forward(&self, req: &mut Request) {
+        if let Some(sends) = &self.sends {
+            req.set_header("Trailer", sends);
+        }
+        if self.takes {
+            expect(req);
+        }
+    }
+}
+
+/// Ask the origin for trailers, and relay them to the client.
+pub fn expect(req: &mut Request) {
+    req.set_header("TE", "trailers");
+    if let Ok(mut relay) = RELAY.lock() {
+        *relay = true;
+    }
+}
+
+/// Whether the current response's trailers should be copied to the client.
+pub fn relays() -> bool {
+    RELAY.lock().is_ok_and(|relay| *relay)
+}
+
+/// The trailers an origin response announces, read before hop-by-hop headers are stripped.
+pub fn announced(resp: &Response) -> Option<String> {
+    resp.get_header_str("Trailer").map(str::to_string)
+}
+
+/// Announce the origin's trailers to a client that's getting them.
+pub fn announce(resp: &mut Response, announced: Option<&str>) {
+    if let Some(announced) = announced.filter(|_| relays()) {
+        resp.set_header("Trailer", announced);
+    }
+}
+
+/// Read the request's body for `f`, then put it back with its trailers.
+pub fn with_body<T>(req: &mut Request, f: impl FnOnce(&mut Request, &[u8]) -> T) -> T {
+    let mut body = req.take_body();
+    let mut bytes = Vec::new();
+    let _ = body.read_to_end(&mut bytes);
+    let trailers = body.get_trailers().unwrap_or_default();
+    let result = f(req, &bytes);
+    let mut body = Body::from(bytes);
+    for (name, value) in trailers.iter() {
+        body.append_trailer(name, value);
+    }
+    req.set_body(body);
+    result
+}
+
+/// Copy a fully read body's trailers to the client's stream.
+pub fn copy(body: &mut Body, out: &mut StreamingBody) {
+    let Ok(trailers) = body.get_trailers() else {
+        return;
+    };
+    for (name, value) in trailers.iter() {
+        out.append_trailer(name, value);
+    }
+}
+
+/// Stream a response to the client, followed by its trailers.
+pub fn relay(mut resp: Response) {
+    let mut body = resp.take_body();
+    let mut out = resp.stream_to_client();
+    // Dropping the stream without finishing it aborts the response
+    if std::io::copy(&mut body, &mut out).is_err() {
+        return;
+    }
+    copy(&mut body, &mut out);
+    let _ = out.finish();
+}
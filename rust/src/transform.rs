@@ -0,0 +1,168 @@
+//! Body transforms applied to outbound requests and origin responses.
+//!
+//! The same [`Transform`] operations are used in both directions so a route
+//! can adapt a legacy client's payload on the way out and the origin's reply on
+//! the way back.
+//!
+//! Bodies are decoded from their declared charset first (see [`charset`]), and
+//! anything rewritten is sent on as UTF-8.
+
+use crate::charset;
+use fastly::{Body, Request, Response};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+/// Bodies larger than this are forwarded untouched rather than buffered.
+const MAX_TRANSFORM_BODY_BYTES: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Transform {
+    /// Set a JSON field at a dotted path, creating parent objects as needed.
+    Set { path: String, value: Value },
+    /// Remove the JSON field at a dotted path if present.
+    Remove { path: String },
+    /// Convert an `application/x-www-form-urlencoded` body into a JSON object.
+    FormToJson,
+}
+
+/// Apply transforms to the body of a request about to be sent to the origin.
+pub fn apply_to_request(req: &mut Request, transforms: &[Transform]) {
+    if transforms.is_empty() || is_encoded(req.get_header_str("Content-Encoding")) {
+        return;
+    }
+    let content_type = req
+        .get_header_str("Content-Type")
+        .unwrap_or_default()
+        .to_string();
+    if let Some(content_type) = apply_to_body(req.get_body_mut(), &content_type, transforms) {
+        req.set_header("Content-Type", content_type);
+    }
+}
+
+/// Apply transforms to the body of a response received from the origin.
+pub fn apply_to_response(resp: &mut Response, transforms: &[Transform]) {
+    if transforms.is_empty() || is_encoded(resp.get_header_str("Content-Encoding")) {
+        return;
+    }
+    let content_type = resp
+        .get_header_str("Content-Type")
+        .unwrap_or_default()
+        .to_string();
+    if let Some(content_type) = apply_to_body(resp.get_body_mut(), &content_type, transforms) {
+        resp.set_header("Content-Type", content_type);
+    }
+}
+
+/// Replace the body with its transformed JSON form.
+///
+/// Returns the `Content-Type` to declare if the body changed.
+fn apply_to_body(body: &mut Body, content_type: &str, transforms: &[Transform]) -> Option<String> {
+    let prefix = body.get_prefix_mut(MAX_TRANSFORM_BODY_BYTES + 1);
+    if prefix.len() > MAX_TRANSFORM_BODY_BYTES {
+        return None;
+    }
+    let original = prefix.take();
+    let transformed = charset::decode(&original, content_type)
+        .and_then(|text| transform_body(transforms, content_type, &text));
+    match transformed {
+        Some(transformed) => {
+            *body = Body::from(transformed);
+            Some(if !is_json(content_type) {
+                "application/json".to_string()
+            } else if charset::declares_non_utf8(content_type) {
+                charset::as_utf8(content_type)
+            } else {
+                content_type.to_string()
+            })
+        }
+        None => {
+            *body = Body::from(original);
+            None
+        }
+    }
+}
+
+/// Compressed bodies can't be inspected, so they are passed through unchanged.
+fn is_encoded(content_encoding: Option<&str>) -> bool {
+    content_encoding.is_some_and(|e| !e.eq_ignore_ascii_case("identity"))
+}
+
+fn essence(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+fn is_json(content_type: &str) -> bool {
+    let essence = essence(content_type);
+    essence == "application/json" || essence.ends_with("+json")
+}
+
+/// Run the transforms over a body, returning the new JSON body if anything applied.
+fn transform_body(transforms: &[Transform], content_type: &str, body: &str) -> Option<Vec<u8>> {
+    let is_form = essence(content_type) == "application/x-www-form-urlencoded";
+
+    let mut json: Option<Value> = if is_json(content_type) {
+        serde_json::from_str(body).ok()
+    } else {
+        None
+    };
+
+    for transform in transforms {
+        match transform {
+            Transform::FormToJson if is_form && json.is_none() => {
+                let fields: Map<String, Value> = url::form_urlencoded::parse(body.as_bytes())
+                    .map(|(k, v)| (k.into_owned(), Value::String(v.into_owned())))
+                    .collect();
+                json = Some(Value::Object(fields));
+            }
+            Transform::FormToJson => {}
+            Transform::Set { path, value } => {
+                if let Some(root) = json.as_mut() {
+                    set_path(root, path, value.clone());
+                }
+            }
+            Transform::Remove { path } => {
+                if let Some(root) = json.as_mut() {
+                    remove_path(root, path);
+                }
+            }
+        }
+    }
+
+    json.and_then(|value| serde_json::to_vec(&value).ok())
+}
+
+fn set_path(root: &mut Value, path: &str, value: Value) {
+    let mut segments: Vec<&str> = path.split('.').collect();
+    let Some(last) = segments.pop() else { return };
+    let mut current = root;
+    for segment in segments {
+        let Value::Object(map) = current else { return };
+        current = map
+            .entry(segment)
+            .or_insert_with(|| Value::Object(Map::new()));
+    }
+    if let Value::Object(map) = current {
+        map.insert(last.to_string(), value);
+    }
+}
+
+fn remove_path(root: &mut Value, path: &str) {
+    let mut segments: Vec<&str> = path.split('.').collect();
+    let Some(last) = segments.pop() else { return };
+    let mut current = root;
+    for segment in segments {
+        match current.get_mut(segment) {
+            Some(next) => current = next,
+            None => return,
+        }
+    }
+    if let Value::Object(map) = current {
+        map.remove(last);
+    }
+}
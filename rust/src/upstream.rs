@@ -0,0 +1,70 @@
+//! Reaching origins through a partner's egress proxy.
+//!
+//! A tenant's `upstream_proxies` send requests for matching origins to an
+//! upstream proxy instead: the dynamic backend connects to the proxy's
+//! `proxy_host` and `proxy_port`, and the request keeps the origin's `Host`
+//! and path, the host-rewrite style of proxying that egress gateways route
+//! on. The whole target URL also goes in `url_header`, for proxies that want
+//! it, and `proxy_authorization` names a secret in `dynserv-secrets` sent as
+//! `Proxy-Authorization`. Compute can't open `CONNECT` tunnels or send
+//! absolute-form request lines, so proxies that only take those can't be
+//! chained.
+
+use crate::{backend, routes, secrets};
+use fastly::Request;
+use serde::Deserialize;
+use url::Url;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpstreamProxy {
+    /// Destination host reached through the proxy, exact or `*.example.com`.
+    pub host: String,
+    pub proxy_host: String,
+    #[serde(default = "default_proxy_port")]
+    pub proxy_port: u16,
+    /// Header carrying the full target URL; `null` leaves it out.
+    #[serde(default = "default_url_header")]
+    pub url_header: Option<String>,
+    /// Name of the secret sent as `Proxy-Authorization`.
+    #[serde(default)]
+    pub proxy_authorization: Option<String>,
+}
+
+fn default_proxy_port() -> u16 {
+    443
+}
+
+fn default_url_header() -> Option<String> {
+    Some("X-Upstream-Url".to_string())
+}
+
+/// The first upstream proxy for requests to `host`, if there is one.
+pub fn find<'a>(proxies: &'a [UpstreamProxy], host: &str) -> Option<&'a UpstreamProxy> {
+    proxies
+        .iter()
+        .find(|proxy| routes::host_matches(&proxy.host, host))
+}
+
+impl UpstreamProxy {
+    /// Where the backend connects: the proxy, under its own TLS names, with
+    /// requests still addressed to `origin`.
+    pub fn endpoint<'a>(&'a self, origin: &'a str) -> backend::Endpoint<'a> {
+        backend::Endpoint {
+            host_header: origin,
+            ..backend::Endpoint::new(&self.proxy_host, self.proxy_port)
+        }
+    }
+
+    /// Tell the proxy where the request is going and who's asking.
+    pub fn prepare(&self, req: &mut Request, target: &Url) -> Result<(), String> {
+        if let Some(name) = &self.url_header {
+            req.set_header(name.as_str(), target.as_str());
+        }
+        if let Some(secret) = &self.proxy_authorization {
+            let value = String::from_utf8(secrets::read(secret)?)
+                .map_err(|_| format!("Secret '{}' isn't text", secret))?;
+            req.set_header("Proxy-Authorization", value.trim());
+        }
+        Ok(())
+    }
+}
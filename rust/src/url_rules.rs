@@ -0,0 +1,68 @@
+//! Tenant rules over target URLs.
+//!
+//! Past the host checks, a tenant's `url_rules` match regular expressions
+//! against the target's canonical path (see [`policy::canonical_path`]) and
+//! query, such as `^/admin(/|$)`, and allow or deny it. Rules are tried in order and the first to match decides; a URL
+//! no rule matches is allowed. Patterns are compiled when the tenant's
+//! settings are loaded, and one that doesn't compile makes them invalid.
+
+use crate::errors::{Code, Problem};
+use crate::policy;
+use fastly::Response;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Deserializer};
+use url::Url;
+
+/// Largest compiled pattern, so one rule can't use up the instance's memory.
+const MAX_PATTERN_BYTES: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UrlRule {
+    #[serde(deserialize_with = "compile")]
+    pub pattern: Regex,
+    pub action: Action,
+}
+
+/// Compile a rule's pattern, with its size limited.
+pub(crate) fn compile<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Regex, D::Error> {
+    let pattern = String::deserialize(deserializer)?;
+    RegexBuilder::new(&pattern)
+        .size_limit(MAX_PATTERN_BYTES)
+        .build()
+        .map_err(serde::de::Error::custom)
+}
+
+/// What rules are matched against: the URL's canonical path and its query.
+pub fn subject(url: &Url) -> String {
+    let path = policy::canonical_path(url);
+    match url.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path,
+    }
+}
+
+/// The index of the rule that denies the URL, if one does.
+pub fn denying_rule(rules: &[UrlRule], url: &Url) -> Option<usize> {
+    let subject = subject(url);
+    rules
+        .iter()
+        .position(|rule| rule.pattern.is_match(&subject))
+        .filter(|index| rules[*index].action == Action::Deny)
+}
+
+/// The refusal for a URL denied by the rule at `index`.
+pub fn refusal(index: usize) -> Response {
+    Problem::new(
+        Code::UrlDenied,
+        "The target URL is denied by the tenant's URL rules",
+    )
+    .with("rule", index)
+    .into_response()
+}
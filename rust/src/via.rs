@@ -0,0 +1,57 @@
+//! The `Via` header, which identifies the proxy as an intermediary.
+//!
+//! RFC 9110 asks proxies to add themselves to `Via` on requests they forward
+//! and responses they return. The proxy appears as `1.1 <pseudonym>`: by
+//! default its Fastly service ID, so the hop can be traced back to the
+//! deployment without giving away hostnames.
+
+use fastly::{Request, Response};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub enabled: bool,
+    /// How the proxy names itself, instead of the service ID.
+    pub pseudonym: Option<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            pseudonym: None,
+        }
+    }
+}
+
+impl Settings {
+    /// The name the proxy adds to `Via`, if it adds itself.
+    pub fn pseudonym(&self) -> Option<String> {
+        if !self.enabled {
+            return None;
+        }
+        self.pseudonym
+            .clone()
+            .or_else(|| std::env::var("FASTLY_SERVICE_ID").ok())
+            .filter(|pseudonym| !pseudonym.is_empty())
+    }
+
+    fn entry(&self) -> Option<String> {
+        self.pseudonym().map(|pseudonym| format!("1.1 {}", pseudonym))
+    }
+
+    /// Add the proxy to the `Via` of a request it forwards.
+    pub fn add_to_request(&self, req: &mut Request) {
+        if let Some(entry) = self.entry() {
+            req.append_header("Via", entry);
+        }
+    }
+
+    /// Add the proxy to the `Via` of a response it returns.
+    pub fn add_to_response(&self, resp: &mut Response) {
+        if let Some(entry) = self.entry() {
+            resp.append_header("Via", entry);
+        }
+    }
+}
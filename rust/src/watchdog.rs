@@ -0,0 +1,184 @@
+//! Edge-side monitoring of long response transfers.
+//!
+//! Without a watchdog the origin's body is handed to the client as-is and a
+//! transfer that stalls simply ends when the backend's between-bytes timeout
+//! fires. Routes with a [`WatchdogPolicy`] have their bodies copied to the
+//! client by [`send`] instead, which writes `transfer_progress` events to the
+//! access log endpoint as the transfer goes on, a `transfer_stalled` or
+//! `transfer_failed` event when it doesn't finish, and `transfer_complete`
+//! when it does.
+
+use crate::{access_log, trailers};
+use fastly::Response;
+use serde::Deserialize;
+use std::io::{Read, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Bytes read from the origin per loop iteration.
+const CHUNK: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WatchdogPolicy {
+    /// Log progress after this many more bytes or seconds, whichever comes first.
+    pub progress_bytes: u64,
+    pub progress_secs: u64,
+    /// Minimum average throughput once `grace_secs` have passed.
+    pub min_bytes_per_sec: Option<u64>,
+    pub grace_secs: u64,
+    /// Longest gap between two reads before the transfer counts as stalled.
+    ///
+    /// A read waits at most the backend's between-bytes timeout, so only
+    /// shorter values have any effect; the gap is known once data arrives.
+    pub stall_secs: Option<u64>,
+    /// What happens to a stalled transfer.
+    pub on_stall: StallAction,
+}
+
+impl Default for WatchdogPolicy {
+    fn default() -> Self {
+        Self {
+            progress_bytes: 1024 * 1024,
+            progress_secs: 5,
+            min_bytes_per_sec: None,
+            grace_secs: 10,
+            stall_secs: None,
+            on_stall: StallAction::Abort,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StallAction {
+    /// End the response early, so the client sees a truncated transfer.
+    #[default]
+    Abort,
+    /// Only log the stall and keep copying.
+    Log,
+}
+
+struct Armed {
+    policy: WatchdogPolicy,
+    host: String,
+}
+
+static ARMED: Mutex<Option<Armed>> = Mutex::new(None);
+
+/// Watch the current request's response transfer.
+pub fn arm(policy: WatchdogPolicy, host: &str) {
+    if let Ok(mut armed) = ARMED.lock() {
+        *armed = Some(Armed {
+            policy,
+            host: host.to_string(),
+        });
+    }
+}
+
+struct Transfer<'a> {
+    request_id: &'a str,
+    host: &'a str,
+    started: Instant,
+    bytes: u64,
+}
+
+impl Transfer<'_> {
+    fn event(&self, event: &str, detail: Option<&str>) {
+        let elapsed = self.started.elapsed();
+        let bytes_per_sec = (self.bytes as f64 / elapsed.as_secs_f64().max(0.001)) as u64;
+        access_log::event(&serde_json::json!({
+            "event": event,
+            "request_id": self.request_id,
+            "target_host": self.host,
+            "bytes": self.bytes,
+            "elapsed_ms": elapsed.as_millis() as u64,
+            "bytes_per_sec": bytes_per_sec,
+            "detail": detail,
+        }));
+    }
+
+    /// Why the transfer breaks the policy, if it does.
+    fn stalled(&self, policy: &WatchdogPolicy, gap: Duration) -> Option<String> {
+        if let Some(stall_secs) = policy.stall_secs {
+            if gap.as_secs() >= stall_secs {
+                return Some(format!("no data for {}s", gap.as_secs()));
+            }
+        }
+        let elapsed = self.started.elapsed();
+        match policy.min_bytes_per_sec {
+            Some(min) if elapsed.as_secs() >= policy.grace_secs => {
+                let rate = self.bytes / elapsed.as_secs().max(1);
+                (rate < min).then(|| format!("{} bytes/s is below {}", rate, min))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Send the response to the client, monitoring its transfer if the route asked.
+pub fn send(mut resp: Response, request_id: &str) {
+    let Some(Armed { policy, host }) = ARMED.lock().ok().and_then(|mut armed| armed.take()) else {
+        if trailers::relays() {
+            trailers::relay(resp);
+        } else {
+            resp.send_to_client();
+        }
+        return;
+    };
+    let mut body = resp.take_body();
+    let mut out = resp.stream_to_client();
+    let mut transfer = Transfer {
+        request_id,
+        host: &host,
+        started: Instant::now(),
+        bytes: 0,
+    };
+    let mut buf = vec![0; CHUNK];
+    let mut last_read = Instant::now();
+    let mut logged_at = (Instant::now(), 0);
+    let mut stall_logged = false;
+    loop {
+        let read = body.read(&mut buf);
+        let gap = last_read.elapsed();
+        last_read = Instant::now();
+        let n = match read {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                // Usually the backend's between-bytes timeout
+                transfer.event("transfer_failed", Some(&e.to_string()));
+                return;
+            }
+        };
+        if out.write_all(&buf[..n]).is_err() {
+            transfer.event("transfer_failed", Some("client went away"));
+            return;
+        }
+        transfer.bytes += n as u64;
+
+        if let Some(reason) = transfer.stalled(&policy, gap) {
+            if policy.on_stall == StallAction::Abort {
+                // Dropping the stream without finishing it aborts the response
+                transfer.event("transfer_stalled", Some(&reason));
+                return;
+            }
+            if !stall_logged {
+                transfer.event("transfer_stalled", Some(&reason));
+                stall_logged = true;
+            }
+        }
+        let (at, bytes) = logged_at;
+        if transfer.bytes - bytes >= policy.progress_bytes
+            || at.elapsed().as_secs() >= policy.progress_secs
+        {
+            transfer.event("transfer_progress", None);
+            logged_at = (Instant::now(), transfer.bytes);
+        }
+    }
+    if trailers::relays() {
+        trailers::copy(&mut body, &mut out);
+    }
+    let _ = out.finish();
+    transfer.event("transfer_complete", None);
+}
@@ -0,0 +1,76 @@
+//! Webhook notifications for API key lifecycle and quota events.
+//!
+//! Notifications are POSTed as JSON to the tenant's `webhook_url`, which goes
+//! through the same destination validation as any proxied target. Repeat
+//! notifications of the same event are suppressed for [`NOTIFY_INTERVAL`] when
+//! the state store is available.
+
+use crate::{backend, limits, ssrf, state};
+use fastly::Request;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::Url;
+
+const NOTIFY_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    KeyBanned,
+    KeyExpired,
+    QuotaNearlyUsed,
+}
+
+impl Event {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Event::KeyBanned => "key_banned",
+            Event::KeyExpired => "key_expired",
+            Event::QuotaNearlyUsed => "quota_nearly_used",
+        }
+    }
+}
+
+/// Deliver an event to the tenant's webhook, ignoring delivery failures.
+pub fn notify(tenant_id: &str, webhook_url: &str, event: Event) {
+    let store = state::open();
+    let dedupe_key = format!("webhook.{}.{}", tenant_id, event.as_str());
+    if let Some(store) = &store {
+        if state::get::<u64>(store, &dedupe_key).is_some() {
+            return;
+        }
+    }
+
+    let Some(target) = Url::parse(webhook_url)
+        .ok()
+        .and_then(|url| ssrf::validate(url).ok())
+    else {
+        return;
+    };
+    if limits::reserve_request().is_err() {
+        return;
+    }
+    let Ok(backend) = backend::create(&target.hostname, target.port) else {
+        return;
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let body = serde_json::json!({
+        "event": event.as_str(),
+        "tenant": tenant_id,
+        "timestamp": now,
+    });
+    let req = Request::post(target.url.clone())
+        .with_header("Host", &target.hostname)
+        .with_header("Content-Type", "application/json")
+        .with_body(body.to_string())
+        .with_pass(true);
+
+    let delivered = req
+        .send(backend.name())
+        .is_ok_and(|resp| resp.get_status().is_success());
+    if let (true, Some(store)) = (delivered, &store) {
+        state::put(store, &dedupe_key, &now, Some(NOTIFY_INTERVAL));
+    }
+}
@@ -0,0 +1,74 @@
+//! WebSocket upgrades handed off to the origin.
+//!
+//! An upgrade request goes through the same authentication, validation and
+//! header rules as any other, then instead of being sent it's handed off to
+//! the origin's backend: through the Fanout proxy by default, or straight to
+//! the origin on services with the WebSockets feature. The connection then
+//! carries on outside this program, which sends no response of its own.
+
+use crate::errors::{Code, Problem};
+use fastly::http::StatusCode;
+use fastly::{Request, Response};
+use serde::Deserialize;
+use std::sync::Mutex;
+
+/// How upgrade requests reach the origin.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Handoff {
+    /// Through Fanout, which needs the Fanout feature on the service.
+    #[default]
+    Fanout,
+    /// Directly, which needs the WebSockets feature on the service.
+    Passthrough,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct WebSockets {
+    pub handoff: Handoff,
+}
+
+/// Whether the current request was handed off, so no response may be sent.
+static HANDED_OFF: Mutex<bool> = Mutex::new(false);
+
+/// Whether a request asks to upgrade to a WebSocket.
+pub fn is_upgrade(req: &Request) -> bool {
+    let upgrade = req
+        .get_header_str("Upgrade")
+        .is_some_and(|upgrade| upgrade.trim().eq_ignore_ascii_case("websocket"));
+    let connection = req.get_header_all_str("Connection").iter().any(|value| {
+        value
+            .split(',')
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+    });
+    upgrade && connection
+}
+
+/// Hand an upgrade request off to the backend.
+///
+/// The hop-by-hop headers that make it an upgrade, removed with the rest,
+/// are put back first. Returns a placeholder response to record the request
+/// by, or an error response if the handoff failed.
+pub fn handoff(mut req: Request, backend: &str, websockets: WebSockets) -> Response {
+    req.set_header("Connection", "Upgrade");
+    req.set_header("Upgrade", "websocket");
+    let handed_off = match websockets.handoff {
+        Handoff::Fanout => req.handoff_fanout(backend),
+        Handoff::Passthrough => req.handoff_websocket(backend),
+    };
+    match handed_off {
+        Ok(()) => {
+            if let Ok(mut flag) = HANDED_OFF.lock() {
+                *flag = true;
+            }
+            Response::from_status(StatusCode::SWITCHING_PROTOCOLS)
+        }
+        Err(e) => Problem::new(Code::WebsocketHandoffFailed, e.to_string()).into_response(),
+    }
+}
+
+/// Whether the request was handed off, and no response may be sent for it.
+pub fn handed_off() -> bool {
+    HANDED_OFF.lock().is_ok_and(|flag| *flag)
+}
@@ -0,0 +1,161 @@
+//! Tests of the `/admin` API, which share the state store with other requests.
+
+use compute_dynbackends_dev::forward;
+use compute_dynbackends_dev::trace::TraceContext;
+use fastly::http::{Method, StatusCode};
+use fastly::{Request, Response};
+use serde_json::Value;
+
+/// The admin key in tests/viceroy.toml.
+const ADMIN_KEY: &str = "admin-testing";
+
+fn handle(req: Request) -> Response {
+    let trace = TraceContext::from_request(&req);
+    forward::handle(req, "test", &trace, None).expect("handle returns a response")
+}
+
+fn admin(method: Method, path: &str) -> Request {
+    Request::new(method, format!("http://proxy.test{}", path)).with_header("X-Admin-Key", ADMIN_KEY)
+}
+
+fn json(resp: &mut Response) -> Value {
+    serde_json::from_slice(&resp.take_body_bytes()).expect("a JSON body")
+}
+
+#[test]
+fn requires_the_admin_key() {
+    let resp = handle(Request::get("http://proxy.test/admin/config"));
+    assert_eq!(resp.get_status(), StatusCode::FORBIDDEN);
+    // Tenant credentials aren't enough
+    let resp = handle(Request::get("http://proxy.test/admin/config?key=testing"));
+    assert_eq!(resp.get_status(), StatusCode::FORBIDDEN);
+    let resp = handle(
+        Request::get("http://proxy.test/admin/config").with_header("X-Admin-Key", "testing"),
+    );
+    assert_eq!(resp.get_status(), StatusCode::FORBIDDEN);
+}
+
+#[test]
+fn shows_the_effective_config() {
+    let mut resp = handle(admin(Method::GET, "/admin/config"));
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    let body = json(&mut resp);
+    assert_eq!(body["proxy"]["allowed_hosts"][0], "origin.example");
+    assert_eq!(body["proxy"]["features"]["stats"], false);
+    assert_eq!(body["proxy"]["timeouts"]["connect_secs"], 10.0);
+}
+
+#[test]
+fn toggles_maintenance_mode() {
+    let mut resp =
+        handle(admin(Method::PUT, "/admin/maintenance").with_body(r#"{"enabled": true}"#));
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    let body = json(&mut resp);
+    assert_eq!(body["enabled"], true);
+    assert_eq!(body["configured"], false);
+    assert_eq!(body["switched_on"], true);
+
+    let mut resp = handle(Request::get(
+        "http://proxy.test/?key=testing&url=https://origin.example/",
+    ));
+    assert_eq!(resp.get_status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(resp.get_header_str("Retry-After"), Some("120"));
+    assert_eq!(json(&mut resp)["code"], "maintenance");
+    // Browsers get the configured page
+    let mut resp = handle(
+        Request::get("http://proxy.test/?key=testing&url=https://origin.example/")
+            .with_header("Accept", "text/html,application/xhtml+xml;q=0.9"),
+    );
+    assert_eq!(resp.get_status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(resp.take_body_str(), "<h1>Back soon</h1>");
+    // The admin API stays up
+    let resp = handle(admin(Method::GET, "/admin/maintenance"));
+    assert_eq!(resp.get_status(), StatusCode::OK);
+
+    let resp = handle(admin(Method::PUT, "/admin/maintenance").with_body(r#"{"enabled": false}"#));
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    let resp = handle(Request::get(
+        "http://proxy.test/?key=testing&url=https://origin.example/",
+    ));
+    assert_eq!(resp.get_status(), StatusCode::OK);
+}
+
+#[test]
+fn lists_proxied_destinations() {
+    use compute_dynbackends_dev::{destinations, stats};
+    let targets = [
+        "https://origin.example/audited",
+        "https://127.0.0.1/audited",
+    ];
+    for (index, target) in targets.iter().enumerate() {
+        let url = format!("http://proxy.test/?key=testing&url={}", target);
+        let resp = handle(Request::get(url));
+        let outcome = stats::Outcome::of(&resp);
+        let request_id = format!("audited-{}", index);
+        destinations::emit(
+            &request_id,
+            Some("audit-key"),
+            None,
+            &stats::take(),
+            &outcome,
+        );
+    }
+
+    let mut resp = handle(admin(Method::GET, "/admin/audit?key_id=audit-key&limit=2"));
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    let body = json(&mut resp);
+    let records = body["destinations"].as_array().expect("a list of records");
+    assert_eq!(records.len(), 2);
+    for record in records {
+        assert_eq!(record["key_id"], "audit-key");
+        match record["target_url"].as_str() {
+            Some("https://origin.example/audited") => {
+                assert_eq!(record["decision"], "allowed");
+                assert_eq!(record["status"], 200);
+            }
+            Some("https://127.0.0.1/audited") => {
+                assert_eq!(record["decision"], "refused");
+                assert_eq!(record["status"], 403);
+            }
+            other => panic!("unexpected target {:?}", other),
+        }
+    }
+    let mut resp = handle(admin(Method::GET, "/admin/audit?key_id=someone-else"));
+    assert_eq!(json(&mut resp)["destinations"], serde_json::json!([]));
+}
+
+#[test]
+fn flushes_circuits() {
+    let mut resp = handle(admin(Method::DELETE, "/admin/circuits/origin.example"));
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    assert_eq!(json(&mut resp)["host"], "origin.example");
+}
+
+#[test]
+fn validates_parameters() {
+    let resp = handle(admin(Method::POST, "/admin/purge"));
+    assert_eq!(resp.get_status(), StatusCode::BAD_REQUEST);
+    let resp = handle(admin(Method::POST, "/admin/purge?url=not-a-url"));
+    assert_eq!(resp.get_status(), StatusCode::BAD_REQUEST);
+    let resp = handle(admin(Method::GET, "/admin/errors?limit=0"));
+    assert_eq!(resp.get_status(), StatusCode::BAD_REQUEST);
+    let resp = handle(admin(Method::GET, "/admin/errors"));
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    let resp = handle(admin(Method::GET, "/admin/nothing"));
+    assert_eq!(resp.get_status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn purges_a_single_url() {
+    let mut resp = handle(admin(
+        Method::POST,
+        "/admin/purge?url=https%3A%2F%2Forigin.example%2Fstyles.css",
+    ));
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    let body = json(&mut resp);
+    assert_eq!(body["url"], "https://origin.example/styles.css");
+    assert_eq!(body["purged"], true);
+    let keys = body["cache_keys"].as_array().expect("the purged keys");
+    assert_eq!(keys.len(), 3);
+    assert!(keys.contains(&Value::from("GET br https://origin.example/styles.css")));
+}
@@ -0,0 +1,85 @@
+//! End-to-end tests of the requests [`forward::handle`] sends besides the
+//! one to the client's target: mirrored copies, fallbacks and redirect hops,
+//! and the URLs of a batch. They fetch from the mock origin in a binary of
+//! their own, so they have a backend budget of their own.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use compute_dynbackends_dev::trace::TraceContext;
+use compute_dynbackends_dev::{batch, config, forward, mirror, tenant};
+use fastly::{Request, Response};
+use serde_json::Value;
+
+/// The API key in tests/viceroy.toml.
+const KEY: &str = "testing";
+
+fn handle(req: Request) -> Response {
+    let trace = TraceContext::from_request(&req);
+    forward::handle(req, "test", &trace, None).expect("handle returns a response")
+}
+
+fn proxied(key: &str, target: &str) -> Request {
+    let mut url = url::Url::parse("http://proxy.test/").unwrap();
+    url.query_pairs_mut()
+        .append_pair("key", key)
+        .append_pair("url", target);
+    Request::get(url)
+}
+
+fn json(resp: &mut Response) -> Value {
+    serde_json::from_slice(&resp.take_body_bytes()).expect("a JSON body")
+}
+
+/// The mock origin's echo of the last request it got for `host`.
+fn last(host: &str) -> Value {
+    let target = format!("https://origin.example/last?host={}", host);
+    json(&mut handle(proxied(KEY, &target)))
+}
+
+#[test]
+fn gives_mirrors_and_fallbacks_only_their_own_credentials() {
+    let mut resp = handle(proxied(
+        "guarded.guarded-testing",
+        "https://origin.example/status/503",
+    ));
+    mirror::finish("test", None);
+    assert_eq!(resp.get_header_str("X-Proxy-Fallback"), Some("1"));
+    let fallback = json(&mut resp);
+    assert_eq!(fallback["path"], "/fallback");
+    assert_eq!(fallback["headers"]["host"], "backup.example");
+    assert!(fallback["headers"].get("x-api-key").is_none());
+
+    let shadow = last("shadow.example");
+    assert_eq!(shadow["path"], "/status/503");
+    assert_eq!(shadow["headers"]["x-proxy-mirror"], "1");
+    assert!(shadow["headers"].get("x-api-key").is_none());
+
+    let primary = last("origin.example");
+    assert_eq!(primary["path"], "/status/503");
+    assert_eq!(primary["headers"]["x-api-key"], "origin-secret");
+}
+
+#[test]
+fn sends_batch_urls_like_the_clients_own_target() {
+    // tests/viceroy.toml turns batches off, so they're asked for directly
+    config::load().expect("the proxy's settings load");
+    let tenant = tenant::load("guarded").expect("the tenant loads");
+    let mut req = Request::post("http://proxy.test/batch")
+        .with_body(r#"["https://origin.example/echo", "https://origin.example/admin"]"#);
+    let results = json(&mut batch::respond(&mut req, "guarded", &tenant));
+
+    let body = STANDARD
+        .decode(results[0]["body_b64"].as_str().expect("a body"))
+        .expect("base64");
+    let echo: Value = serde_json::from_slice(&body).expect("the origin's echo");
+    assert_eq!(echo["headers"]["x-api-key"], "origin-secret");
+    let signature = echo["headers"]["x-signature-sha256"].as_str();
+    assert_eq!(signature.map(str::len), Some(64), "{}", echo);
+    assert_eq!(echo["headers"]["x-proxy-loop"], "dynserv");
+    let via = echo["headers"]["via"].as_str().unwrap_or_default();
+    assert!(via.starts_with("1.1 "), "{}", via);
+
+    // The destination policy's no-admin rule
+    assert!(results[1]["error"].is_string(), "{}", results[1]);
+    assert!(results[1].get("status").is_none());
+}
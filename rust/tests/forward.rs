@@ -0,0 +1,1014 @@
+//! End-to-end tests of [`forward::handle`], fetching from the mock origin
+//! that tests/viceroy.sh starts.
+
+use compute_dynbackends_dev::trace::TraceContext;
+use compute_dynbackends_dev::{
+    cache, config, fingerprint, forward, html, images, latency, metering, shielding, state, stats,
+};
+use fastly::http::{Method, StatusCode};
+use fastly::image_optimizer::Format;
+use fastly::{Request, Response};
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The API key in tests/viceroy.toml.
+const KEY: &str = "testing";
+
+fn handle(req: Request) -> Response {
+    let trace = TraceContext::from_request(&req);
+    forward::handle(req, "test", &trace, None).expect("handle returns a response")
+}
+
+fn proxied(target: &str) -> Request {
+    let mut url = url::Url::parse("http://proxy.test/").unwrap();
+    url.query_pairs_mut()
+        .append_pair("key", KEY)
+        .append_pair("url", target);
+    Request::get(url)
+}
+
+fn json(resp: &mut Response) -> Value {
+    serde_json::from_slice(&resp.take_body_bytes()).expect("a JSON body")
+}
+
+#[test]
+fn refuses_missing_or_wrong_keys() {
+    let resp = handle(Request::get(
+        "http://proxy.test/?url=https://origin.example/",
+    ));
+    assert_eq!(resp.get_status(), StatusCode::FORBIDDEN);
+    let resp = handle(Request::get(
+        "http://proxy.test/?key=wrong&url=https://origin.example/",
+    ));
+    assert_eq!(resp.get_status(), StatusCode::FORBIDDEN);
+}
+
+#[test]
+fn requires_a_valid_https_target() {
+    let resp = handle(Request::get(format!("http://proxy.test/?key={}", KEY)));
+    assert_eq!(resp.get_status(), StatusCode::BAD_REQUEST);
+
+    let mut resp = handle(proxied("not a url"));
+    assert_eq!(resp.get_status(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        resp.get_header_str("Content-Type"),
+        Some("application/problem+json")
+    );
+    let problem = json(&mut resp);
+    assert_eq!(problem["code"], "invalid_url");
+    assert_eq!(problem["status"], 400);
+    assert_eq!(problem["type"], "urn:dynserv:problem:invalid_url");
+
+    let mut resp = handle(proxied("http://origin.example/"));
+    assert_eq!(resp.get_status(), StatusCode::BAD_REQUEST);
+    assert_eq!(json(&mut resp)["code"], "https_required");
+}
+
+#[test]
+fn refuses_private_targets_and_fallbacks() {
+    for target in ["https://127.0.0.1/", "https://[::1]/", "https://localhost/"] {
+        let resp = handle(proxied(target));
+        assert_eq!(resp.get_status(), StatusCode::FORBIDDEN, "{}", target);
+    }
+    let mut req = proxied("https://origin.example/");
+    req.get_url_mut()
+        .query_pairs_mut()
+        .append_pair("fallback_url", "https://169.254.169.254/");
+    let resp = handle(req);
+    assert_eq!(resp.get_status(), StatusCode::FORBIDDEN);
+}
+
+#[test]
+fn forwards_to_the_target() {
+    let mut req = proxied("https://origin.example/echo?q=1&q=2");
+    req.set_header("X-Custom", "kept");
+    req.set_header("X-Forwarded-For", "203.0.113.1");
+    let mut resp = handle(req);
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    let echo = json(&mut resp);
+    assert_eq!(echo["method"], "GET");
+    assert_eq!(echo["path"], "/echo?q=1&q=2");
+    assert_eq!(echo["headers"]["host"], "origin.example");
+    assert_eq!(echo["headers"]["x-custom"], "kept");
+    assert!(echo["headers"].get("x-forwarded-for").is_none());
+    assert_eq!(echo["headers"]["x-proxy-loop"], "dynserv");
+    let via = echo["headers"]["via"].as_str().unwrap_or_default();
+    assert!(via.starts_with("1.1 "), "{}", via);
+    assert_eq!(resp.get_header_str("Via"), Some(via));
+}
+
+#[test]
+fn refuses_proxy_loops() {
+    // A target on the domain the proxy was reached on
+    let mut resp = handle(proxied("https://proxy.test/elsewhere"));
+    assert_eq!(resp.get_status(), StatusCode::LOOP_DETECTED);
+    assert_eq!(json(&mut resp)["code"], "loop_detected");
+    // A request the proxy already sent on
+    for (name, value) in [("X-Proxy-Loop", "other, dynserv"), ("Via", "1.1 dynserv")] {
+        let resp = handle(proxied("https://origin.example/").with_header(name, value));
+        assert_eq!(resp.get_status(), StatusCode::LOOP_DETECTED, "{}", name);
+    }
+    let resp = handle(proxied("https://origin.example/").with_header("X-Proxy-Loop", "other"));
+    assert_eq!(resp.get_status(), StatusCode::OK);
+}
+
+#[test]
+fn refuses_clients_whose_tls_fingerprint_is_blocked() {
+    const JA3: &str = "e7d705a3286e19ea42f587b344ee6865";
+    const JA4: &str = "t13d1516h2_8daaf6152771_02713d6af862";
+    let blocked = serde_json::json!({"blocked": [JA3.to_uppercase(), format!(" {} ", JA4)]});
+    let settings: fingerprint::Settings = serde_json::from_value(blocked).unwrap();
+    let client = |ja3: &str, ja4: &str| fingerprint::Fingerprint {
+        ja3: Some(ja3.to_string()),
+        ja4: Some(ja4.to_string()),
+    };
+    // JA3 is hex, so its case doesn't matter; JA4's does
+    let mut resp = settings
+        .check(&client(JA3, "t13d1516h2_other"))
+        .expect("a refusal");
+    assert_eq!(resp.get_status(), StatusCode::FORBIDDEN);
+    let problem = json(&mut resp);
+    assert_eq!(problem["code"], "fingerprint_blocked");
+    assert_eq!(problem["fingerprint"], JA3.to_uppercase());
+    assert!(settings.check(&client("0123456789abcdef", JA4)).is_some());
+    let shouted = client("0123456789abcdef", &JA4.to_uppercase());
+    assert!(settings.check(&shouted).is_none());
+
+    // Plain HTTP clients have no fingerprint to block
+    assert!(settings.check(&fingerprint::Fingerprint::NONE).is_none());
+}
+
+#[test]
+fn takes_the_client_address_from_hops_signed_by_an_edge_pop() {
+    // A shield backend that doesn't exist, so requests are never handed on
+    let settings: shielding::Settings =
+        serde_json::from_str(r#"{"backend": "no-such-shield"}"#).unwrap();
+    let hop = |ip: &str, ttl: i64, secret: &str| {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let payload = format!("{},{}", ip, now + ttl);
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(payload.as_bytes());
+        let signature = hex::encode(mac.finalize().into_bytes());
+        Request::get("http://proxy.test/").with_header(
+            shielding::SHIELD_HEADER,
+            format!("{},{}", payload, signature),
+        )
+    };
+    let mut req = hop("203.0.113.7", 60, "shielding-testing");
+    shielding::reset();
+    assert!(settings.forward(&mut req).is_none());
+    assert_eq!(
+        shielding::client_ip(&req),
+        Some("203.0.113.7".parse().unwrap())
+    );
+
+    // Forged and expired hops are ignored
+    for mut req in [
+        hop("203.0.113.7", 60, "forged"),
+        hop("203.0.113.7", -1, "shielding-testing"),
+    ] {
+        shielding::reset();
+        assert!(settings.forward(&mut req).is_none());
+        assert_eq!(shielding::forwarded_client(), None);
+    }
+}
+
+#[test]
+fn passes_the_deadline_on_and_gives_up_after_it() {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    // Longer than the origin timeouts, which would otherwise be cut to fit
+    let mut req = proxied("https://origin.example/echo");
+    req.set_header("X-Request-Deadline", (now + 50_000).to_string());
+    let mut resp = handle(req);
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    let echo = json(&mut resp);
+    let deadline: u128 = echo["headers"]["x-request-deadline"]
+        .as_str()
+        .and_then(|deadline| deadline.parse().ok())
+        .expect("a deadline");
+    assert!(deadline > now && deadline <= now + 50_000, "{}", deadline);
+
+    let mut req = proxied("https://origin.example/echo");
+    req.set_header("X-Request-Deadline", (now - 1000).to_string());
+    let mut resp = handle(req);
+    assert_eq!(resp.get_status(), StatusCode::GATEWAY_TIMEOUT);
+    assert_eq!(json(&mut resp)["code"], "deadline_exceeded");
+}
+
+#[test]
+fn forwards_request_bodies() {
+    let mut req = proxied("https://origin.example/submit");
+    req.set_method("POST");
+    req.set_body("payload");
+    let mut resp = handle(req);
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    let echo = json(&mut resp);
+    assert_eq!(echo["method"], "POST");
+    assert_eq!(echo["body"], "payload");
+}
+
+#[test]
+fn passes_origin_errors_through() {
+    let resp = handle(proxied("https://origin.example/status/404"));
+    assert_eq!(resp.get_status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn dry_runs_describe_without_fetching() {
+    let mut req = proxied("https://origin.example/status/500");
+    req.get_url_mut()
+        .query_pairs_mut()
+        .append_pair("dry_run", "1");
+    let mut resp = handle(req);
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    let plan = json(&mut resp);
+    assert!(plan.to_string().contains("origin.example"), "{}", plan);
+}
+
+#[test]
+fn injects_headers_from_parameters_except_the_proxys_own() {
+    let inject = |name: &str| {
+        let mut req = proxied("https://origin.example/echo");
+        req.get_url_mut()
+            .query_pairs_mut()
+            .append_pair(&format!("h_{}", name), "injected")
+            .append_pair("dry_run", "1");
+        handle(req)
+    };
+    let mut resp = inject("X-Custom");
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    let plan = json(&mut resp);
+    assert!(plan.to_string().contains("x-custom"), "{}", plan);
+
+    let own = [
+        "Host",
+        "X-Proxy-Signature",
+        "X-Proxy-Loop",
+        "Via",
+        "X-Request-Deadline",
+        "grpc-timeout",
+    ];
+    for name in own {
+        let mut resp = inject(name);
+        assert_eq!(resp.get_status(), StatusCode::BAD_REQUEST, "{}", name);
+        assert_eq!(json(&mut resp)["code"], "invalid_parameter");
+    }
+}
+
+#[test]
+fn relays_and_streams_announced_uploads() {
+    let upload = |expect: &str| {
+        let mut req = proxied("https://origin.example/upload");
+        req.set_method(fastly::http::Method::POST);
+        req.set_header("Expect", expect);
+        req.set_body("a large upload");
+        handle(req)
+    };
+    let mut resp = upload("100-continue");
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    let echo = json(&mut resp);
+    assert_eq!(echo["headers"]["expect"], "100-continue");
+    assert_eq!(echo["body"], "a large upload");
+
+    let mut resp = upload("something-else");
+    assert_eq!(resp.get_status(), StatusCode::EXPECTATION_FAILED);
+    assert_eq!(json(&mut resp)["code"], "expectation_failed");
+}
+
+#[test]
+fn pins_targets_to_a_public_address_for_tenants_that_allow_it() {
+    let request = |key: &str, resolve: &str| {
+        let mut url = url::Url::parse("http://proxy.test/").unwrap();
+        url.query_pairs_mut()
+            .append_pair("key", key)
+            .append_pair("url", "https://origin.example/echo")
+            .append_pair("resolve", resolve)
+            .append_pair("dry_run", "1");
+        handle(Request::get(url))
+    };
+    let mut resp = request(KEY, "origin.example:93.184.216.34");
+    assert_eq!(resp.get_status(), StatusCode::FORBIDDEN);
+    assert_eq!(json(&mut resp)["code"], "dns_override_not_allowed");
+    let mut resp = request(KEY, "origin.example:10.0.0.1");
+    assert_eq!(resp.get_status(), StatusCode::FORBIDDEN);
+    assert_eq!(json(&mut resp)["code"], "ssrf_blocked");
+    let resp = request(KEY, "elsewhere.example:93.184.216.34");
+    assert_eq!(resp.get_status(), StatusCode::BAD_REQUEST);
+
+    let mut resp = request("trusted.trusted-testing", "origin.example:93.184.216.34");
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    let plan = json(&mut resp);
+    assert_eq!(plan["backend"]["host"], "93.184.216.34");
+    assert_eq!(plan["backend"]["tls"]["sni_hostname"], "origin.example");
+    assert_eq!(
+        plan["backend"]["tls"]["check_certificate"],
+        "origin.example"
+    );
+}
+
+#[test]
+fn sends_fronted_targets_a_host_header_of_the_tenants_choosing() {
+    let request = |key: &str, host_header: &str| {
+        let mut url = url::Url::parse("http://proxy.test/").unwrap();
+        url.query_pairs_mut()
+            .append_pair("key", key)
+            .append_pair("url", "https://origin.example/echo")
+            .append_pair("host_header", host_header)
+            .append_pair("dry_run", "1");
+        handle(Request::get(url))
+    };
+    let mut resp = request(KEY, "variant.example");
+    assert_eq!(resp.get_status(), StatusCode::FORBIDDEN);
+    assert_eq!(json(&mut resp)["code"], "tls_override_not_allowed");
+    for (host_header, code) in [
+        ("localhost", "ssrf_blocked"),
+        ("elsewhere.example", "host_not_allowed"),
+        ("10.0.0.1", "invalid_parameter"),
+    ] {
+        let mut resp = request("trusted.trusted-testing", host_header);
+        assert_eq!(json(&mut resp)["code"], code);
+    }
+
+    let mut resp = request("trusted.trusted-testing", "variant.example");
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    let plan = json(&mut resp);
+    assert_eq!(plan["backend"]["host"], "origin.example");
+    assert_eq!(plan["backend"]["host_header"], "variant.example");
+    assert_eq!(plan["backend"]["tls"]["sni_hostname"], "origin.example");
+    let headers = plan["headers"].as_array().unwrap();
+    assert!(headers.contains(&serde_json::json!(["host", "variant.example"])));
+}
+
+#[test]
+fn refuses_hosts_off_the_allowlist() {
+    let mut resp = handle(proxied("https://elsewhere.example/"));
+    assert_eq!(resp.get_status(), StatusCode::FORBIDDEN);
+    let problem = json(&mut resp);
+    assert_eq!(problem["code"], "host_not_allowed");
+    assert_eq!(
+        problem["detail"],
+        "Target host is not on the proxy's allowlist"
+    );
+}
+
+#[test]
+fn matches_policy_paths_however_they_are_spelled() {
+    for path in [
+        "/admin/users",
+        "/%61dmin/users",
+        "/x/%2E%2E/admin",
+        "/./admin",
+    ] {
+        let mut resp = handle(proxied(&format!("https://origin.example{}", path)));
+        assert_eq!(resp.get_status(), StatusCode::FORBIDDEN, "{}", path);
+        assert_eq!(json(&mut resp)["code"], "policy_denied", "{}", path);
+    }
+}
+
+#[test]
+fn layers_environment_settings_over_the_proxy_entry() {
+    // "proxy" turns stats off and "proxy.local" turns batches off
+    for path in ["/stats", "/metrics", "/batch"] {
+        let mut resp = handle(Request::get(format!(
+            "http://proxy.test{}?key={}",
+            path, KEY
+        )));
+        assert_eq!(resp.get_status(), StatusCode::NOT_FOUND, "{}", path);
+        assert_eq!(json(&mut resp)["code"], "endpoint_disabled");
+    }
+    let resp = handle(Request::get(format!(
+        "http://proxy.test/debug/echo?key={}",
+        KEY
+    )));
+    assert_eq!(resp.get_status(), StatusCode::OK);
+}
+
+#[test]
+fn renders_the_tenants_error_pages() {
+    use compute_dynbackends_dev::state;
+    let store = state::open().expect("the state store is linked");
+    let template = serde_json::json!({
+        "content_type": "text/html",
+        "body": "<p>{{status}} for {{target_host}}: {{reason}} ({{request_id}})</p>",
+    });
+    assert!(state::put(
+        &store,
+        "error_pages.default.4xx",
+        &template,
+        None
+    ));
+
+    let mut resp = handle(proxied("https://elsewhere.example/"));
+    assert_eq!(resp.get_status(), StatusCode::FORBIDDEN);
+    assert_eq!(
+        resp.get_content_type()
+            .map(|mime| mime.to_string())
+            .as_deref(),
+        Some("text/html")
+    );
+    assert_eq!(
+        resp.take_body_str(),
+        "<p>403 for elsewhere.example: Target host is not on the proxy&#39;s allowlist (test)</p>"
+    );
+    // The origin's own errors pass through
+    let mut resp = handle(proxied("https://origin.example/status/404"));
+    assert_eq!(resp.get_status(), StatusCode::NOT_FOUND);
+    assert!(!resp.take_body_str().contains("<p>"));
+
+    store
+        .delete("error_pages.default.4xx")
+        .expect("the template is removed");
+}
+
+#[test]
+fn mints_signed_urls_that_stand_in_for_the_key() {
+    let mut url = url::Url::parse("http://proxy.test/sign").unwrap();
+    url.query_pairs_mut()
+        .append_pair("key", KEY)
+        .append_pair("url", "https://origin.example/echo")
+        .append_pair("ttl", "60");
+    let mut resp = handle(Request::get(url));
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    let signed = json(&mut resp);
+    let link = signed["url"].as_str().expect("a signed URL");
+    assert!(!link.contains("key="), "{}", link);
+
+    let mut resp = handle(Request::get(link));
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    assert_eq!(json(&mut resp)["path"], "/echo");
+
+    // Any change to the link breaks its signature
+    let resp = handle(Request::get(format!("{}&fields=path", link)));
+    assert_eq!(resp.get_status(), StatusCode::FORBIDDEN);
+    // A link can't mint others
+    let resent = link.replacen("http://proxy.test/?", "http://proxy.test/sign?", 1);
+    let resp = handle(Request::get(resent));
+    assert_eq!(resp.get_status(), StatusCode::FORBIDDEN);
+}
+
+#[test]
+fn signs_every_parameter_of_hmac_requests() {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        .to_string();
+    let mut mac = Hmac::<Sha256>::new_from_slice(b"trusted-hmac-testing").unwrap();
+    let signed = "/?dry_run=1&url=https%3A%2F%2Forigin.example%2Fecho";
+    mac.update(format!("{}\nGET\n{}", timestamp, signed).as_bytes());
+    let signature = hex::encode(mac.finalize().into_bytes());
+    let request = |query: &str| {
+        let req = Request::get(format!("http://proxy.test/?{}", query))
+            .with_header("X-Proxy-Key-Id", "trusted")
+            .with_header("X-Proxy-Timestamp", &timestamp)
+            .with_header("X-Proxy-Signature", &signature);
+        handle(req)
+    };
+    // The parameters are signed sorted, whatever order they're sent in
+    let resp = request("url=https%3A%2F%2Forigin.example%2Fecho&dry_run=1");
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    let resp = request("url=https%3A%2F%2Forigin.example%2Fecho&dry_run=1&fbto=1");
+    assert_eq!(resp.get_status(), StatusCode::FORBIDDEN);
+    let resp = request("url=https%3A%2F%2Forigin.example%2Fother&dry_run=1");
+    assert_eq!(resp.get_status(), StatusCode::FORBIDDEN);
+}
+
+#[test]
+fn enforces_daily_quotas_per_key() {
+    let limited = |target: &str| {
+        let mut url = url::Url::parse("http://proxy.test/").unwrap();
+        url.query_pairs_mut()
+            .append_pair("key", "limited.limited-testing")
+            .append_pair("url", target);
+        handle(Request::get(url))
+    };
+    for remaining in ["1", "0"] {
+        let resp = limited("https://origin.example/echo");
+        assert_eq!(resp.get_status(), StatusCode::OK);
+        assert_eq!(resp.get_header_str("X-RateLimit-Limit"), Some("2"));
+        assert_eq!(
+            resp.get_header_str("X-RateLimit-Remaining"),
+            Some(remaining)
+        );
+        assert!(resp.contains_header("X-RateLimit-Reset"));
+    }
+    let mut resp = limited("https://origin.example/echo");
+    assert_eq!(resp.get_status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.get_header_str("X-RateLimit-Remaining"), Some("0"));
+    assert!(resp.contains_header("Retry-After"));
+    assert_eq!(json(&mut resp)["code"], "quota_exceeded");
+
+    // Keys without a quota aren't limited
+    let resp = handle(proxied("https://origin.example/echo"));
+    assert!(!resp.contains_header("X-RateLimit-Limit"));
+}
+
+#[test]
+fn adds_up_each_keys_usage_over_the_metering_window() {
+    // lib::serve meters requests once they're sent, so it's done directly
+    config::load().expect("the proxy's settings load");
+    let sample = |cache_hit| stats::Sample {
+        tenant: Some("metered".to_string()),
+        cache_hit,
+        ..Default::default()
+    };
+    let outcome = stats::Outcome {
+        status: StatusCode::OK,
+        bytes_out: 1200,
+        latency: Duration::from_millis(5),
+    };
+    metering::emit(Some("metered-key"), &sample(false), &outcome);
+    metering::emit(Some("metered-key"), &sample(true), &outcome);
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let key = format!("metering.{}.metered-key", now / 3600 * 3600);
+    let store = state::open().expect("the state store");
+    let tally: Value = state::get(&store, &key).expect("the window's tally");
+    assert_eq!(tally["tenant"], "metered");
+    assert_eq!(tally["requests"], 2);
+    assert_eq!(tally["cache_hits"], 1);
+    // Cache hits aren't fetched from the origin
+    assert_eq!(tally["origin_bytes"], 1200);
+}
+
+#[test]
+fn flushes_origin_latency_after_the_first_request_then_every_interval() {
+    // lib::serve observes latencies once responses are sent, so it's done directly
+    config::load().expect("the proxy's settings load");
+    let sample = stats::Sample {
+        tenant: Some("timed".to_string()),
+        origin: Some("origin.example".to_string()),
+        origin_latency: Some(Duration::from_millis(30)),
+        ..Default::default()
+    };
+    let outcome = stats::Outcome {
+        status: StatusCode::OK,
+        bytes_out: 0,
+        latency: Duration::from_millis(300),
+    };
+    let store = state::open().expect("the state store");
+    // The first request is flushed, and the second kept in memory until the
+    // next flush is due
+    for _ in 0..2 {
+        latency::observe(&sample, &outcome);
+        let origins = latency::aggregate(&store, "timed");
+        let hour = &origins["origin.example"].hour;
+        assert_eq!(hour.ttfb.counts, [1, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(hour.total.counts, [0, 0, 0, 1, 0, 0, 0, 0, 0]);
+        assert_eq!((hour.ttfb.ms_total, hour.total.ms_total), (30, 300));
+    }
+}
+
+#[test]
+fn applies_the_tenants_url_rules_in_order() {
+    let mut resp = handle(proxied("https://origin.example/private/keys"));
+    assert_eq!(resp.get_status(), StatusCode::FORBIDDEN);
+    let body = json(&mut resp);
+    assert_eq!(body["code"], "url_denied");
+    assert_eq!(body["rule"], 1);
+
+    let mut resp = handle(proxied("https://origin.example/echo?debug=1"));
+    assert_eq!(resp.get_status(), StatusCode::FORBIDDEN);
+    assert_eq!(json(&mut resp)["rule"], 2);
+
+    // An earlier allow wins over a later deny
+    let resp = handle(proxied("https://origin.example/private/status"));
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    let resp = handle(proxied("https://origin.example/privateer"));
+    assert_eq!(resp.get_status(), StatusCode::OK);
+
+    // Rules see the canonical path, however the target spells it
+    for path in ["/%70rivate/keys", "/public/../private/keys"] {
+        let mut resp = handle(proxied(&format!("https://origin.example{}", path)));
+        assert_eq!(json(&mut resp)["code"], "url_denied", "{}", path);
+    }
+}
+
+#[test]
+fn refuses_user_agents_the_tenants_bot_rules_match() {
+    let mut resp =
+        handle(proxied("https://origin.example/echo").with_header("User-Agent", "BadBot/2.0"));
+    assert_eq!(resp.get_status(), StatusCode::FORBIDDEN);
+    let body = json(&mut resp);
+    assert_eq!(body["code"], "bot_blocked");
+    assert_eq!(body["rule"], 0);
+
+    // Tarpitted requests are held before they're refused
+    let started = std::time::Instant::now();
+    let mut resp =
+        handle(proxied("https://origin.example/echo").with_header("User-Agent", "slowbot"));
+    assert!(started.elapsed() >= std::time::Duration::from_millis(200));
+    assert_eq!(json(&mut resp)["rule"], 1);
+
+    let resp =
+        handle(proxied("https://origin.example/echo").with_header("User-Agent", "Mozilla/5.0"));
+    assert_eq!(resp.get_status(), StatusCode::OK);
+}
+
+#[test]
+fn splits_clients_between_two_origins_by_cookie() {
+    let fetch = |cookie: &str| {
+        let mut url = url::Url::parse("http://proxy.test/").unwrap();
+        url.query_pairs_mut()
+            .append_pair("key", "split.split-testing")
+            .append_pair("url", "https://origin.example/echo");
+        let resp = handle(Request::get(url).with_header("Cookie", cookie));
+        assert_eq!(resp.get_status(), StatusCode::OK);
+        resp
+    };
+    // Clients are bucketed by a hash of their cookie, the same for every request
+    let mut affinity = String::new();
+    for (uid, variant, host) in [("0", "a", "origin.example"), ("1", "b", "variant.example")] {
+        let mut resp = fetch(&format!("uid={}", uid));
+        assert_eq!(resp.get_header_str("X-Proxy-Variant"), Some(variant));
+        if variant == "a" {
+            let set_cookie = resp.get_header_str("Set-Cookie").unwrap();
+            assert!(set_cookie.starts_with("dynserv_affinity=a.-."));
+            affinity = set_cookie.split(';').next().unwrap().to_string();
+        }
+        let echo = json(&mut resp);
+        assert_eq!(echo["headers"]["x-proxy-variant"], variant);
+        assert_eq!(echo["headers"]["host"], host);
+    }
+
+    // The affinity cookie keeps a client on its variant, and isn't forwarded
+    let mut resp = fetch(&format!("uid=1; {}", affinity));
+    assert_eq!(resp.get_header_str("X-Proxy-Variant"), Some("a"));
+    assert_eq!(resp.get_header_str("Set-Cookie"), None);
+    assert_eq!(json(&mut resp)["headers"]["cookie"], "uid=1");
+}
+
+#[test]
+fn announces_trailers_to_origins_and_clients_that_take_them() {
+    use fastly::experimental::BodyExt;
+    let mut req = proxied("https://origin.example/trailers");
+    req.set_method(fastly::http::Method::POST);
+    req.set_header("TE", "trailers");
+    req.set_header("Trailer", "X-Checksum");
+    let mut body = fastly::Body::from("upload");
+    body.append_trailer("X-Checksum", "5d41402a");
+    req.set_body(body);
+    let mut resp = handle(req);
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    // Viceroy drops the trailer fields themselves, so only their announcements arrive
+    assert_eq!(resp.get_header_str("Trailer"), Some("x-echo-length"));
+    let echo = json(&mut resp);
+    assert_eq!(echo["headers"]["te"], "trailers");
+    assert_eq!(echo["headers"]["trailer"], "X-Checksum");
+    assert_eq!(echo["body"], "upload");
+}
+
+#[test]
+fn replays_captured_requests_as_the_replaying_client() {
+    let mut url = url::Url::parse("http://proxy.test/").unwrap();
+    url.query_pairs_mut()
+        .append_pair("key", "trusted.trusted-testing")
+        .append_pair("url", "https://origin.example/echo?x=1")
+        .append_pair("h_X-Origin-Key", "secret")
+        .append_pair("capture", "1");
+    let mut req = Request::post(url.clone());
+    req.set_header("X-Test", "captured");
+    req.set_body("payload");
+    let mut resp = handle(req);
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    assert_eq!(json(&mut resp)["headers"]["x-origin-key"], "secret");
+    // The tests' request ID
+    assert_eq!(resp.get_header_str("X-Proxy-Capture"), Some("test"));
+
+    let replay = |id: &str| {
+        let url = format!(
+            "http://proxy.test/debug/replay/{}?key=trusted.trusted-testing",
+            id
+        );
+        handle(Request::get(url))
+    };
+    let mut resp = replay("test");
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    let echo = json(&mut resp);
+    assert_eq!(echo["method"], "POST");
+    assert_eq!(echo["path"], "/echo?x=1");
+    assert_eq!(echo["headers"]["x-test"], "captured");
+    assert_eq!(echo["body"], "payload");
+    // Injected headers often carry origin credentials, so they aren't kept
+    assert!(echo["headers"].get("x-origin-key").is_none());
+    let mut resp = replay("elsewhere");
+    assert_eq!(resp.get_status(), StatusCode::NOT_FOUND);
+    assert_eq!(json(&mut resp)["code"], "not_found");
+
+    // An announced upload still streams, so its body isn't kept to replay
+    let mut req = Request::post(url);
+    req.set_header("Expect", "100-continue");
+    req.set_body("a large upload");
+    let mut resp = handle(req);
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    assert_eq!(json(&mut resp)["body"], "a large upload");
+    let mut resp = replay("test");
+    assert_eq!(resp.get_status(), StatusCode::CONFLICT);
+    assert_eq!(json(&mut resp)["code"], "capture_incomplete");
+}
+
+#[test]
+fn answers_mocked_requests_from_the_tenants_fixtures() {
+    let trusted = |path: &str, target: &str| {
+        let mut url = url::Url::parse("http://proxy.test/").unwrap();
+        url.set_path(path);
+        url.query_pairs_mut()
+            .append_pair("key", "trusted.trusted-testing")
+            .append_pair("url", target);
+        url
+    };
+    let register = |fixtures: &str| {
+        let mut req = Request::put(trusted("/mocks", ""));
+        req.set_body(fixtures);
+        handle(req)
+    };
+    let resp = register(r#"[{"method": "GET", "url": "^https://[a-z"}]"#);
+    assert_eq!(resp.get_status(), StatusCode::BAD_REQUEST);
+    // Fixtures that couldn't be sent are refused, not kept
+    for invalid in [
+        r#"[{"url": "^https://", "headers": {"bad header": "x"}}]"#,
+        r#"[{"url": "^https://", "headers": {"x-ok": "line\u0000break"}}]"#,
+        r#"[{"url": "^https://", "status": 42}]"#,
+    ] {
+        let mut resp = register(invalid);
+        assert_eq!(resp.get_status(), StatusCode::BAD_REQUEST, "{}", invalid);
+        assert_eq!(json(&mut resp)["mock"], 0, "{}", invalid);
+    }
+    let resp = register(
+        r#"[{"method": "GET", "url": "^https://origin\\.example/mocked", "status": 201,
+            "headers": {"content-type": "text/plain"}, "body": "canned"}]"#,
+    );
+    assert_eq!(resp.get_status(), StatusCode::OK);
+
+    let mocked = |target: &str| {
+        let mut req = Request::get(trusted("/", target));
+        req.set_header("X-Proxy-Mock", "1");
+        handle(req)
+    };
+    let mut resp = mocked("https://origin.example/mocked?page=1");
+    assert_eq!(resp.get_status(), StatusCode::CREATED);
+    assert_eq!(resp.get_header_str("X-Proxy-Mock"), Some("1"));
+    assert_eq!(resp.take_body_str(), "canned");
+    let resp = mocked("https://origin.example/unmocked");
+    assert_eq!(resp.get_status(), StatusCode::NOT_FOUND);
+
+    let mut req = proxied("https://origin.example/mocked");
+    req.set_header("X-Proxy-Mock", "1");
+    let mut resp = handle(req);
+    assert_eq!(resp.get_status(), StatusCode::FORBIDDEN);
+    assert_eq!(json(&mut resp)["code"], "mocks_not_allowed");
+}
+
+#[test]
+fn injects_the_tenants_faults_into_requests_that_ask_for_them() {
+    let mut url = url::Url::parse("http://proxy.test/").unwrap();
+    url.query_pairs_mut()
+        .append_pair("key", "flaky.flaky-testing")
+        .append_pair("url", "https://origin.example/echo");
+    // Faulted requests are held, then answered in the origin's place
+    let started = std::time::Instant::now();
+    let resp = handle(Request::get(url).with_header("X-Proxy-Chaos", "1"));
+    assert!(started.elapsed() >= std::time::Duration::from_millis(50));
+    assert_eq!(resp.get_status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(
+        resp.get_header_str("X-Proxy-Chaos"),
+        Some("latency, status")
+    );
+}
+
+#[test]
+fn backs_off_from_origins_that_ask_until_their_retry_after_passes() {
+    let shielded = |path: &str| {
+        let mut url = url::Url::parse("http://proxy.test/").unwrap();
+        url.query_pairs_mut()
+            .append_pair("key", "flaky.flaky-testing")
+            .append_pair("url", &format!("https://throttled.example{}", path));
+        handle(Request::get(url))
+    };
+    let resp = shielded("/status/503?retry_after=120");
+    assert_eq!(resp.get_status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(resp.get_header_str("Retry-After"), Some("120"));
+
+    // The window holds for every path on the host, answered without the origin
+    let mut resp = shielded("/echo");
+    assert_eq!(resp.get_status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = resp.get_header_str("Retry-After").unwrap().parse().unwrap();
+    assert!((1..=120).contains(&retry_after));
+    let body = json(&mut resp);
+    assert_eq!(body["code"], "origin_backoff");
+    assert_eq!(body["origin_status"], 503);
+}
+
+#[test]
+fn sends_the_hosts_canary_share_to_its_alternate_origin() {
+    let mut resp = handle(proxied("https://canary.example/echo?page=2"));
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    assert_eq!(resp.get_header_str("X-Proxy-Canary"), Some("1"));
+    let echo = json(&mut resp);
+    assert_eq!(echo["headers"]["host"], "origin.example");
+    assert_eq!(echo["path"], "/echo?page=2");
+}
+
+#[test]
+fn reaches_origins_behind_an_upstream_proxy() {
+    let mut resp = handle(proxied("https://upstream.example/echo?q=1"));
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    let echo = json(&mut resp);
+    assert_eq!(echo["path"], "/echo?q=1");
+    assert_eq!(echo["headers"]["host"], "upstream.example");
+    assert_eq!(
+        echo["headers"]["x-upstream-url"],
+        "https://upstream.example/echo?q=1"
+    );
+}
+
+#[test]
+fn follows_robots_txt_for_crawling_tenants() {
+    let crawl = |target: &str| {
+        let mut url = url::Url::parse("http://proxy.test/").unwrap();
+        url.query_pairs_mut()
+            .append_pair("key", "crawler.crawler-testing")
+            .append_pair("url", target);
+        handle(Request::get(url))
+    };
+    let mut resp = crawl("https://origin.example/crawl/page");
+    assert_eq!(resp.get_status(), StatusCode::FORBIDDEN);
+    let body = json(&mut resp);
+    assert_eq!(body["code"], "robots_disallowed");
+    assert_eq!(body["user_agent"], "dynserv-test/1.0");
+    assert_eq!(
+        crawl("https://origin.example/docs/manual.pdf").get_status(),
+        StatusCode::FORBIDDEN
+    );
+
+    // The longer allow wins, and only the agent's own group applies
+    assert_eq!(
+        crawl("https://origin.example/crawl/open").get_status(),
+        StatusCode::OK
+    );
+    assert_eq!(
+        crawl("https://origin.example/private/page").get_status(),
+        StatusCode::OK
+    );
+
+    // Tenants that don't crawl aren't held to it
+    let resp = handle(proxied("https://origin.example/crawl/page"));
+    assert_eq!(resp.get_status(), StatusCode::OK);
+}
+
+#[test]
+fn rewrites_link_and_refresh_targets_to_go_through_the_proxy() {
+    // Only routes that ask for it rewrite links, so it's done directly
+    let page = url::Url::parse("https://origin.example/docs/page").unwrap();
+    let proxy = url::Url::parse("http://proxy.test/?key=testing&url=x").unwrap();
+    let via_proxy = |path: &str| {
+        format!(
+            "http://proxy.test/?key=testing&url=https%3A%2F%2Forigin.example{}",
+            path.replace('/', "%2F")
+        )
+    };
+    let mut resp = Response::new()
+        .with_header(
+            "Link",
+            "</style.css>; rel=preload, <http://plain.example/>; rel=next",
+        )
+        .with_header("Refresh", "5;URL='next'")
+        .with_content_type(fastly::mime::TEXT_HTML_UTF_8)
+        .with_body(r#"<meta http-equiv="Refresh" content="0; url=/moved">"#);
+    html::rewrite_links(&mut resp, &page, &proxy);
+
+    let link = format!(
+        "<{}>; rel=preload, <http://plain.example/>; rel=next",
+        via_proxy("/style.css")
+    );
+    assert_eq!(resp.get_header_str("Link"), Some(link.as_str()));
+    let refresh = format!("5; url={}", via_proxy("/docs/next"));
+    assert_eq!(resp.get_header_str("Refresh"), Some(refresh.as_str()));
+    let meta = format!(
+        r#"<meta http-equiv="Refresh" content="0; url={}">"#,
+        via_proxy("/moved")
+    );
+    assert_eq!(resp.into_body_str(), meta);
+}
+
+#[test]
+fn checks_image_parameters_but_needs_a_route_to_use_them() {
+    let with_param = |name: &str, value: &str| {
+        let mut req = proxied("https://origin.example/photo.jpg");
+        let mut url = req.get_url().clone();
+        url.query_pairs_mut().append_pair(name, value);
+        req.set_url(url);
+        handle(req)
+    };
+    let mut resp = with_param("fmt", "bmp");
+    assert_eq!(resp.get_status(), StatusCode::BAD_REQUEST);
+    assert_eq!(json(&mut resp)["code"], "invalid_parameter");
+    assert_eq!(with_param("w", "0").get_status(), StatusCode::BAD_REQUEST);
+
+    // Without an `images` route the image is fetched as it is
+    let mut resp = with_param("w", "320");
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    assert_eq!(json(&mut resp)["path"], "/photo.jpg");
+}
+
+#[test]
+fn converts_jpeg_and_png_images_to_the_newest_format_the_client_takes() {
+    let images: images::Images = serde_json::from_str(r#"{"region": "us_east"}"#).unwrap();
+    let photo = url::Url::parse("https://origin.example/photo.jpg").unwrap();
+    let format = |accept: &str, target: &url::Url| {
+        images
+            .options(None, Some(accept), &Method::GET, target)
+            .and_then(|options| options.format)
+    };
+    assert!(matches!(
+        format("image/avif,image/webp,*/*", &photo),
+        Some(Format::AVIF)
+    ));
+    assert!(matches!(
+        format("image/avif;q=0, image/webp", &photo),
+        Some(Format::WebP)
+    ));
+    // Wildcards don't ask for a newer format
+    assert!(format("image/*", &photo).is_none());
+
+    // GIFs, images the client picked a format for and other methods aren't
+    // converted, nor is anything with negotiation turned off
+    let gif = url::Url::parse("https://origin.example/anim.gif").unwrap();
+    assert!(format("image/avif", &gif).is_none());
+    let png = images::ImageRequest {
+        format: Some(Format::PNG),
+        ..Default::default()
+    };
+    assert!(!images.negotiates(Some(&png), &Method::GET, &photo));
+    assert!(!images.negotiates(None, &Method::POST, &photo));
+    let fixed: images::Images =
+        serde_json::from_str(r#"{"region": "us_east", "negotiate_formats": false}"#).unwrap();
+    assert!(fixed
+        .options(None, Some("image/avif"), &Method::GET, &photo)
+        .is_none());
+}
+
+#[test]
+fn keys_cached_entries_only_by_the_vary_names_the_route_honours() {
+    let policy: cache::CachePolicy =
+        serde_json::from_str(r#"{"ttl_secs": 60, "vary": ["X-Device"]}"#).unwrap();
+    let url = url::Url::parse("https://origin.example/cached/vary").unwrap();
+    let key = cache::key_for("test", &url, "identity");
+    let device = |name: &str| {
+        let req = Request::get(url.as_str()).with_header("X-Device", name);
+        cache::Variant::of(&req, &policy)
+    };
+    let resp = Response::from_body("for phones")
+        .with_header("Vary", "*, Cookie, Accept-Encoding")
+        .with_header("Vary", "X-Device, x-device");
+    let resp = cache::store(
+        key.clone(),
+        resp,
+        &policy,
+        "origin.example",
+        &device("phone"),
+    );
+    let vary: Vec<&str> = resp.get_header_all_str("Vary");
+    assert_eq!(vary, ["accept-encoding, x-device"]);
+
+    let hit = cache::lookup(&key, &Method::GET, &device("phone")).expect("a hit");
+    assert_eq!(hit.into_body_str(), "for phones");
+    assert!(cache::lookup(&key, &Method::GET, &device("desktop")).is_none());
+}
+
+#[test]
+fn overrides_the_clients_cache_control() {
+    let with_cc = |key: &str, cc: &str| {
+        let mut url = url::Url::parse("http://proxy.test/").unwrap();
+        url.query_pairs_mut()
+            .append_pair("key", key)
+            .append_pair("url", "https://origin.example/echo")
+            .append_pair("cc", cc);
+        handle(Request::get(url))
+    };
+    let resp = with_cc(KEY, "public, max-age=31536000, immutable");
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    assert_eq!(
+        resp.get_header_str("Cache-Control"),
+        Some("public, max-age=31536000, immutable")
+    );
+    let mut resp = with_cc(KEY, "max-age=60\u{7f}");
+    assert_eq!(resp.get_status(), StatusCode::BAD_REQUEST);
+    assert_eq!(json(&mut resp)["parameter"], "cc");
+
+    // A tenant's own setting can't be loosened
+    let resp = with_cc("crawler.crawler-testing", "max-age=60");
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    assert_eq!(resp.get_header_str("Cache-Control"), Some("no-store"));
+}
@@ -0,0 +1,132 @@
+"""Mock origin for the integration tests.
+
+Echoes each request back as JSON: its method, path, headers and body.
+`/status/<code>` answers with that status instead, with a `Retry-After` of
+`?retry_after=<secs>` when that's given, `/robots.txt` with
+`ROBOTS` and `/trailers` with a chunked echo whose trailers repeat the
+request's, plus an `X-Echo-Length`. `/last?host=<host>` answers with the
+echo of the last other request sent with that `Host`, for requests whose
+responses the tests never see. Chunked request bodies are read with
+their trailers, which the echo lists under `trailers`. Binds 127.0.0.1:7878,
+which tests/viceroy.toml routes the test origins to, then forks into the
+background and prints the server's process ID.
+"""
+
+import json
+import os
+import sys
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+from urllib.parse import parse_qs, urlsplit
+
+ADDRESS = ("127.0.0.1", 7878)
+
+# The echo of the last request for each Host
+LAST = {}
+
+ROBOTS = b"""User-agent: *
+Disallow: /private/
+
+User-agent: dynserv-test
+Disallow: /crawl/
+Allow: /crawl/open$
+Disallow: /*.pdf$
+"""
+
+
+class Handler(BaseHTTPRequestHandler):
+    protocol_version = "HTTP/1.1"
+
+    def read_chunked(self):
+        body, trailers = b"", {}
+        while True:
+            size = int(self.rfile.readline().split(b";")[0], 16)
+            if size == 0:
+                break
+            body += self.rfile.read(size)
+            self.rfile.readline()
+        while True:
+            line = self.rfile.readline().strip()
+            if not line:
+                return body, trailers
+            name, _, value = line.decode("latin-1").partition(":")
+            trailers[name.strip().lower()] = value.strip()
+
+    def respond(self):
+        trailers = {}
+        if "chunked" in self.headers.get("Transfer-Encoding", "").lower():
+            body, trailers = self.read_chunked()
+        else:
+            body = self.rfile.read(int(self.headers.get("Content-Length") or 0))
+        body = body.decode("utf-8", "replace")
+        if self.path == "/robots.txt":
+            self.send_response(200)
+            self.send_header("Content-Type", "text/plain")
+            self.send_header("Content-Length", str(len(ROBOTS)))
+            self.end_headers()
+            if self.command != "HEAD":
+                self.wfile.write(ROBOTS)
+            return
+        query = parse_qs(urlsplit(self.path).query)
+        if self.path.startswith("/last"):
+            last = LAST.get(query.get("host", [""])[0], b"null")
+            self.send_response(200)
+            self.send_header("Content-Type", "application/json")
+            self.send_header("Content-Length", str(len(last)))
+            self.end_headers()
+            self.wfile.write(last)
+            return
+        status = 200
+        if self.path.startswith("/status/"):
+            status = int(self.path.split("/")[2].split("?")[0])
+        echo = json.dumps({
+            "method": self.command,
+            "path": self.path,
+            "headers": {k.lower(): v for k, v in self.headers.items()},
+            "body": body,
+            "trailers": trailers,
+        }).encode()
+        LAST[self.headers.get("Host", "")] = echo
+        if self.path.startswith("/trailers"):
+            trailers["x-echo-length"] = str(len(echo))
+            self.send_response(status)
+            self.send_header("Content-Type", "application/json")
+            self.send_header("Transfer-Encoding", "chunked")
+            self.send_header("Trailer", ", ".join(trailers))
+            self.end_headers()
+            chunk = b"%x\r\n%s\r\n" % (len(echo), echo)
+            fields = "".join("%s: %s\r\n" % field for field in trailers.items())
+            self.wfile.write(chunk + b"0\r\n" + fields.encode("latin-1") + b"\r\n")
+            return
+        self.send_response(status)
+        retry_after = query.get("retry_after")
+        if retry_after:
+            self.send_header("Retry-After", retry_after[0])
+        self.send_header("Content-Type", "application/json")
+        self.send_header("Content-Length", str(len(echo)))
+        self.end_headers()
+        if self.command != "HEAD":
+            self.wfile.write(echo)
+
+    do_GET = do_HEAD = do_POST = do_PUT = do_PATCH = do_DELETE = do_OPTIONS = respond
+
+    def log_message(self, format, *args):
+        pass
+
+
+def main():
+    ThreadingHTTPServer.allow_reuse_address = True
+    server = ThreadingHTTPServer(ADDRESS, Handler)
+    pid = os.fork()
+    if pid:
+        print(pid)
+        sys.exit(0)
+    os.setsid()
+    # Let the runner's command substitution finish reading the process ID
+    devnull = os.open(os.devnull, os.O_RDWR)
+    for fd in (0, 1, 2):
+        os.dup2(devnull, fd)
+    server.serve_forever()
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,18 @@
+use compute_dynbackends_dev::routes::host_matches;
+
+#[test]
+fn matches_exact_host_patterns_in_any_case() {
+    assert!(host_matches("api.example.com", "api.example.com"));
+    assert!(host_matches("API.Example.com", "api.EXAMPLE.com"));
+    assert!(!host_matches("api.example.com", "www.example.com"));
+}
+
+#[test]
+fn matches_wildcard_host_patterns_in_any_case() {
+    assert!(host_matches("*.example.com", "api.example.com"));
+    assert!(host_matches("*.Example.COM", "api.example.com"));
+    assert!(host_matches("*.example.com", "Deep.API.Example.Com"));
+    // The wildcard needs a label of its own
+    assert!(!host_matches("*.example.com", "example.com"));
+    assert!(!host_matches("*.example.com", "badexample.com"));
+}
@@ -0,0 +1,113 @@
+use compute_dynbackends_dev::ssrf::{self, Rejection};
+use url::Url;
+
+fn validate(url: &str) -> Result<ssrf::Target, Rejection> {
+    ssrf::validate(Url::parse(url).expect("test URLs parse"))
+}
+
+#[test]
+fn accepts_public_https_targets() {
+    let target = validate("https://example.com/path?q=1").unwrap();
+    assert_eq!(target.hostname, "example.com");
+    assert_eq!(target.port, 443);
+    assert_eq!(target.url.as_str(), "https://example.com/path?q=1");
+
+    let target = validate("https://93.184.215.14:8443/").unwrap();
+    assert_eq!(target.hostname, "93.184.215.14");
+    assert_eq!(target.port, 8443);
+}
+
+#[test]
+fn refuses_other_schemes() {
+    for url in [
+        "http://example.com/",
+        "ftp://example.com/",
+        "file:///etc/passwd",
+    ] {
+        assert_eq!(validate(url).unwrap_err(), Rejection::NotHttps, "{}", url);
+    }
+}
+
+#[test]
+fn refuses_private_and_reserved_ipv4() {
+    for url in [
+        "https://127.0.0.1/",
+        "https://10.1.2.3/",
+        "https://172.16.0.1/",
+        "https://192.168.1.1/",
+        "https://169.254.169.254/latest/meta-data/",
+        "https://0.0.0.0/",
+        "https://100.64.0.1/",
+        "https://198.18.0.1/",
+        "https://224.0.0.1/",
+        "https://255.255.255.255/",
+    ] {
+        assert_eq!(
+            validate(url).unwrap_err(),
+            Rejection::PrivateAddress,
+            "{}",
+            url
+        );
+    }
+}
+
+#[test]
+fn refuses_ipv4_in_other_notations() {
+    // The URL parser normalizes these to 127.0.0.1 before they're checked
+    for url in [
+        "https://2130706433/",
+        "https://0x7f.1/",
+        "https://0177.0.0.1/",
+        "https://127.1/",
+    ] {
+        assert_eq!(
+            validate(url).unwrap_err(),
+            Rejection::PrivateAddress,
+            "{}",
+            url
+        );
+    }
+}
+
+#[test]
+fn refuses_private_and_reserved_ipv6() {
+    for url in [
+        "https://[::1]/",
+        "https://[::]/",
+        "https://[fd00::1]/",
+        "https://[fe80::1]/",
+        "https://[ff02::1]/",
+        "https://[::ffff:127.0.0.1]/",
+        "https://[::ffff:a9fe:a9fe]/",
+        "https://[::127.0.0.1]/",
+        "https://[::a00:1]/",
+        "https://[64:ff9b::7f00:1]/",
+        "https://[64:ff9b::a9fe:a9fe]/",
+        "https://[2002:7f00:1::]/",
+        "https://[2002:c0a8:101::1]/",
+        "https://[2001:db8::1]/",
+    ] {
+        assert_eq!(
+            validate(url).unwrap_err(),
+            Rejection::PrivateAddress,
+            "{}",
+            url
+        );
+    }
+}
+
+#[test]
+fn refuses_local_names() {
+    for url in [
+        "https://localhost/",
+        "https://LOCALHOST./",
+        "https://api.localhost/",
+    ] {
+        assert_eq!(
+            validate(url).unwrap_err(),
+            Rejection::PrivateAddress,
+            "{}",
+            url
+        );
+    }
+}
@@ -0,0 +1,8 @@
+#!/bin/sh
+# Cargo's runner for wasm32-wasip1: runs a test binary under Viceroy, with the
+# mock origin listening for as long as it runs.
+set -e
+cd "$(dirname "$0")/.."
+origin=$(python3 tests/mock_origin.py)
+trap 'kill "$origin"' EXIT
+viceroy run -C tests/viceroy.toml -- "$@"